@@ -12,15 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{array, fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 
-use clap::Parser;
-use risc0_zkvm::{Executor, ExecutorEnv};
+use clap::{Parser, Subcommand};
+use risc0_zkvm::{Executor, ExecutorEnv, ExitCode, Program, MEM_SIZE};
 
 /// Runs a RISC-V ELF binary within the RISC Zero ZKVM.
 #[derive(Parser)]
-#[clap(about, version, author)]
+#[clap(about, version, author, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The ELF file to run
     #[clap(long)]
     elf: PathBuf,
@@ -40,12 +48,54 @@ struct Args {
     /// Add environment vairables in the form of NAME=value.
     #[clap(long, action = clap::ArgAction::Append)]
     env: Vec<String>,
+
+    /// Stop the guest after this many cycles if it hasn't halted.
+    #[clap(long)]
+    max_cycles: Option<usize>,
+
+    /// Stop the guest after this many seconds of wall-clock time if it
+    /// hasn't halted.
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Stop the guest if it jumps to the instruction it's at.
+    #[clap(long)]
+    detect_infinite_loops: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the instructions in an ELF's `.text` that can't be decoded,
+    /// without running it.
+    Scan {
+        /// The ELF file to scan
+        #[clap(long)]
+        elf: PathBuf,
+    },
+}
+
+fn scan(elf: &Path) {
+    let elf_contents = fs::read(elf).unwrap();
+    let program = Program::load_elf(&elf_contents, MEM_SIZE as u64).unwrap();
+    let undecodable = program.scan_decodable();
+    for (addr, insn) in undecodable.iter() {
+        println!("0x{addr:08x}: 0x{insn:08x}");
+    }
+    if !undecodable.is_empty() {
+        eprintln!("{} undecodable instructions", undecodable.len());
+        std::process::exit(1);
+    }
 }
 
 fn main() {
     env_logger::init();
 
     let args = Args::parse();
+    if let Some(Command::Scan { elf }) = args.command.as_ref() {
+        scan(elf);
+        return;
+    }
+
     let elf_contents = fs::read(&args.elf).unwrap();
 
     if args.verbose > 0 {
@@ -69,18 +119,54 @@ fn main() {
         builder.stdin(fs::File::open(input).unwrap());
     }
 
+    if let Some(max_cycles) = args.max_cycles {
+        builder.session_limit(max_cycles);
+    }
+
+    builder.detect_infinite_loops(args.detect_infinite_loops);
+
+    if let Some(timeout) = args.timeout {
+        // The executor can't be interrupted from outside, so give up on it
+        // from a watchdog thread
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(timeout));
+            eprintln!("Timed out after {timeout} seconds before the guest halted");
+            std::process::exit(1);
+        });
+    }
+
+    builder.on_error(|record| {
+        eprintln!("error at pc 0x{:08x}: {:?}", record.state.pc, record.error);
+        record
+            .registers
+            .iter()
+            .enumerate()
+            .for_each(|(idx, value)| eprintln!("x{idx}: 0x{value:016x}"));
+    });
+
     let env = builder.build();
     let mut exec = Executor::from_elf(env, &elf_contents).unwrap();
-    let session = match exec.run() {
-        Ok(session) => session,
-        Err(err) => {
-            let regs: [u64; 32] = exec.monitor.load_registers(array::from_fn(|idx| idx));
-            regs.iter().enumerate().for_each(|(idx, value)| {
-                println!("value loaded {:08x}, idx: {:?}", value, idx,);
-            });
-            panic!("error {:?}", err)
+    let session = exec.run().unwrap();
+
+    match session.exit_code {
+        ExitCode::SessionLimit => {
+            let cycles = exec.pause().state().cycle;
+            eprintln!("Session limit reached after {cycles} cycles before the guest halted");
+            std::process::exit(1);
         }
-    };
+        ExitCode::Fault => std::process::exit(1),
+        ExitCode::InfiniteLoop(pc) => {
+            eprintln!("Guest stuck in an infinite loop at pc 0x{pc:08x}");
+            std::process::exit(1);
+        }
+        ExitCode::Aborted(message) => {
+            let message = message.as_deref().unwrap_or("no message");
+            eprintln!("Guest aborted: {message}");
+            std::process::exit(1);
+        }
+        ExitCode::Halted(_) => {}
+        ExitCode::SystemSplit => unreachable!("Session ended with a segment split"),
+    }
 
     // let receipt = session.prove(hal.as_ref(), &eval).unwrap();
 
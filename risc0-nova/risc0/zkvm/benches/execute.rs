@@ -0,0 +1,70 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use risc0_zkvm::{Executor, ExecutorEnv, MemoryImage, Program, PAGE_SIZE};
+
+const ENTRY: u64 = 0x1000;
+
+// Runs 12288 iterations of a loop mixing ALU, memory and branch instructions,
+// so the time is dominated by the per-instruction decode and dispatch.
+//
+//       lui x6, 3
+// loop: addi x5, x5, 1
+//       add x7, x7, x5
+//       sd x7, 0x400(x0)
+//       ld x8, 0x400(x0)
+//       bne x5, x6, loop
+//       addi a7, x0, 0
+//       addi a0, x0, 0
+//       ecall
+const PROGRAM: [u32; 9] = [
+    0x00003337, 0x00128293, 0x005383b3, 0x40703023, 0x40003403, 0xfe6298e3, 0x00000893, 0x00000513,
+    0x00000073,
+];
+
+fn program() -> Program {
+    let image: BTreeMap<u64, u32> = PROGRAM
+        .iter()
+        .enumerate()
+        .map(|(idx, insn)| (ENTRY + idx as u64 * 4, *insn))
+        .collect();
+    let text = ENTRY..ENTRY + PROGRAM.len() as u64 * 4;
+    Program {
+        entry: ENTRY,
+        image,
+        text_ranges: vec![text],
+        data_ranges: Vec::new(),
+        entry_symbol: None,
+    }
+}
+
+fn execute(c: &mut Criterion) {
+    let program = program();
+    c.bench_function("execute_loop", |b| {
+        b.iter_batched(
+            || {
+                let image = MemoryImage::new(&program, PAGE_SIZE as u64);
+                Executor::new(ExecutorEnv::default(), image, ENTRY)
+            },
+            |mut exec| exec.run().unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, execute);
+criterion_main!(benches);
@@ -22,6 +22,12 @@ pub mod ecall {
     pub const HALT: u64 = 0;
     pub const OUTPUT: u64 = 1;
     pub const SOFTWARE: u64 = 2;
+    /// `getenv(name_ptr, name_len, buf_ptr, buf_len)`, copying up to
+    /// `buf_len` bytes of the variable's value to `buf_ptr` and returning the
+    /// full length of the value, or [GETENV_MISSING] if it isn't set
+    pub const GETENV: u64 = 3;
+    /// Returned by [GETENV] for a variable that isn't set
+    pub const GETENV_MISSING: u64 = u64::MAX;
 
     pub const FCNTL: u64 = 25;
     pub const OPEN: u64 = 56;
@@ -40,12 +46,16 @@ pub mod ecall {
     pub const MUNMAP: u64 = 215;
 
     pub const MINCORE: u64 = 232;
+    pub const GETRANDOM: u64 = 278;
 }
 
 pub mod halt {
     pub const TERMINATE: u64 = 0;
     pub const PAUSE: u64 = 1;
     pub const SPLIT: u64 = 2;
+    /// The guest gave up, e.g. on a panic, with `a1` pointing at an optional
+    /// NUL-terminated message or null
+    pub const ABORT: u64 = 3;
 }
 
 pub mod reg_abi {
@@ -13,10 +13,13 @@
 // limitations under the License.
 
 use alloc::collections::BTreeMap;
+use std::ops::Range;
 
 use anyhow::{anyhow, bail, Context, Result};
 use elf::{endian::LittleEndian, file::Class, ElfBytes};
 
+use crate::opcode::OpCode;
+
 /// A RISC Zero program
 pub struct Program {
     /// The entrypoint of the program
@@ -24,11 +27,48 @@ pub struct Program {
 
     /// The initial memory image
     pub image: BTreeMap<u64, u32>,
+
+    /// Addresses covered by executable sections such as `.text`,
+    /// `.text.init` and `.plt`. Taken from the executable `PT_LOAD` segments
+    /// when the ELF has no section headers, and empty if there is neither.
+    pub text_ranges: Vec<Range<u64>>,
+
+    /// Addresses covered by allocated, writable sections such as `.data` and
+    /// `.bss`. Taken from the writable `PT_LOAD` segments when the ELF has no
+    /// section headers.
+    pub data_ranges: Vec<Range<u64>>,
+
+    /// The name of the function symbol at [Program::entry], if any
+    pub entry_symbol: Option<String>,
+}
+
+/// Options for [Program::load_elf_with_options]
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions<'a> {
+    /// Start at the symbol with this name rather than the ELF's entrypoint,
+    /// e.g. to run a single function in isolation
+    pub entry_override: Option<&'a str>,
 }
 
 impl Program {
     /// Initialize a RISC Zero Program from an appropriate ELF file
+    ///
+    /// Every loaded segment must lie within the first `max_mem` bytes of
+    /// memory, otherwise it would be silently dropped when building the
+    /// [MemoryImage](crate::MemoryImage).
     pub fn load_elf(input: &[u8], max_mem: u64) -> Result<Program> {
+        Self::load_elf_with_options(input, max_mem, &LoadOptions::default())
+    }
+
+    /// Initialize a RISC Zero Program from an appropriate ELF file as
+    /// [Program::load_elf] does, configured by `options`
+    ///
+    /// Fails if the entry override doesn't name a symbol in the ELF.
+    pub fn load_elf_with_options(
+        input: &[u8],
+        max_mem: u64,
+        options: &LoadOptions,
+    ) -> Result<Program> {
         let mut image: BTreeMap<u64, u32> = BTreeMap::new();
         let elf = ElfBytes::<LittleEndian>::minimal_parse(input)?;
         if elf.ehdr.class != Class::ELF64 {
@@ -40,7 +80,34 @@ impl Program {
         if elf.ehdr.e_type != elf::abi::ET_EXEC {
             bail!("Invalid ELF type, must be executable");
         }
-        let entry: u64 = elf.ehdr.e_entry.try_into()?;
+        let symbols = elf.symbol_table().context("Failed to read symbol table")?;
+        // Every symbol with its name, empty for a symbol without one
+        let named_symbols = || {
+            symbols.iter().flat_map(|(symtab, strtab)| {
+                symtab
+                    .iter()
+                    .map(|symbol| (strtab.get(symbol.st_name as usize).unwrap_or(""), symbol))
+            })
+        };
+        let (entry, entry_symbol) = match options.entry_override {
+            Some(name) => {
+                let (_, symbol) = named_symbols()
+                    .find(|(symbol_name, _)| *symbol_name == name)
+                    .ok_or_else(|| anyhow!("No symbol {name} to use as the entrypoint"))?;
+                (symbol.st_value, Some(name.to_string()))
+            }
+            None => {
+                let entry = elf.ehdr.e_entry;
+                let entry_symbol = named_symbols()
+                    .find(|(name, symbol)| {
+                        !name.is_empty()
+                            && symbol.st_value == entry
+                            && symbol.st_symtype() == elf::abi::STT_FUNC
+                    })
+                    .map(|(name, _)| name.to_string());
+                (entry, entry_symbol)
+            }
+        };
         if entry >= max_mem || entry % 4 != 0 {
             bail!("Invalid entrypoint");
         }
@@ -48,6 +115,8 @@ impl Program {
         if segments.len() > 256 {
             bail!("Too many program headers");
         }
+        let mut text_ranges = Vec::new();
+        let mut data_ranges = Vec::new();
         for segment in segments.iter().filter(|x| x.p_type == elf::abi::PT_LOAD) {
             let file_size: u64 = segment.p_filesz.try_into()?;
             if file_size >= max_mem {
@@ -59,72 +128,294 @@ impl Program {
             }
             let vaddr: u64 = segment.p_vaddr.try_into()?;
             let offset: u64 = segment.p_offset.try_into()?;
-            for i in (0..mem_size).step_by(4) {
-                let addr = vaddr.checked_add(i).context("Invalid segment vaddr")?;
-                if i >= file_size {
-                    // Past the file size, all zeros.
-                    image.insert(addr, 0);
-                } else {
-                    let mut word = 0;
-                    // Don't read past the end of the file.
-                    let len = std::cmp::min(file_size - i, 4);
-                    for j in 0..len {
-                        let offset = (offset + i + j) as usize;
-                        let byte = input.get(offset).context("Invalid segment offset")?;
-                        word |= (*byte as u32) << (j * 8);
+            let range = vaddr
+                ..vaddr
+                    .checked_add(mem_size)
+                    .context("Invalid segment vaddr")?;
+            if range.end > max_mem {
+                bail!(
+                    "Segment 0x{:08x}..0x{:08x} lies outside memory of 0x{max_mem:x} bytes",
+                    range.start,
+                    range.end
+                );
+            }
+            if segment.p_flags & elf::abi::PF_X != 0 {
+                text_ranges.push(range.clone());
+            }
+            if segment.p_flags & elf::abi::PF_W != 0 {
+                data_ranges.push(range.clone());
+            }
+            // Assemble aligned words byte by byte, so a segment that doesn't
+            // start or end on a word boundary keeps the bytes of its
+            // neighbours in the words it shares with them.
+            for addr in ((vaddr & !0x3)..range.end).step_by(4) {
+                let mut word = image.get(&addr).copied().unwrap_or(0).to_le_bytes();
+                for (byte_addr, byte) in (addr..).zip(word.iter_mut()) {
+                    if !range.contains(&byte_addr) {
+                        continue;
+                    }
+                    let i = byte_addr - vaddr;
+                    *byte = if i < file_size {
+                        let offset = (offset + i) as usize;
+                        *input.get(offset).context("Invalid segment offset")?
+                    } else {
+                        // Past the file size (.bss), all zeros.
+                        0
+                    };
+                }
+                image.insert(addr, u32::from_le_bytes(word));
+            }
+        }
+        // Prefer the more precise section boundaries where they're available,
+        // stripped binaries keep the ranges found from the segments.
+        if let Some(shdrs) = elf.section_headers() {
+            let mut section_text_ranges = Vec::new();
+            let mut section_data_ranges = Vec::new();
+            for shdr in shdrs.iter() {
+                if shdr.sh_flags & elf::abi::SHF_ALLOC as u64 == 0 {
+                    continue;
+                }
+                let start = shdr.sh_addr;
+                let range = start
+                    ..start
+                        .checked_add(shdr.sh_size)
+                        .context("Invalid section size")?;
+                if shdr.sh_flags & elf::abi::SHF_EXECINSTR as u64 != 0 {
+                    if !range.is_empty() {
+                        section_text_ranges.push(range);
                     }
-                    image.insert(addr, word);
+                } else if shdr.sh_flags & elf::abi::SHF_WRITE as u64 != 0 {
+                    section_data_ranges.push(range);
                 }
             }
+            if !section_text_ranges.is_empty() {
+                text_ranges = section_text_ranges;
+                data_ranges = section_data_ranges;
+            }
         }
         // patch below symbols to `ret` assembly
         // refer https://github.com/ethereum-optimism/cannon/blob/32c76db43dc4b5fb25f49ba8fbdb84fed8e5615a/mipsevm/patch.go#L66
-        let (symtab, strtab) = elf
-            .symbol_table()
-            .expect("Failed to read symbol table")
-            .expect("Failed to find strtab table");
-        symtab.iter().for_each(|entry| {
-            let symbol_name = strtab.get(entry.st_name as usize).unwrap();
-            match symbol_name {
-                "runtime.gcenable"
-                | "runtime.init.5"  // patch out: init() { go forcegchelper() }
-                | "runtime.main.func1" // patch out: main.func() { newm(sysmon, ....) }
-                | "runtime.deductSweepCredit" // uses floating point nums and interacts with gc we disabled
-                | "runtime.(*gcControllerState).commit"
-                // these prometheus packages rely on concurrent background things. We cannot run those.
-                | "github.com/prometheus/client_golang/prometheus.init"
-                | "github.com/prometheus/client_golang/prometheus.init.0"
-                | "github.com/prometheus/procfs.init"
-                | "github.com/prometheus/common/model.init"
-                | "github.com/prometheus/client_model/go.init"
-                | "github.com/prometheus/client_model/go.init.0"
-                | "github.com/prometheus/client_model/go.init.1"
-                // skip flag pkg init, we need to debug arg-processing more to see why this fails
-                | "flag.init"
-                | "runtime.fastexprand" // for mcache profiling, got float point inside
-                | "runtime.getRandomData" // we do not need randomness. Besides it got os.open/os.read
-                // We need to patch this out, we don't pass float64nan because we don't support floats
-                | "runtime.initsig" // we dont need init signal since target on baremental env https://github.com/golang/go/blob/512361fb1fa805f10f183e0b96248e523e68c192/src/runtime/signal_unix.go#LL114C6-L114C13
-                | "runtime.check"
-                | "runtime.doInit"  // patch out doInit https://github.com/golang/go/blob/512361fb1fa805f10f183e0b96248e523e68c192/src/runtime/proc.go#L198, since it got float point inside
-                // | "runtime.lock2" // another choice is implement lock, which just need to implement `amoswap.w.aq`,
-                // | "runtime.args"
-                // | "runtime.osinit"
-                // | "runtime.schedinit"
-                => {
-                    println!(
-                        "symbol_name: {:?}, st_value {:08x}, image.get(key): {:08x}",
-                        symbol_name,
-                        entry.st_value,
-                        image.get(&entry.st_value).unwrap(),
-                    );
-                    image.insert(entry.st_value, 0x00008067); // ret, immediate return
-                    ()
+        // Stripped binaries have no symbol table, so there is nothing to patch.
+        if let Some((symtab, strtab)) = &symbols {
+            symtab.iter().for_each(|entry| {
+                let symbol_name = strtab.get(entry.st_name as usize).unwrap();
+                match symbol_name {
+                    "runtime.gcenable"
+                    | "runtime.init.5"  // patch out: init() { go forcegchelper() }
+                    | "runtime.main.func1" // patch out: main.func() { newm(sysmon, ....) }
+                    | "runtime.deductSweepCredit" // uses floating point nums and interacts with gc we disabled
+                    | "runtime.(*gcControllerState).commit"
+                    // these prometheus packages rely on concurrent background things. We cannot run those.
+                    | "github.com/prometheus/client_golang/prometheus.init"
+                    | "github.com/prometheus/client_golang/prometheus.init.0"
+                    | "github.com/prometheus/procfs.init"
+                    | "github.com/prometheus/common/model.init"
+                    | "github.com/prometheus/client_model/go.init"
+                    | "github.com/prometheus/client_model/go.init.0"
+                    | "github.com/prometheus/client_model/go.init.1"
+                    // skip flag pkg init, we need to debug arg-processing more to see why this fails
+                    | "flag.init"
+                    | "runtime.fastexprand" // for mcache profiling, got float point inside
+                    | "runtime.getRandomData" // we do not need randomness. Besides it got os.open/os.read
+                    // We need to patch this out, we don't pass float64nan because we don't support floats
+                    | "runtime.initsig" // we dont need init signal since target on baremental env https://github.com/golang/go/blob/512361fb1fa805f10f183e0b96248e523e68c192/src/runtime/signal_unix.go#LL114C6-L114C13
+                    | "runtime.check"
+                    | "runtime.doInit"  // patch out doInit https://github.com/golang/go/blob/512361fb1fa805f10f183e0b96248e523e68c192/src/runtime/proc.go#L198, since it got float point inside
+                    // | "runtime.lock2" // another choice is implement lock, which just need to implement `amoswap.w.aq`,
+                    // | "runtime.args"
+                    // | "runtime.osinit"
+                    // | "runtime.schedinit"
+                    => {
+                        println!(
+                            "symbol_name: {:?}, st_value {:08x}, image.get(key): {:08x}",
+                            symbol_name,
+                            entry.st_value,
+                            image.get(&entry.st_value).unwrap(),
+                        );
+                        image.insert(entry.st_value, 0x00008067); // ret, immediate return
+                        ()
+                    }
+                    _ => (),
                 }
-                _ => (),
-            }
-        });
+            });
+        }
         // common.symtab.iter().map(|f| f)
-        Ok(Program { entry, image })
+        Ok(Program {
+            entry,
+            image,
+            text_ranges,
+            data_ranges,
+            entry_symbol,
+        })
+    }
+
+    /// The name of the function symbol [Program::entry] points at, e.g.
+    /// `_start`, or `None` if the ELF has no symbol there
+    pub fn entry_symbol(&self) -> Option<&str> {
+        self.entry_symbol.as_deref()
+    }
+
+    /// Find the instructions in `.text` the executor can't decode.
+    ///
+    /// Returns the address and word of every instruction in
+    /// [Program::text_ranges] that would fail to decode if executed, so
+    /// unsupported extensions show up before running the program rather than
+    /// partway through.
+    pub fn scan_decodable(&self) -> Vec<(u64, u32)> {
+        self.text_ranges
+            .iter()
+            .flat_map(|range| self.image.range(range.clone()))
+            .filter(|(addr, insn)| OpCode::decode(**insn, **addr).is_err())
+            .map(|(addr, insn)| (*addr, *insn))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binfmt::test_elf::{insn_bytes, ElfBuilder, RW, RX};
+
+    const TEXT_ADDR: u64 = 0x10000;
+    const DATA_ADDR: u64 = 0x11000;
+    // addi x5, x0, 1
+    // addi x6, x0, 2
+    const TEXT: [u32; 2] = [0x00100293, 0x00200313];
+    const DATA: [u8; 8] = 0x1122334455667788u64.to_le_bytes();
+
+    // A minimal RISC-V executable with `text` in a read/exec segment and 8
+    // bytes of data in a read/write segment at `data_addr`. With sections it
+    // also has a symbol table with a function at each instruction, `_start`
+    // being the entrypoint.
+    fn elf_with(text: &[u32], data_addr: u64, with_sections: bool) -> Vec<u8> {
+        let mut builder = ElfBuilder::new(TEXT_ADDR);
+        builder
+            .segment(RX, TEXT_ADDR, &insn_bytes(text))
+            .segment(RW, data_addr, &DATA);
+        if with_sections {
+            builder
+                .section(".text", elf::abi::SHF_EXECINSTR, TEXT_ADDR, 8)
+                .section(".data", elf::abi::SHF_WRITE, data_addr, 8)
+                .symbol("_start", elf::abi::STT_FUNC, TEXT_ADDR)
+                .symbol("second", elf::abi::STT_FUNC, TEXT_ADDR + 4);
+        }
+        builder.build()
+    }
+
+    fn build_elf(with_sections: bool) -> Vec<u8> {
+        elf_with(&TEXT, DATA_ADDR, with_sections)
+    }
+
+    #[test]
+    fn section_ranges() {
+        let program = Program::load_elf(&build_elf(true), 0x100000).unwrap();
+
+        assert_eq!(program.text_ranges, vec![TEXT_ADDR..TEXT_ADDR + 8]);
+        assert_eq!(program.data_ranges, vec![DATA_ADDR..DATA_ADDR + 8]);
+        assert_eq!(program.image.get(&TEXT_ADDR), Some(&0x00100293));
+    }
+
+    #[test]
+    fn stripped_ranges() {
+        let program = Program::load_elf(&build_elf(false), 0x100000).unwrap();
+
+        assert_eq!(program.text_ranges, vec![TEXT_ADDR..TEXT_ADDR + 8]);
+        assert_eq!(program.data_ranges, vec![DATA_ADDR..DATA_ADDR + 8]);
+    }
+
+    #[test]
+    fn bss_boundary() {
+        let load = |vaddr: u64| {
+            // Load 6 of the data segment's 8 file bytes followed by 10 bytes
+            // of .bss
+            let elf = ElfBuilder::new(TEXT_ADDR)
+                .segment(RX, TEXT_ADDR, &insn_bytes(&TEXT))
+                .bss_segment(RW, vaddr, &DATA[..6], 16)
+                .build();
+            Program::load_elf(&elf, 0x100000).unwrap().image
+        };
+        let words = |image: &BTreeMap<u64, u32>| {
+            image
+                .range(DATA_ADDR..)
+                .map(|(addr, word)| (*addr, *word))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            words(&load(DATA_ADDR)),
+            vec![
+                (DATA_ADDR, 0x55667788),
+                (DATA_ADDR + 4, 0x00003344),
+                (DATA_ADDR + 8, 0),
+                (DATA_ADDR + 12, 0),
+            ]
+        );
+
+        // An unaligned segment still fills whole aligned words
+        assert_eq!(
+            words(&load(DATA_ADDR + 2)),
+            vec![
+                (DATA_ADDR, 0x77880000),
+                (DATA_ADDR + 4, 0x33445566),
+                (DATA_ADDR + 8, 0),
+                (DATA_ADDR + 12, 0),
+                (DATA_ADDR + 16, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_undecodable() {
+        let program = Program::load_elf(&build_elf(true), 0x100000).unwrap();
+        assert_eq!(program.scan_decodable(), vec![]);
+
+        // Replace the second instruction with fadd.d f0, f0, f0
+        let elf = elf_with(&[TEXT[0], 0x02000053], DATA_ADDR, true);
+        let program = Program::load_elf(&elf, 0x100000).unwrap();
+        assert_eq!(program.scan_decodable(), vec![(TEXT_ADDR + 4, 0x02000053)]);
+    }
+
+    #[test]
+    fn entry_symbol() {
+        let program = Program::load_elf(&build_elf(true), 0x100000).unwrap();
+        assert_eq!(program.entry, TEXT_ADDR);
+        assert_eq!(program.entry_symbol(), Some("_start"));
+
+        let stripped = Program::load_elf(&build_elf(false), 0x100000).unwrap();
+        assert_eq!(stripped.entry_symbol(), None);
+
+        let options = LoadOptions {
+            entry_override: Some("second"),
+        };
+        let program = Program::load_elf_with_options(&build_elf(true), 0x100000, &options).unwrap();
+        assert_eq!(program.entry, TEXT_ADDR + 4);
+        assert_eq!(program.entry_symbol(), Some("second"));
+
+        let options = LoadOptions {
+            entry_override: Some("missing"),
+        };
+        let err = Program::load_elf_with_options(&build_elf(true), 0x100000, &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "No symbol missing to use as the entrypoint"
+        );
+        assert!(Program::load_elf_with_options(&build_elf(false), 0x100000, &options).is_err());
+    }
+
+    #[test]
+    fn segment_outside_memory() {
+        let max_mem: u64 = 0x100000;
+        // Move the data segment so it straddles the end of memory
+        let elf = elf_with(&TEXT, max_mem - 4, false);
+
+        let err = Program::load_elf(&elf, max_mem).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Segment 0x000ffffc..0x00100004 lies outside memory of 0x100000 bytes"
+        );
+
+        assert!(Program::load_elf(&elf, max_mem + 4).is_ok());
     }
 }
@@ -11,14 +11,53 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use risc0_zkvm_platform::memory::{MEM_SIZE, PAGE_TABLE};
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+    ops::Range,
+};
+
+use anyhow::{anyhow, bail, Result};
+use risc0_zkvm_platform::{
+    memory::{MEM_SIZE, PAGE_TABLE, SYSTEM},
+    syscall::DIGEST_BYTES,
+    PAGE_SIZE,
+};
 use rrs_lib::{
-    memories::{MemorySpace, VecMemory},
+    memories::{MemorySpace, Permissions, VecMemory},
     MemAccessSize, Memory,
 };
+use sha2::{Digest, Sha256};
 
 use crate::binfmt::elf::Program;
 
+/// The scratch region mapped by [MemoryImage::new], which callers usually
+/// exclude from [MemoryImage::compute_image_id]
+pub const PLAYGROUND: Range<u64> = 0xd000000000..0xd000000000 + MEM_SIZE as u64;
+
+/// Leads every image written by [MemoryImage::serialize]
+const IMAGE_MAGIC: [u8; 8] = *b"R0IMAGE1";
+
+/// Region flags in a serialized image
+const FLAG_READ: u8 = 1;
+const FLAG_WRITE: u8 = 2;
+const FLAG_EXEC: u8 = 4;
+const FLAG_VOLATILE: u8 = 8;
+
+/// The most memory a serialized image may map across its regions: main
+/// memory plus [PLAYGROUND], as [MemoryImage::new] maps
+const MAX_IMAGE_SIZE: u64 = 2 * MEM_SIZE as u64;
+
+fn write_u64(w: &mut impl Write, value: u64) -> Result<()> {
+    Ok(w.write_all(&value.to_le_bytes())?)
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0_u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
 /// Compute `ceil(a / b)` via truncated integer division.
 const fn div_ceil(a: u64, b: u64) -> u64 {
     (a + b - 1) / b
@@ -29,6 +68,87 @@ const fn round_up(a: u64, b: u64) -> u64 {
     div_ceil(a, b) * b
 }
 
+/// The layout of the page table of a [MemoryImage]
+///
+/// Each page of memory is hashed into a digest, the digests are stored in the
+/// pages of the next layer up and so on until a layer fits in a single page,
+/// whose digests form the root. The layers are stored consecutively from the
+/// start of [PAGE_TABLE].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageTableInfo {
+    page_size: u64,
+    num_pages: u64,
+    page_table_size: u64,
+    root_addr: u64,
+    root_idx: u64,
+    num_root_entries: u64,
+}
+
+impl PageTableInfo {
+    /// Compute the page table layout for `mem_size` bytes of memory split into
+    /// pages of `page_size` bytes. `page_size` must be a power of two larger
+    /// than a digest.
+    pub fn new(mem_size: u64, page_size: u64) -> Self {
+        assert!(
+            page_size.is_power_of_two() && page_size > DIGEST_BYTES as u64,
+            "Invalid page size {page_size}"
+        );
+
+        let num_pages = mem_size / page_size;
+        let mut page_table_size = 0;
+        let mut remain = mem_size;
+        loop {
+            remain = remain / page_size * DIGEST_BYTES as u64;
+            page_table_size += remain;
+            if remain <= page_size {
+                break;
+            }
+        }
+
+        let root_addr = PAGE_TABLE.start() as u64 + page_table_size;
+        let root_page_addr = root_addr.saturating_sub(page_size);
+
+        Self {
+            page_size,
+            num_pages,
+            page_table_size,
+            root_addr,
+            root_idx: root_page_addr / page_size,
+            num_root_entries: remain / DIGEST_BYTES as u64,
+        }
+    }
+
+    /// The size of a page in bytes
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// The number of pages of memory covered by the page table
+    pub fn num_pages(&self) -> u64 {
+        self.num_pages
+    }
+
+    /// The total size in bytes of all layers of the page table
+    pub fn page_table_size(&self) -> u64 {
+        self.page_table_size
+    }
+
+    /// The address just past the page table, where the root digest goes
+    pub fn root_addr(&self) -> u64 {
+        self.root_addr
+    }
+
+    /// The index of the page holding the root layer of the page table
+    pub fn root_idx(&self) -> u64 {
+        self.root_idx
+    }
+
+    /// The number of digests in the root layer of the page table
+    pub fn num_root_entries(&self) -> u64 {
+        self.num_root_entries
+    }
+}
+
 /// An image of a zkVM guest's memory
 ///
 /// This is an image of the full memory state of the zkVM, including the data,
@@ -40,6 +160,9 @@ pub struct MemoryImage {
 
     /// memorySpace to support memory segment across different region
     pub memory_space: MemorySpace,
+
+    /// Metadata about the structure of the page table
+    pub info: PageTableInfo,
 }
 
 impl MemoryImage {
@@ -48,31 +171,570 @@ impl MemoryImage {
     /// The result is a MemoryImage with the ELF of `program` loaded (but
     /// execution not yet begun), and with the page table Merkle tree
     /// constructed.
+    ///
+    /// The [text ranges](Program::text_ranges) of `program` are mapped
+    /// read/execute and the rest of memory, including the register file,
+    /// read/write. If `program` has no text ranges all of memory is left
+    /// unrestricted.
     pub fn new(program: &Program, page_size: u64) -> Self {
+        Self::with_mem_size(program, page_size, MEM_SIZE as u64)
+    }
+
+    /// Construct the initial memory image for `program` as [MemoryImage::new]
+    /// does, but with `mem_size` bytes of main memory rather than [MEM_SIZE].
+    ///
+    /// `mem_size` must be 8-byte aligned. If it leaves out any of [SYSTEM],
+    /// which holds the register file, that part is mapped on its own so the
+    /// executor still works. The default stack at the top of [MEM_SIZE] isn't,
+    /// so a stack within `mem_size` needs setting up.
+    ///
+    /// Panics if any of `program` lies outside of `mem_size`, as
+    /// [Program::load_elf] with a `max_mem` of `mem_size` rules out.
+    pub fn with_mem_size(program: &Program, page_size: u64, mem_size: u64) -> Self {
         // let mut buf = vec![0_u8; MEM_SIZE];
 
+        assert_eq!(mem_size % 8, 0, "Unaligned memory size {mem_size}");
+        // The text ranges widened to whole doublewords, merging any that then
+        // touch
+        let mut ranges: Vec<_> = program
+            .text_ranges
+            .iter()
+            .map(|range| (range.start & !0x7).min(mem_size)..round_up(range.end, 8).min(mem_size))
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        let mut text: Vec<Range<u64>> = Vec::new();
+        for range in ranges {
+            match text.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => text.push(range),
+            }
+        }
+        let regions = if text.is_empty() {
+            vec![(0, mem_size, Permissions::ALL)]
+        } else {
+            let mut regions = Vec::new();
+            let mut start = 0;
+            for range in text {
+                regions.push((start, range.start, Permissions::READ_WRITE));
+                regions.push((range.start, range.end, Permissions::READ_EXECUTE));
+                start = range.end;
+            }
+            regions.push((start, mem_size, Permissions::READ_WRITE));
+            regions
+        };
+        let regions: Vec<_> = regions
+            .into_iter()
+            .filter(|(start, end, _)| start < end)
+            .collect();
+
         let mut memory_space = MemorySpace::new();
+        for (start, end, perms) in regions.iter() {
+            let size = end - start;
+            let _ = memory_space
+                .add_memory_with_perms(
+                    *start,
+                    size,
+                    Box::new(VecMemory::new(vec![0_u64; size as usize / 8])),
+                    *perms,
+                )
+                .unwrap();
+        }
+        // Load the ELF into the memory image. This goes directly to the
+        // backing memories as `.text` isn't writable through `memory_space`.
+        if let Err(err) = memory_space.load_image(&program.image) {
+            panic!("Program doesn't fit in 0x{mem_size:x} bytes of memory: {err:?}");
+        }
         let _ = memory_space
             .add_memory(
-                0,
-                MEM_SIZE as u64,
+                PLAYGROUND.start,
+                PLAYGROUND.end - PLAYGROUND.start,
                 Box::new(VecMemory::new(vec![0_u64; MEM_SIZE / 8])),
             )
             .unwrap();
-        // Load the ELF into the memory image.
-        let program_region = memory_space.get_memory_mut::<VecMemory>(0).unwrap();
-        for (addr, data) in program.image.iter() {
-            program_region.write_mem(*addr, MemAccessSize::Word, u64::from(*data));
+        // Map whatever part of the register file `mem_size` leaves out
+        let system_start = (SYSTEM.start() as u64).max(mem_size);
+        let system_end = SYSTEM.end() as u64;
+        if system_start < system_end {
+            let size = system_end - system_start;
+            let _ = memory_space
+                .add_memory_with_perms(
+                    system_start,
+                    size,
+                    Box::new(VecMemory::new(vec![0_u64; size as usize / 8])),
+                    Permissions::READ_WRITE,
+                )
+                .unwrap();
         }
-        // add memory region `0xd000000000` as playground
-        let _ = memory_space
-            .add_memory(
-                0xd000000000,
-                MEM_SIZE as u64,
-                Box::new(VecMemory::new(vec![0_u64; MEM_SIZE / 8])),
+        Self {
+            memory_space,
+            info: PageTableInfo::new(mem_size, page_size),
+        }
+    }
+
+    /// Construct a memory image from a flat buffer
+    ///
+    /// The result has a single region of `total_size` bytes at `base`, with
+    /// `data` copied to its start and the remainder zero-filled. Both `base`
+    /// and `total_size` must be 8-byte aligned. The page table layout is
+    /// computed for `total_size` bytes of [PAGE_SIZE] pages.
+    pub fn from_bytes(base: u64, data: &[u8], total_size: u64) -> Result<Self> {
+        if data.len() as u64 > total_size {
+            bail!(
+                "Image data of {} bytes exceeds region size of {total_size} bytes",
+                data.len()
+            );
+        }
+
+        let mut words = vec![0_u64; (total_size / 8) as usize];
+        for (word, chunk) in words.iter_mut().zip(data.chunks(8)) {
+            let mut bytes = [0_u8; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = u64::from_le_bytes(bytes);
+        }
+
+        let mut memory_space = MemorySpace::new();
+        memory_space
+            .add_memory(base, total_size, Box::new(VecMemory::new(words)))
+            .map_err(|err| anyhow!("Invalid image region: {err:?}"))?;
+
+        Ok(Self {
+            memory_space,
+            info: PageTableInfo::new(total_size, PAGE_SIZE as u64),
+        })
+    }
+
+    /// Compute the image ID, a SHA-256 digest of the bounds and contents of
+    /// every region in [MemoryImage::memory_space]
+    ///
+    /// Bytes within `exclude` hash as zero, so writes to scratch memory such
+    /// as [PLAYGROUND] leave the ID unchanged. A region wholly within
+    /// `exclude` only contributes its bounds.
+    ///
+    /// Regions backed by a [VecMemory] are hashed straight from their words,
+    /// a page at a time, rather than read through
+    /// [MemoryImage::memory_space] a doubleword at a time.
+    pub fn compute_image_id(&mut self, exclude: &[Range<u64>]) -> [u8; DIGEST_BYTES] {
+        let mut regions: Vec<_> = self
+            .memory_space
+            .regions()
+            .iter()
+            .enumerate()
+            .map(|(idx, region)| (region.base, region.base + region.size, idx))
+            .collect();
+        regions.sort();
+
+        let mut hasher = Sha256::new();
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        for (start, end, idx) in regions {
+            hasher.update(start.to_le_bytes());
+            hasher.update(end.to_le_bytes());
+            if exclude
+                .iter()
+                .any(|range| range.start <= start && end <= range.end)
+            {
+                continue;
+            }
+
+            let backed = self.memory_space.regions()[idx].perms.read
+                && matches!(
+                    self.memory_space.get_memory_ref::<VecMemory>(idx),
+                    Some(memory) if memory.mem.len() as u64 * 8 == end - start
+                );
+            let words = if backed {
+                let memory = self.memory_space.get_memory_ref::<VecMemory>(idx).unwrap();
+                Cow::Borrowed(memory.mem.as_slice())
+            } else {
+                Cow::Owned(
+                    (start..end)
+                        .step_by(8)
+                        .map(|addr| {
+                            self.memory_space
+                                .read_mem(addr, MemAccessSize::DoubleWord)
+                                .unwrap_or(0)
+                        })
+                        .collect(),
+                )
+            };
+            for (page_start, chunk) in (start..)
+                .step_by(PAGE_SIZE)
+                .zip(words.chunks(PAGE_SIZE / 8))
+            {
+                page.clear();
+                page.extend(chunk.iter().flat_map(|word| word.to_le_bytes()));
+                let page_end = page_start + page.len() as u64;
+                for range in exclude {
+                    let zero_start = range.start.clamp(page_start, page_end);
+                    let zero_end = range.end.clamp(page_start, page_end);
+                    if zero_start < zero_end {
+                        page[(zero_start - page_start) as usize..(zero_end - page_start) as usize]
+                            .fill(0);
+                    }
+                }
+                hasher.update(&page);
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    /// Write this image to `w` so that [MemoryImage::deserialize] can
+    /// reconstruct it, e.g. in another process
+    ///
+    /// The format is a header with the page table layout and region count,
+    /// then each region's base, size, and permissions followed by its non-zero
+    /// pages, so the mostly empty images built by [MemoryImage::new] stay
+    /// small. All integers are little-endian. Every region must be backed by a
+    /// [VecMemory].
+    pub fn serialize(&self, w: &mut impl Write) -> Result<()> {
+        let page_size = self.info.page_size();
+        w.write_all(&IMAGE_MAGIC)?;
+        write_u64(w, page_size)?;
+        write_u64(w, self.info.num_pages() * page_size)?;
+        write_u64(w, self.memory_space.regions().len() as u64)?;
+
+        let page_words = (page_size / 8) as usize;
+        for (idx, region) in self.memory_space.regions().iter().enumerate() {
+            let memory = self
+                .memory_space
+                .get_memory_ref::<VecMemory>(idx)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Region at 0x{:x} isn't backed by a VecMemory and can't be serialized",
+                        region.base
+                    )
+                })?;
+            let flags = [
+                (region.perms.read, FLAG_READ),
+                (region.perms.write, FLAG_WRITE),
+                (region.perms.exec, FLAG_EXEC),
+                (region.volatile, FLAG_VOLATILE),
+            ]
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .fold(0, |flags, (_, flag)| flags | flag);
+
+            let pages: Vec<_> = memory
+                .mem
+                .chunks(page_words)
+                .enumerate()
+                .filter(|(_, page)| page.iter().any(|word| *word != 0))
+                .collect();
+
+            write_u64(w, region.base)?;
+            write_u64(w, region.size)?;
+            w.write_all(&[flags])?;
+            write_u64(w, pages.len() as u64)?;
+            for (page_idx, page) in pages {
+                write_u64(w, page_idx as u64 * page_size)?;
+                write_u64(w, page.len() as u64 * 8)?;
+                for word in page {
+                    write_u64(w, *word)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read an image written by [MemoryImage::serialize]
+    ///
+    /// Fails without allocating if the page table covers more than
+    /// [MEM_SIZE] bytes, or the regions map more than main memory and
+    /// [PLAYGROUND] together, as only non-zero pages are stored and the size
+    /// of the input says nothing of the memory it asks for.
+    pub fn deserialize(r: &mut impl Read) -> Result<Self> {
+        let mut magic = [0_u8; 8];
+        r.read_exact(&mut magic)?;
+        if magic != IMAGE_MAGIC {
+            bail!("Not a serialized memory image");
+        }
+
+        let page_size = read_u64(r)?;
+        let mem_size = read_u64(r)?;
+        if !page_size.is_power_of_two() || page_size <= DIGEST_BYTES as u64 {
+            bail!("Invalid page size {page_size}");
+        }
+        if mem_size > MEM_SIZE as u64 {
+            bail!("Memory size {mem_size} exceeds {MEM_SIZE}");
+        }
+
+        let mut memory_space = MemorySpace::new();
+        let mut mapped = 0_u64;
+        for _ in 0..read_u64(r)? {
+            let base = read_u64(r)?;
+            let size = read_u64(r)?;
+            let mut flags = [0_u8];
+            r.read_exact(&mut flags)?;
+            let flags = flags[0];
+            if size % 8 != 0 {
+                bail!("Region at 0x{base:x} has unaligned size {size}");
+            }
+            mapped = match mapped.checked_add(size) {
+                Some(mapped) if mapped <= MAX_IMAGE_SIZE => mapped,
+                _ => bail!("Region at 0x{base:x} maps more than {MAX_IMAGE_SIZE} bytes in total"),
+            };
+
+            let mut words = vec![0_u64; (size / 8) as usize];
+            for _ in 0..read_u64(r)? {
+                let offset = read_u64(r)?;
+                let len = read_u64(r)?;
+                if offset % 8 != 0 || len % 8 != 0 || offset.saturating_add(len) > size {
+                    bail!("Page at offset 0x{offset:x} is outside of region at 0x{base:x}");
+                }
+                let start = (offset / 8) as usize;
+                for word in &mut words[start..start + (len / 8) as usize] {
+                    *word = read_u64(r)?;
+                }
+            }
+
+            let memory = Box::new(VecMemory::new(words));
+            let result = if flags & FLAG_VOLATILE != 0 {
+                memory_space.add_volatile_memory(base, size, memory)
+            } else {
+                let perms = Permissions {
+                    read: flags & FLAG_READ != 0,
+                    write: flags & FLAG_WRITE != 0,
+                    exec: flags & FLAG_EXEC != 0,
+                };
+                memory_space.add_memory_with_perms(base, size, memory, perms)
+            };
+            result.map_err(|err| anyhow!("Invalid image region: {err:?}"))?;
+        }
+
+        Ok(Self {
+            memory_space,
+            info: PageTableInfo::new(mem_size, page_size),
+        })
+    }
+
+    /// The bounds of every region in [MemoryImage::memory_space], sorted by
+    /// address
+    fn region_bounds(&self) -> Vec<Range<u64>> {
+        let mut regions: Vec<_> = self
+            .memory_space
+            .regions()
+            .iter()
+            .map(|region| region.base..region.base + region.size)
+            .collect();
+        regions.sort_by_key(|region| region.start);
+        regions
+    }
+
+    /// Compare this image against `other`, returning `(addr, before, after)`
+    /// for each 32-bit word that differs, `before` being the value in this
+    /// image
+    ///
+    /// Fails if the two images don't map the same regions.
+    pub fn diff(&mut self, other: &mut MemoryImage) -> Result<Vec<(u64, u64, u64)>> {
+        let regions = self.region_bounds();
+        let other_regions = other.region_bounds();
+        if regions != other_regions {
+            bail!("Images have different regions: {regions:x?} and {other_regions:x?}");
+        }
+
+        let mut diffs = Vec::new();
+        for region in regions {
+            for addr in region.step_by(8) {
+                let before = self.memory_space.read_mem(addr, MemAccessSize::DoubleWord);
+                let after = other.memory_space.read_mem(addr, MemAccessSize::DoubleWord);
+                if before == after {
+                    continue;
+                }
+
+                let (before, after) = (before.unwrap_or(0), after.unwrap_or(0));
+                for shift in [0, 32] {
+                    let (before, after) = ((before >> shift) as u32, (after >> shift) as u32);
+                    if before != after {
+                        diffs.push((addr + shift / 8, before as u64, after as u64));
+                    }
+                }
+            }
+        }
+        Ok(diffs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use risc0_zkvm_platform::{
+        memory::{MEM_SIZE, SYSTEM},
+        PAGE_SIZE,
+    };
+    use rrs_lib::{
+        memories::{Permissions, VecMemory},
+        MemAccessSize, Memory,
+    };
+
+    use super::{MemoryImage, FLAG_READ, IMAGE_MAGIC};
+    use crate::binfmt::elf::Program;
+
+    #[test]
+    fn from_bytes() {
+        let data = [0x13, 0x05, 0x10, 0x00, 0xaa];
+        let mut image = MemoryImage::from_bytes(0x1000, &data, 0x100).unwrap();
+        let mem = &mut image.memory_space;
+
+        assert_eq!(mem.read_mem(0x1000, MemAccessSize::Word), Some(0x00100513));
+        assert_eq!(mem.read_mem(0x1004, MemAccessSize::Word), Some(0xaa));
+        assert_eq!(mem.read_mem(0x10f8, MemAccessSize::Word), Some(0));
+        assert_eq!(mem.read_mem(0x1100, MemAccessSize::Word), None);
+
+        assert!(MemoryImage::from_bytes(0x1000, &data, 4).is_err());
+        assert!(MemoryImage::from_bytes(0x1001, &data, 0x100).is_err());
+    }
+
+    #[test]
+    fn page_table_info() {
+        let text = 0x1000..0x1004;
+        let program = Program {
+            entry: 0x1000,
+            image: BTreeMap::from([(0x1000, 0x00000073)]),
+            text_ranges: vec![text],
+            data_ranges: Vec::new(),
+            entry_symbol: None,
+        };
+
+        for page_size in [PAGE_SIZE as u64, 4096] {
+            let image = MemoryImage::new(&program, page_size);
+            let info = &image.info;
+            assert_eq!(info.page_size(), page_size);
+            assert_eq!(info.num_pages(), MEM_SIZE as u64 / page_size);
+            assert!(info.num_root_entries() * 32 <= page_size);
+            assert_eq!(info.root_idx(), (info.root_addr() - page_size) / page_size);
+        }
+
+        // 2^28 bytes of 1KiB pages hash to layers of 2^23, 2^18, 2^13 and 2^8
+        // bytes, the last holding 8 root digests
+        let info = MemoryImage::new(&program, PAGE_SIZE as u64).info;
+        assert_eq!(
+            info.page_table_size(),
+            (1 << 23) + (1 << 18) + (1 << 13) + (1 << 8)
+        );
+        assert_eq!(info.num_root_entries(), 8);
+    }
+
+    #[test]
+    fn image_id_excludes_scratch() {
+        let mut image = MemoryImage::from_bytes(0x1000, &[1, 2, 3, 4], 0x100).unwrap();
+        image
+            .memory_space
+            .add_memory(0x2000, 0x100, Box::new(VecMemory::new(vec![0; 0x20])))
+            .unwrap();
+        let exclude = [0x1081..0x1083, 0x2000..0x2100];
+        let id = image.compute_image_id(&exclude);
+        let full_id = image.compute_image_id(&[]);
+
+        let mem = &mut image.memory_space;
+        assert!(mem.write_mem(0x2008, MemAccessSize::DoubleWord, 0x1234));
+        assert!(mem.write_mem(0x1082, MemAccessSize::Byte, 0xff));
+        assert_eq!(image.compute_image_id(&exclude), id);
+        assert_ne!(image.compute_image_id(&[]), full_id);
+
+        assert!(image
+            .memory_space
+            .write_mem(0x1083, MemAccessSize::Byte, 0xff));
+        assert_ne!(image.compute_image_id(&exclude), id);
+    }
+
+    #[test]
+    fn diff() {
+        let data = [1, 2, 3, 4];
+        let mut before = MemoryImage::from_bytes(0x1000, &data, 0x100).unwrap();
+        let mut after = MemoryImage::from_bytes(0x1000, &data, 0x100).unwrap();
+        assert_eq!(before.diff(&mut after).unwrap(), vec![]);
+
+        assert!(after
+            .memory_space
+            .write_mem(0x1044, MemAccessSize::Word, 0xdeadbeef));
+        assert_eq!(
+            before.diff(&mut after).unwrap(),
+            vec![(0x1044, 0, 0xdeadbeef)]
+        );
+        assert_eq!(
+            after.diff(&mut before).unwrap(),
+            vec![(0x1044, 0xdeadbeef, 0)]
+        );
+
+        let mut moved = MemoryImage::from_bytes(0x2000, &data, 0x100).unwrap();
+        assert!(before.diff(&mut moved).is_err());
+    }
+
+    #[test]
+    fn custom_mem_size() {
+        let text = 0x1000..0x1004;
+        let program = Program {
+            entry: 0x1000,
+            image: BTreeMap::from([(0x1000, 0x00000073)]),
+            text_ranges: vec![text],
+            data_ranges: Vec::new(),
+            entry_symbol: None,
+        };
+        let mem_size = 0x10_0000;
+        let mut image = MemoryImage::with_mem_size(&program, PAGE_SIZE as u64, mem_size);
+        assert_eq!(image.info.num_pages(), mem_size / PAGE_SIZE as u64);
+
+        let mem = &mut image.memory_space;
+        assert_eq!(mem.read_insn(0x1000), Some(0x73));
+        assert!(mem.write_mem(mem_size - 8, MemAccessSize::DoubleWord, 1));
+        assert!(!mem.write_mem(mem_size, MemAccessSize::Byte, 1));
+        assert!(!mem.write_mem(MEM_SIZE as u64 - 8, MemAccessSize::DoubleWord, 1));
+        // The register file stays mapped
+        assert!(mem.write_mem(SYSTEM.start() as u64, MemAccessSize::DoubleWord, 1));
+        assert!(!mem.is_mapped(SYSTEM.end() as u64));
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let data = [0x13, 0x05, 0x10, 0x00, 0xaa];
+        let mut image = MemoryImage::from_bytes(0x1000, &data, 0x10_0000).unwrap();
+        let mut text = vec![0_u64; 0x100];
+        text[0x20] = 0x0000_0073_0010_0513;
+        image
+            .memory_space
+            .add_memory_with_perms(
+                0x20_0000,
+                0x800,
+                Box::new(VecMemory::new(text)),
+                Permissions::READ_EXECUTE,
             )
             .unwrap();
-        // Compute the page table hashes except for the very last root hash.
-        Self { memory_space }
+        assert!(image
+            .memory_space
+            .write_mem(0x8_0004, MemAccessSize::Word, 0xdeadbeef));
+
+        let mut buf = Vec::new();
+        image.serialize(&mut buf).unwrap();
+        // Only the three non-zero pages are written out.
+        assert!(buf.len() < 4 * PAGE_SIZE);
+
+        let mut loaded = MemoryImage::deserialize(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.info, image.info);
+        assert_eq!(image.diff(&mut loaded).unwrap(), vec![]);
+        let mem = &mut loaded.memory_space;
+        assert_eq!(mem.read_mem(0x1000, MemAccessSize::Word), Some(0x00100513));
+        assert_eq!(
+            mem.read_mem(0x8_0004, MemAccessSize::Word),
+            Some(0xdeadbeef)
+        );
+        assert_eq!(mem.read_insn(0x20_0104), Some(0x73));
+        assert!(!mem.write_mem(0x20_0100, MemAccessSize::Word, 0));
+        assert_eq!(mem.regions()[1].perms, Permissions::READ_EXECUTE);
+
+        assert!(MemoryImage::deserialize(&mut &buf[..buf.len() - 1]).is_err());
+        assert!(MemoryImage::deserialize(&mut &b"not an image"[..]).is_err());
+
+        // A header claiming a huge region is rejected before it's allocated
+        let mut huge = IMAGE_MAGIC.to_vec();
+        for value in [PAGE_SIZE as u64, MEM_SIZE as u64, 1, 0, !0x7] {
+            huge.extend(value.to_le_bytes());
+        }
+        huge.push(FLAG_READ);
+        huge.extend(0_u64.to_le_bytes());
+        assert!(MemoryImage::deserialize(&mut huge.as_slice()).is_err());
+
+        let mut too_big = buf.clone();
+        too_big[16..24].copy_from_slice(&(2 * MEM_SIZE as u64).to_le_bytes());
+        assert!(MemoryImage::deserialize(&mut too_big.as_slice()).is_err());
     }
 }
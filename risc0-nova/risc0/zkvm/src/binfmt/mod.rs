@@ -16,3 +16,5 @@
 
 pub(crate) mod elf;
 pub(crate) mod image;
+#[cfg(test)]
+pub(crate) mod test_elf;
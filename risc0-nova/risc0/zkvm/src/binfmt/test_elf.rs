@@ -0,0 +1,299 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal RISC-V executables for tests, built in memory rather than checked
+//! in.
+
+use elf::abi;
+
+/// Flags of a read/exec segment
+pub(crate) const RX: u32 = abi::PF_R | abi::PF_X;
+/// Flags of a read/write segment
+pub(crate) const RW: u32 = abi::PF_R | abi::PF_W;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+struct Segment {
+    flags: u32,
+    vaddr: u64,
+    data: Vec<u8>,
+    mem_size: u64,
+}
+
+struct Section {
+    name: &'static str,
+    flags: u64,
+    addr: u64,
+    size: u64,
+}
+
+struct Symbol {
+    name: &'static str,
+    sym_type: u8,
+    value: u64,
+}
+
+/// Builds a 64-bit RISC-V executable from `PT_LOAD` segments and, optionally,
+/// sections and global symbols over them.
+///
+/// The file is the headers followed by each segment's bytes, then, if there
+/// are any sections or symbols, the symbol and string tables and the section
+/// headers.
+pub(crate) struct ElfBuilder {
+    entry: u64,
+    segments: Vec<Segment>,
+    sections: Vec<Section>,
+    symbols: Vec<Symbol>,
+}
+
+/// The little-endian bytes of `insns`.
+pub(crate) fn insn_bytes(insns: &[u32]) -> Vec<u8> {
+    insns.iter().flat_map(|insn| insn.to_le_bytes()).collect()
+}
+
+fn put(elf: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    if elf.len() < offset + bytes.len() {
+        elf.resize(offset + bytes.len(), 0);
+    }
+    elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn fields(fields: &[(u64, usize)]) -> Vec<u8> {
+    fields
+        .iter()
+        .flat_map(|(value, size)| value.to_le_bytes()[..*size].to_vec())
+        .collect()
+}
+
+// A string table of `names`, with the offset of each
+fn string_table<'n>(names: impl Iterator<Item = &'n str>) -> (Vec<u8>, Vec<u64>) {
+    let mut table = vec![0];
+    let offsets = names
+        .map(|name| {
+            let offset = table.len() as u64;
+            table.extend(name.as_bytes());
+            table.push(0);
+            offset
+        })
+        .collect();
+    (table, offsets)
+}
+
+impl ElfBuilder {
+    /// An executable starting at `entry`, with nothing in it yet.
+    pub(crate) fn new(entry: u64) -> Self {
+        Self {
+            entry,
+            segments: Vec::new(),
+            sections: Vec::new(),
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Load `data` at `vaddr` with the `PF_*` `flags`.
+    pub(crate) fn segment(&mut self, flags: u32, vaddr: u64, data: &[u8]) -> &mut Self {
+        self.bss_segment(flags, vaddr, data, data.len() as u64)
+    }
+
+    /// Load `data` at `vaddr` zero-filled to `mem_size` bytes.
+    pub(crate) fn bss_segment(
+        &mut self,
+        flags: u32,
+        vaddr: u64,
+        data: &[u8],
+        mem_size: u64,
+    ) -> &mut Self {
+        self.segments.push(Segment {
+            flags,
+            vaddr,
+            data: data.to_vec(),
+            mem_size,
+        });
+        self
+    }
+
+    /// Add an allocated `SHT_PROGBITS` section covering `size` bytes at
+    /// `addr`, with `SHF_ALLOC` added to the `SHF_*` `flags`.
+    pub(crate) fn section(
+        &mut self,
+        name: &'static str,
+        flags: u32,
+        addr: u64,
+        size: u64,
+    ) -> &mut Self {
+        self.sections.push(Section {
+            name,
+            flags: (flags | abi::SHF_ALLOC) as u64,
+            addr,
+            size,
+        });
+        self
+    }
+
+    /// Add a global symbol of the `STT_*` `sym_type` at `value`, in whichever
+    /// section covers it.
+    pub(crate) fn symbol(&mut self, name: &'static str, sym_type: u8, value: u64) -> &mut Self {
+        self.symbols.push(Symbol {
+            name,
+            sym_type,
+            value,
+        });
+        self
+    }
+
+    /// The executable's bytes.
+    pub(crate) fn build(&self) -> Vec<u8> {
+        let mut elf = Vec::new();
+
+        // Segment data follows the program headers, each 8-byte aligned
+        let mut offset = EHDR_SIZE + PHDR_SIZE * self.segments.len();
+        let mut segment_offsets = Vec::new();
+        for (idx, segment) in self.segments.iter().enumerate() {
+            offset = (offset + 7) & !7;
+            segment_offsets.push(offset as u64);
+            let phdr = fields(&[
+                (abi::PT_LOAD as u64, 4),
+                (segment.flags as u64, 4),
+                (offset as u64, 8),
+                (segment.vaddr, 8),
+                (segment.vaddr, 8),
+                (segment.data.len() as u64, 8),
+                (segment.mem_size, 8),
+                (0x1000, 8),
+            ]);
+            put(&mut elf, EHDR_SIZE + PHDR_SIZE * idx, &phdr);
+            put(&mut elf, offset, &segment.data);
+            offset += segment.data.len();
+        }
+
+        let (shoff, shnum, shstrndx) = if self.sections.is_empty() && self.symbols.is_empty() {
+            (0, 0, 0)
+        } else {
+            self.put_sections(&mut elf, &segment_offsets)
+        };
+
+        let mut ehdr = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        ehdr.extend(fields(&[
+            (abi::ET_EXEC as u64, 2),
+            (abi::EM_RISCV as u64, 2),
+            (1, 4),
+            (self.entry, 8),
+            (EHDR_SIZE as u64, 8),
+            (shoff as u64, 8),
+            (0, 4),
+            (EHDR_SIZE as u64, 2),
+            (PHDR_SIZE as u64, 2),
+            (self.segments.len() as u64, 2),
+            (SHDR_SIZE as u64, 2),
+            (shnum as u64, 2),
+            (shstrndx as u64, 2),
+        ]));
+        put(&mut elf, 0, &ehdr);
+
+        elf
+    }
+
+    // Append the sections, then `.symtab`, `.strtab` and `.shstrtab`, and
+    // their headers, returning the offset of the headers, their number and
+    // the index of `.shstrtab`.
+    fn put_sections(&self, elf: &mut Vec<u8>, segment_offsets: &[u64]) -> (usize, usize, usize) {
+        let file_offset = |addr: u64| {
+            self.segments
+                .iter()
+                .zip(segment_offsets)
+                .find(|(segment, _)| {
+                    (segment.vaddr..segment.vaddr + segment.data.len() as u64).contains(&addr)
+                })
+                .map_or(0, |(segment, offset)| offset + addr - segment.vaddr)
+        };
+        let symtab_idx = self.sections.len() + 1;
+        let shstrndx = symtab_idx + 2;
+
+        let (strtab, symbol_names) = string_table(self.symbols.iter().map(|symbol| symbol.name));
+        let mut symtab = vec![0; SYMBOL_SIZE];
+        for (symbol, name) in self.symbols.iter().zip(symbol_names) {
+            let shndx = self
+                .sections
+                .iter()
+                .position(|section| {
+                    (section.addr..section.addr + section.size).contains(&symbol.value)
+                })
+                .map_or(abi::SHN_ABS as u64, |idx| idx as u64 + 1);
+            symtab.extend(fields(&[
+                (name, 4),
+                (((abi::STB_GLOBAL << 4) | symbol.sym_type) as u64, 1),
+                (0, 1),
+                (shndx, 2),
+                (symbol.value, 8),
+                (0, 8),
+            ]));
+        }
+
+        let names = self.sections.iter().map(|section| section.name).chain([
+            ".symtab",
+            ".strtab",
+            ".shstrtab",
+        ]);
+        let (shstrtab, section_names) = string_table(names);
+
+        // (type, flags, addr, offset, size, link, info, entsize) of each
+        // section after the null one
+        let mut headers: Vec<_> = self
+            .sections
+            .iter()
+            .map(|section| {
+                let offset = file_offset(section.addr);
+                let (flags, addr, size) = (section.flags, section.addr, section.size);
+                (abi::SHT_PROGBITS, flags, addr, offset, size, 0, 0, 0)
+            })
+            .collect();
+        let mut offset = elf.len() as u64;
+        for (sh_type, table, link, info, entsize) in [
+            (abi::SHT_SYMTAB, &symtab, symtab_idx + 1, 1, SYMBOL_SIZE),
+            (abi::SHT_STRTAB, &strtab, 0, 0, 0),
+            (abi::SHT_STRTAB, &shstrtab, 0, 0, 0),
+        ] {
+            offset = (offset + 7) & !7;
+            put(elf, offset as usize, table);
+            let size = table.len() as u64;
+            headers.push((sh_type, 0, 0, offset, size, link, info, entsize));
+            offset += size;
+        }
+
+        let shoff = ((offset + 7) & !7) as usize;
+        let mut shdrs = vec![0; SHDR_SIZE];
+        for ((sh_type, flags, addr, offset, size, link, info, entsize), name) in
+            headers.into_iter().zip(section_names)
+        {
+            shdrs.extend(fields(&[
+                (name, 4),
+                (sh_type as u64, 4),
+                (flags, 8),
+                (addr, 8),
+                (offset, 8),
+                (size, 8),
+                (link as u64, 4),
+                (info, 4),
+                (8, 8),
+                (entsize as u64, 8),
+            ]));
+        }
+        put(elf, shoff, &shdrs);
+
+        (shoff, shstrndx + 1, shstrndx)
+    }
+}
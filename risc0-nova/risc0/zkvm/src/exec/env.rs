@@ -18,19 +18,22 @@ use std::{
     cell::RefCell,
     collections::HashMap,
     io::{BufRead, BufReader, Cursor, Read, Write},
+    ops::Range,
     rc::Rc,
 };
 
+use anyhow::{bail, Result};
 use bytemuck::Pod;
 use risc0_zkvm_platform::{
     fileno,
+    memory::{MEM_SIZE, STACK, STACK_INITIAL_ADDRESS},
     syscall::{
         nr::{SYS_GETENV, SYS_READ, SYS_READ_AVAIL, SYS_WRITE},
         SyscallName,
     },
 };
 
-use super::io::PosixIo;
+use super::{fault::FaultPlan, io::PosixIo, trace::TraceWriter, ErrorRecord, SyscallRecord};
 
 // use super::io::{slice_io_from_fn, syscalls, PosixIo, SliceIo, Syscall,
 // SyscallTable};
@@ -39,9 +42,51 @@ use super::io::PosixIo;
 /// to try and fit with 8GB of RAM.
 const DEFAULT_SEGMENT_LIMIT_PO2: usize = 20; // 1M cycles
 
+/// The size of the length prefix at the start of an
+/// [output region](ExecutorEnvBuilder::output_region).
+pub(crate) const OUTPUT_LEN_SIZE: u64 = 4;
+
 /// The default session limit specified in cycles.
 const DEFAULT_SESSION_LIMIT: usize = 64 * 1024 * 1024; // 64M cycles
 
+/// The source of the value returned to the guest by the `rdtime` instruction.
+///
+/// Proving requires execution to be reproducible, so the default is [ClockSource::Zero]
+/// rather than anything derived from the host's wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ClockSource {
+    /// `rdtime` always reads as zero.
+    #[default]
+    Zero,
+
+    /// `rdtime` always reads as the given value.
+    Fixed(u64),
+
+    /// `rdtime` reads as the number of cycles executed so far in the session.
+    CycleCount,
+
+    /// `rdtime` reads whatever the callback returns. Execution is only
+    /// reproducible if the callback is.
+    Callback(fn() -> u64),
+}
+
+/// The number of cycles each instruction costs, for estimating the cost of
+/// proving under different assumptions than the default.
+///
+/// A `HashMap` from mnemonic to cycles is a [CostModel] overriding just the
+/// instructions it holds.
+pub trait CostModel {
+    /// The cycles taken by an instruction with the upper case `mnemonic`, such
+    /// as `"MUL"` or `"ECALL"`, which takes `default` cycles by default.
+    fn cycles(&self, mnemonic: &str, default: usize) -> usize;
+}
+
+impl CostModel for HashMap<String, usize> {
+    fn cycles(&self, mnemonic: &str, default: usize) -> usize {
+        self.get(mnemonic).copied().unwrap_or(default)
+    }
+}
+
 /// A builder pattern used to construct an [ExecutorEnv].
 #[derive(Clone)]
 pub struct ExecutorEnvBuilder<'a> {
@@ -57,6 +102,23 @@ pub struct ExecutorEnv<'a> {
     // syscalls: SyscallTable<'a>,
     pub(crate) io: Rc<RefCell<PosixIo<'a>>>,
     input: Vec<u8>,
+    clock: ClockSource,
+    trace: Option<Rc<RefCell<TraceWriter<Box<dyn Write + 'a>>>>>,
+    syscall_callback: Option<Rc<RefCell<dyn FnMut(&SyscallRecord) + 'a>>>,
+    error_callback: Option<Rc<RefCell<dyn FnMut(&ErrorRecord) + 'a>>>,
+    stack_initial_sp: u64,
+    stack_size: u64,
+    args: Option<Vec<String>>,
+    rng_seed: u64,
+    fault_plan: Option<FaultPlan>,
+    halt_on_write: Option<u64>,
+    detect_infinite_loops: bool,
+    trap_low_memory: u64,
+    cost_model: Option<Rc<dyn CostModel + 'a>>,
+    output_region: Option<(u64, u64)>,
+    mem_size: u64,
+    record_mem_txns: bool,
+    auto_seed_sp: bool,
 }
 
 impl<'a> ExecutorEnv<'a> {
@@ -73,6 +135,93 @@ impl<'a> ExecutorEnv<'a> {
         self.session_limit
     }
 
+    pub(crate) fn get_clock(&self) -> ClockSource {
+        self.clock
+    }
+
+    pub(crate) fn get_trace(&self) -> Option<Rc<RefCell<TraceWriter<Box<dyn Write + 'a>>>>> {
+        self.trace.clone()
+    }
+
+    pub(crate) fn get_stack_initial_sp(&self) -> u64 {
+        self.stack_initial_sp
+    }
+
+    /// The address range of the guest stack, which grows down from the
+    /// initial SP.
+    pub fn stack_region(&self) -> Range<u64> {
+        self.stack_initial_sp.saturating_sub(self.stack_size)..self.stack_initial_sp
+    }
+
+    pub(crate) fn get_rng_seed(&self) -> u64 {
+        self.rng_seed
+    }
+
+    pub(crate) fn get_fault_plan(&self) -> Option<&FaultPlan> {
+        self.fault_plan.as_ref()
+    }
+
+    pub(crate) fn get_halt_on_write(&self) -> Option<u64> {
+        self.halt_on_write
+    }
+
+    pub(crate) fn get_cost_model(&self) -> Option<Rc<dyn CostModel + 'a>> {
+        self.cost_model.clone()
+    }
+
+    pub(crate) fn get_trap_low_memory(&self) -> u64 {
+        self.trap_low_memory
+    }
+
+    pub(crate) fn get_detect_infinite_loops(&self) -> bool {
+        self.detect_infinite_loops
+    }
+
+    // The output region as an address range, or an error if it can't hold
+    // its length or runs past the end of the address space.
+    pub(crate) fn get_output_region(&self) -> Result<Option<Range<u64>>> {
+        let Some((addr, len)) = self.output_region else {
+            return Ok(None);
+        };
+        if len < OUTPUT_LEN_SIZE {
+            bail!("Output region of {len} bytes can't hold its {OUTPUT_LEN_SIZE} byte length");
+        }
+        let Some(end) = addr.checked_add(len) else {
+            bail!("Output region of {len} bytes at 0x{addr:x} overflows the address space");
+        };
+        Ok(Some(addr..end))
+    }
+
+    pub(crate) fn get_record_mem_txns(&self) -> bool {
+        self.record_mem_txns
+    }
+
+    pub(crate) fn get_auto_seed_sp(&self) -> bool {
+        self.auto_seed_sp
+    }
+
+    pub(crate) fn get_mem_size(&self) -> u64 {
+        self.mem_size
+    }
+
+    pub(crate) fn get_args(&self) -> Option<&[String]> {
+        self.args.as_deref()
+    }
+
+    pub(crate) fn get_env_vars(&self) -> &HashMap<String, String> {
+        &self.env_vars
+    }
+
+    pub(crate) fn get_syscall_callback(
+        &self,
+    ) -> Option<Rc<RefCell<dyn FnMut(&SyscallRecord) + 'a>>> {
+        self.syscall_callback.clone()
+    }
+
+    pub(crate) fn get_error_callback(&self) -> Option<Rc<RefCell<dyn FnMut(&ErrorRecord) + 'a>>> {
+        self.error_callback.clone()
+    }
+
     // pub(crate) fn get_syscall(&self, name: &str) -> Option<&Rc<RefCell<(dyn
     // Syscall + 'a)>>> {     self.syscalls.inner.get(name)
     // }
@@ -94,6 +243,23 @@ impl<'a> Default for ExecutorEnvBuilder<'a> {
                 // syscalls: Default::default(),
                 io: Default::default(),
                 input: Default::default(),
+                clock: Default::default(),
+                trace: None,
+                syscall_callback: None,
+                error_callback: None,
+                stack_initial_sp: STACK_INITIAL_ADDRESS as u64,
+                stack_size: STACK.len_bytes() as u64,
+                args: None,
+                rng_seed: 0,
+                fault_plan: None,
+                halt_on_write: None,
+                detect_infinite_loops: false,
+                trap_low_memory: 0,
+                cost_model: None,
+                output_region: None,
+                mem_size: MEM_SIZE as u64,
+                record_mem_txns: false,
+                auto_seed_sp: true,
             },
         }
     }
@@ -128,11 +294,184 @@ impl<'a> ExecutorEnvBuilder<'a> {
     }
 
     /// Set a session limit, specified in number of cycles.
+    ///
+    /// Once the limit is reached [super::Executor::run] stops and returns a
+    /// session with [crate::ExitCode::SessionLimit].
     pub fn session_limit(&mut self, limit: usize) -> &mut Self {
         self.inner.session_limit = limit;
         self
     }
 
+    /// Count the cycles taken by each instruction with `model` rather than
+    /// the default costs, which affects the [session
+    /// limit](Self::session_limit) and [ClockSource::CycleCount] too.
+    pub fn cost_model(&mut self, model: impl CostModel + 'a) -> &mut Self {
+        self.inner.cost_model = Some(Rc::new(model));
+        self
+    }
+
+    /// Set the source of the value the guest reads with `rdtime`.
+    pub fn clock(&mut self, clock: ClockSource) -> &mut Self {
+        self.inner.clock = clock;
+        self
+    }
+
+    /// Write a [TraceRecord](super::TraceRecord) for every retired
+    /// instruction to `writer`.
+    pub fn trace_writer(&mut self, writer: impl Write + 'a) -> &mut Self {
+        let writer: Box<dyn Write + 'a> = Box::new(writer);
+        self.inner.trace = Some(Rc::new(RefCell::new(TraceWriter::new(writer))));
+        self
+    }
+
+    /// Set the guest stack to `size` bytes growing down from `initial_sp`.
+    ///
+    /// `sp` (x2) is seeded with `initial_sp` the first time the guest reads
+    /// it. The default is [STACK_INITIAL_ADDRESS].
+    pub fn stack(&mut self, initial_sp: u64, size: u64) -> &mut Self {
+        self.inner.stack_initial_sp = initial_sp;
+        self.inner.stack_size = size;
+        self
+    }
+
+    /// Call `callback` with the [SyscallRecord] of every `ecall` the guest
+    /// makes.
+    ///
+    /// The callback runs synchronously as each `ecall` retires, after its
+    /// register and memory writes are committed, so it sees the values
+    /// returned to the guest. An `ecall` that fails isn't retired and isn't
+    /// reported.
+    pub fn on_syscall(&mut self, callback: impl FnMut(&SyscallRecord) + 'a) -> &mut Self {
+        self.inner.syscall_callback = Some(Rc::new(RefCell::new(callback)));
+        self
+    }
+
+    /// Call `callback` with the [ErrorRecord] of an instruction that fails to
+    /// decode or execute, then end the session with [crate::ExitCode::Fault]
+    /// rather than failing [Executor::run](super::Executor::run).
+    ///
+    /// This lets a host running untrusted guests log the failure and carry
+    /// on. The failing instruction isn't retired.
+    pub fn on_error(&mut self, callback: impl FnMut(&ErrorRecord) + 'a) -> &mut Self {
+        self.inner.error_callback = Some(Rc::new(RefCell::new(callback)));
+        self
+    }
+
+    /// Seed the generator behind the guest's `getrandom` syscall.
+    ///
+    /// The bytes returned are pseudo-random but fully determined by the seed,
+    /// which defaults to 0, so every run with the same seed sees the same
+    /// randomness.
+    pub fn rng_seed(&mut self, seed: u64) -> &mut Self {
+        self.inner.rng_seed = seed;
+        self
+    }
+
+    /// Inject the faults in `plan` while executing.
+    ///
+    /// Instructions a fault was injected into are marked in the
+    /// [trace](Self::trace_writer), including one failed by an injected
+    /// load fault, which is traced although it doesn't retire.
+    pub fn fault_plan(&mut self, plan: FaultPlan) -> &mut Self {
+        self.inner.fault_plan = Some(plan);
+        self
+    }
+
+    /// Halt when the guest stores to the 8 byte aligned doubleword at
+    /// `tohost`, the way riscv-tests and other HTIF-style harnesses signal
+    /// completion.
+    ///
+    /// A written value with its low bit set halts the session with
+    /// [ExitCode::Halted] of the value shifted right by one, so writing 1
+    /// passes with exit code 0. Writes with the low bit clear don't halt.
+    pub fn halt_on_write(&mut self, tohost: u64) -> &mut Self {
+        self.inner.halt_on_write = Some(tohost);
+        self
+    }
+
+    /// End the session with [ExitCode::InfiniteLoop](crate::ExitCode) when
+    /// the guest takes a branch or jump to itself that changes nothing, such
+    /// as `j .`, rather than running until the session limit.
+    ///
+    /// Only single instruction loops are detected, so a loop polling memory,
+    /// which may change under it, never is.
+    pub fn detect_infinite_loops(&mut self, enable: bool) -> &mut Self {
+        self.inner.detect_infinite_loops = enable;
+        self
+    }
+
+    /// Give the guest `size` bytes of main memory rather than [MEM_SIZE] when
+    /// loading an ELF with [Executor::from_elf](super::Executor::from_elf).
+    ///
+    /// Segments must fit within it, and anything else beyond it, the default
+    /// stack included, is unmapped, so a smaller memory usually needs a
+    /// [stack](Self::stack) within it too.
+    pub fn mem_size(&mut self, size: u64) -> &mut Self {
+        self.inner.mem_size = size;
+        self
+    }
+
+    /// Record every load and store the guest's instructions make, in order,
+    /// as a [MemTxn](super::MemTxn) on the [Segment](crate::Segment).
+    ///
+    /// Accesses by syscalls and the executor itself, e.g. to the register
+    /// file, aren't recorded, nor are those of an instruction that faults.
+    pub fn record_mem_txns(&mut self, enable: bool) -> &mut Self {
+        self.inner.record_mem_txns = enable;
+        self
+    }
+
+    /// Seed `sp` (x2) with the [stack](Self::stack)'s initial SP the first
+    /// time it's read, and again whenever zero is written to it. On by
+    /// default.
+    ///
+    /// Turn it off for guests that set up their own stack: `sp` then starts
+    /// at zero like any other register, and the [args](Self::args) stack is
+    /// still written but its address is left for the embedder to load.
+    pub fn auto_seed_sp(&mut self, enable: bool) -> &mut Self {
+        self.inner.auto_seed_sp = enable;
+        self
+    }
+
+    /// Fault loads and stores below `limit`, e.g. `0x1000`, so a null
+    /// pointer dereference fails with a `LoadAccessFault` or
+    /// `StoreAccessFault` rather than quietly touching the start of memory.
+    ///
+    /// Only the guest's own loads and stores are trapped. The program is
+    /// still loaded at its real addresses, instructions are still fetched
+    /// from below `limit` and syscalls may still read and write there.
+    pub fn trap_low_memory(&mut self, limit: u64) -> &mut Self {
+        self.inner.trap_low_memory = limit;
+        self
+    }
+
+    /// Copy the `len` bytes of guest memory at `addr` into the [Session]
+    /// when it ends, for the host to read with
+    /// [Session::output_region](crate::Session::output_region).
+    ///
+    /// The region starts with a little-endian `u32` holding the number of
+    /// bytes of output following it, so the guest can return less than the
+    /// whole region. A length that runs past the end of the region fails the
+    /// run, as does a region too short to hold the length or running past
+    /// the end of the address space, before the guest starts.
+    pub fn output_region(&mut self, addr: u64, len: u64) -> &mut Self {
+        self.inner.output_region = Some((addr, len));
+        self
+    }
+
+    /// Set the guest's command line arguments, `args[0]` conventionally
+    /// being the program name.
+    ///
+    /// With arguments set the executor lays out `argc`, `argv`, `envp` and
+    /// `auxv` at the top of the stack, as a Linux `_start` expects, and
+    /// starts the guest with `sp` pointing at `argc`. `envp` holds the
+    /// [environment variables](Self::env_var) sorted by name so the layout is
+    /// reproducible. Without arguments the stack is left empty.
+    pub fn args(&mut self, args: &[&str]) -> &mut Self {
+        self.inner.args = Some(args.iter().map(|arg| arg.to_string()).collect());
+        self
+    }
+
     /// Add environment variables to the guest environment.
     pub fn env_vars(&mut self, vars: HashMap<String, String>) -> &mut Self {
         self.inner.env_vars = vars;
@@ -140,6 +479,10 @@ impl<'a> ExecutorEnvBuilder<'a> {
     }
 
     /// Add an environment variable to the guest environment.
+    ///
+    /// Guests can look variables up with the
+    /// [GETENV](risc0_zkvm_platform::syscall::ecall::GETENV) ecall as well as
+    /// through `envp` when [args](Self::args) are set.
     pub fn env_var(&mut self, name: &str, val: &str) -> &mut Self {
         self.inner
             .env_vars
@@ -0,0 +1,136 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deterministic fault injection for testing how guests handle errors.
+
+/// A single fault to inject, see [FaultPlan].
+///
+/// Loads and register writes are counted from 0 in execution order. Loads
+/// are the data reads made by guest instructions (including the read of an
+/// atomic), register writes are those made by retired instructions other
+/// than `ecall`s.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Flip `bit` of the value returned by load number `load`
+    FlipLoadBit {
+        /// The load to corrupt
+        load: u64,
+        /// The bit to flip, 0 being the least significant. Must be less
+        /// than 64.
+        bit: u32,
+    },
+    /// Fail load number `load` with a `LoadAccessFault`
+    LoadAccessFault {
+        /// The load to fail
+        load: u64,
+    },
+    /// XOR `mask` into the value of register write number `write`
+    CorruptRegisterWrite {
+        /// The register write to corrupt
+        write: u64,
+        /// The bits to flip
+        mask: u64,
+    },
+}
+
+/// The faults to inject into an execution.
+///
+/// Which faults fire depends only on the plan and the guest's execution, so
+/// runs with the same plan fail the same way.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultPlan {
+    faults: Vec<Fault>,
+}
+
+impl FaultPlan {
+    /// Construct a plan injecting no faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `fault` to the plan.
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// The faults in the plan.
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+}
+
+/// Applies a [FaultPlan], counting loads and register writes as they happen.
+#[derive(Clone)]
+pub(crate) struct FaultInjector {
+    plan: FaultPlan,
+    loads: u64,
+    register_writes: u64,
+    /// Whether a fault was injected since the last [FaultInjector::take_injected]
+    injected: bool,
+}
+
+impl FaultInjector {
+    pub fn new(plan: FaultPlan) -> Self {
+        Self {
+            plan,
+            loads: 0,
+            register_writes: 0,
+            injected: false,
+        }
+    }
+
+    /// Count a load that read `value`, returning the value the guest sees.
+    pub fn on_load(&mut self, value: Option<u64>) -> Option<u64> {
+        let load = self.loads;
+        self.loads += 1;
+
+        let mut value = value?;
+        for fault in self.plan.faults.iter() {
+            match *fault {
+                Fault::LoadAccessFault { load: n } if n == load => {
+                    self.injected = true;
+                    return None;
+                }
+                Fault::FlipLoadBit { load: n, bit } if n == load => {
+                    self.injected = true;
+                    value ^= 1 << bit;
+                }
+                _ => {}
+            }
+        }
+        Some(value)
+    }
+
+    /// Count a register write of `value`, returning the value written.
+    pub fn on_register_write(&mut self, mut value: u64) -> u64 {
+        let write = self.register_writes;
+        self.register_writes += 1;
+
+        for fault in self.plan.faults.iter() {
+            if let Fault::CorruptRegisterWrite { write: n, mask } = *fault {
+                if n == write {
+                    self.injected = true;
+                    value ^= mask;
+                }
+            }
+        }
+        value
+    }
+
+    /// Whether a fault was injected since the last call, clearing the flag.
+    pub fn take_injected(&mut self) -> bool {
+        std::mem::take(&mut self.injected)
+    }
+}
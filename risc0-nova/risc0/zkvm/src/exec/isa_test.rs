@@ -0,0 +1,180 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running self-checking ISA tests in the format of
+//! [riscv-tests](https://github.com/riscv-software-src/riscv-tests).
+//!
+//! Each test ends by writing to its `tohost` symbol: 1 if every case passed,
+//! otherwise `(n << 1) | 1` where `n` is the number of the failing case.
+//!
+//! The stock `p` environment links tests at `0x80000000` and sets up traps
+//! with machine mode CSRs, neither of which the executor supports. Build the
+//! suite against an environment that links below
+//! [MEM_SIZE](crate::MEM_SIZE), keeps `tohost` out of `.text` and has
+//! `RVTEST_PASS`/`RVTEST_FAIL` store to `tohost` directly, such as the one in
+//! `testdata/rv64ui`.
+
+use anyhow::{anyhow, bail, Result};
+use elf::{endian::LittleEndian, ElfBytes};
+
+use super::{Executor, ExecutorEnv};
+use crate::ExitCode;
+
+/// Cycles an ISA test may run for before it's considered hung.
+const ISA_TEST_CYCLE_LIMIT: usize = 1 << 24;
+
+/// The outcome of an ISA test run by [run_isa_test].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TestResult {
+    /// Every case passed
+    Pass,
+    /// The numbered case failed
+    Fail(u32),
+}
+
+/// Run the riscv-tests style ISA test in `elf` until it writes `tohost`.
+///
+/// Fails if the ELF has no `tohost` symbol, or the test faults, halts some
+/// other way or runs for too long without writing a result.
+pub fn run_isa_test(elf: &[u8]) -> Result<TestResult> {
+    let tohost = find_symbol(elf, "tohost")?;
+    // The tests use sp as an ordinary register, zero included
+    let env = ExecutorEnv::builder()
+        .auto_seed_sp(false)
+        .halt_on_write(tohost)
+        .detect_infinite_loops(true)
+        .session_limit(ISA_TEST_CYCLE_LIMIT)
+        .build();
+    let mut exec = Executor::from_elf(env, elf)?;
+
+    match exec.run()?.exit_code {
+        // A halt ecall exits with a code too, only the tohost write counts
+        ExitCode::Halted(_) if exec.monitor.load_u64(tohost)? & 1 == 0 => {
+            bail!("ISA test halted without writing tohost")
+        }
+        ExitCode::Halted(0) => Ok(TestResult::Pass),
+        ExitCode::Halted(case) => Ok(TestResult::Fail(case)),
+        ExitCode::SessionLimit => {
+            bail!("ISA test didn't write tohost within {ISA_TEST_CYCLE_LIMIT} cycles")
+        }
+        ExitCode::Fault => bail!("ISA test faulted"),
+        ExitCode::Aborted(message) => bail!("ISA test aborted: {message:?}"),
+        ExitCode::InfiniteLoop(pc) => bail!("ISA test looped forever at pc 0x{pc:08x}"),
+        // Only segments end with a split, never a whole session
+        ExitCode::SystemSplit => unreachable!("Session ended with a segment split"),
+    }
+}
+
+fn find_symbol(elf: &[u8], name: &str) -> Result<u64> {
+    let elf = ElfBytes::<LittleEndian>::minimal_parse(elf)?;
+    let (symtab, strtab) = elf
+        .symbol_table()?
+        .ok_or_else(|| anyhow!("ELF has no symbol table"))?;
+    for symbol in symtab.iter() {
+        if strtab.get(symbol.st_name as usize)? == name {
+            return Ok(symbol.st_value);
+        }
+    }
+    bail!("ELF has no {name} symbol")
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::abi::{SHF_EXECINSTR, SHF_WRITE, STT_OBJECT};
+
+    use super::{run_isa_test, TestResult};
+    use crate::binfmt::test_elf::{insn_bytes, ElfBuilder, RW, RX};
+
+    const TEXT_ADDR: u64 = 0x1000;
+    const TOHOST_ADDR: u64 = 0x2000;
+
+    // Two cases in the style of riscv-tests, with the case number in gp:
+    //
+    // test_2:
+    //   addi x1, x0, 3
+    //   addi x14, x1, 4
+    //   addi x7, x0, 7
+    //   addi gp, x0, 2
+    //   bne x14, x7, fail
+    // test_3:
+    //   addi x1, x0, -1
+    //   srli x14, x1, 63
+    //   addi x7, x0, 1
+    //   addi gp, x0, 3
+    //   bne x14, x7, fail
+    // pass:
+    //   addi x5, x0, 1
+    //   lui x6, 0x2
+    //   sd x5, 0(x6)
+    //   jal x0, 0
+    // fail:
+    //   slli x5, gp, 1
+    //   ori x5, x5, 1
+    //   lui x6, 0x2
+    //   sd x5, 0(x6)
+    //   jal x0, 0
+    const TEST: [u32; 19] = [
+        0x00300093, 0x00408713, 0x00700393, 0x00200193, 0x02771463, 0xfff00093, 0x03f0d713,
+        0x00100393, 0x00300193, 0x00771a63, 0x00100293, 0x00002337, 0x00533023, 0x0000006f,
+        0x00119293, 0x0012e293, 0x00002337, 0x00533023, 0x0000006f,
+    ];
+
+    // A RISC-V executable with `insns` in `.text` and a `tohost` symbol in a
+    // writable `.tohost` section.
+    fn build_elf(insns: &[u32]) -> Vec<u8> {
+        let text = insn_bytes(insns);
+        ElfBuilder::new(TEXT_ADDR)
+            .segment(RX, TEXT_ADDR, &text)
+            .segment(RW, TOHOST_ADDR, &[0; 16])
+            .section(".text", SHF_EXECINSTR, TEXT_ADDR, text.len() as u64)
+            .section(".tohost", SHF_WRITE, TOHOST_ADDR, 16)
+            .symbol("tohost", STT_OBJECT, TOHOST_ADDR)
+            .build()
+    }
+
+    // riscv-tests' rv64ui add test, built by testdata/rv64ui/build.sh
+    const RV64UI_ADD: &[u8] = include_bytes!("../testdata/rv64ui/rv64ui-p-add");
+
+    #[test]
+    fn rv64ui_add() {
+        assert_eq!(run_isa_test(RV64UI_ADD).unwrap(), TestResult::Pass);
+    }
+
+    #[test]
+    fn isa_test_pass() {
+        assert_eq!(run_isa_test(&build_elf(&TEST)).unwrap(), TestResult::Pass);
+    }
+
+    #[test]
+    fn isa_test_fail() {
+        // Expect 2 from the srli in case 3
+        let mut insns = TEST;
+        insns[7] = 0x00200393;
+        assert_eq!(
+            run_isa_test(&build_elf(&insns)).unwrap(),
+            TestResult::Fail(3)
+        );
+    }
+
+    #[test]
+    fn isa_test_halt_isnt_pass() {
+        // addi a7, x0, 0
+        // addi a0, x0, 0
+        // ecall
+        let err = run_isa_test(&build_elf(&[0x00000893, 0x00000513, 0x00000073]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "ISA test halted without writing tohost");
+    }
+}
@@ -18,32 +18,53 @@
 //! [Segment]s, each which contains an execution trace of the specified program.
 
 mod env;
+mod fault;
 mod io;
+mod isa_test;
 mod monitor;
-use std::{array, cell::RefCell, fmt::Debug, io::Write, mem::take, rc::Rc, str};
+mod run;
+mod trace;
+use std::{cell::RefCell, collections::HashMap, fmt::Debug, io::Write, mem::take, rc::Rc, str};
 
 use anyhow::{anyhow, bail, Result};
 use risc0_zkvm_platform::{
-    memory::{HEAP_INITIAL_ADDRESS, MEM_SIZE},
+    memory::HEAP_INITIAL_ADDRESS,
     syscall::{
         ecall, halt,
         reg_abi::{REG_A0, REG_A1, REG_A2, REG_A3, REG_A4, REG_A7},
     },
-    PAGE_SIZE, WORD_SIZE,
+    DOUBLE_WORD_SIZE, PAGE_SIZE, WORD_SIZE,
+};
+use rrs_lib::{
+    instruction_executor::{InstructionException, InstructionExecutor},
+    memories::VecMemory,
+    HartState, MemAccessSize, Memory,
 };
-use rrs_lib::{instruction_executor::InstructionExecutor, memories::VecMemory, HartState, Memory};
 use serde::{Deserialize, Serialize};
 
-pub use self::env::{ExecutorEnv, ExecutorEnvBuilder};
-use self::monitor::MemoryMonitor;
+pub use self::env::{ClockSource, CostModel, ExecutorEnv, ExecutorEnvBuilder};
+pub use self::fault::{Fault, FaultPlan};
+pub use self::isa_test::{run_isa_test, TestResult};
+pub use self::run::{run_elf, RunResult};
+pub use self::trace::{
+    read_trace, AccessKind, MemTxn, TraceRecord, TraceReplay, TraceWriter, TRACE_VERSION,
+};
+use self::{
+    env::OUTPUT_LEN_SIZE,
+    fault::FaultInjector,
+    monitor::{MemoryMonitor, MonitorSnapshot},
+};
 use crate::{
     opcode::{MajorType, OpCode},
-    ExitCode, MemoryImage, Program, Session,
+    ExitCode, MemoryImage, Program, Segment, Session,
 };
 
 /// The number of cycles required to compress a SHA-256 block.
 const SHA_CYCLES: usize = 72;
 
+/// The longest abort message read from the guest, see [ExitCode::Aborted]
+const MAX_ABORT_MESSAGE_LEN: usize = 1024;
+
 /// The Executor provides an implementation for the execution phase.
 ///
 /// The proving phase uses an execution trace generated by the Executor.
@@ -57,12 +78,166 @@ pub struct Executor<'a> {
     anonymous_heap_watermark: u64,
     // segments: Vec<Segment>,
     insn_counter: u32,
+    cycle: usize,
+    reservation: Option<u64>,
+    fregisters: [u32; 32],
+    fcsr: u32,
+    rng_state: u64,
+}
+
+/// The architectural and bookkeeping state of an [Executor] other than its
+/// memory and environment.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExecutorState {
+    /// The program counter of the next instruction to execute
+    pub pc: u64,
+    /// The program counter the session started from
+    pub pre_pc: u64,
+    /// Base address of the next anonymous `mmap`
+    pub anonymous_heap_watermark: u64,
+    /// The number of instructions executed so far
+    pub insn_counter: u32,
+    /// The number of cycles executed so far
+    pub cycle: usize,
+    /// The address reserved by an outstanding LR, if any
+    pub reservation: Option<u64>,
+    /// The single-precision floating point registers
+    pub fregisters: [u32; 32],
+    /// The floating point control and status register
+    pub fcsr: u32,
+    /// The state of the generator behind the `getrandom` syscall
+    pub rng_state: u64,
+}
+
+/// An [Executor] stopped between two instructions by [Executor::pause].
+///
+/// [PausedExecution::resume] continues execution exactly where it stopped.
+/// The [ExecutorState] is serializable so it can be stored alongside the
+/// [image](PausedExecution::image) for resuming in another process with
+/// [Executor::from_state].
+pub struct PausedExecution<'a> {
+    env: ExecutorEnv<'a>,
+    monitor: MemoryMonitor,
+    state: ExecutorState,
+}
+
+impl<'a> PausedExecution<'a> {
+    /// The state captured when execution was paused.
+    pub fn state(&self) -> &ExecutorState {
+        &self.state
+    }
+
+    /// The memory, register file included, when execution was paused.
+    pub fn image(&self) -> &MemoryImage {
+        &self.monitor.image
+    }
+
+    /// Continue execution from where it was paused.
+    pub fn resume(self) -> Executor<'a> {
+        let mut exec = Executor::with_monitor(self.env, self.monitor, self.state.pc);
+        exec.set_state(self.state);
+        exec
+    }
+}
+
+/// A point in the execution of an [Executor] that it can be
+/// [restored](Executor::restore) to, taken with [Executor::snapshot].
+///
+/// Besides the [ExecutorState] this covers memory, the register file
+/// included, the syscalls recorded so far and the progress of any
+/// [FaultPlan]. The [ExecutorEnv] is not captured, so input already read
+/// stays consumed and callbacks aren't undone.
+pub struct ExecutorSnapshot {
+    state: ExecutorState,
+    monitor: MonitorSnapshot,
+}
+
+impl ExecutorSnapshot {
+    /// The state captured by the snapshot.
+    pub fn state(&self) -> &ExecutorState {
+        &self.state
+    }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// A record of a single `ecall` made by the guest.
+///
+/// Holds everything needed to replay the syscall: its number and arguments,
+/// the value returned in `a0` and the bytes it moved between the guest and
+/// the host.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct SyscallRecord {
-    pub to_guest: Vec<u64>,
-    pub regs: (u64, u64),
+    num: u64,
+    args: [u64; 7],
+    result: u64,
+    bytes_in: Vec<u8>,
+    bytes_out: Vec<u8>,
+}
+
+impl SyscallRecord {
+    /// Construct a record of syscall `num` called with `args` (`a0` to `a6`),
+    /// which returned `result` and moved no bytes.
+    pub fn new(num: u64, args: [u64; 7], result: u64) -> Self {
+        Self {
+            num,
+            args,
+            result,
+            ..Default::default()
+        }
+    }
+
+    /// Set the bytes the syscall wrote to guest memory.
+    pub fn with_bytes_in(mut self, bytes: Vec<u8>) -> Self {
+        self.bytes_in = bytes;
+        self
+    }
+
+    /// Set the bytes the syscall read from guest memory.
+    pub fn with_bytes_out(mut self, bytes: Vec<u8>) -> Self {
+        self.bytes_out = bytes;
+        self
+    }
+
+    /// The syscall number, taken from `a7`
+    pub fn num(&self) -> u64 {
+        self.num
+    }
+
+    /// The arguments in `a0` to `a6` when the syscall was made
+    pub fn args(&self) -> &[u64; 7] {
+        &self.args
+    }
+
+    /// The value of `a0` returned to the guest
+    pub fn result(&self) -> u64 {
+        self.result
+    }
+
+    /// The bytes written to guest memory, e.g. the buffer filled by
+    /// `getrandom`
+    pub fn bytes_in(&self) -> &[u8] {
+        &self.bytes_in
+    }
+
+    /// The bytes read from guest memory, e.g. the buffer passed to `write`
+    pub fn bytes_out(&self) -> &[u8] {
+        &self.bytes_out
+    }
+}
+
+/// An instruction that failed to decode or execute, passed to the callback set
+/// with [ExecutorEnvBuilder::on_error].
+#[derive(Debug)]
+pub struct ErrorRecord {
+    /// Why the instruction failed, e.g. an [InstructionException] or a decode
+    /// error
+    pub error: anyhow::Error,
+    /// The failing instruction, if it could be fetched
+    pub insn: Option<u32>,
+    /// The integer registers when the instruction failed
+    pub registers: [u64; 32],
+    /// The state of the executor, with `pc` pointing at the failing
+    /// instruction
+    pub state: ExecutorState,
 }
 
 #[derive(Clone)]
@@ -109,7 +284,38 @@ impl<'a> Executor<'a> {
     /// Construct a new [Executor] from a [MemoryImage] and entry point.
     pub fn new(env: ExecutorEnv<'a>, image: MemoryImage, pc: u64) -> Self {
         // let pre_image = image.clone();
-        let monitor = MemoryMonitor::new(image);
+        let mut monitor = MemoryMonitor::new(image);
+        let mut initial_sp = env.get_stack_initial_sp();
+        if let Some(args) = env.get_args() {
+            initial_sp = write_initial_stack(&mut monitor, initial_sp, args, env.get_env_vars());
+        }
+        monitor.set_initial_sp(initial_sp);
+        Self::with_monitor(env, monitor, pc)
+    }
+
+    /// Construct an [Executor] that carries on from a [PausedExecution], given
+    /// its [ExecutorState] and [image](PausedExecution::image), e.g. after
+    /// deserializing both in another process.
+    ///
+    /// The stack and arguments are already in `image`, so `env`'s aren't laid
+    /// out again. Input already read isn't replayed, and a [FaultPlan] in
+    /// `env` starts over.
+    pub fn from_state(env: ExecutorEnv<'a>, image: MemoryImage, state: ExecutorState) -> Self {
+        let mut monitor = MemoryMonitor::new(image);
+        monitor.skip_sp_seed();
+        let mut exec = Self::with_monitor(env, monitor, state.pc);
+        exec.set_state(state);
+        exec
+    }
+
+    // An executor at `pc` with fresh bookkeeping, its monitor configured from
+    // `env`.
+    fn with_monitor(env: ExecutorEnv<'a>, mut monitor: MemoryMonitor, pc: u64) -> Self {
+        let rng_state = env.get_rng_seed();
+        monitor.fault_injector = env.get_fault_plan().cloned().map(FaultInjector::new);
+        monitor.low_memory_limit = env.get_trap_low_memory();
+        monitor.mem_txns = env.get_record_mem_txns().then(Vec::new);
+        monitor.auto_seed_sp = env.get_auto_seed_sp();
 
         Self {
             env,
@@ -120,19 +326,114 @@ impl<'a> Executor<'a> {
             anonymous_heap_watermark: HEAP_INITIAL_ADDRESS as u64,
             // segments: Vec::new(),
             insn_counter: 0,
+            cycle: 0,
+            reservation: None,
+            fregisters: [0; 32],
+            fcsr: 0,
+            rng_state,
         }
     }
 
+    /// The stack pointer the program starts with, pointing at `argc` when
+    /// [args](ExecutorEnvBuilder::args) are laid out on the stack.
+    ///
+    /// `sp` is only set to this automatically when
+    /// [auto_seed_sp](ExecutorEnvBuilder::auto_seed_sp) is on, otherwise the
+    /// host must set it.
+    pub fn initial_sp(&self) -> u64 {
+        self.monitor.initial_sp()
+    }
+
     /// Construct a new [Executor] from an ELF binary.
     pub fn from_elf(env: ExecutorEnv<'a>, elf: &[u8]) -> Result<Self> {
-        let program = Program::load_elf(&elf, MEM_SIZE as u64)?;
-        let image = MemoryImage::new(&program, PAGE_SIZE as u64);
+        let mem_size = env.get_mem_size();
+        let program = Program::load_elf(elf, mem_size)?;
+        let image = MemoryImage::with_mem_size(&program, PAGE_SIZE as u64, mem_size);
         Ok(Self::new(env, image, program.entry))
     }
 
+    /// Stop execution between instructions, capturing everything needed to
+    /// [resume](PausedExecution::resume) it later.
+    pub fn pause(mut self) -> PausedExecution<'a> {
+        // Resuming from the image alone needs sp in the register file
+        self.monitor.seed_sp();
+        PausedExecution {
+            state: self.state(),
+            env: self.env,
+            monitor: self.monitor,
+        }
+    }
+
+    /// Capture the state of execution between instructions, to
+    /// [restore](Self::restore) later.
+    ///
+    /// Memory isn't copied. Instead, from the first snapshot on, the executor
+    /// remembers the previous contents of everything it writes, so a snapshot
+    /// costs about as much as the memory written after it.
+    pub fn snapshot(&mut self) -> ExecutorSnapshot {
+        ExecutorSnapshot {
+            state: self.state(),
+            monitor: self.monitor.snapshot(),
+        }
+    }
+
+    /// Return execution to where `snapshot` was taken, undoing every memory
+    /// write since.
+    ///
+    /// A snapshot can be restored any number of times. Restoring one
+    /// invalidates the snapshots taken after it, and restoring an invalidated
+    /// snapshot fails.
+    pub fn restore(&mut self, snapshot: &ExecutorSnapshot) -> Result<()> {
+        self.monitor.restore(&snapshot.monitor)?;
+        self.set_state(snapshot.state.clone());
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: ExecutorState) {
+        let ExecutorState {
+            pc,
+            pre_pc,
+            anonymous_heap_watermark,
+            insn_counter,
+            cycle,
+            reservation,
+            fregisters,
+            fcsr,
+            rng_state,
+        } = state;
+        self.pc = pc;
+        self.pre_pc = pre_pc;
+        self.anonymous_heap_watermark = anonymous_heap_watermark;
+        self.insn_counter = insn_counter;
+        self.cycle = cycle;
+        self.reservation = reservation;
+        self.fregisters = fregisters;
+        self.fcsr = fcsr;
+        self.rng_state = rng_state;
+    }
+
+    pub(crate) fn state(&self) -> ExecutorState {
+        ExecutorState {
+            pc: self.pc,
+            pre_pc: self.pre_pc,
+            anonymous_heap_watermark: self.anonymous_heap_watermark,
+            insn_counter: self.insn_counter,
+            cycle: self.cycle,
+            reservation: self.reservation,
+            fregisters: self.fregisters,
+            fcsr: self.fcsr,
+            rng_state: self.rng_state,
+        }
+    }
+
     /// Run the executor until [ExitCode::Paused] or [ExitCode::Halted] is
     /// reached, producing a [Session] as a result.
+    ///
+    /// An instruction that fails to decode or execute fails the run, unless
+    /// an [on_error](ExecutorEnvBuilder::on_error) callback is set, in which
+    /// case the session ends with [ExitCode::Fault].
     pub fn run(&mut self) -> Result<Session> {
+        self.env.get_output_region()?;
         self.monitor.clear_session();
 
         //        let journal = Journal::default();
@@ -141,28 +442,58 @@ impl<'a> Executor<'a> {
         //     .borrow_mut()
         //     .with_write_fd(fileno::JOURNAL, journal.clone());
 
+        let mut segments = Vec::new();
+        let segment_limit = self.env.get_segment_limit();
         let mut run_loop = || -> Result<ExitCode> {
+            let (mut pre_image, mut pre_state) = self.segment_start()?;
             loop {
-                if let Some(exit_code) = self.step()? {
+                let exit_code = match self.step() {
+                    Ok(exit_code) => exit_code,
+                    Err(err) => Some(self.handle_error(err)?),
+                };
+                let exit_code = exit_code.or_else(|| {
+                    (self.cycle - pre_state.cycle >= segment_limit).then_some(ExitCode::SystemSplit)
+                });
+                if let Some(exit_code) = exit_code {
                     // let total_cycles = self.total_cycles();
                     // log::debug!("exit_code: {exit_code:?}, total_cycles: {total_cycles}");
                     // assert!(total_cycles <= (1 << self.env.segment_limit_po2));
-                    // let pre_image = self.pre_image.clone();
                     let syscalls = take(&mut self.monitor.syscalls);
+                    let mem_txns = self.monitor.mem_txns.as_mut().map(take);
                     // let faults = take(&mut self.monitor.faults);
-                    // self.segments.push(Segment::new(
-                    //     pre_image,
-                    //     post_image_id,
-                    //     self.pre_pc,
-                    //     // faults,
-                    //     syscalls,
-                    //     exit_code,
-                    //     // log2_ceil(total_cycles.next_power_of_two()),
-                    // ));
-                    match exit_code {
-                        ExitCode::SessionLimit => bail!("Session limit exceeded"),
-                        ExitCode::Halted(inner) => {
-                            println!("success!");
+                    segments.push(Segment::new(
+                        take(&mut pre_image),
+                        // post_image_id,
+                        pre_state.clone(),
+                        // faults,
+                        syscalls,
+                        mem_txns.unwrap_or_default(),
+                        exit_code.clone(),
+                        // log2_ceil(total_cycles.next_power_of_two()),
+                    ));
+                    match &exit_code {
+                        ExitCode::SystemSplit => {
+                            log::debug!("segment split after {} cycles", self.cycle);
+                            (pre_image, pre_state) = self.segment_start()?;
+                        }
+                        ExitCode::SessionLimit => {
+                            log::debug!("session limit reached after {} cycles", self.cycle);
+                            return Ok(exit_code);
+                        }
+                        ExitCode::Halted(code) => {
+                            log::debug!("halted with code {code}");
+                            return Ok(exit_code);
+                        }
+                        ExitCode::Fault => {
+                            log::debug!("fault at pc 0x{:08x}", self.pc);
+                            return Ok(exit_code);
+                        }
+                        ExitCode::InfiniteLoop(pc) => {
+                            log::debug!("infinite loop at pc 0x{pc:08x}");
+                            return Ok(exit_code);
+                        }
+                        ExitCode::Aborted(message) => {
+                            log::debug!("aborted at pc 0x{:08x}: {message:?}", self.pc);
                             return Ok(exit_code);
                         }
                     };
@@ -171,53 +502,147 @@ impl<'a> Executor<'a> {
         };
 
         let exit_code = run_loop()?;
-        let mut segments = Vec::new();
-        // std::mem::swap(&mut segments, &mut self.segments);
-        Ok(Session::new(segments, exit_code))
+        let mut session = Session::new(segments, exit_code);
+        session.output_region = self.read_output_region()?;
+        Ok(session)
+    }
+
+    // The memory, serialized, and state the next segment starts from. sp is
+    // seeded first so the register file in memory holds it, as a replay
+    // from the image won't seed it.
+    fn segment_start(&mut self) -> Result<(Vec<u8>, ExecutorState)> {
+        self.monitor.seed_sp();
+        self.monitor.commit();
+        let mut pre_image = Vec::new();
+        self.monitor.image.serialize(&mut pre_image)?;
+        Ok((pre_image, self.state()))
+    }
+
+    // The output the guest wrote to the output region, if there is one,
+    // trimmed to the length at its start.
+    fn read_output_region(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(region) = self.env.get_output_region()? else {
+            return Ok(None);
+        };
+        let len_bytes = self.monitor.load_array(region.start)?;
+        let len = u32::from_le_bytes(len_bytes) as u64;
+        let capacity = region.end - region.start - OUTPUT_LEN_SIZE;
+        if len > capacity {
+            bail!("Output region length {len} exceeds its capacity of {capacity} bytes");
+        }
+        let data = region.start + OUTPUT_LEN_SIZE;
+        (data..data + len)
+            .map(|addr| self.monitor.load_u8(addr))
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    // Hand a failed instruction to the `on_error` callback, ending the session
+    // with a fault, or pass the error on if there's no callback.
+    fn handle_error(&mut self, error: anyhow::Error) -> Result<ExitCode> {
+        let Some(callback) = self.env.get_error_callback() else {
+            return Err(error);
+        };
+
+        let record = ErrorRecord {
+            error,
+            insn: self.monitor.read_insn(self.pc).map(|insn| insn as u32),
+            registers: self.monitor.register_snapshot(),
+            state: self.state(),
+        };
+        callback.borrow_mut()(&record);
+        Ok(ExitCode::Fault)
     }
 
     /// Execute a single instruction.
     ///
     /// This can be directly used by debuggers.
     pub fn step(&mut self) -> Result<Option<ExitCode>> {
-        // if self.session_cycle() > self.env.get_session_limit() {
-        //     return Ok(Some(ExitCode::SessionLimit));
-        // }
+        if self.cycle >= self.env.get_session_limit() {
+            return Ok(Some(ExitCode::SessionLimit));
+        }
 
-        let insn = self.monitor.load_u32(self.pc);
+        let insn = self
+            .monitor
+            .read_insn(self.pc)
+            .ok_or_else(|| anyhow!("{:?}", InstructionException::FetchError(self.pc)))?
+            as u32;
         let opcode = OpCode::decode(insn, self.pc)?;
 
         if let Some(op_result) = self.monitor.restore_op() {
             return Ok(self.advance(opcode, op_result));
         }
 
-        let op_result = if opcode.major == MajorType::ECall {
-            self.ecall()?
-        } else {
-            let registers = self.monitor.load_registers(array::from_fn(|idx| idx));
-            // if self.pc >= 0x00011ad4 && self.pc <= 0x00011ad4 {
-            //     registers.iter().enumerate().for_each(|(idx, value)| {
-            //         println!("value loaded {:08x}, idx: {:?}", value, idx,);
-            //     });
-            // }
-            let mut hart = HartState {
-                registers,
-                pc: self.pc,
-                last_register_write: None,
-            };
+        let pc = self.pc;
+        // The register written and the memory accessed, for the trace
+        let mut effects = None;
+        let mut mem_write = None;
+        let mut self_loop = false;
 
-            InstructionExecutor {
-                mem: &mut self.monitor,
-                hart_state: &mut hart,
+        let op_result = match opcode.decoded {
+            // Only ECALL and EBREAK aren't decoded by rrs-lib
+            None if opcode.mnemonic == "EBREAK" => {
+                bail!("{:?}", InstructionException::Breakpoint(pc))
             }
-            .step()
-            .map_err(|err| anyhow!("{:?}", err))?;
+            None => self.ecall()?,
+            Some(decoded) => {
+                let registers = self.monitor.register_snapshot();
+                // if self.pc >= 0x00011ad4 && self.pc <= 0x00011ad4 {
+                //     registers.iter().enumerate().for_each(|(idx, value)| {
+                //         println!("value loaded {:08x}, idx: {:?}", value, idx,);
+                //     });
+                // }
+                let time = if opcode.mnemonic == "RDTIME" {
+                    self.read_clock()
+                } else {
+                    0
+                };
+                let mut hart = HartState {
+                    registers,
+                    pc: self.pc,
+                    reservation: self.reservation,
+                    time,
+                    fregisters: self.fregisters,
+                    fcsr: self.fcsr,
+                    ..HartState::new()
+                };
 
-            if let Some(idx) = hart.last_register_write {
-                self.monitor.store_register(idx, hart.registers[idx]);
-            }
+                let result =
+                    InstructionExecutor::new(&mut self.monitor, &mut hart).step_decoded(decoded);
+                if let Err(err) = result {
+                    if self.take_fault_injected() {
+                        self.trace_injected_fault(pc, insn)?;
+                    }
+                    bail!("{:?}", err);
+                }
+
+                if let Some(idx) = hart.last_register_write {
+                    if let Some(injector) = self.monitor.fault_injector.as_mut() {
+                        hart.registers[idx] = injector.on_register_write(hart.registers[idx]);
+                    }
+                    self.monitor.store_register(idx, hart.registers[idx]);
+                }
+                self.reservation = hart.reservation;
+                self.fregisters = hart.fregisters;
+                self.fcsr = hart.fcsr;
+                mem_write = hart.last_mem_write;
+                // Nothing else runs in a loop of one instruction, so if it
+                // writes nothing new it'll repeat forever
+                self_loop = hart.pc == pc
+                    && mem_write.is_none()
+                    && hart
+                        .last_register_write
+                        .into_iter()
+                        .all(|idx| hart.registers[idx] == registers[idx]);
 
-            OpCodeResult::new(hart.pc, None, 0, None)
+                effects = Some((
+                    hart.last_register_write
+                        .map(|idx| (idx, hart.registers[idx])),
+                    trace::mem_access(&decoded, &registers, hart.last_mem_write),
+                ));
+
+                OpCodeResult::new(hart.pc, None, 0, None)
+            }
         };
         self.monitor.save_op(op_result.clone());
 
@@ -241,9 +666,78 @@ impl<'a> Executor<'a> {
         // } else {
         let exit_code = self.advance(opcode, op_result);
         // };
+        let exit_code = exit_code.or_else(|| self.tohost_exit(mem_write));
+        let exit_code = exit_code.or_else(|| {
+            (self_loop && self.env.get_detect_infinite_loops())
+                .then_some(ExitCode::InfiniteLoop(pc))
+        });
+
+        let fault = self.take_fault_injected();
+        if let Some(trace) = self.env.get_trace() {
+            let (rd, access) = effects.unwrap_or((None, None));
+            let (rd, rd_value) = rd.unwrap_or((0, 0));
+            let (access_kind, mem_addr, mem_value) = match access {
+                // Read after the commit so stores trace the value written
+                Some((kind, addr, size)) => {
+                    let value = self.monitor.image.memory_space.read_mem(addr, size);
+                    (kind, addr, value.unwrap_or(0))
+                }
+                None => (AccessKind::None, 0, 0),
+            };
+            trace.borrow_mut().write_record(&TraceRecord {
+                pc,
+                insn,
+                rd: rd as u8,
+                rd_value,
+                mem_addr,
+                mem_value,
+                access_kind,
+                fault,
+            })?;
+        }
+
         Ok(exit_code)
     }
 
+    // The exit code written to the `halt_on_write` address by a store to
+    // `mem_write`, if the write signals completion.
+    fn tohost_exit(&mut self, mem_write: Option<u64>) -> Option<ExitCode> {
+        let tohost = self.env.get_halt_on_write()?;
+        if mem_write? & !0x7 != tohost {
+            return None;
+        }
+        let value = self
+            .monitor
+            .image
+            .memory_space
+            .read_mem(tohost, MemAccessSize::DoubleWord)?;
+        (value & 1 == 1).then_some(ExitCode::Halted((value >> 1) as u32))
+    }
+
+    fn take_fault_injected(&mut self) -> bool {
+        self.monitor
+            .fault_injector
+            .as_mut()
+            .map_or(false, |injector| injector.take_injected())
+    }
+
+    // Trace an instruction that an injected fault stopped from retiring.
+    fn trace_injected_fault(&mut self, pc: u64, insn: u32) -> Result<()> {
+        if let Some(trace) = self.env.get_trace() {
+            trace.borrow_mut().write_record(&TraceRecord {
+                pc,
+                insn,
+                rd: 0,
+                rd_value: 0,
+                mem_addr: 0,
+                mem_value: 0,
+                access_kind: AccessKind::None,
+                fault: true,
+            })?;
+        }
+        Ok(())
+    }
+
     fn advance(&mut self, opcode: OpCode, op_result: OpCodeResult) -> Option<ExitCode> {
         log::debug!(
             "pc: 0x{:08x}, insn: 0x{:08x} => {:?}",
@@ -254,10 +748,40 @@ impl<'a> Executor<'a> {
 
         self.pc = op_result.pc;
         self.insn_counter += 1;
+        self.cycle += match self.env.get_cost_model() {
+            Some(model) => model.cycles(opcode.mnemonic, opcode.cycles),
+            None => opcode.cycles,
+        };
         self.monitor.commit();
+        if opcode.major == MajorType::ECall {
+            self.record_syscall(op_result.syscall);
+        }
         op_result.exit_code
     }
 
+    // Record a retired ecall and pass it to the `on_syscall` callback. This
+    // happens after the commit so the record holds the values returned to the
+    // guest.
+    fn record_syscall(&mut self, syscall: Option<SyscallRecord>) {
+        let Some(mut record) = syscall else {
+            return;
+        };
+        record.result = self.monitor.load_register(REG_A0);
+        if let Some(callback) = self.env.get_syscall_callback() {
+            (callback.borrow_mut())(&record);
+        }
+        self.monitor.syscalls.push(record);
+    }
+
+    fn read_clock(&self) -> u64 {
+        match self.env.get_clock() {
+            ClockSource::Zero => 0,
+            ClockSource::Fixed(time) => time,
+            ClockSource::CycleCount => self.cycle as u64,
+            ClockSource::Callback(clock) => clock(),
+        }
+    }
+
     // fn total_cycles(&self) -> usize {
     //     self.init_cycles
     //         + self.monitor.total_fault_cycles()
@@ -290,10 +814,13 @@ impl<'a> Executor<'a> {
     fn ecall(&mut self) -> Result<OpCodeResult> {
         // previously it used REG_TO. Seems it's for RIV32E (embedded version spec). A reference https://github.com/chipsalliance/VeeR-ISS/blob/main/Syscall.cpp#L788-L791
         // here in RIV64 we switch to REG_A7
-        match self.monitor.load_register(REG_A7) {
+        let num = self.monitor.load_register(REG_A7);
+        let args = std::array::from_fn(|idx| self.monitor.load_register(REG_A0 + idx));
+        let mut op_result = match num {
             ecall::HALT => self.ecall_halt(),
             ecall::EXIT => self.ecall_halt(),
             ecall::OUTPUT => self.ecall_output(),
+            ecall::GETENV => self.ecall_getenv(),
             // ecall::SOFTWARE => self.ecall_software(),
             ecall::FCNTL => self.ecall_fcntl(),
             ecall::OPEN => self.ecall_open(),
@@ -308,8 +835,89 @@ impl<'a> Executor<'a> {
             ecall::SIGACTION => self.ecall_do_nth(),
             ecall::GETAFFINITY => self.ecall_do_nth(),
             ecall::CLOCKGETTIME => self.ecall_do_nth(),
+            ecall::GETRANDOM => self.ecall_getrandom(),
             ecall => bail!("Unknown ecall {ecall:08x} in decimal {ecall:?}"),
+        }?;
+
+        // Handlers only fill in the bytes they moved, the result is taken
+        // once the ecall retires
+        let record = op_result.syscall.take().unwrap_or_default();
+        op_result.syscall = Some(SyscallRecord {
+            num,
+            args,
+            ..record
+        });
+        Ok(op_result)
+    }
+
+    // getrandom(buf, buflen, flags), filling `buf` from a generator seeded by
+    // `ExecutorEnvBuilder::rng_seed` so runs are reproducible. The whole of
+    // `buf` must be writable, which also bounds `buflen` by guest memory.
+    fn ecall_getrandom(&mut self) -> Result<OpCodeResult> {
+        let buf = self.monitor.load_register(REG_A0);
+        let len = self.monitor.load_register(REG_A1);
+        self.monitor.check_guest_writable(buf, len)?;
+        let mut bytes_in = Vec::new();
+        for offset in (0..len).step_by(8) {
+            let bytes = self.next_random().to_le_bytes();
+            let count = (len - offset).min(8) as usize;
+            self.monitor.store_region(buf + offset, &bytes[..count]);
+            bytes_in.extend_from_slice(&bytes[..count]);
         }
+        self.monitor.store_register(REG_A0, len);
+        let syscall = SyscallRecord::default().with_bytes_in(bytes_in);
+        Ok(OpCodeResult::new(
+            self.pc + WORD_SIZE as u64,
+            None,
+            0,
+            Some(syscall),
+        ))
+    }
+
+    // getenv(name, name_len, buf, buf_len), copying as much of the value as
+    // fits in `buf` and returning its full length so a guest can retry with a
+    // bigger buffer.
+    fn ecall_getenv(&mut self) -> Result<OpCodeResult> {
+        let [name, name_len, buf, buf_len] = self
+            .monitor
+            .load_registers([REG_A0, REG_A1, REG_A2, REG_A3]);
+        let name_end = name
+            .checked_add(name_len)
+            .ok_or_else(|| anyhow!("Name at 0x{name:016x} wraps the address space"))?;
+        let name_bytes = (name..name_end)
+            .map(|addr| self.monitor.load_u8(addr))
+            .collect::<Result<Vec<_>>>()?;
+        let value = str::from_utf8(&name_bytes)
+            .ok()
+            .and_then(|name| self.env.get_env_vars().get(name))
+            .map(|value| value.as_bytes().to_vec());
+
+        let mut syscall = SyscallRecord::default().with_bytes_out(name_bytes);
+        let result = match value {
+            Some(value) => {
+                let count = value.len().min(buf_len as usize);
+                self.monitor.store_guest_region(buf, &value[..count])?;
+                syscall = syscall.with_bytes_in(value[..count].to_vec());
+                value.len() as u64
+            }
+            None => ecall::GETENV_MISSING,
+        };
+        self.monitor.store_register(REG_A0, result);
+        Ok(OpCodeResult::new(
+            self.pc + WORD_SIZE as u64,
+            None,
+            0,
+            Some(syscall),
+        ))
+    }
+
+    // SplitMix64, which is well distributed even from a zero seed
+    fn next_random(&mut self) -> u64 {
+        self.rng_state = self.rng_state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
     }
 
     fn ecall_gettid(&mut self) -> Result<OpCodeResult> {
@@ -482,23 +1090,21 @@ impl<'a> Executor<'a> {
             a0, a1, a2, a3
         );
 
-        let raw: Vec<u8> = (0..a2)
-            .map(|n| {
-                self.monitor
-                    .image
-                    .memory_space
-                    .read_mem(a1 + n, rrs_lib::MemAccessSize::Byte)
-                    .unwrap() as u8
-            })
-            .collect();
-        println!(
-            "ecall_write res {:?}",
-            u64::from_le_bytes(raw.try_into().unwrap())
-        );
+        let end = a1
+            .checked_add(a2)
+            .ok_or_else(|| anyhow!("Write buffer at 0x{a1:016x} wraps the address space"))?;
+        let raw = (a1..end)
+            .map(|addr| self.monitor.load_u8(addr))
+            .collect::<Result<Vec<_>>>()?;
 
         let value = self.monitor.load_register(REG_A2); // write A2 length to A0 return value as write convention
         self.monitor.store_register(REG_A0, value);
-        Ok(OpCodeResult::new(self.pc + WORD_SIZE as u64, None, 0, None))
+        Ok(OpCodeResult::new(
+            self.pc + WORD_SIZE as u64,
+            None,
+            0,
+            Some(SyscallRecord::default().with_bytes_out(raw)),
+        ))
     }
 
     fn ecall_halt(&mut self) -> Result<OpCodeResult> {
@@ -510,16 +1116,94 @@ impl<'a> Executor<'a> {
                 0,
                 None,
             )),
+            halt::ABORT => {
+                let message = self.load_abort_message();
+                Ok(OpCodeResult::new(
+                    self.pc,
+                    Some(ExitCode::Aborted(message)),
+                    0,
+                    None,
+                ))
+            }
             _ => bail!("Illegal halt type: {halt_type}"),
         }
     }
 
+    // The message passed in `a1` to an abort, if it's non-null and a readable
+    // string. A bad message doesn't fail the run as the guest is already
+    // failing.
+    fn load_abort_message(&mut self) -> Option<String> {
+        let addr = self.monitor.load_register(REG_A1);
+        if addr == 0 {
+            return None;
+        }
+        self.monitor
+            .load_string(addr, MAX_ABORT_MESSAGE_LEN)
+            .map_err(|err| log::debug!("unreadable abort message: {err}"))
+            .ok()
+    }
+
     fn ecall_output(&mut self) -> Result<OpCodeResult> {
         log::debug!("ecall(output)");
         Ok(OpCodeResult::new(self.pc + WORD_SIZE as u64, None, 0, None))
     }
 }
 
+// The auxiliary vector entry types written by `write_initial_stack`.
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+
+// Lay out the initial process stack below `top` the way the Linux ABI hands
+// it to `_start`, returning the new stack pointer:
+//
+//   sp -> argc
+//         argv[0..argc], NULL
+//         envp[..], NULL
+//         auxv pairs, ending with (AT_NULL, 0)
+//         padding
+//         NUL-terminated argument and environment strings
+//   top
+fn write_initial_stack(
+    monitor: &mut MemoryMonitor,
+    top: u64,
+    args: &[String],
+    env_vars: &HashMap<String, String>,
+) -> u64 {
+    let mut env_vars: Vec<_> = env_vars.iter().collect();
+    env_vars.sort();
+    let env_strings = env_vars
+        .into_iter()
+        .map(|(name, value)| format!("{name}={value}"));
+
+    let mut addr = top;
+    let mut string_ptr = |string: &str| {
+        addr -= string.len() as u64 + 1;
+        monitor.store_region(addr, string.as_bytes());
+        monitor.store_u8(addr + string.len() as u64, 0);
+        addr
+    };
+    let argv: Vec<u64> = args.iter().map(|arg| string_ptr(arg)).collect();
+    let envp: Vec<u64> = env_strings.map(|var| string_ptr(&var)).collect();
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    words.extend([AT_PAGESZ, PAGE_SIZE as u64, AT_NULL, 0]);
+
+    // The ABI requires a 16 byte aligned stack pointer
+    let sp = (addr - words.len() as u64 * DOUBLE_WORD_SIZE as u64) & !0xf;
+    for (idx, word) in words.into_iter().enumerate() {
+        monitor.store_u64(sp + (idx * DOUBLE_WORD_SIZE) as u64, word);
+    }
+    monitor.commit();
+    sp
+}
+
+#[cfg(test)]
+mod tests;
+
 /// An event traced from the running VM.
 #[allow(dead_code)] // TODO
 #[derive(PartialEq)]
@@ -12,36 +12,67 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{array, collections::BTreeSet};
+use std::{array, collections::BTreeMap};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use risc0_zkvm_platform::{
     memory::{STACK_INITIAL_ADDRESS, SYSTEM},
     DOUBLE_WORD_SIZE, WORD_SIZE,
 };
-use rrs_lib::{MemAccessSize, Memory};
+use rrs_lib::{
+    instruction_executor::InstructionException, memories::VecMemory, HartState, MemAccessSize,
+    Memory,
+};
 
-use super::{OpCodeResult, SyscallRecord};
+use super::{fault::FaultInjector, AccessKind, MemTxn, OpCodeResult, SyscallRecord};
 use crate::MemoryImage;
 
 const SHA_INIT: usize = 5;
 const SHA_LOAD: usize = 16;
 const SHA_MAIN: usize = 52;
 
-#[derive(Eq, Ord, PartialEq, PartialOrd)]
-struct MemStore {
-    addr: u64,
-    data: u8,
+// Every byte overwritten since the first snapshot, in order, so memory can be
+// rewound to any snapshot by writing the old values back.
+#[derive(Default)]
+struct WriteJournal {
+    // (addr, old value)
+    writes: Vec<(u64, u8)>,
+    // The length `writes` was cut back to by each restore
+    restores: Vec<usize>,
+}
+
+/// The state of a [MemoryMonitor] at a point between instructions, for
+/// [MemoryMonitor::restore].
+pub(crate) struct MonitorSnapshot {
+    writes: usize,
+    restores: usize,
+    syscalls: Vec<SyscallRecord>,
+    mem_txns: usize,
+    initial: bool,
+    fault_injector: Option<FaultInjector>,
 }
 
 pub struct MemoryMonitor {
     pub image: MemoryImage,
     // pub faults: PageFaults,
     // pending_faults: PageFaults,
-    pending_writes: BTreeSet<MemStore>,
+    // Keyed by address so a later store to the same byte replaces the earlier one.
+    pending_writes: BTreeMap<u64, u8>,
     op_result: Option<OpCodeResult>,
     pub syscalls: Vec<SyscallRecord>,
+    /// The guest's loads and stores, when they're being recorded
+    pub(crate) mem_txns: Option<Vec<MemTxn>>,
+    // Those of the instruction in flight, kept until it commits
+    pending_txns: Vec<MemTxn>,
     initial: bool,
+    initial_sp: u64,
+    /// Whether `sp` is seeded with `initial_sp` on first use and reseeded
+    /// if zero is written to it
+    pub(crate) auto_seed_sp: bool,
+    pub(crate) fault_injector: Option<FaultInjector>,
+    /// Loads and stores below this address fault
+    pub(crate) low_memory_limit: u64,
+    journal: Option<WriteJournal>,
 }
 
 impl MemoryMonitor {
@@ -50,53 +81,146 @@ impl MemoryMonitor {
             image,
             // faults: PageFaults::default(),
             // pending_faults: PageFaults::default(),
-            pending_writes: BTreeSet::new(),
+            pending_writes: BTreeMap::new(),
             op_result: None,
             syscalls: Vec::new(),
+            mem_txns: None,
+            pending_txns: Vec::new(),
             initial: false,
+            initial_sp: STACK_INITIAL_ADDRESS as u64,
+            auto_seed_sp: true,
+            fault_injector: None,
+            low_memory_limit: 0,
+            journal: None,
+        }
+    }
+
+    /// Sets the value `sp` is seeded with the first time it's read.
+    pub fn set_initial_sp(&mut self, initial_sp: u64) {
+        self.initial_sp = initial_sp;
+    }
+
+    /// The value `sp` is seeded with the first time it's read.
+    pub fn initial_sp(&self) -> u64 {
+        self.initial_sp
+    }
+
+    /// Seed `sp` now if it's still due to be seeded, so the register file
+    /// holds it. Must only be used between instructions as it commits any
+    /// pending writes.
+    pub(crate) fn seed_sp(&mut self) {
+        if self.auto_seed_sp && !self.initial {
+            self.load_register(2);
+            self.commit();
         }
     }
 
-    pub fn load_u8(&mut self, addr: u64) -> u8 {
+    /// Treat `sp` as already seeded, e.g. for a register file restored from
+    /// an image where it was.
+    pub(crate) fn skip_sp_seed(&mut self) {
+        self.initial = true;
+    }
+
+    pub fn load_u8(&mut self, addr: u64) -> Result<u8> {
         self.image
             .memory_space
             .read_mem(addr, MemAccessSize::Byte)
-            .unwrap() as u8
+            .map(|b| b as u8)
+            .ok_or_else(|| anyhow!("Load from unmapped address 0x{addr:016x}"))
     }
 
-    pub fn load_u16(&mut self, addr: u64) -> u16 {
-        assert_eq!(addr % 2, 0, "unaligned load");
-        u16::from_le_bytes(self.load_array(addr))
+    pub fn load_u16(&mut self, addr: u64) -> Result<u16> {
+        check_aligned(addr, 2)?;
+        Ok(u16::from_le_bytes(self.load_array(addr)?))
     }
 
-    pub fn load_u32(&mut self, addr: u64) -> u32 {
-        assert_eq!(addr % WORD_SIZE as u64, 0, "unaligned load");
+    pub fn load_u32(&mut self, addr: u64) -> Result<u32> {
+        check_aligned(addr, WORD_SIZE as u64)?;
         // log::debug!("load_u32: 0x{addr:08x}");
-        u32::from_le_bytes(self.load_array(addr))
+        Ok(u32::from_le_bytes(self.load_array(addr)?))
     }
 
-    pub fn load_u64(&mut self, addr: u64) -> u64 {
-        assert_eq!(addr % DOUBLE_WORD_SIZE as u64, 0, "unaligned load");
+    pub fn load_u64(&mut self, addr: u64) -> Result<u64> {
+        check_aligned(addr, DOUBLE_WORD_SIZE as u64)?;
         // log::debug!("load_u32: 0x{addr:08x}");
-        u64::from_le_bytes(self.load_array(addr))
+        Ok(u64::from_le_bytes(self.load_array(addr)?))
+    }
+
+    /// Loads a byte sign extended to 64 bits, as `lb` does.
+    pub fn load_i8(&mut self, addr: u64) -> Result<i64> {
+        let value = self.load_u8(addr)?;
+        Ok(MemAccessSize::Byte.sign_extend(value.into()))
+    }
+
+    /// Loads a halfword sign extended to 64 bits, as `lh` does.
+    pub fn load_i16(&mut self, addr: u64) -> Result<i64> {
+        let value = self.load_u16(addr)?;
+        Ok(MemAccessSize::HalfWord.sign_extend(value.into()))
+    }
+
+    /// Loads a word sign extended to 64 bits, as `lw` does.
+    pub fn load_i32(&mut self, addr: u64) -> Result<i64> {
+        let value = self.load_u32(addr)?;
+        Ok(MemAccessSize::Word.sign_extend(value.into()))
+    }
+
+    pub fn load_array<const N: usize>(&mut self, addr: u64) -> Result<[u8; N]> {
+        let mut bytes = [0; N];
+        for (idx, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.load_u8(addr + idx as u64)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Loads a halfword as [MemoryMonitor::load_u16] does, but returns a
+    /// fault for a misaligned or unmapped `addr` rather than panicking.
+    pub fn try_load_u16(&mut self, addr: u64) -> Result<u16, InstructionException> {
+        self.try_load_array(addr).map(u16::from_le_bytes)
     }
 
-    pub fn load_array<const N: usize>(&mut self, addr: u64) -> [u8; N] {
-        array::from_fn(|idx| self.load_u8(addr + idx as u64))
+    /// Loads a word as [MemoryMonitor::load_u32] does, but returns a fault
+    /// for a misaligned or unmapped `addr` rather than panicking.
+    pub fn try_load_u32(&mut self, addr: u64) -> Result<u32, InstructionException> {
+        self.try_load_array(addr).map(u32::from_le_bytes)
+    }
+
+    /// Loads a doubleword as [MemoryMonitor::load_u64] does, but returns a
+    /// fault for a misaligned or unmapped `addr` rather than panicking.
+    pub fn try_load_u64(&mut self, addr: u64) -> Result<u64, InstructionException> {
+        self.try_load_array(addr).map(u64::from_le_bytes)
+    }
+
+    // Loads `N` bytes from `addr`, which must be aligned to `N`, the way the
+    // guest would, faulting at `addr` rather than failing.
+    fn try_load_array<const N: usize>(
+        &mut self,
+        addr: u64,
+    ) -> Result<[u8; N], InstructionException> {
+        if addr & (N as u64 - 1) != 0 {
+            return Err(InstructionException::AlignmentFault(addr));
+        }
+        let mut bytes = [0; N];
+        for (byte_addr, byte) in (addr..).zip(bytes.iter_mut()) {
+            *byte = self
+                .load_u8(byte_addr)
+                .map_err(|_| InstructionException::LoadAccessFault(addr))?;
+        }
+        Ok(bytes)
     }
 
     pub fn load_register(&mut self, idx: usize) -> u64 {
-        if idx == 2 && self.initial == false {
+        if idx == 2 && self.auto_seed_sp && !self.initial {
             // sp address
             // set stack address at the end
             self.initial = true;
             // FIXME: it will take effect at next instructoin
-            self.store_u64(get_register_addr(idx), STACK_INITIAL_ADDRESS as u64);
+            self.store_u64(get_register_addr(idx), self.initial_sp);
             // cant call load_u64 here since it haven't updated
-            STACK_INITIAL_ADDRESS as u64
+            self.initial_sp
         } else {
             let register_addr = get_register_addr(idx);
             self.load_u64(register_addr)
+                .expect("register file must be mapped")
         }
     }
 
@@ -104,21 +228,97 @@ impl MemoryMonitor {
         idxs.map(|idx| self.load_register(idx))
     }
 
-    pub fn load_string(&mut self, mut addr: u64) -> Result<String> {
+    /// All 32 registers, as [MemoryMonitor::load_registers] would return
+    /// them, read from the register file in one pass rather than a lookup per
+    /// register.
+    pub fn register_snapshot(&mut self) -> [u64; 32] {
+        let seed_sp = self.auto_seed_sp && !self.initial;
+        let mut registers = match self.read_slice(get_register_addr(0), 32) {
+            Some(words) => array::from_fn(|idx| words[idx]),
+            None => return self.load_registers(array::from_fn(|idx| idx)),
+        };
+        if seed_sp {
+            registers[2] = self.load_register(2);
+        }
+        registers
+    }
+
+    /// The `len` doublewords at the 8-byte aligned `addr`, borrowed straight
+    /// from the backing [VecMemory], or [None] if the range isn't within a
+    /// single readable [VecMemory] region.
+    pub fn read_slice(&self, addr: u64, len: usize) -> Option<&[u64]> {
+        if addr & (DOUBLE_WORD_SIZE as u64 - 1) != 0 {
+            return None;
+        }
+        let end = addr.checked_add(len as u64 * DOUBLE_WORD_SIZE as u64)?;
+        let memory_space = &self.image.memory_space;
+        let (idx, region) = memory_space
+            .regions()
+            .iter()
+            .enumerate()
+            .find(|(_, region)| region.base <= addr && end <= region.base + region.size)?;
+        if !region.perms.read {
+            return None;
+        }
+        let start = ((addr - region.base) / DOUBLE_WORD_SIZE as u64) as usize;
+        memory_space
+            .get_memory_ref::<VecMemory>(idx)?
+            .mem
+            .get(start..start + len)
+    }
+
+    /// The register file as a [HartState], for running it through rrs-lib
+    /// directly or comparing against one that was.
+    ///
+    /// Only the integer registers live in the monitor, everything else in
+    /// the [HartState] (the PC included) is left at its default.
+    // Reading memory takes `&mut self`
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_hart_state(&mut self) -> HartState {
+        HartState {
+            registers: self.register_snapshot(),
+            ..HartState::new()
+        }
+    }
+
+    /// Overwrite the register file with the integer registers of `hart`.
+    ///
+    /// Must only be used between instructions as it commits any pending
+    /// writes. `sp` is taken from `hart` rather than seeded afterwards.
+    pub fn load_from_hart_state(&mut self, hart: &HartState) {
+        for idx in 1..hart.registers.len() {
+            self.store_u64(get_register_addr(idx), hart.registers[idx]);
+        }
+        self.skip_sp_seed();
+        self.commit();
+    }
+
+    /// Loads a NUL-terminated string of at most `max_len` bytes (excluding the
+    /// terminator), failing rather than panicking if the string runs past
+    /// `max_len` or off the end of mapped memory.
+    pub fn load_string(&mut self, addr: u64, max_len: usize) -> Result<String> {
         let mut s: Vec<u8> = Vec::new();
-        loop {
-            let b = self.load_u8(addr);
+        for offset in 0..=max_len as u64 {
+            let byte_addr = addr
+                .checked_add(offset)
+                .ok_or_else(|| anyhow!("String at 0x{addr:016x} wraps the address space"))?;
+            let b = self
+                .image
+                .memory_space
+                .read_mem(byte_addr, MemAccessSize::Byte)
+                .ok_or_else(|| {
+                    anyhow!("String at 0x{addr:016x} runs into unmapped address 0x{byte_addr:016x}")
+                })? as u8;
             if b == 0 {
-                break;
+                return String::from_utf8(s).map_err(anyhow::Error::msg);
             }
             s.push(b);
-            addr += 1;
         }
-        String::from_utf8(s).map_err(anyhow::Error::msg)
+        bail!("String at 0x{addr:016x} is not terminated within {max_len} bytes")
     }
 
     pub fn store_u8(&mut self, addr: u64, data: u8) {
-        self.pending_writes.insert(MemStore { addr, data });
+        self.pending_writes.insert(addr, data);
     }
 
     pub fn store_u16(&mut self, addr: u64, data: u16) {
@@ -136,17 +336,34 @@ impl MemoryMonitor {
         self.store_region(addr, &data.to_le_bytes());
     }
 
+    /// Stores a contiguous region, replacing any pending stores it overlaps.
     pub fn store_region(&mut self, addr: u64, slice: &[u8]) {
-        slice
-            .iter()
-            .enumerate()
-            .for_each(|(i, x)| self.store_u8(addr + i as u64, *x));
+        self.pending_writes
+            .extend((addr..).zip(slice.iter().copied()));
+    }
+
+    /// Stores a region at a guest supplied `addr` as
+    /// [store_region](Self::store_region) does, failing instead if any of it
+    /// would land outside writable memory.
+    pub fn store_guest_region(&mut self, addr: u64, slice: &[u8]) -> Result<()> {
+        self.check_guest_writable(addr, slice.len() as u64)?;
+        self.store_region(addr, slice);
+        Ok(())
+    }
+
+    /// Fails unless the `len` bytes at a guest supplied `addr` all lie in
+    /// writable memory, so a syscall can check its whole output buffer
+    /// before producing anything for it.
+    pub fn check_guest_writable(&self, addr: u64, len: u64) -> Result<()> {
+        if !self.is_writable_range(addr, len) {
+            bail!("Store of {len} bytes to 0x{addr:016x} leaves writable memory");
+        }
+        Ok(())
     }
 
     pub fn store_register(&mut self, idx: usize, data: u64) {
-        if idx == 2 && data == 0u64 {
-            println!("reset sp happened here!");
-            self.store_u64(get_register_addr(idx), STACK_INITIAL_ADDRESS as u64)
+        if idx == 2 && data == 0u64 && self.auto_seed_sp {
+            self.store_u64(get_register_addr(idx), self.initial_sp)
         } else {
             self.store_u64(get_register_addr(idx), data);
         }
@@ -163,23 +380,31 @@ impl MemoryMonitor {
     // commit all pending activity
     pub fn commit(&mut self) {
         // cycle: usize) {
-        for op in self.pending_writes.iter() {
-            let res =
-                self.image
-                    .memory_space
-                    .write_mem(op.addr, MemAccessSize::Byte, u64::from(op.data));
+        for (&addr, &data) in self.pending_writes.iter() {
+            if let Some(journal) = &mut self.journal {
+                if let Some(old) = self.image.memory_space.read_mem(addr, MemAccessSize::Byte) {
+                    journal.writes.push((addr, old as u8));
+                }
+            }
+            let res = self
+                .image
+                .memory_space
+                .write_mem(addr, MemAccessSize::Byte, u64::from(data));
+            // Guest stores are checked by `write_mem`, so this is a store by
+            // the executor itself, e.g. a syscall writing to a bad buffer
             if res == false {
-                println!("addr out of bound, addr {:16x}", op.addr);
+                log::error!("Dropped write to unmapped address 0x{addr:016x}");
             }
             // self.image.buf[op.addr as usize] = op.data;
         }
         self.pending_writes.clear();
+        if let Some(mem_txns) = &mut self.mem_txns {
+            mem_txns.append(&mut self.pending_txns);
+        }
         // self.faults.append(&mut self.pending_faults);
         // self.cycle = cycle;
-        let op_result = self.op_result.take().unwrap();
-        if let Some(syscall) = op_result.syscall {
-            self.syscalls.push(syscall);
-        }
+        // The executor records syscalls once their writes are committed
+        self.op_result = None;
         // self.faults.dump();
     }
 
@@ -226,37 +451,171 @@ impl MemoryMonitor {
     //     })
     // }
 
+    /// Capture the state of the monitor for [MemoryMonitor::restore].
+    ///
+    /// Must only be used between instructions. From the first snapshot on
+    /// the monitor keeps the old value of every byte it writes, so a
+    /// snapshot costs no more than the memory written after it.
+    pub(crate) fn snapshot(&mut self) -> MonitorSnapshot {
+        let journal = self.journal.get_or_insert_with(WriteJournal::default);
+        MonitorSnapshot {
+            writes: journal.writes.len(),
+            restores: journal.restores.len(),
+            syscalls: self.syscalls.clone(),
+            mem_txns: self.mem_txns.as_ref().map_or(0, Vec::len),
+            initial: self.initial,
+            fault_injector: self.fault_injector.clone(),
+        }
+    }
+
+    /// Return memory and everything else captured by `snapshot` to how it
+    /// was when the snapshot was taken, dropping any pending writes.
+    ///
+    /// Fails if a restore to an earlier snapshot has since discarded the
+    /// writes needed to get back to this one.
+    pub(crate) fn restore(&mut self, snapshot: &MonitorSnapshot) -> Result<()> {
+        let journal = self
+            .journal
+            .as_mut()
+            .ok_or_else(|| anyhow!("Snapshot was taken from another monitor"))?;
+        let discarded = match journal.restores.get(snapshot.restores..) {
+            Some(restores) => restores.iter().any(|&writes| writes < snapshot.writes),
+            None => true,
+        };
+        if discarded || snapshot.writes > journal.writes.len() {
+            bail!("Snapshot was invalidated by restoring an earlier snapshot");
+        }
+
+        for (addr, old) in journal.writes.drain(snapshot.writes..).rev() {
+            self.image
+                .memory_space
+                .write_mem(addr, MemAccessSize::Byte, u64::from(old));
+        }
+        journal.restores.push(snapshot.writes);
+
+        self.pending_writes.clear();
+        self.pending_txns.clear();
+        self.op_result = None;
+        self.syscalls = snapshot.syscalls.clone();
+        if let Some(mem_txns) = &mut self.mem_txns {
+            mem_txns.truncate(snapshot.mem_txns);
+        }
+        self.initial = snapshot.initial;
+        self.fault_injector = snapshot.fault_injector.clone();
+        Ok(())
+    }
+
     pub fn clear_segment(&mut self) {
         // self.faults.clear();
         self.syscalls.clear();
+        if let Some(mem_txns) = &mut self.mem_txns {
+            mem_txns.clear();
+        }
     }
 
     pub fn clear_session(&mut self) {
         self.clear_segment();
         // self.pending_faults.clear();
         self.pending_writes.clear();
+        self.pending_txns.clear();
+    }
+
+    // Whether a store of `size` at `addr` lands wholly in writable memory
+    fn is_writable(&self, addr: u64, size: MemAccessSize) -> bool {
+        let len = match size {
+            MemAccessSize::Byte => 1,
+            MemAccessSize::HalfWord => 2,
+            MemAccessSize::Word => 4,
+            MemAccessSize::DoubleWord => 8,
+        };
+        self.is_writable_range(addr, len)
+    }
+
+    // Whether the `len` bytes at `addr` all land in writable memory, walking
+    // the regions they span rather than each byte
+    fn is_writable_range(&self, addr: u64, len: u64) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        let mut next = addr;
+        while next < end {
+            let region = self
+                .image
+                .memory_space
+                .regions()
+                .iter()
+                .find(|region| region.base <= next && next - region.base < region.size);
+            match region {
+                Some(region) if region.perms.write => next = region.base + region.size,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // Note a load or store by the guest, if they're being recorded, with
+    // `value` cut down to the size of the access.
+    fn record_txn(&mut self, addr: u64, value: u64, size: MemAccessSize, kind: AccessKind) {
+        if self.mem_txns.is_none() {
+            return;
+        }
+        let (size, value) = match size {
+            MemAccessSize::Byte => (1, value as u8 as u64),
+            MemAccessSize::HalfWord => (2, value as u16 as u64),
+            MemAccessSize::Word => (4, value as u32 as u64),
+            MemAccessSize::DoubleWord => (8, value),
+        };
+        self.pending_txns.push(MemTxn {
+            addr,
+            value,
+            size,
+            kind,
+        });
     }
 }
 
 impl Memory for MemoryMonitor {
     fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
-        match size {
-            MemAccessSize::Byte => Some(self.load_u8(addr) as u64),
-            MemAccessSize::HalfWord => Some(self.load_u16(addr) as u64),
-            MemAccessSize::Word => Some(self.load_u32(addr) as u64),
-            MemAccessSize::DoubleWord => Some(self.load_u64(addr)),
+        if addr < self.low_memory_limit {
+            return None;
         }
+
+        let value = match size {
+            MemAccessSize::Byte => self.load_u8(addr).ok().map(u64::from),
+            MemAccessSize::HalfWord => self.try_load_u16(addr).ok().map(u64::from),
+            MemAccessSize::Word => self.try_load_u32(addr).ok().map(u64::from),
+            MemAccessSize::DoubleWord => self.try_load_u64(addr).ok(),
+        };
+        let value = match self.fault_injector.as_mut() {
+            Some(injector) => injector.on_load(value),
+            None => value,
+        };
+        if let Some(value) = value {
+            self.record_txn(addr, value, size, AccessKind::Load);
+        }
+        value
     }
 
     fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        // Stores are only committed later, so check every byte is mapped and
+        // writable now to fault at the store itself.
+        if addr < self.low_memory_limit || !self.is_writable(addr, size) {
+            return false;
+        }
+
         match size {
             MemAccessSize::Byte => self.store_u8(addr, store_data as u8),
             MemAccessSize::HalfWord => self.store_u16(addr, store_data as u16),
             MemAccessSize::Word => self.store_u32(addr, store_data as u32),
             MemAccessSize::DoubleWord => self.store_u64(addr, store_data),
         };
+        self.record_txn(addr, store_data, size, AccessKind::Store);
         true
     }
+
+    fn read_insn(&mut self, addr: u64) -> Option<u64> {
+        self.image.memory_space.read_insn(addr)
+    }
 }
 
 impl MemoryMonitor {
@@ -277,6 +636,16 @@ impl MemoryMonitor {
     // }
 }
 
+// Fail a load from an `addr` that isn't aligned to `size`, a power of two,
+// with the AlignmentFault the guest would see, as it may have supplied
+// `addr`.
+fn check_aligned(addr: u64, size: u64) -> Result<()> {
+    if addr & (size - 1) != 0 {
+        bail!("{:?}", InstructionException::AlignmentFault(addr));
+    }
+    Ok(())
+}
+
 fn get_register_addr(idx: usize) -> u64 {
     (SYSTEM.start() + idx * DOUBLE_WORD_SIZE) as u64
 }
@@ -0,0 +1,118 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running an ELF start to finish in one call, for scripts and tests that
+//! only want to know how it ended.
+
+use anyhow::Result;
+use risc0_zkvm_platform::{fileno, syscall::ecall};
+
+use super::{Executor, ExecutorEnv};
+use crate::{ExitCode, Session};
+
+/// The outcome of a program run by [run_elf].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunResult {
+    /// The integer registers when the program stopped
+    pub registers: [u64; 32],
+    /// The bytes the program wrote to
+    /// [JOURNAL](risc0_zkvm_platform::fileno::JOURNAL), in order
+    pub journal: Vec<u8>,
+    /// How the program stopped
+    pub exit_code: ExitCode,
+    /// The cycles the program ran for
+    pub cycles: usize,
+}
+
+/// Run the program in `elf` with `stdin` as its standard input, using the
+/// default [ExecutorEnv], until it stops.
+///
+/// Fails if the ELF can't be loaded or the executor itself fails. A program
+/// that faults, aborts or hits the session limit still returns a
+/// [RunResult], its [ExitCode] saying which.
+pub fn run_elf(elf: &[u8], stdin: &[u8]) -> Result<RunResult> {
+    let env = ExecutorEnv::builder().stdin(stdin).build();
+    let mut exec = Executor::from_elf(env, elf)?;
+    let session = exec.run()?;
+
+    Ok(RunResult {
+        registers: exec.monitor.register_snapshot(),
+        journal: journal(&session),
+        exit_code: session.exit_code,
+        cycles: exec.cycle,
+    })
+}
+
+// Everything written to the journal over the session.
+fn journal(session: &Session) -> Vec<u8> {
+    session
+        .syscalls()
+        .filter(|record| record.num() == ecall::WRITE && record.args()[0] == fileno::JOURNAL as u64)
+        .flat_map(|record| record.bytes_out().iter().copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::abi::PF_R;
+    use risc0_zkvm_platform::memory::STACK_INITIAL_ADDRESS;
+
+    use super::run_elf;
+    use crate::{
+        binfmt::test_elf::{insn_bytes, ElfBuilder, RX},
+        ExitCode,
+    };
+
+    const TEXT_ADDR: u64 = 0x1000;
+    const DATA_ADDR: u64 = 0x2000;
+
+    // Writes "hello" to the journal, sets x5 and halts:
+    //
+    //   addi a0, x0, 3
+    //   lui a1, 0x2
+    //   addi a2, x0, 5
+    //   addi a7, x0, 64
+    //   ecall
+    //   addi x5, x0, 42
+    //   addi a0, x0, 0
+    //   addi a7, x0, 0
+    //   ecall
+    const PROGRAM: [u32; 9] = [
+        0x00300513, 0x000025b7, 0x00500613, 0x04000893, 0x00000073, 0x02a00293, 0x00000513,
+        0x00000893, 0x00000073,
+    ];
+    const DATA: &[u8] = b"hello";
+
+    // A RISC-V executable, without sections, with `PROGRAM` in a read/exec
+    // segment and `DATA` in a read-only one.
+    fn build_elf() -> Vec<u8> {
+        ElfBuilder::new(TEXT_ADDR)
+            .segment(RX, TEXT_ADDR, &insn_bytes(&PROGRAM))
+            .segment(PF_R, DATA_ADDR, DATA)
+            .build()
+    }
+
+    #[test]
+    fn run_elf_result() {
+        let result = run_elf(&build_elf(), &[]).unwrap();
+
+        assert_eq!(result.exit_code, ExitCode::Halted(0));
+        assert_eq!(result.journal, DATA);
+        assert_eq!(result.cycles, PROGRAM.len());
+        assert_eq!(result.registers[2], STACK_INITIAL_ADDRESS as u64);
+        assert_eq!(result.registers[5], 42);
+        assert_eq!(result.registers[11], DATA_ADDR);
+        assert_eq!(result.registers[12], DATA.len() as u64);
+    }
+}
@@ -0,0 +1,1257 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    array,
+    collections::{BTreeMap, HashMap},
+};
+
+use elf::abi::{SHF_EXECINSTR, STT_FUNC};
+use risc0_zkvm_platform::{
+    fileno,
+    memory::STACK_INITIAL_ADDRESS,
+    syscall::{ecall, reg_abi::REG_A0},
+    PAGE_SIZE,
+};
+use rrs_lib::{
+    instruction_executor::{InstructionException, InstructionExecutor},
+    memories::VecMemory,
+};
+
+use super::{
+    monitor::MemoryMonitor, read_trace, AccessKind, ClockSource, Executor, ExecutorEnv,
+    ExecutorEnvBuilder, ExecutorState, Fault, FaultPlan, MemTxn, SyscallRecord, TraceRecord,
+    TraceReplay, TRACE_VERSION,
+};
+use crate::{
+    binfmt::{
+        elf::Program,
+        image::MemoryImage,
+        test_elf::{insn_bytes, ElfBuilder, RX},
+    },
+    ExitCode,
+};
+
+const ENTRY: u64 = 0x1000;
+
+// addi a7, x0, 0
+// addi a0, x0, 0
+// ecall
+const HALT: [u32; 3] = [0x00000893, 0x00000513, 0x00000073];
+
+fn executor<'a>(env: ExecutorEnv<'a>, insns: &[u32]) -> Executor<'a> {
+    let image: BTreeMap<u64, u32> = insns
+        .iter()
+        .chain(HALT.iter())
+        .enumerate()
+        .map(|(idx, insn)| (ENTRY + idx as u64 * 4, *insn))
+        .collect();
+    let text = ENTRY..ENTRY + image.len() as u64 * 4;
+    let program = Program {
+        entry: ENTRY,
+        image,
+        text_ranges: vec![text],
+        data_ranges: Vec::new(),
+        entry_symbol: None,
+    };
+    let image = MemoryImage::new(&program, PAGE_SIZE as u64);
+    Executor::new(env, image, ENTRY)
+}
+
+#[test]
+fn rdtime_fixed_clock() {
+    let env = ExecutorEnv::builder()
+        .clock(ClockSource::Fixed(12345))
+        .build();
+
+    // rdtime x5
+    let mut exec = executor(env, &[0xc01022f3]);
+    let session = exec.run().unwrap();
+
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+    assert_eq!(exec.monitor.load_register(5), 12345);
+}
+
+#[test]
+fn rdtime_defaults_to_zero() {
+    let env = ExecutorEnv::default();
+
+    // addi x5, x0, -1
+    // rdtime x5
+    let mut exec = executor(env, &[0xfff00293, 0xc01022f3]);
+    exec.run().unwrap();
+
+    assert_eq!(exec.monitor.load_register(5), 0);
+}
+
+#[test]
+fn float_registers_persist() {
+    // addi x5, x0, 3
+    // addi x6, x0, 4
+    // fcvt.s.w f1, x5
+    // fcvt.s.w f2, x6
+    // fadd.s f3, f1, f2
+    // fcvt.w.s x7, f3, rtz
+    let insns = [
+        0x00300293, 0x00400313, 0xd00280d3, 0xd0030153, 0x002081d3, 0xc00193d3,
+    ];
+    let mut exec = executor(ExecutorEnv::default(), &insns);
+    exec.run().unwrap();
+
+    assert_eq!(exec.monitor.load_register(7), 7);
+
+    let paused = exec.pause();
+    assert_eq!(f32::from_bits(paused.state().fregisters[3]), 7.0);
+    assert_eq!(paused.state().fcsr, 0);
+
+    let words = crate::serde::to_vec(paused.state()).unwrap();
+    let state: ExecutorState = crate::serde::from_slice(&words).unwrap();
+    assert_eq!(&state, paused.state());
+}
+
+#[test]
+fn pause_and_resume() {
+    // addi x5, x0, 1
+    // addi x6, x5, 2
+    // add x7, x5, x6
+    // slli x7, x7, 3
+    // sd x7, 0x400(x0)
+    // ld x8, 0x400(x0)
+    let insns = [
+        0x00100293, 0x00228313, 0x006283b3, 0x00339393, 0x40703023, 0x40003403,
+    ];
+
+    let mut straight = executor(ExecutorEnv::default(), &insns);
+    straight.run().unwrap();
+
+    let mut first_half = executor(ExecutorEnv::default(), &insns);
+    for _ in 0..3 {
+        assert_eq!(first_half.step().unwrap(), None);
+    }
+
+    let paused = first_half.pause();
+    assert_eq!(paused.state().pc, ENTRY + 12);
+    assert_eq!(paused.state().insn_counter, 3);
+
+    let words = crate::serde::to_vec(paused.state()).unwrap();
+    let state: ExecutorState = crate::serde::from_slice(&words).unwrap();
+    assert_eq!(&state, paused.state());
+
+    let mut resumed = paused.resume();
+    let session = resumed.run().unwrap();
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+
+    for idx in 0..32 {
+        assert_eq!(
+            resumed.monitor.load_register(idx),
+            straight.monitor.load_register(idx)
+        );
+    }
+    assert_eq!(resumed.monitor.load_register(8), 32);
+    assert_eq!(resumed.pause().state(), straight.pause().state());
+}
+
+// The bytes written to the journal by `records`.
+fn journal<'r>(records: impl IntoIterator<Item = &'r SyscallRecord>) -> Vec<u8> {
+    records
+        .into_iter()
+        .filter(|record| record.num() == ecall::WRITE && record.args()[0] == fileno::JOURNAL as u64)
+        .flat_map(|record| record.bytes_out().iter().copied())
+        .collect()
+}
+
+#[test]
+fn resume_from_serialized_state() {
+    // addi x5, x0, 0x61
+    // sb x5, 0x400(x0)
+    // addi x5, x0, 0x62
+    // sb x5, 0x401(x0)
+    // addi a0, x0, 3
+    // addi a1, x0, 0x400
+    // addi a2, x0, 1
+    // addi a7, x0, 64
+    // ecall
+    // addi a0, x0, 3
+    // addi a1, x0, 0x401
+    // ecall
+    // addi x9, sp, 0
+    let insns = [
+        0x06100293, 0x40500023, 0x06200293, 0x405000a3, 0x00300513, 0x40000593, 0x00100613,
+        0x04000893, 0x00000073, 0x00300513, 0x40100593, 0x00000073, 0x00010493,
+    ];
+
+    let mut straight = executor(ExecutorEnv::default(), &insns);
+    let session = straight.run().unwrap();
+    assert_eq!(journal(session.syscalls()), b"ab");
+
+    // Pause after the first write, before sp is ever read
+    let mut first_half = executor(ExecutorEnv::default(), &insns);
+    for _ in 0..9 {
+        assert_eq!(first_half.step().unwrap(), None);
+    }
+    let mut written = journal(&first_half.monitor.syscalls);
+    let paused = first_half.pause();
+
+    let state = crate::serde::to_vec(paused.state()).unwrap();
+    let mut image = Vec::new();
+    paused.image().serialize(&mut image).unwrap();
+    drop(paused);
+
+    let state: ExecutorState = crate::serde::from_slice(&state).unwrap();
+    let image = MemoryImage::deserialize(&mut image.as_slice()).unwrap();
+    let mut resumed = Executor::from_state(ExecutorEnv::default(), image, state);
+    let session = resumed.run().unwrap();
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+
+    written.extend(journal(session.syscalls()));
+    assert_eq!(written, b"ab");
+    assert_eq!(
+        resumed.monitor.load_register(9),
+        STACK_INITIAL_ADDRESS as u64
+    );
+    assert_eq!(
+        resumed.monitor.register_snapshot(),
+        straight.monitor.register_snapshot()
+    );
+    assert_eq!(resumed.pause().state(), straight.pause().state());
+}
+
+#[test]
+fn verify_replays_segments() {
+    // The program from `resume_from_serialized_state`
+    let insns = [
+        0x06100293, 0x40500023, 0x06200293, 0x405000a3, 0x00300513, 0x40000593, 0x00100613,
+        0x04000893, 0x00000073, 0x00300513, 0x40100593, 0x00000073, 0x00010493,
+    ];
+    let env = ExecutorEnv::builder().segment_limit_po2(2).build();
+    let session = executor(env, &insns).run().unwrap();
+    assert!(session.segments.len() > 1);
+    let (last, rest) = session.segments.split_last().unwrap();
+    assert!(rest
+        .iter()
+        .all(|segment| segment.exit_code == ExitCode::SystemSplit));
+    assert_eq!(last.exit_code, ExitCode::Halted(0));
+    assert_eq!(journal(session.syscalls()), b"ab");
+    session.verify(&ExecutorEnv::default()).unwrap();
+
+    let mut moved = executor(ExecutorEnv::builder().segment_limit_po2(2).build(), &insns)
+        .run()
+        .unwrap();
+    moved.segments[1].pre_state.pc += 4;
+    assert!(moved.verify(&ExecutorEnv::default()).is_err());
+
+    let mut misreported = executor(ExecutorEnv::builder().segment_limit_po2(2).build(), &insns)
+        .run()
+        .unwrap();
+    misreported.exit_code = ExitCode::Fault;
+    assert!(misreported.verify(&ExecutorEnv::default()).is_err());
+
+    let mut unsplit = executor(ExecutorEnv::builder().segment_limit_po2(2).build(), &insns)
+        .run()
+        .unwrap();
+    unsplit.segments[0].exit_code = ExitCode::Halted(0);
+    assert!(unsplit.verify(&ExecutorEnv::default()).is_err());
+}
+
+#[test]
+fn snapshot_and_restore() {
+    // addi x5, x0, 1
+    // sd x5, 0x400(x0)
+    // addi x5, x5, 1
+    // sd x5, 0x400(x0)
+    // addi x5, x5, 1
+    // sd x5, 0x400(x0)
+    let insns = [
+        0x00100293, 0x40503023, 0x00128293, 0x40503023, 0x00128293, 0x40503023,
+    ];
+    let mut exec = executor(ExecutorEnv::default(), &insns);
+    for _ in 0..2 {
+        assert_eq!(exec.step().unwrap(), None);
+    }
+
+    let first = exec.snapshot();
+    assert_eq!(first.state().pc, ENTRY + 8);
+    for _ in 0..2 {
+        assert_eq!(exec.step().unwrap(), None);
+    }
+    assert_eq!(exec.monitor.load_register(5), 2);
+    assert_eq!(exec.monitor.load_u64(0x400).unwrap(), 2);
+    let second = exec.snapshot();
+
+    // Restoring twice replays the same steps from the same state
+    for _ in 0..2 {
+        exec.restore(&first).unwrap();
+        assert_eq!(exec.state(), *first.state());
+        assert_eq!(exec.monitor.load_register(5), 1);
+        assert_eq!(exec.monitor.load_u64(0x400).unwrap(), 1);
+
+        let session = exec.run().unwrap();
+        assert_eq!(session.exit_code, ExitCode::Halted(0));
+        assert_eq!(exec.monitor.load_u64(0x400).unwrap(), 3);
+    }
+
+    // The writes needed to get back to `second` were undone
+    let err = exec.restore(&second).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Snapshot was invalidated by restoring an earlier snapshot"
+    );
+}
+
+#[test]
+fn session_limit() {
+    let env = ExecutorEnv::builder().session_limit(100).build();
+
+    // loop: jal x0, loop
+    let mut exec = executor(env, &[0x0000006f]);
+    let session = exec.run().unwrap();
+
+    assert_eq!(session.exit_code, ExitCode::SessionLimit);
+    assert_eq!(exec.pause().state().cycle, 100);
+}
+
+#[test]
+fn cost_model() {
+    // addi x5, x0, 3
+    // mul x6, x5, x5
+    let insns = [0x00300293, 0x02528333];
+    let run = |env: ExecutorEnv| {
+        let mut exec = executor(env, &insns);
+        exec.run().unwrap();
+        assert_eq!(exec.monitor.load_register(6), 9);
+        exec.pause().state().cycle
+    };
+
+    let default = run(ExecutorEnv::default());
+    let costs = HashMap::from([("MUL".to_string(), 5)]);
+    let custom = run(ExecutorEnv::builder().cost_model(costs).build());
+    assert_eq!(custom, default + 4);
+}
+
+#[test]
+fn infinite_loop() {
+    let run = |insns: &[u32]| {
+        let env = ExecutorEnv::builder()
+            .session_limit(100)
+            .detect_infinite_loops(true)
+            .build();
+        let mut exec = executor(env, insns);
+        let exit_code = exec.run().unwrap().exit_code;
+        (exit_code, exec.pause().state().cycle)
+    };
+
+    // loop: jal x0, loop
+    assert_eq!(run(&[0x0000006f]), (ExitCode::InfiniteLoop(ENTRY), 1));
+
+    // The first jump writes the link register, the second changes nothing
+    // loop: jal x1, loop
+    assert_eq!(run(&[0x000000ef]), (ExitCode::InfiniteLoop(ENTRY), 2));
+
+    // A loop polling memory isn't detected
+    // loop: ld x5, 0x400(x0)
+    // beq x5, x0, loop
+    assert_eq!(
+        run(&[0x40003283, 0xfe028ee3]),
+        (ExitCode::SessionLimit, 100)
+    );
+}
+
+#[test]
+fn load_signed() {
+    let data = [0xff, 0x7f, 0x00, 0x80, 0xfe, 0xff, 0xff, 0xff];
+    let image = MemoryImage::from_bytes(0x1000, &data, 0x10).unwrap();
+    let mut monitor = MemoryMonitor::new(image);
+
+    assert_eq!(monitor.load_i8(0x1000).unwrap(), -1);
+    assert_eq!(monitor.load_i8(0x1001).unwrap(), 0x7f);
+    assert_eq!(monitor.load_u8(0x1000).unwrap(), 0xff);
+    assert_eq!(monitor.load_i16(0x1000).unwrap(), 0x7fff);
+    assert_eq!(monitor.load_i16(0x1002).unwrap(), -0x8000);
+    assert_eq!(monitor.load_i32(0x1004).unwrap(), -2);
+    assert_eq!(monitor.load_i32(0x1000).unwrap(), -0x7fff_8001);
+    assert!(monitor.load_i8(0x1010).is_err());
+    assert_eq!(
+        monitor.load_i32(0x1002).unwrap_err().to_string(),
+        format!("AlignmentFault({})", 0x1002)
+    );
+    assert!(monitor.load_i16(0x1001).is_err());
+    assert!(monitor.load_u64(0x1004).is_err());
+}
+
+#[test]
+fn load_string_bounded() {
+    let image = MemoryImage::from_bytes(0x1000, b"hello\0unterminated", 0x18).unwrap();
+    let mut monitor = MemoryMonitor::new(image);
+
+    assert_eq!(monitor.load_string(0x1000, 5).unwrap(), "hello");
+
+    let err = monitor.load_string(0x1000, 4).unwrap_err();
+    assert!(err.to_string().contains("not terminated within 4 bytes"));
+
+    // A string filling the region without a NUL stops at the end of mapped memory
+    let image = MemoryImage::from_bytes(0x1000, b"no terminator...", 0x10).unwrap();
+    let mut monitor = MemoryMonitor::new(image);
+    let err = monitor.load_string(0x1000, 0x100).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("unmapped address 0x0000000000001010"));
+}
+
+#[test]
+fn store_region_bulk() {
+    const LEN: usize = 0x10000;
+    let image = MemoryImage::from_bytes(0x10000, &[], 2 * LEN as u64).unwrap();
+    let mut monitor = MemoryMonitor::new(image);
+
+    let data: Vec<u8> = (0..LEN).map(|i| (i * 7 + i / 251) as u8).collect();
+    monitor.store_region(0x10000, &data);
+    // Overlapping stores before a commit must leave the last value written
+    monitor.store_u64(0x10008, 0x0807060504030201);
+    monitor.store_u8(0x1000a, 0xff);
+    monitor.commit();
+
+    let mut expected = data;
+    expected[8..16].copy_from_slice(&[1, 2, 0xff, 4, 5, 6, 7, 8]);
+    for (idx, byte) in expected.iter().enumerate() {
+        assert_eq!(monitor.load_u8(0x10000 + idx as u64).unwrap(), *byte);
+    }
+    assert_eq!(monitor.load_u64(0x10008).unwrap(), 0x08070605_04ff0201);
+    assert_eq!(monitor.load_u8(0x10000 + LEN as u64).unwrap(), 0);
+}
+
+#[test]
+fn ebreak_faults() {
+    let mut syscalls = 0;
+    let env = ExecutorEnvBuilder::default()
+        .on_syscall(|_| syscalls += 1)
+        .build();
+    // ebreak
+    let err = executor(env, &[0x00100073]).run().err().unwrap();
+    assert_eq!(err.to_string(), format!("Breakpoint({ENTRY})"));
+    assert_eq!(syscalls, 0);
+}
+
+#[test]
+fn unmapped_load_faults() {
+    // lui x6, 0x20000
+    // ld x5, 0(x6)
+    let mut exec = executor(ExecutorEnv::default(), &[0x20000337, 0x00033283]);
+
+    assert_eq!(exec.step().unwrap(), None);
+    let err = exec.step().unwrap_err();
+    assert_eq!(err.to_string(), format!("LoadAccessFault({})", 0x2000_0000));
+}
+
+#[test]
+fn entry_in_text_init() {
+    // .text.init:
+    //   jal x0, 0x100
+    // .text:
+    //   halt
+    let mut text = insn_bytes(&[0x1000006f]);
+    text.resize(0x100, 0);
+    text.extend(insn_bytes(&HALT));
+    let elf = ElfBuilder::new(ENTRY)
+        .segment(RX, ENTRY, &text)
+        .section(".text.init", SHF_EXECINSTR, ENTRY, 4)
+        .section(".text", SHF_EXECINSTR, ENTRY + 0x100, 12)
+        .symbol("_start", STT_FUNC, ENTRY)
+        .build();
+
+    let mut exec = Executor::from_elf(ExecutorEnv::default(), &elf).unwrap();
+    assert_eq!(exec.run().unwrap().exit_code, ExitCode::Halted(0));
+}
+
+#[test]
+fn unmapped_store_faults() {
+    // lui x6, 0x10000
+    // sd x5, 0(x6)
+    let mut exec = executor(ExecutorEnv::default(), &[0x10000337, 0x00533023]);
+
+    assert_eq!(exec.step().unwrap(), None);
+    let err = exec.step().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("StoreAccessFault({})", 0x1000_0000)
+    );
+    assert!(!exec.monitor.image.memory_space.is_mapped(0x1000_0000));
+}
+
+#[test]
+fn misaligned_load_faults() {
+    // lw x5, 0x402(x0)
+    let mut exec = executor(ExecutorEnv::default(), &[0x40202283]);
+
+    let err = exec.step().unwrap_err();
+    assert_eq!(err.to_string(), format!("AlignmentFault({})", 0x402));
+
+    let monitor = &mut exec.monitor;
+    monitor.store_u32(0x400, 0x44332211);
+    monitor.commit();
+    assert_eq!(monitor.try_load_u16(0x402), Ok(0x4433));
+    assert_eq!(monitor.try_load_u32(0x400), Ok(0x44332211));
+    assert_eq!(
+        monitor.try_load_u32(0x402),
+        Err(InstructionException::AlignmentFault(0x402))
+    );
+    assert_eq!(
+        monitor.try_load_u64(0x2000_0000),
+        Err(InstructionException::LoadAccessFault(0x2000_0000))
+    );
+}
+
+#[test]
+fn trap_low_memory() {
+    let run = |insns: &[u32]| {
+        let env = ExecutorEnv::builder().trap_low_memory(0x400).build();
+        let mut exec = executor(env, insns);
+        exec.run().map(|session| session.exit_code)
+    };
+
+    // ld x5, 0(x0)
+    let err = run(&[0x00003283]).unwrap_err();
+    assert_eq!(err.to_string(), "LoadAccessFault(0)");
+
+    // sd x5, 0x3f8(x0)
+    let err = run(&[0x3e503c23]).unwrap_err();
+    assert_eq!(err.to_string(), format!("StoreAccessFault({})", 0x3f8));
+
+    // sd x5, 0x400(x0)
+    // ld x5, 0x400(x0)
+    assert_eq!(run(&[0x40503023, 0x40003283]).unwrap(), ExitCode::Halted(0));
+}
+
+#[test]
+fn text_is_not_writable_and_data_not_executable() {
+    // lui x5, 0x1
+    // sw x0, 0(x5)
+    let mut exec = executor(ExecutorEnv::default(), &[0x000012b7, 0x0002a023]);
+
+    assert_eq!(exec.step().unwrap(), None);
+    let err = exec.step().unwrap_err();
+    assert_eq!(err.to_string(), format!("StoreAccessFault({})", ENTRY));
+
+    // jalr x0, 0x400(x0)
+    let mut exec = executor(ExecutorEnv::default(), &[0x40000067]);
+
+    assert_eq!(exec.step().unwrap(), None);
+    let err = exec.step().unwrap_err();
+    assert_eq!(err.to_string(), format!("FetchError({})", 0x400));
+}
+
+#[test]
+fn trace_round_trip() {
+    // addi x5, x0, 0x400
+    // addi x6, x0, 42
+    // sd x6, 0(x5)
+    // ld x7, 0(x5)
+    // beq x0, x0, 8
+    // addi x6, x0, 1
+    let insns = [
+        0x40000293, 0x02a00313, 0x0062b023, 0x0002b383, 0x00000463, 0x00100313,
+    ];
+
+    let mut buf = Vec::new();
+    let env = ExecutorEnv::builder().trace_writer(&mut buf).build();
+    let mut exec = executor(env, &insns);
+    exec.run().unwrap();
+    drop(exec);
+
+    let trace = read_trace(buf.as_slice()).unwrap();
+    // The skipped addi isn't retired, the halt sequence is
+    assert_eq!(trace.len(), 5 + HALT.len());
+
+    let record = |pc: u64, insn: u32, rd: u8, rd_value: u64| TraceRecord {
+        pc,
+        insn,
+        rd,
+        rd_value,
+        mem_addr: 0,
+        mem_value: 0,
+        access_kind: AccessKind::None,
+        fault: false,
+    };
+    assert_eq!(trace[0], record(ENTRY, insns[0], 5, 0x400));
+    assert_eq!(
+        trace[2],
+        TraceRecord {
+            mem_addr: 0x400,
+            mem_value: 42,
+            access_kind: AccessKind::Store,
+            ..record(ENTRY + 8, insns[2], 0, 0)
+        }
+    );
+    assert_eq!(
+        trace[3],
+        TraceRecord {
+            mem_addr: 0x400,
+            mem_value: 42,
+            access_kind: AccessKind::Load,
+            ..record(ENTRY + 12, insns[3], 7, 42)
+        }
+    );
+    // A taken branch has no register or memory effects
+    assert_eq!(trace[4], record(ENTRY + 16, insns[4], 0, 0));
+    assert_eq!(trace[7], record(ENTRY + 32, HALT[2], 0, 0));
+
+    // The header carries the format version, and other versions are refused
+    assert_eq!(buf[..4], *b"RVTR");
+    assert_eq!(buf[4..8], TRACE_VERSION.to_le_bytes());
+    buf[4..8].copy_from_slice(&(TRACE_VERSION + 1).to_le_bytes());
+    let err = read_trace(buf.as_slice()).unwrap_err();
+    assert!(err.to_string().contains("Unsupported trace version"));
+    assert!(read_trace(&buf[8..]).is_err());
+}
+
+#[test]
+fn on_syscall_hook() {
+    // addi a7, x0, CLOSE
+    // ecall
+    // addi a7, x0, GETTID
+    // ecall
+    let insns = [
+        0x00000893 | (ecall::CLOSE as u32) << 20,
+        0x00000073,
+        0x00000893 | (ecall::GETTID as u32) << 20,
+        0x00000073,
+    ];
+
+    let mut records = Vec::new();
+    let env = ExecutorEnvBuilder::default()
+        .on_syscall(|record| records.push(record.clone()))
+        .build();
+    let mut exec = executor(env, &insns);
+    let session = exec.run().unwrap();
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+    drop(exec);
+
+    // Both syscalls and the halt
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].num(), ecall::CLOSE);
+    assert_eq!(records[0].result(), 0);
+    assert_eq!(records[1].num(), ecall::GETTID);
+    assert_eq!(records[1].result(), 1000);
+}
+
+#[test]
+fn write_syscall_record() {
+    // addi a0, x0, 1
+    // addi a1, x0, 0x400
+    // addi a2, x0, 5
+    // addi a7, x0, WRITE
+    // ecall
+    let insns = [
+        0x00100513,
+        0x40000593,
+        0x00500613,
+        0x00000893 | (ecall::WRITE as u32) << 20,
+        0x00000073,
+    ];
+
+    let mut records = Vec::new();
+    let env = ExecutorEnvBuilder::default()
+        .on_syscall(|record| records.push(record.clone()))
+        .build();
+    let mut exec = executor(env, &insns);
+    exec.monitor.store_region(0x400, b"hello");
+    exec.monitor.commit();
+    exec.run().unwrap();
+    drop(exec);
+
+    let record = &records[0];
+    assert_eq!(record.num(), ecall::WRITE);
+    assert_eq!(record.args()[..3], [1, 0x400, 5]);
+    assert_eq!(record.result(), 5);
+    assert_eq!(record.bytes_out(), b"hello");
+    assert!(record.bytes_in().is_empty());
+}
+
+#[test]
+fn write_from_unmapped_buffer() {
+    // addi a0, x0, 1
+    // lui a1, 0x20000
+    // addi a2, x0, 5
+    // addi a7, x0, WRITE
+    // ecall
+    let insns = [
+        0x00100513,
+        0x200005b7,
+        0x00500613,
+        0x00000893 | (ecall::WRITE as u32) << 20,
+        0x00000073,
+    ];
+
+    let mut exec = executor(ExecutorEnv::default(), &insns);
+    let err = exec.run().err().unwrap();
+    assert!(err
+        .to_string()
+        .contains("unmapped address 0x0000000020000000"));
+}
+
+#[test]
+fn session_syscalls() {
+    // addi a0, x0, 1
+    // addi a1, x0, 0x400
+    // addi a2, x0, 5
+    // addi a7, x0, WRITE
+    // ecall
+    // addi a2, x0, 2
+    // ecall
+    let insns = [
+        0x00100513,
+        0x40000593,
+        0x00500613,
+        0x00000893 | (ecall::WRITE as u32) << 20,
+        0x00000073,
+        0x00200613,
+        0x00000073,
+    ];
+
+    let mut exec = executor(ExecutorEnv::default(), &insns);
+    exec.monitor.store_region(0x400, b"hello");
+    exec.monitor.commit();
+    let session = exec.run().unwrap();
+
+    assert_eq!(session.segments.len(), 1);
+    let records: Vec<_> = session.syscalls().collect();
+    assert_eq!(
+        records,
+        session.segments[0].syscalls().iter().collect::<Vec<_>>()
+    );
+    // Both writes, then the halt
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].bytes_out(), b"hello");
+    assert_eq!(records[1].bytes_out(), b"he");
+    assert_eq!(records[2].num(), ecall::HALT);
+}
+
+#[test]
+fn on_error_ends_session() {
+    // addi x5, x0, 7
+    // (illegal)
+    let insns = [0x00700293, 0xffffffff];
+
+    assert!(executor(ExecutorEnv::default(), &insns).run().is_err());
+
+    let mut records = Vec::new();
+    let env = ExecutorEnvBuilder::default()
+        .on_error(|record| {
+            records.push((
+                record.state.pc,
+                record.insn,
+                record.registers[5],
+                record.error.to_string(),
+            ))
+        })
+        .build();
+    let mut exec = executor(env, &insns);
+    let session = exec.run().unwrap();
+    assert_eq!(session.exit_code, ExitCode::Fault);
+    assert_eq!(exec.pause().state().insn_counter, 1);
+
+    assert_eq!(records.len(), 1);
+    let (pc, insn, x5, error) = &records[0];
+    assert_eq!(*pc, ENTRY + 4);
+    assert_eq!(*insn, Some(0xffffffff));
+    assert_eq!(*x5, 7);
+    assert!(!error.is_empty());
+}
+
+#[test]
+fn custom_stack() {
+    let initial_sp = 0x0800_0000;
+    let env = ExecutorEnvBuilder::default()
+        .stack(initial_sp, 0x10_0000)
+        .build();
+    assert_eq!(env.stack_region(), 0x07f0_0000..initial_sp);
+
+    // addi x5, x2, 0
+    let mut exec = executor(env, &[0x00010293]);
+    assert_eq!(exec.step().unwrap(), None);
+
+    assert_eq!(exec.monitor.load_register(5), initial_sp);
+    assert_eq!(exec.monitor.load_register(2), initial_sp);
+}
+
+#[test]
+fn args_on_stack() {
+    let env = ExecutorEnvBuilder::default()
+        .args(&["prog", "--flag"])
+        .env_var("NAME", "value")
+        .build();
+
+    // ld x5, 0(x2)
+    // ld x6, 8(x2)
+    // ld x7, 24(x2)
+    let mut exec = executor(env, &[0x00013283, 0x00813303, 0x01813383]);
+    exec.run().unwrap();
+
+    let sp = exec.monitor.load_register(2);
+    assert_eq!(sp % 16, 0);
+    assert_eq!(exec.monitor.load_register(5), 2);
+    let argv0 = exec.monitor.load_register(6);
+    assert_eq!(exec.monitor.load_string(argv0, 16).unwrap(), "prog");
+    // argv[2] is the NULL terminator
+    assert_eq!(exec.monitor.load_register(7), 0);
+
+    // envp follows argv, then auxv ends with AT_NULL
+    let envp0 = exec.monitor.load_u64(sp + 32).unwrap();
+    assert_eq!(exec.monitor.load_string(envp0, 16).unwrap(), "NAME=value");
+    assert_eq!(exec.monitor.load_u64(sp + 40).unwrap(), 0);
+    assert_eq!(exec.monitor.load_u64(sp + 64).unwrap(), 0);
+    assert_eq!(exec.monitor.load_u64(sp + 72).unwrap(), 0);
+}
+
+#[test]
+fn getrandom_is_seeded() {
+    // addi a0, x0, 0x400
+    // addi a1, x0, 16
+    // addi a7, x0, GETRANDOM
+    // ecall
+    let insns = [
+        0x40000513,
+        0x01000593,
+        0x00000893 | (ecall::GETRANDOM as u32) << 20,
+        0x00000073,
+    ];
+    let random_bytes = |seed: u64| {
+        let mut records = Vec::new();
+        let env = ExecutorEnvBuilder::default()
+            .rng_seed(seed)
+            .on_syscall(|record| records.push(record.clone()))
+            .build();
+        let mut exec = executor(env, &insns);
+        exec.run().unwrap();
+        let words = vec![
+            exec.monitor.load_u64(0x400).unwrap(),
+            exec.monitor.load_u64(0x408).unwrap(),
+        ];
+        // Nothing past the 16 bytes asked for is written
+        assert_eq!(exec.monitor.load_u64(0x410).unwrap(), 0);
+        drop(exec);
+
+        assert_eq!(records[0].args()[..2], [0x400, 16]);
+        assert_eq!(records[0].result(), 16);
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        assert_eq!(records[0].bytes_in(), bytes);
+        words
+    };
+
+    let first = random_bytes(42);
+    assert_ne!(first, [0, 0]);
+    assert_eq!(random_bytes(42), first);
+    assert_ne!(random_bytes(43), first);
+
+    // A buffer wrapping the address space, or bigger than guest memory, is
+    // refused before anything is generated
+    for (buf, len) in [(0xfff00513, 0x01000593), (0x40000513, 0xfff00593)] {
+        let insns = [buf, len, insns[2], insns[3]];
+        let err = executor(ExecutorEnv::default(), &insns)
+            .run()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("leaves writable memory"), "{err}");
+    }
+}
+
+#[test]
+fn injected_faults() {
+    // addi x5, x0, 0x400
+    // addi x6, x0, 42
+    // sd x6, 0(x5)
+    // ld x7, 0(x5)
+    // ld x8, 0(x5)
+    let insns = [0x40000293, 0x02a00313, 0x0062b023, 0x0002b383, 0x0002b403];
+
+    let plan = FaultPlan::new()
+        .with_fault(Fault::FlipLoadBit { load: 0, bit: 8 })
+        .with_fault(Fault::CorruptRegisterWrite { write: 1, mask: 1 });
+    let env = ExecutorEnvBuilder::default().fault_plan(plan).build();
+    let mut exec = executor(env, &insns);
+    exec.run().unwrap();
+    // The corrupted x6 is stored, and the first load of it flipped
+    assert_eq!(exec.monitor.load_register(6), 43);
+    assert_eq!(exec.monitor.load_register(7), 43 ^ 0x100);
+    assert_eq!(exec.monitor.load_register(8), 43);
+
+    let mut buf = Vec::new();
+    let plan = FaultPlan::new().with_fault(Fault::LoadAccessFault { load: 1 });
+    let env = ExecutorEnvBuilder::default()
+        .fault_plan(plan)
+        .trace_writer(&mut buf)
+        .build();
+    let mut exec = executor(env, &insns);
+    let err = exec.run().err().unwrap();
+    assert_eq!(err.to_string(), format!("LoadAccessFault({})", 0x400));
+    assert_eq!(exec.monitor.load_register(7), 42);
+    drop(exec);
+
+    let trace = read_trace(buf.as_slice()).unwrap();
+    assert_eq!(trace.len(), 5);
+    assert!(trace[..4].iter().all(|record| !record.fault));
+    assert_eq!(trace[4].pc, ENTRY + 16);
+    assert!(trace[4].fault);
+}
+
+#[test]
+fn hart_state_round_trip() {
+    // addi x5, x0, 0x400
+    // addi x6, x0, -7
+    // slli x7, x6, 4
+    // sd x7, 0(x5)
+    // ld x8, 0(x5)
+    // add x9, x8, x2
+    let insns = [
+        0x40000293, 0xff900313, 0x00431393, 0x0072b023, 0x0002b403, 0x002404b3,
+    ];
+
+    let mut exec = executor(ExecutorEnv::default(), &insns);
+    let mut hart = exec.monitor.to_hart_state();
+    hart.pc = ENTRY;
+    for _ in insns.iter() {
+        assert_eq!(exec.step().unwrap(), None);
+    }
+
+    // The same program straight through rrs-lib
+    let mut words = vec![0_u64; (ENTRY as usize + insns.len() * 4) / 8];
+    for (idx, insn) in insns.iter().enumerate() {
+        let word = (ENTRY as usize / 4 + idx) / 2;
+        words[word] |= (*insn as u64) << (32 * (idx % 2));
+    }
+    let mut mem = VecMemory::new(words);
+    let mut rrs = InstructionExecutor::new(&mut mem, &mut hart);
+    for _ in insns.iter() {
+        rrs.step().unwrap();
+    }
+
+    assert_eq!(exec.monitor.to_hart_state().registers, hart.registers);
+    assert_eq!(
+        hart.registers[9],
+        ((-7_i64 << 4) as u64).wrapping_add(hart.registers[2])
+    );
+
+    // Loading a state into a fresh monitor reads it back unchanged
+    let mut other = executor(ExecutorEnv::default(), &insns).monitor;
+    other.load_from_hart_state(&hart);
+    assert_eq!(other.to_hart_state().registers, hart.registers);
+}
+
+#[test]
+fn output_region() {
+    let run = |len: u32| {
+        let env = ExecutorEnvBuilder::default()
+            .output_region(0x400, 0x20)
+            .build();
+        // addi x5, x0, len
+        // sw x5, 0x400(x0)
+        // addi x6, x0, 42
+        // sw x6, 0x404(x0)
+        // addi x7, x0, -2
+        // sd x7, 0x408(x0)
+        // sb x6, 0x410(x0)
+        let insns = [
+            0x00000293 | len << 20,
+            0x40502023,
+            0x02a00313,
+            0x40602223,
+            0xffe00393,
+            0x40703423,
+            0x40600823,
+        ];
+        let mut exec = executor(env, &insns);
+        exec.run()
+    };
+
+    // A struct { u32, u64 }, leaving out the byte written after it
+    let session = run(12).unwrap();
+    let output = session.output_region().unwrap();
+    assert_eq!(output[..4], 42u32.to_le_bytes());
+    assert_eq!(output[4..], (-2i64).to_le_bytes());
+
+    assert_eq!(run(0).unwrap().output_region(), Some(&[][..]));
+
+    let Err(err) = run(0x1d) else {
+        panic!("length past the end of the region should fail the run");
+    };
+    assert_eq!(
+        err.to_string(),
+        "Output region length 29 exceeds its capacity of 28 bytes"
+    );
+
+    let session = executor(ExecutorEnv::default(), &[]).run().unwrap();
+    assert_eq!(session.output_region(), None);
+
+    for (addr, len) in [(0x400, 3), (u64::MAX - 7, 0x10)] {
+        let env = ExecutorEnvBuilder::default()
+            .output_region(addr, len)
+            .build();
+        assert!(executor(env, &[]).run().is_err());
+    }
+}
+
+#[test]
+fn halt_on_write() {
+    let run = |value: u32| {
+        let env = ExecutorEnvBuilder::default().halt_on_write(0x400).build();
+        // addi x5, x0, value
+        // sw x0, 0x404(x0)
+        // sw x5, 0x400(x0)
+        // loop: jal x0, loop
+        let insns = [0x00000293 | value << 20, 0x40002223, 0x40502023, 0x0000006f];
+        let mut exec = executor(env, &insns);
+        exec.run().unwrap().exit_code
+    };
+
+    assert_eq!(run(1), ExitCode::Halted(0));
+    assert_eq!(run(7), ExitCode::Halted(3));
+}
+
+#[test]
+fn abort() {
+    let run = |message_addr: u32| {
+        // lui x5, 0x73707
+        // addi x5, x5, -0x91
+        // sw x5, 0x400(x0)
+        // sw x0, 0x404(x0)
+        // addi x10, x0, 3
+        // addi x11, x0, message_addr
+        // addi x17, x0, 0
+        // ecall
+        let insns = [
+            0x737072b7,
+            0xf6f28293,
+            0x40502023,
+            0x40002223,
+            0x00300513,
+            0x00000593 | message_addr << 20,
+            0x00000893,
+            0x00000073,
+        ];
+        let mut exec = executor(ExecutorEnv::default(), &insns);
+        exec.run().unwrap().exit_code
+    };
+
+    assert_eq!(run(0x400), ExitCode::Aborted(Some("oops".to_string())));
+    assert_eq!(run(0), ExitCode::Aborted(None));
+    // An unreadable message is dropped rather than failing the run
+    // addi x11, x0, -1
+    assert_eq!(run(0xfff), ExitCode::Aborted(None));
+}
+
+#[test]
+fn register_snapshot() {
+    // addi x5, x0, 5
+    // addi x31, x0, -1
+    let insns = [0x00500293, 0xfff00f93];
+    let mut exec = executor(ExecutorEnv::default(), &insns);
+    let mut other = executor(ExecutorEnv::default(), &insns);
+    exec.monitor.set_initial_sp(0x1234_5678);
+    other.monitor.set_initial_sp(0x1234_5678);
+
+    // sp is seeded by the first read, whichever way the registers are read
+    let snapshot = exec.monitor.register_snapshot();
+    assert_eq!(snapshot[2], 0x1234_5678);
+    let registers = other.monitor.load_registers(array::from_fn(|idx| idx));
+    assert_eq!(snapshot, registers);
+
+    exec.step().unwrap();
+    exec.step().unwrap();
+    let snapshot = exec.monitor.register_snapshot();
+    let registers = exec.monitor.load_registers(array::from_fn(|idx| idx));
+    assert_eq!(snapshot, registers);
+    assert_eq!(
+        (snapshot[2], snapshot[5], snapshot[31]),
+        (0x1234_5678, 5, u64::MAX)
+    );
+}
+
+#[test]
+fn auto_seed_sp_disabled() {
+    // addi x5, x2, 8
+    // addi x2, x0, 0
+    let env = ExecutorEnvBuilder::default().auto_seed_sp(false).build();
+    let mut exec = executor(env, &[0x00810293, 0x00000113]);
+
+    assert_eq!(exec.monitor.register_snapshot()[2], 0);
+    exec.step().unwrap();
+    exec.step().unwrap();
+    let registers = exec.monitor.load_registers([2, 5]);
+    assert_eq!(registers, [0, 8]);
+
+    // Arguments are still laid out, for the host to point sp at
+    let env = ExecutorEnvBuilder::default()
+        .args(&["prog"])
+        .auto_seed_sp(false)
+        .build();
+    let mut exec = executor(env, &[]);
+    let initial_sp = exec.initial_sp();
+    assert!(initial_sp < STACK_INITIAL_ADDRESS as u64);
+    assert_eq!(exec.monitor.load_u64(initial_sp).unwrap(), 1);
+    assert_eq!(exec.monitor.load_register(2), 0);
+}
+
+#[test]
+fn getenv() {
+    let run = |name_len: u32, buf_len: u32| {
+        let env = ExecutorEnvBuilder::default().env_var("FOO", "bar").build();
+        // lui x5, 0x4f5
+        // addi x5, x5, -0xba
+        // sw x5, 0x400(x0)
+        // addi x10, x0, 0x400
+        // addi x11, x0, name_len
+        // addi x12, x0, 0x410
+        // addi x13, x0, buf_len
+        // addi x17, x0, GETENV
+        // ecall
+        let insns = [
+            0x004f52b7,
+            0xf4628293,
+            0x40502023,
+            0x40000513,
+            0x00000593 | name_len << 20,
+            0x41000613,
+            0x00000693 | buf_len << 20,
+            0x00000893 | (ecall::GETENV as u32) << 20,
+            0x00000073,
+        ];
+        let mut exec = executor(env, &insns);
+        for _ in insns {
+            exec.step().unwrap();
+        }
+        let value = exec.monitor.load_array::<4>(0x410).unwrap();
+        (exec.monitor.load_register(REG_A0), value)
+    };
+
+    assert_eq!(run(3, 8), (3, *b"bar\0"));
+    // A short buffer gets what fits and the length needed
+    assert_eq!(run(3, 2), (3, *b"ba\0\0"));
+    // "FO" isn't set
+    assert_eq!(run(2, 8), (ecall::GETENV_MISSING, [0; 4]));
+
+    // A name running off the end of the address space
+    // addi x10, x0, -1
+    // addi x11, x0, 2
+    // addi x17, x0, GETENV
+    // ecall
+    let insns = [
+        0xfff00513,
+        0x00200593,
+        0x00000893 | (ecall::GETENV as u32) << 20,
+        0x00000073,
+    ];
+    let err = executor(ExecutorEnv::default(), &insns)
+        .run()
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("wraps the address space"));
+
+    // A buffer at the very top of the address space fails the call rather
+    // than the host
+    // lui x5, 0x4f5
+    // addi x5, x5, -0xba
+    // sw x5, 0x400(x0)
+    // addi x10, x0, 0x400
+    // addi x11, x0, 3
+    // addi x12, x0, -1
+    // addi x13, x0, 8
+    // addi x17, x0, GETENV
+    // ecall
+    let insns = [
+        0x004f52b7,
+        0xf4628293,
+        0x40502023,
+        0x40000513,
+        0x00300593,
+        0xfff00613,
+        0x00800693,
+        0x00000893 | (ecall::GETENV as u32) << 20,
+        0x00000073,
+    ];
+    let env = ExecutorEnvBuilder::default().env_var("FOO", "bar").build();
+    let err = executor(env, &insns).run().err().unwrap();
+    assert!(err.to_string().contains("leaves writable memory"), "{err}");
+}
+
+#[test]
+fn trace_replay() {
+    // addi x5, x0, 0x400
+    // addi x6, x0, 42
+    // sd x6, 0(x5)
+    // ld x7, 0(x5)
+    let insns = [0x40000293, 0x02a00313, 0x0062b023, 0x0002b383];
+
+    let mut buf = Vec::new();
+    let env = ExecutorEnv::builder().trace_writer(&mut buf).build();
+    executor(env, &insns).run().unwrap();
+
+    let mut replay = TraceReplay::new(buf.as_slice()).unwrap();
+    let env = ExecutorEnv::builder().trace_writer(&mut replay).build();
+    executor(env, &insns).run().unwrap();
+    replay.finish().unwrap();
+    assert_eq!(replay.replayed(), insns.len() + HALT.len());
+
+    // addi x6, x0, 43
+    let mut changed = insns;
+    changed[1] = 0x02b00313;
+    let mut replay = TraceReplay::new(buf.as_slice()).unwrap();
+    let env = ExecutorEnv::builder().trace_writer(&mut replay).build();
+    let Err(err) = executor(env, &changed).run() else {
+        panic!("replay of a different program should diverge");
+    };
+    let err = err.to_string();
+    assert!(err.contains("diverges at step 1, pc 0x00001004"), "{err}");
+    assert!(
+        err.contains("rd_value: 2a") && err.contains("rd_value: 2b"),
+        "{err}"
+    );
+    assert_eq!(replay.replayed(), 1);
+    assert!(replay.finish().is_err());
+}
+
+#[test]
+fn record_mem_txns() {
+    // addi x5, x0, 0x400
+    // lw x6, 0(x5)
+    // addi x6, x6, 1
+    // sh x6, 8(x5)
+    let insns = [0x40000293, 0x0002a303, 0x00130313, 0x00629423];
+    let run = |record: bool| {
+        let env = ExecutorEnv::builder().record_mem_txns(record).build();
+        let mut exec = executor(env, &insns);
+        exec.monitor.store_u32(0x400, 0xdeadbeef);
+        exec.monitor.commit();
+        exec.run().unwrap()
+    };
+
+    let session = run(true);
+    assert_eq!(
+        session.segments[0].mem_txns(),
+        [
+            MemTxn {
+                addr: 0x400,
+                value: 0xdeadbeef,
+                size: 4,
+                kind: AccessKind::Load,
+            },
+            MemTxn {
+                addr: 0x408,
+                value: 0xbef0,
+                size: 2,
+                kind: AccessKind::Store,
+            },
+        ]
+    );
+
+    assert!(run(false).segments[0].mem_txns().is_empty());
+}
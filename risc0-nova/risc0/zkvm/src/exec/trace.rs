@@ -0,0 +1,374 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A compact binary trace of retired instructions.
+//!
+//! A trace starts with an 8 byte header, the magic bytes `RVTR` followed by
+//! the little-endian `u32` [TRACE_VERSION] of the format. Traces of any other
+//! version are rejected when read.
+//!
+//! Each instruction is then written as a fixed size [TraceRecord] of
+//! [TraceRecord::SIZE] little-endian bytes:
+//!
+//! | field         | bytes |
+//! |---------------|-------|
+//! | `pc`          | 8     |
+//! | `insn`        | 4     |
+//! | `rd`          | 1     |
+//! | `rd_value`    | 8     |
+//! | `mem_addr`    | 8     |
+//! | `mem_value`   | 8     |
+//! | `access_kind` | 1     |
+//! | `fault`       | 1     |
+
+use std::io::{self, Read, Write};
+
+use anyhow::{bail, Result};
+use rrs_lib::{instruction_decoder::DecodedInsn, MemAccessSize};
+use serde::{Deserialize, Serialize};
+
+/// Version of the trace format written by [TraceWriter], bumped whenever the
+/// header or [TraceRecord] encoding changes.
+pub const TRACE_VERSION: u32 = 1;
+
+const TRACE_MAGIC: [u8; 4] = *b"RVTR";
+const HEADER_SIZE: usize = 8;
+
+fn encode_header() -> [u8; HEADER_SIZE] {
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[0..4].copy_from_slice(&TRACE_MAGIC);
+    bytes[4..8].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+    bytes
+}
+
+fn check_header(bytes: &[u8]) -> Result<()> {
+    if bytes.len() < HEADER_SIZE || bytes[0..4] != TRACE_MAGIC {
+        bail!("Trace doesn't start with a trace header");
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != TRACE_VERSION {
+        bail!("Unsupported trace version {version}, expected {TRACE_VERSION}");
+    }
+    Ok(())
+}
+
+/// The kind of memory access made by a traced instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AccessKind {
+    /// The instruction didn't access memory
+    None = 0,
+    /// The instruction read memory
+    Load = 1,
+    /// The instruction wrote memory. Atomic read-modify-write instructions
+    /// are traced as stores.
+    Store = 2,
+}
+
+/// The effects of a single retired instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    /// Address of the instruction
+    pub pc: u64,
+    /// The instruction word
+    pub insn: u32,
+    /// Index of the register written, or 0 if no register was written
+    pub rd: u8,
+    /// Value written to `rd`, 0 if no register was written
+    pub rd_value: u64,
+    /// Address of the memory access, 0 if memory wasn't accessed
+    pub mem_addr: u64,
+    /// Value read or written by the memory access, 0 if memory wasn't
+    /// accessed
+    pub mem_value: u64,
+    /// The kind of memory access
+    pub access_kind: AccessKind,
+    /// Whether a fault from the [FaultPlan](super::FaultPlan) was injected
+    /// into this instruction
+    pub fault: bool,
+}
+
+impl TraceRecord {
+    /// Size in bytes of an encoded record.
+    pub const SIZE: usize = 39;
+
+    /// Encode this record in the packed trace format.
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.insn.to_le_bytes());
+        bytes[12] = self.rd;
+        bytes[13..21].copy_from_slice(&self.rd_value.to_le_bytes());
+        bytes[21..29].copy_from_slice(&self.mem_addr.to_le_bytes());
+        bytes[29..37].copy_from_slice(&self.mem_value.to_le_bytes());
+        bytes[37] = self.access_kind as u8;
+        bytes[38] = self.fault as u8;
+        bytes
+    }
+
+    /// Decode a record from the packed trace format.
+    pub fn decode(bytes: &[u8; Self::SIZE]) -> Result<Self> {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let access_kind = match bytes[37] {
+            0 => AccessKind::None,
+            1 => AccessKind::Load,
+            2 => AccessKind::Store,
+            kind => bail!("Invalid trace access kind {kind}"),
+        };
+        let fault = match bytes[38] {
+            0 => false,
+            1 => true,
+            fault => bail!("Invalid trace fault flag {fault}"),
+        };
+
+        Ok(Self {
+            pc: u64_at(0),
+            insn: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            rd: bytes[12],
+            rd_value: u64_at(13),
+            mem_addr: u64_at(21),
+            mem_value: u64_at(29),
+            access_kind,
+            fault,
+        })
+    }
+}
+
+/// A load or store made by the guest, recorded in order on the [Segment]
+/// when [record_mem_txns](super::ExecutorEnvBuilder::record_mem_txns) is set.
+///
+/// [Segment]: crate::Segment
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MemTxn {
+    /// Address accessed
+    pub addr: u64,
+    /// Value loaded or stored, zero-extended
+    pub value: u64,
+    /// Size of the access in bytes
+    pub size: u8,
+    /// [AccessKind::Load] or [AccessKind::Store]
+    pub kind: AccessKind,
+}
+
+/// Writes [TraceRecord]s to an underlying [Write].
+///
+/// The header is written ahead of the first record, or by
+/// [flush](Self::flush) if there are none.
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Construct a [TraceWriter] writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            header_written: false,
+        }
+    }
+
+    /// Append `record` to the trace.
+    pub fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.write_header()?;
+        self.writer.write_all(&record.encode())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.writer.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(&encode_header())?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+/// Read a whole trace written by a [TraceWriter].
+///
+/// Fails if the trace is of a different [TRACE_VERSION].
+pub fn read_trace(mut reader: impl Read) -> Result<Vec<TraceRecord>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    check_header(&bytes)?;
+    let records = &bytes[HEADER_SIZE..];
+    if records.len() % TraceRecord::SIZE != 0 {
+        bail!("Trace ends with a truncated record");
+    }
+
+    records
+        .chunks_exact(TraceRecord::SIZE)
+        .map(|chunk| TraceRecord::decode(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// Checks a live run against a trace recorded earlier, e.g. a known-good run
+/// of the same guest.
+///
+/// Pass it to [trace_writer](super::ExecutorEnvBuilder::trace_writer) and
+/// each record the executor writes is compared against the next recorded
+/// one, the first mismatch failing the step with the PC and the expected and
+/// actual values. Call [TraceReplay::finish] after the run to check it didn't
+/// stop short of the recording.
+pub struct TraceReplay {
+    expected: Vec<TraceRecord>,
+    replayed: usize,
+    // Whether the live trace's header has been checked
+    header_checked: bool,
+    // Bytes of the header or a record split across writes
+    pending: Vec<u8>,
+}
+
+impl TraceReplay {
+    /// Construct a [TraceReplay] of a trace written by a [TraceWriter].
+    pub fn new(reader: impl Read) -> Result<Self> {
+        Ok(Self::from_records(read_trace(reader)?))
+    }
+
+    /// Construct a [TraceReplay] of already decoded records.
+    pub fn from_records(expected: Vec<TraceRecord>) -> Self {
+        Self {
+            expected,
+            replayed: 0,
+            header_checked: false,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The number of records matched so far.
+    pub fn replayed(&self) -> usize {
+        self.replayed
+    }
+
+    /// Check every recorded step was replayed.
+    pub fn finish(&self) -> Result<()> {
+        if !self.pending.is_empty() {
+            bail!("Replay ends with a truncated record");
+        }
+        if self.replayed < self.expected.len() {
+            let next = &self.expected[self.replayed];
+            bail!(
+                "Replay stopped after {} of {} recorded steps, the next being at pc 0x{:08x}",
+                self.replayed,
+                self.expected.len(),
+                next.pc
+            );
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, actual: TraceRecord) -> Result<()> {
+        let step = self.replayed;
+        let Some(expected) = self.expected.get(step) else {
+            bail!(
+                "Trace diverges at step {step}: nothing was recorded past pc 0x{:08x}",
+                actual.pc
+            );
+        };
+        if *expected != actual {
+            bail!(
+                "Trace diverges at step {step}, pc 0x{:08x}: expected {expected:x?}, got {actual:x?}",
+                expected.pc
+            );
+        }
+        self.replayed += 1;
+        Ok(())
+    }
+}
+
+impl Write for TraceReplay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if !self.header_checked {
+            if self.pending.len() < HEADER_SIZE {
+                return Ok(buf.len());
+            }
+            let header: Vec<_> = self.pending.drain(..HEADER_SIZE).collect();
+            check_header(&header)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            self.header_checked = true;
+        }
+        while self.pending.len() >= TraceRecord::SIZE {
+            let bytes: Vec<_> = self.pending.drain(..TraceRecord::SIZE).collect();
+            TraceRecord::decode(bytes.as_slice().try_into().unwrap())
+                .and_then(|record| self.check(record))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The memory access `insn` makes, as its kind, address and size.
+///
+/// `registers` are the register values before `insn` executed. Stores are
+/// only reported at `store_addr`, the address the instruction actually wrote
+/// (a failed SC doesn't write).
+pub(crate) fn mem_access(
+    insn: &DecodedInsn,
+    registers: &[u64; 32],
+    store_addr: Option<u64>,
+) -> Option<(AccessKind, u64, MemAccessSize)> {
+    use DecodedInsn::*;
+
+    let offset_addr = |rs1: usize, imm: i64| registers[rs1].wrapping_add(imm as u64);
+
+    let (kind, addr, size) = match *insn {
+        Lb(i) | Lbu(i) => (
+            AccessKind::Load,
+            offset_addr(i.rs1, i.imm),
+            MemAccessSize::Byte,
+        ),
+        Lh(i) | Lhu(i) => (
+            AccessKind::Load,
+            offset_addr(i.rs1, i.imm),
+            MemAccessSize::HalfWord,
+        ),
+        Lw(i) | Lwu(i) | Flw(i) => (
+            AccessKind::Load,
+            offset_addr(i.rs1, i.imm),
+            MemAccessSize::Word,
+        ),
+        Ld(i) => (
+            AccessKind::Load,
+            offset_addr(i.rs1, i.imm),
+            MemAccessSize::DoubleWord,
+        ),
+        Amolrw(a) => (AccessKind::Load, registers[a.rs1], MemAccessSize::Word),
+        Amolrd(a) => (
+            AccessKind::Load,
+            registers[a.rs1],
+            MemAccessSize::DoubleWord,
+        ),
+        Sb(_) => (AccessKind::Store, store_addr?, MemAccessSize::Byte),
+        Sh(_) => (AccessKind::Store, store_addr?, MemAccessSize::HalfWord),
+        Sw(_) | Fsw(_) | Amoscw(_) | Amoswapw(_) | Amoaddw(_) | Amoorw(_) | Amoandw(_) => {
+            (AccessKind::Store, store_addr?, MemAccessSize::Word)
+        }
+        Sd(_) | Amoscd(_) | Amoswapd(_) | Amoaddd(_) => {
+            (AccessKind::Store, store_addr?, MemAccessSize::DoubleWord)
+        }
+        _ => return None,
+    };
+
+    Some((kind, addr, size))
+}
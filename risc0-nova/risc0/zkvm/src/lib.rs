@@ -36,10 +36,18 @@ pub use anyhow::Result;
 pub use risc0_zkvm_platform::{declare_syscall, memory::MEM_SIZE, PAGE_SIZE};
 
 #[cfg(feature = "binfmt")]
-pub use self::binfmt::{elf::Program, image::MemoryImage};
+pub use self::binfmt::{
+    elf::{LoadOptions, Program},
+    image::{MemoryImage, PageTableInfo, PLAYGROUND},
+};
 #[cfg(feature = "prove")]
 pub use self::{
-    exec::{Executor, ExecutorEnv, ExecutorEnvBuilder},
+    exec::{
+        read_trace, run_elf, run_isa_test, AccessKind, ClockSource, CostModel, ErrorRecord,
+        Executor, ExecutorEnv, ExecutorEnvBuilder, ExecutorSnapshot, ExecutorState, Fault,
+        FaultPlan, MemTxn, PausedExecution, RunResult, SyscallRecord, TestResult, TraceRecord,
+        TraceReplay, TraceWriter, TRACE_VERSION,
+    },
     session::{ExitCode, Segment, Session},
 };
 
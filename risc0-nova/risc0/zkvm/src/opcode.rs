@@ -14,7 +14,11 @@
 
 use anyhow::{bail, Result};
 use num_traits::FromPrimitive;
-use rrs_lib::{instruction_string_outputter::InstructionStringOutputter, process_instruction};
+use rrs_lib::{
+    instruction_decoder::{decode, DecodedInsn},
+    instruction_executor::InstructionException,
+    instruction_string_outputter::InstructionStringOutputter,
+};
 
 #[allow(dead_code)]
 #[derive(Debug, num_derive::FromPrimitive, PartialEq)]
@@ -33,6 +37,19 @@ pub enum MajorType {
     ShaLoad,
     ShaMain,
     PageFault,
+    /// The RV64 loads and stores, which don't fit in the eight minors of
+    /// [MajorType::MemIo] alongside the RV32 ones
+    MemIo64,
+    /// The A extension, which has too many instructions for a single major
+    Atomic0,
+    Atomic1,
+    Atomic2,
+    /// RV64 and extension instructions handled like the other compute ones
+    Compute3,
+    /// The RV64 divides, which don't fit in [MajorType::Divide]
+    Divide64,
+    /// The F extension
+    Float,
     MuxSize,
 }
 
@@ -43,6 +60,8 @@ pub struct OpCode {
     pub major: MajorType,
     pub minor: u32,
     pub cycles: usize,
+    /// The instruction as decoded by rrs-lib, `None` for ECALL and EBREAK
+    pub decoded: Option<DecodedInsn>,
 }
 
 impl MajorType {
@@ -60,6 +79,7 @@ impl OpCode {
             major: FromPrimitive::from_u32(idx / 8).unwrap(),
             minor: idx % 8,
             cycles,
+            decoded: None,
         }
     }
 
@@ -78,141 +98,142 @@ impl OpCode {
             major,
             minor,
             cycles,
+            decoded: None,
         }
     }
 
+    /// Decode `insn` once, classifying it for the circuit. The decoded
+    /// instruction is kept so it can be executed without decoding it again.
     pub fn decode(insn: u32, insn_pc: u64) -> Result<Self> {
-        let opcode = insn & 0x0000007f;
-        let rs2 = (insn & 0x01f00000) >> 20;
-        let funct3 = (insn & 0x00007000) >> 12;
-        let funct7 = (insn & 0xfe000000) >> 25;
-        // RV64 bit 25 is used as shamt[5]
-        let funct7_rv64 = (insn & 0xfc000000) >> 26;
-        let funct5 = (insn & 0xf8000000) >> 27;
-        // log::debug!("decode: 0x{word:08X}");
-        println!("decode: 0x{insn:08x} at pc 0x{insn_pc:08x}");
-        Ok(match opcode {
-            0b0000011 => match funct3 {
-                0x0 => OpCode::new(insn, insn_pc, "LB", 24, 1),
-                0x1 => OpCode::new(insn, insn_pc, "LH", 25, 1),
-                0x2 => OpCode::new(insn, insn_pc, "LW", 26, 1),
-                0x3 => OpCode::new(insn, insn_pc, "LD", 27, 1), // RV64I
-                0x4 => OpCode::new(insn, insn_pc, "LBU", 28, 1),
-                0x5 => OpCode::new(insn, insn_pc, "LHU", 29, 1),
-                0x6 => OpCode::new(insn, insn_pc, "LWU", 30, 1),
-                _ => {
-                    println!("opcode {:#8x}", insn);
-                    log::debug!("opcode {:?}", opcode);
-                    unreachable!()
-                }
-            },
-            0b0010011 => match funct3 {
-                0x0 => OpCode::new(insn, insn_pc, "ADDI", 7, 1),
-                0x1 => OpCode::new(insn, insn_pc, "SLLI", 37, 1),
-                0x2 => OpCode::new(insn, insn_pc, "SLTI", 11, 1),
-                0x3 => OpCode::new(insn, insn_pc, "SLTIU", 12, 1),
-                0x4 => OpCode::new(insn, insn_pc, "XORI", 8, 2),
-                0x5 => match funct7_rv64 {
-                    0b000000 => OpCode::new(insn, insn_pc, "SRLI", 46, 2),
-                    0b010000 => OpCode::new(insn, insn_pc, "SRAI", 47, 2),
-                    _ => {
-                        println!("funct7 {:8x}", funct7);
-                        unreachable!()
-                    }
-                },
-                0x6 => OpCode::new(insn, insn_pc, "ORI", 9, 2),
-                0x7 => OpCode::new(insn, insn_pc, "ANDI", 10, 2),
-                _ => unreachable!(),
-            },
-            0b0010111 => OpCode::new(insn, insn_pc, "AUIPC", 22, 1),
-            0b0100011 => match funct3 {
-                0x0 => OpCode::new(insn, insn_pc, "SB", 29, 1),
-                0x1 => OpCode::new(insn, insn_pc, "SH", 30, 1),
-                0x2 => OpCode::new(insn, insn_pc, "SW", 31, 1),
-                0x3 => OpCode::new(insn, insn_pc, "SD", 31, 1),
-                _ => unreachable!(),
-            },
-            0b0110011 => match (funct3, funct7) {
-                (0x0, 0x00) => OpCode::new(insn, insn_pc, "ADD", 0, 1),
-                (0x0, 0x20) => OpCode::new(insn, insn_pc, "SUB", 1, 1),
-                (0x1, 0x00) => OpCode::new(insn, insn_pc, "SLL", 36, 1),
-                (0x2, 0x00) => OpCode::new(insn, insn_pc, "SLT", 5, 1),
-                (0x3, 0x00) => OpCode::new(insn, insn_pc, "SLTU", 6, 1),
-                (0x4, 0x00) => OpCode::new(insn, insn_pc, "XOR", 2, 2),
-                (0x5, 0x00) => OpCode::new(insn, insn_pc, "SRL", 44, 2),
-                (0x5, 0x20) => OpCode::new(insn, insn_pc, "SRA", 45, 2),
-                (0x6, 0x00) => OpCode::new(insn, insn_pc, "OR", 3, 2),
-                (0x7, 0x00) => OpCode::new(insn, insn_pc, "AND", 4, 2),
-                (0x0, 0x01) => OpCode::new(insn, insn_pc, "MUL", 32, 1),
-                (0x1, 0x01) => OpCode::new(insn, insn_pc, "MULH", 33, 1),
-                (0x2, 0x01) => OpCode::new(insn, insn_pc, "MULSU", 34, 1),
-                (0x3, 0x01) => OpCode::new(insn, insn_pc, "MULU", 35, 1),
-                (0x4, 0x01) => OpCode::new(insn, insn_pc, "DIV", 40, 2),
-                (0x5, 0x01) => OpCode::new(insn, insn_pc, "DIVU", 41, 2),
-                (0x6, 0x01) => OpCode::new(insn, insn_pc, "REM", 42, 2),
-                (0x7, 0x01) => OpCode::new(insn, insn_pc, "REMU", 43, 2),
-                _ => unreachable!(),
-            },
-            0b0101111 => match (funct3, funct5) {
-                (0b010, 0b00001) => OpCode::new(insn, insn_pc, "AMOSWAP.W", 0, 1),
-                (0b010, 0b00010) => OpCode::new(insn, insn_pc, "LR.W", 2, 1),
-                (0b010, 0b00011) => OpCode::new(insn, insn_pc, "SC.W", 3, 1),
-                (0b010, 0b01000) => OpCode::new(insn, insn_pc, "AMOOR.W", 0, 1),
-                (0b010, 0b00000) => OpCode::new(insn, insn_pc, "AMOADD.W", 1, 1),
-                (0b010, 0b01100) => OpCode::new(insn, insn_pc, "AMOAND.W", 0, 1),
-                (0b011, 0b00000) => OpCode::new(insn, insn_pc, "AMOADD.D", 1, 1),
-                (0b011, 0b00001) => OpCode::new(insn, insn_pc, "AMOSWAP.D", 2, 1),
-                (0b011, 0b00010) => OpCode::new(insn, insn_pc, "LR.D", 3, 1),
-                (0b011, 0b00011) => OpCode::new(insn, insn_pc, "SC.D", 4, 1),
-                _ => unreachable!(),
-            },
-            0b0110111 => OpCode::new(insn, insn_pc, "LUI", 21, 1),
-            0b1100011 => match funct3 {
-                0x0 => OpCode::new(insn, insn_pc, "BEQ", 13, 1),
-                0x1 => OpCode::new(insn, insn_pc, "BNE", 14, 1),
-                0x4 => OpCode::new(insn, insn_pc, "BLT", 15, 1),
-                0x5 => OpCode::new(insn, insn_pc, "BGE", 16, 1),
-                0x6 => OpCode::new(insn, insn_pc, "BLTU", 17, 1),
-                0x7 => OpCode::new(insn, insn_pc, "BGEU", 18, 1),
-                _ => unreachable!(),
-            },
-            0b1100111 => match funct3 {
-                0x0 => OpCode::new(insn, insn_pc, "JALR", 20, 1),
-                _ => unreachable!(),
-            },
-            0b0011011 => match funct3 {
-                0b000 => OpCode::new(insn, insn_pc, "ADDIW", 0, 1),
-                _ => unreachable!(),
-            },
-            0b0111011 => match (funct3, funct7) {
-                (0b000, 0b0000001) => OpCode::new(insn, insn_pc, "MULW", 0, 1),
-                (0b111, 0b0000001) => OpCode::new(insn, insn_pc, "REMUW", 1, 1),
-                _ => unreachable!(),
-            },
-            0b1101111 => OpCode::new(insn, insn_pc, "JAL", 19, 1),
-            0b1110011 => match funct3 {
-                0x0 => match (rs2, funct7) {
-                    (0x0, 0x0) => {
-                        OpCode::with_major_minor(insn, insn_pc, "ECALL", MajorType::ECall, 0, 1)
-                    }
-                    (0x1, 0x0) => {
-                        OpCode::with_major_minor(insn, insn_pc, "EBREAK", MajorType::ECall, 1, 1)
-                    }
-                    _ => unreachable!(),
-                },
-                // system call
-                0b010 => OpCode::new(insn, insn_pc, "RDTIME", 0, 1),
-                _ => unreachable!(),
-            },
-            0b0001111 => OpCode::new(insn, insn_pc, "FENCE", 0, 1),
-            _ => bail!("Illegal opcode: 0b{opcode:07b}"),
-        })
+        log::trace!("decode: 0x{insn:08x} at pc 0x{insn_pc:08x}");
+        // rrs-lib doesn't decode ECALL/EBREAK, only the opcode, funct3, rs2 and
+        // funct7 fields distinguish them
+        match insn & 0xfff0707f {
+            0x00000073 => {
+                return Ok(OpCode::with_major_minor(
+                    insn,
+                    insn_pc,
+                    "ECALL",
+                    MajorType::ECall,
+                    0,
+                    1,
+                ))
+            }
+            0x00100073 => {
+                return Ok(OpCode::with_major_minor(
+                    insn,
+                    insn_pc,
+                    "EBREAK",
+                    MajorType::ECall,
+                    1,
+                    1,
+                ))
+            }
+            _ => {}
+        }
+
+        let decoded = match decode(insn) {
+            Some(decoded) => decoded,
+            None => bail!(
+                "{:?}",
+                InstructionException::IllegalInstruction(insn_pc, insn)
+            ),
+        };
+        let (mnemonic, idx, cycles) = match decoded {
+            DecodedInsn::Lb(_) => ("LB", 24, 1),
+            DecodedInsn::Lh(_) => ("LH", 25, 1),
+            DecodedInsn::Lw(_) => ("LW", 26, 1),
+            DecodedInsn::Ld(_) => ("LD", 104, 1), // RV64I
+            DecodedInsn::Lbu(_) => ("LBU", 27, 1),
+            DecodedInsn::Lhu(_) => ("LHU", 28, 1),
+            DecodedInsn::Lwu(_) => ("LWU", 105, 1), // RV64I
+            DecodedInsn::Addi(_) => ("ADDI", 7, 1),
+            DecodedInsn::Slli(_) => ("SLLI", 37, 1),
+            DecodedInsn::Slti(_) => ("SLTI", 11, 1),
+            DecodedInsn::Sltui(_) => ("SLTIU", 12, 1),
+            DecodedInsn::Xori(_) => ("XORI", 8, 2),
+            DecodedInsn::Srli(_) => ("SRLI", 46, 2),
+            DecodedInsn::Srai(_) => ("SRAI", 47, 2),
+            DecodedInsn::Ori(_) => ("ORI", 9, 2),
+            DecodedInsn::Andi(_) => ("ANDI", 10, 2),
+            DecodedInsn::Auipc(_) => ("AUIPC", 22, 1),
+            DecodedInsn::Sb(_) => ("SB", 29, 1),
+            DecodedInsn::Sh(_) => ("SH", 30, 1),
+            DecodedInsn::Sw(_) => ("SW", 31, 1),
+            DecodedInsn::Sd(_) => ("SD", 106, 1), // RV64I
+            DecodedInsn::Add(_) => ("ADD", 0, 1),
+            DecodedInsn::Sub(_) => ("SUB", 1, 1),
+            DecodedInsn::Sll(_) => ("SLL", 36, 1),
+            DecodedInsn::Slt(_) => ("SLT", 5, 1),
+            DecodedInsn::Sltu(_) => ("SLTU", 6, 1),
+            DecodedInsn::Xor(_) => ("XOR", 2, 2),
+            DecodedInsn::Srl(_) => ("SRL", 44, 2),
+            DecodedInsn::Sra(_) => ("SRA", 45, 2),
+            DecodedInsn::Or(_) => ("OR", 3, 2),
+            DecodedInsn::And(_) => ("AND", 4, 2),
+            DecodedInsn::Mul(_) => ("MUL", 32, 1),
+            DecodedInsn::Mulh(_) => ("MULH", 33, 1),
+            DecodedInsn::Mulhsu(_) => ("MULSU", 34, 1),
+            DecodedInsn::Mulhu(_) => ("MULU", 35, 1),
+            DecodedInsn::Div(_) => ("DIV", 40, 2),
+            DecodedInsn::Divu(_) => ("DIVU", 41, 2),
+            DecodedInsn::Rem(_) => ("REM", 42, 2),
+            DecodedInsn::Remu(_) => ("REMU", 43, 2),
+            DecodedInsn::Amolrw(_) => ("LR.W", 112, 1),
+            DecodedInsn::Amoscw(_) => ("SC.W", 113, 1),
+            DecodedInsn::Amoswapw(_) => ("AMOSWAP.W", 114, 1),
+            DecodedInsn::Amoaddw(_) => ("AMOADD.W", 115, 1),
+            DecodedInsn::Amoxorw(_) => ("AMOXOR.W", 116, 1),
+            DecodedInsn::Amoandw(_) => ("AMOAND.W", 117, 1),
+            DecodedInsn::Amoorw(_) => ("AMOOR.W", 118, 1),
+            DecodedInsn::Amominw(_) => ("AMOMIN.W", 119, 1),
+            DecodedInsn::Amomaxw(_) => ("AMOMAX.W", 120, 1),
+            DecodedInsn::Amominuw(_) => ("AMOMINU.W", 121, 1),
+            DecodedInsn::Amomaxuw(_) => ("AMOMAXU.W", 122, 1),
+            DecodedInsn::Amolrd(_) => ("LR.D", 123, 1),
+            DecodedInsn::Amoscd(_) => ("SC.D", 124, 1),
+            DecodedInsn::Amoswapd(_) => ("AMOSWAP.D", 125, 1),
+            DecodedInsn::Amoaddd(_) => ("AMOADD.D", 126, 1),
+            DecodedInsn::Amoxord(_) => ("AMOXOR.D", 127, 1),
+            DecodedInsn::Amoandd(_) => ("AMOAND.D", 128, 1),
+            DecodedInsn::Amoord(_) => ("AMOOR.D", 129, 1),
+            DecodedInsn::Amomind(_) => ("AMOMIN.D", 130, 1),
+            DecodedInsn::Amomaxd(_) => ("AMOMAX.D", 131, 1),
+            DecodedInsn::Amominud(_) => ("AMOMINU.D", 132, 1),
+            DecodedInsn::Amomaxud(_) => ("AMOMAXU.D", 133, 1),
+            DecodedInsn::Lui(_) => ("LUI", 21, 1),
+            DecodedInsn::Beq(_) => ("BEQ", 13, 1),
+            DecodedInsn::Bne(_) => ("BNE", 14, 1),
+            DecodedInsn::Blt(_) => ("BLT", 15, 1),
+            DecodedInsn::Bge(_) => ("BGE", 16, 1),
+            DecodedInsn::Bltu(_) => ("BLTU", 17, 1),
+            DecodedInsn::Bgeu(_) => ("BGEU", 18, 1),
+            DecodedInsn::Jalr(_) => ("JALR", 20, 1),
+            DecodedInsn::Addiw(_) => ("ADDIW", 136, 1),
+            DecodedInsn::Mulw(_) => ("MULW", 38, 1),
+            DecodedInsn::Remuw(_) => ("REMUW", 144, 1),
+            DecodedInsn::Jal(_) => ("JAL", 19, 1),
+            DecodedInsn::Rdtime(_) => ("RDTIME", 137, 1),
+            DecodedInsn::Fence(_) => ("FENCE", 138, 1),
+            DecodedInsn::Flw(_) => ("FLW", 152, 1),
+            DecodedInsn::Fsw(_) => ("FSW", 153, 1),
+            DecodedInsn::FaddS(_) => ("FADD.S", 154, 1),
+            DecodedInsn::FsubS(_) => ("FSUB.S", 155, 1),
+            DecodedInsn::FmulS(_) => ("FMUL.S", 156, 1),
+            DecodedInsn::FdivS(_) => ("FDIV.S", 157, 1),
+            DecodedInsn::FcvtWS(_) => ("FCVT.W.S", 158, 1),
+            DecodedInsn::FcvtSW(_) => ("FCVT.S.W", 159, 1),
+        };
+
+        let mut opcode = OpCode::new(insn, insn_pc, mnemonic, idx, cycles);
+        opcode.decoded = Some(decoded);
+        Ok(opcode)
     }
 
     #[allow(dead_code)]
     pub fn debug(&self, cycle: usize, insn_pc: u64) -> String {
         let mut outputter = InstructionStringOutputter { insn_pc };
-        let desc = process_instruction(&mut outputter, self.insn);
+        let desc = self.decoded.map(|insn| insn.process(&mut outputter));
         format!(
             "[{}] pc: 0x{:08x}, insn: 0x{:08x} => {}",
             cycle,
@@ -228,7 +249,254 @@ impl core::fmt::Debug for OpCode {
         let mut outputter = InstructionStringOutputter {
             insn_pc: self.insn_pc,
         };
-        let desc = process_instruction(&mut outputter, self.insn);
+        let desc = self.decoded.map(|insn| insn.process(&mut outputter));
         f.write_fmt(format_args!("{}", desc.unwrap_or(self.mnemonic.into())))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rrs_lib::{disassemble, instruction_decoder::DecodedInsn};
+
+    use super::{MajorType, OpCode};
+
+    #[test]
+    fn decode_once() {
+        // add x1, x2, x3
+        let opcode = OpCode::decode(0x003100b3, 0x1000).unwrap();
+        assert_eq!(opcode.mnemonic, "ADD");
+        assert_eq!((opcode.major, opcode.minor), (MajorType::Compute0, 0));
+        assert!(matches!(opcode.decoded, Some(DecodedInsn::Add(_))));
+
+        // lw x5, 6(x0)
+        let opcode = OpCode::decode(0x00602283, 0x1000).unwrap();
+        assert_eq!(opcode.mnemonic, "LW");
+        assert_eq!((opcode.major, opcode.minor), (MajorType::MemIo, 2));
+        assert!(matches!(opcode.decoded, Some(DecodedInsn::Lw(_))));
+
+        let opcode = OpCode::decode(0x00000073, 0x1000).unwrap();
+        assert_eq!(opcode.mnemonic, "ECALL");
+        assert_eq!((opcode.major, opcode.minor), (MajorType::ECall, 0));
+        assert_eq!(opcode.decoded, None);
+
+        let opcode = OpCode::decode(0x00100073, 0x1000).unwrap();
+        assert_eq!((opcode.major, opcode.minor), (MajorType::ECall, 1));
+
+        let err = OpCode::decode(0x0, 0x1000).unwrap_err();
+        assert_eq!(err.to_string(), "IllegalInstruction(4096, 0)");
+    }
+
+    #[test]
+    fn load_store_minors_are_unique() {
+        // (funct3, mnemonic) with rd/rs2 x5 and rs1 x0
+        let loads = [
+            (0, "LB"),
+            (1, "LH"),
+            (2, "LW"),
+            (3, "LD"),
+            (4, "LBU"),
+            (5, "LHU"),
+            (6, "LWU"),
+        ];
+        let stores = [(0, "SB"), (1, "SH"), (2, "SW"), (3, "SD")];
+        let insns = loads
+            .iter()
+            .map(|(funct3, mnemonic)| (0x00000283 | funct3 << 12, mnemonic))
+            .chain(
+                stores
+                    .iter()
+                    .map(|(funct3, mnemonic)| (0x00500023 | funct3 << 12, mnemonic)),
+            );
+
+        let mut seen = Vec::new();
+        for (insn, mnemonic) in insns {
+            let opcode = OpCode::decode(insn, 0x1000).unwrap();
+            assert_eq!(opcode.mnemonic, *mnemonic);
+            assert!(matches!(
+                opcode.major,
+                MajorType::MemIo | MajorType::MemIo64
+            ));
+            let key = (opcode.major.as_u32(), opcode.minor);
+            assert!(!seen.contains(&key), "{mnemonic} collides on {key:?}");
+            seen.push(key);
+        }
+    }
+
+    #[test]
+    fn atomics_decode_consistently() {
+        // (funct5, mnemonic) for each width
+        let ops = [
+            (0b00010, "LR"),
+            (0b00011, "SC"),
+            (0b00001, "AMOSWAP"),
+            (0b00000, "AMOADD"),
+            (0b00100, "AMOXOR"),
+            (0b01100, "AMOAND"),
+            (0b01000, "AMOOR"),
+            (0b10000, "AMOMIN"),
+            (0b10100, "AMOMAX"),
+            (0b11000, "AMOMINU"),
+            (0b11100, "AMOMAXU"),
+        ];
+
+        let mut seen = Vec::new();
+        for (funct3, width) in [(0b010, "W"), (0b011, "D")] {
+            for (funct5, op) in ops {
+                // rd x7, rs2 x6, rs1 x5
+                let insn = funct5 << 27 | 0x0062a3af | funct3 << 12;
+                let opcode = OpCode::decode(insn, 0x1000).unwrap();
+                assert_eq!(opcode.mnemonic, format!("{op}.{width}"));
+                assert!(matches!(
+                    opcode.major,
+                    MajorType::Atomic0 | MajorType::Atomic1 | MajorType::Atomic2
+                ));
+                let key = (opcode.major.as_u32(), opcode.minor);
+                assert!(!seen.contains(&key), "{op}.{width} collides on {key:?}");
+                seen.push(key);
+            }
+        }
+
+        // Whatever rrs-lib can process in the AMO opcode space the decoder
+        // classifies, and the other way round
+        for funct3 in 0..8 {
+            for funct5 in 0..32 {
+                let insn = funct5 << 27 | 0x0062a0af | funct3 << 12;
+                assert_eq!(
+                    disassemble(insn, 0).is_some(),
+                    OpCode::decode(insn, 0x1000).is_ok(),
+                    "0x{insn:08x}"
+                );
+            }
+        }
+        assert_eq!(seen.len(), 22);
+    }
+
+    #[test]
+    fn random_words_never_panic() {
+        // xorshift64, with a fixed seed so a failure reproduces
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u32
+        };
+
+        // Every funct7 of every R-type OP and OP-32 instruction, with random
+        // registers. Only ADD/SUB and SRL/SRA (and their W forms) use bit 5
+        // of funct7, anything else rrs-lib rejects must be an error too.
+        for opcode in [0b0110011, 0b0111011] {
+            for funct3 in 0..8 {
+                for funct7 in 0..128 {
+                    let insn = funct7 << 25 | next() & 0x01ff8f80 | funct3 << 12 | opcode;
+                    let decoded = OpCode::decode(insn, 0x1000);
+                    assert_eq!(
+                        decoded.is_ok(),
+                        disassemble(insn, 0).is_some(),
+                        "0x{insn:08x}"
+                    );
+
+                    let expected = match (opcode, funct3, funct7) {
+                        (0b0110011, 0b000, 0x00) => Some("ADD"),
+                        (0b0110011, 0b000, 0x20) => Some("SUB"),
+                        (0b0110011, 0b101, 0x00) => Some("SRL"),
+                        (0b0110011, 0b101, 0x20) => Some("SRA"),
+                        (_, 0b000 | 0b101, 0x02..=0x1f | 0x21..=0x7f) => None,
+                        _ => continue,
+                    };
+                    assert_eq!(
+                        decoded.ok().map(|opcode| opcode.mnemonic),
+                        expected,
+                        "0x{insn:08x}"
+                    );
+                }
+            }
+        }
+
+        for _ in 0..100_000 {
+            let insn = next();
+            let _ = OpCode::decode(insn, 0x1000);
+        }
+    }
+
+    #[test]
+    fn majors() {
+        // Shifts share the multiply and divide circuits, the A extension is
+        // covered by atomics_decode_consistently
+        let insns = [
+            (0x003100b3, "ADD", MajorType::Compute0),
+            (0x403100b3, "SUB", MajorType::Compute0),
+            (0x003140b3, "XOR", MajorType::Compute0),
+            (0x003160b3, "OR", MajorType::Compute0),
+            (0x003170b3, "AND", MajorType::Compute0),
+            (0x003120b3, "SLT", MajorType::Compute0),
+            (0x003130b3, "SLTU", MajorType::Compute0),
+            (0x00310093, "ADDI", MajorType::Compute0),
+            (0x00314093, "XORI", MajorType::Compute1),
+            (0x00316093, "ORI", MajorType::Compute1),
+            (0x00317093, "ANDI", MajorType::Compute1),
+            (0x00312093, "SLTI", MajorType::Compute1),
+            (0x00313093, "SLTIU", MajorType::Compute1),
+            (0x00310463, "BEQ", MajorType::Compute1),
+            (0x00311463, "BNE", MajorType::Compute1),
+            (0x00314463, "BLT", MajorType::Compute1),
+            (0x00315463, "BGE", MajorType::Compute2),
+            (0x00316463, "BLTU", MajorType::Compute2),
+            (0x00317463, "BGEU", MajorType::Compute2),
+            (0x008000ef, "JAL", MajorType::Compute2),
+            (0x008100e7, "JALR", MajorType::Compute2),
+            (0x000030b7, "LUI", MajorType::Compute2),
+            (0x00003097, "AUIPC", MajorType::Compute2),
+            (0x00310083, "LB", MajorType::MemIo),
+            (0x00311083, "LH", MajorType::MemIo),
+            (0x00312083, "LW", MajorType::MemIo),
+            (0x00314083, "LBU", MajorType::MemIo),
+            (0x00315083, "LHU", MajorType::MemIo),
+            (0x003101a3, "SB", MajorType::MemIo),
+            (0x003111a3, "SH", MajorType::MemIo),
+            (0x003121a3, "SW", MajorType::MemIo),
+            (0x00313083, "LD", MajorType::MemIo64),
+            (0x00316083, "LWU", MajorType::MemIo64),
+            (0x003131a3, "SD", MajorType::MemIo64),
+            (0x023100b3, "MUL", MajorType::Multiply),
+            (0x023110b3, "MULH", MajorType::Multiply),
+            (0x023120b3, "MULSU", MajorType::Multiply),
+            (0x023130b3, "MULU", MajorType::Multiply),
+            (0x003110b3, "SLL", MajorType::Multiply),
+            (0x00311093, "SLLI", MajorType::Multiply),
+            (0x023100bb, "MULW", MajorType::Multiply),
+            (0x023140b3, "DIV", MajorType::Divide),
+            (0x023150b3, "DIVU", MajorType::Divide),
+            (0x023160b3, "REM", MajorType::Divide),
+            (0x023170b3, "REMU", MajorType::Divide),
+            (0x003150b3, "SRL", MajorType::Divide),
+            (0x403150b3, "SRA", MajorType::Divide),
+            (0x00315093, "SRLI", MajorType::Divide),
+            (0x40315093, "SRAI", MajorType::Divide),
+            (0x00000073, "ECALL", MajorType::ECall),
+            (0x00100073, "EBREAK", MajorType::ECall),
+            (0x0031009b, "ADDIW", MajorType::Compute3),
+            (0xc01020f3, "RDTIME", MajorType::Compute3),
+            (0x0ff0000f, "FENCE", MajorType::Compute3),
+            (0x023170bb, "REMUW", MajorType::Divide64),
+            (0x00312087, "FLW", MajorType::Float),
+            (0x003121a7, "FSW", MajorType::Float),
+            (0x003170d3, "FADD.S", MajorType::Float),
+            (0x083170d3, "FSUB.S", MajorType::Float),
+            (0x103170d3, "FMUL.S", MajorType::Float),
+            (0x183170d3, "FDIV.S", MajorType::Float),
+            (0xc00110d3, "FCVT.W.S", MajorType::Float),
+            (0xd00170d3, "FCVT.S.W", MajorType::Float),
+        ];
+
+        let mut seen = Vec::new();
+        for (insn, mnemonic, major) in insns {
+            let opcode = OpCode::decode(insn, 0x1000).unwrap();
+            assert_eq!(opcode.mnemonic, mnemonic);
+            assert_eq!(opcode.major, major, "{mnemonic}");
+            let key = (opcode.major.as_u32(), opcode.minor);
+            assert!(!seen.contains(&key), "{mnemonic} collides on {key:?}");
+            seen.push(key);
+        }
+    }
+}
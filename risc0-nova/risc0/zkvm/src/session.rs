@@ -17,19 +17,43 @@
 
 use alloc::collections::BTreeSet;
 
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::{exec::SyscallRecord, MemoryImage};
+use crate::{
+    exec::{ExecutorState, MemTxn, SyscallRecord},
+    Executor, ExecutorEnv, MemoryImage,
+};
 
 /// Indicates how a [Segment] or [Session]'s execution has terminated
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ExitCode {
     /// This indicates that the session limit has been reached.
     SessionLimit,
 
+    /// This indicates that the segment limit has been reached and execution
+    /// carries on in the next [Segment]. Only ever ends a segment, never a
+    /// [Session].
+    SystemSplit,
+
     /// This indicates normal termination of a program with an interior exit
     /// code returned from the guest.
     Halted(u32),
+
+    /// This indicates that an instruction failed to decode or execute and
+    /// the failure was handed to the
+    /// [on_error](crate::ExecutorEnvBuilder::on_error) callback.
+    Fault,
+
+    /// This indicates that the guest is stuck in a loop of a single branch or
+    /// jump to itself at the given PC, detected when enabled with
+    /// [detect_infinite_loops](crate::ExecutorEnvBuilder::detect_infinite_loops).
+    InfiniteLoop(u64),
+
+    /// This indicates that the guest gave up, e.g. on a panic, by a halt
+    /// ecall of type [ABORT](risc0_zkvm_platform::syscall::halt::ABORT),
+    /// with the message it passed if there was a readable one.
+    Aborted(Option<String>),
 }
 
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
@@ -57,6 +81,8 @@ pub struct Session {
 
     /// The [ExitCode] of the session.
     pub exit_code: ExitCode,
+
+    pub(crate) output_region: Option<Vec<u8>>,
 }
 
 /// The execution trace of a portion of a program.
@@ -69,11 +95,15 @@ pub struct Session {
 /// termination.
 #[derive(Serialize, Deserialize)]
 pub struct Segment {
-    // pub(crate) pre_image: MemoryImage,
-    // pub(crate) post_image_id: Digest,
-    pub(crate) pc: u64,
+    // The memory the segment starts from, registers included, as written by
+    // `MemoryImage::serialize`
+    pub(crate) pre_image: Vec<u8>,
+    // post_image_id: Digest,
+    // The rest of the state the segment starts from, its PC included
+    pub(crate) pre_state: ExecutorState,
     // pub(crate) faults: PageFaults,
-    // pub(crate) syscalls: Vec<SyscallRecord>,
+    pub(crate) syscalls: Vec<SyscallRecord>,
+    pub(crate) mem_txns: Vec<MemTxn>,
     pub(crate) exit_code: ExitCode,
     // The number of cycles in powers of 2.
     // pub po2: usize,
@@ -86,29 +116,154 @@ impl Session {
             segments,
             // journal,
             exit_code,
+            output_region: None,
         }
     }
+
+    /// The output the guest left in its
+    /// [output region](crate::ExecutorEnvBuilder::output_region) when the
+    /// session ended, without the length prefix, or [None] if there's no
+    /// output region.
+    pub fn output_region(&self) -> Option<&[u8]> {
+        self.output_region.as_deref()
+    }
+
+    /// Check the [Segment]s of this session form a valid chain, e.g. after
+    /// deserializing it, by replaying each one.
+    ///
+    /// Every segment but the last must end with [ExitCode::SystemSplit] and
+    /// the last with [Session::exit_code]. Each segment is re-executed from
+    /// its pre-image with [Executor::from_state] in a clone of `env`, and
+    /// must make the same syscalls and end with the same exit code, and,
+    /// if another segment follows, in exactly the memory and state that one
+    /// starts from.
+    ///
+    /// `env` must provide whatever the guest reads from the host as the
+    /// original run's did, e.g. the same environment variables, clock and
+    /// session limit. A [FaultPlan](crate::FaultPlan) starts over in each
+    /// segment, so a session run with one only replays if its faults all
+    /// fall in the first segment.
+    pub fn verify(&self, env: &ExecutorEnv) -> Result<()> {
+        let last = self.segments.len().saturating_sub(1);
+        for (idx, segment) in self.segments.iter().enumerate() {
+            let expected = if idx == last {
+                &self.exit_code
+            } else {
+                &ExitCode::SystemSplit
+            };
+            if segment.exit_code != *expected {
+                bail!(
+                    "Segment {idx} ends with {:?} where {expected:?} was expected",
+                    segment.exit_code
+                );
+            }
+        }
+
+        for (idx, segment) in self.segments.iter().enumerate() {
+            let next = self.segments.get(idx + 1);
+            let (exit_code, exec) = segment.replay(env.clone(), next)?;
+            if exit_code != segment.exit_code {
+                bail!(
+                    "Segment {idx} replays to {exit_code:?} but ended with {:?}",
+                    segment.exit_code
+                );
+            }
+            if exec.monitor.syscalls != segment.syscalls {
+                bail!("Segment {idx} replays with different syscalls than it recorded");
+            }
+
+            let Some(next) = next else {
+                continue;
+            };
+            let state = exec.state();
+            if state != next.pre_state {
+                bail!(
+                    "Segment {idx} replays to {state:x?} but segment {} starts from {:x?}",
+                    idx + 1,
+                    next.pre_state
+                );
+            }
+            let mut image = Vec::new();
+            exec.monitor.image.serialize(&mut image)?;
+            if image != next.pre_image {
+                bail!(
+                    "Segment {idx} replays to different memory than segment {} starts from",
+                    idx + 1
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The syscalls made by the guest over the whole session, in the order
+    /// they were made.
+    pub fn syscalls(&self) -> impl Iterator<Item = &SyscallRecord> {
+        self.segments.iter().flat_map(|segment| segment.syscalls())
+    }
 }
 
 impl Segment {
     /// Create a new [Segment] from its constituent components.
     pub(crate) fn new(
-        pre_image: MemoryImage,
+        pre_image: Vec<u8>,
         // post_image_id: Digest,
-        pc: u64,
+        pre_state: ExecutorState,
         // faults: PageFaults,
-        // syscalls: Vec<SyscallRecord>,
+        syscalls: Vec<SyscallRecord>,
+        mem_txns: Vec<MemTxn>,
         exit_code: ExitCode,
         // po2: usize,
     ) -> Self {
         Self {
-            // pre_image,
+            pre_image,
             // post_image_id,
-            pc,
+            pre_state,
             // faults,
-            // syscalls,
+            syscalls,
+            mem_txns,
             exit_code,
             // po2,
         }
     }
+
+    /// The syscalls made by the guest during this segment, in the order they
+    /// were made.
+    pub fn syscalls(&self) -> &[SyscallRecord] {
+        &self.syscalls
+    }
+
+    /// The loads and stores made by the guest during this segment, in order,
+    /// or none unless
+    /// [record_mem_txns](crate::ExecutorEnvBuilder::record_mem_txns) is set.
+    pub fn mem_txns(&self) -> &[MemTxn] {
+        &self.mem_txns
+    }
+
+    // Re-execute the segment in `env`, up to where `next` starts if it's
+    // followed by another segment, otherwise until it exits. An instruction
+    // failing replays as a fault, as with an `on_error` callback.
+    fn replay<'a>(
+        &self,
+        env: ExecutorEnv<'a>,
+        next: Option<&Segment>,
+    ) -> Result<(ExitCode, Executor<'a>)> {
+        let image = MemoryImage::deserialize(&mut self.pre_image.as_slice())?;
+        let mut exec = Executor::from_state(env, image, self.pre_state.clone());
+        let end = next.map(|next| next.pre_state.insn_counter);
+        if matches!(end, Some(end) if end < self.pre_state.insn_counter) {
+            bail!("Segment ends before it starts");
+        }
+
+        let exit_code = loop {
+            if end == Some(exec.state().insn_counter) {
+                break ExitCode::SystemSplit;
+            }
+            match exec.step() {
+                Ok(Some(exit_code)) => break exit_code,
+                Ok(None) => {}
+                Err(_) => break ExitCode::Fault,
+            }
+        };
+        Ok((exit_code, exec))
+    }
 }
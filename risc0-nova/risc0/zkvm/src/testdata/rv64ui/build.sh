@@ -0,0 +1,14 @@
+#!/bin/sh
+# Rebuild the bundled riscv-tests ELFs from their sources against the
+# executor's environment in env/, e.g. `./build.sh add`.
+#
+# Needs a C preprocessor, llvm-mc and an lld (rust-lld works).
+set -e
+cd "$(dirname "$0")"
+LLD=${LLD:-ld.lld}
+for test in "$@"; do
+    cpp -P -D__riscv_xlen=64 -Ienv -Imacros "$test.S" \
+        | llvm-mc -triple=riscv64 -mattr=+m,+a -filetype=obj -o "$test.o"
+    $LLD -flavor gnu -T link.ld -o "rv64ui-p-$test" "$test.o"
+    rm "$test.o"
+done
@@ -0,0 +1,45 @@
+// The riscv-tests `p` environment cut down for the executor, which has no
+// machine mode CSRs or traps: tests start straight at `_start` and report
+// through `tohost`, 1 for a pass and `(n << 1) | 1` for a failure of case n.
+
+#ifndef _ENV_PHYSICAL_SINGLE_CORE_H
+#define _ENV_PHYSICAL_SINGLE_CORE_H
+
+#define RVTEST_RV64U
+#define TESTNUM gp
+
+#define RVTEST_CODE_BEGIN                                               \
+        .section .text.init;                                            \
+        .align  6;                                                      \
+        .globl _start;                                                  \
+_start:
+
+#define RVTEST_CODE_END                                                 \
+        unimp
+
+#define RVTEST_PASS                                                     \
+        li a0, 1;                                                       \
+        la t0, tohost;                                                  \
+        sd a0, 0(t0);                                                   \
+1:      j 1b;
+
+#define RVTEST_FAIL                                                     \
+        sll a0, TESTNUM, 1;                                             \
+        or a0, a0, 1;                                                   \
+        la t0, tohost;                                                  \
+        sd a0, 0(t0);                                                   \
+1:      j 1b;
+
+#define EXTRA_DATA
+
+#define RVTEST_DATA_BEGIN                                               \
+        EXTRA_DATA                                                      \
+        .pushsection .tohost,"aw",@progbits;                            \
+        .align 6; .global tohost; tohost: .dword 0;                     \
+        .align 6; .global fromhost; fromhost: .dword 0;                 \
+        .popsection;                                                    \
+        .align 4; .global begin_signature; begin_signature:
+
+#define RVTEST_DATA_END .align 4; .global end_signature; end_signature:
+
+#endif
@@ -159,8 +159,10 @@ fn setup_memory_space(cli_opts: &CliOpts) -> Result<MemorySpace, String> {
         )
         .expect("Adding base memory is expected to succeed");
 
+    // Devices are mapped as IO, which isn't executable, leaving the base memory as the only place
+    // code runs from
     mem_space
-        .add_memory(
+        .add_volatile_memory(
             0x80000000,
             0x4,
             Box::new(CharOutputterDevice::new(
@@ -187,7 +189,7 @@ fn setup_sim_environment(args: &ArgMatches) -> Result<SimEnvironment, String> {
 
     sim_environment.sim_ctrl_dev_idx = sim_environment
         .memory_space
-        .add_memory(0x80000004, 0x4, Box::new(SimulationCtrlDevice::new()))
+        .add_volatile_memory(0x80000004, 0x4, Box::new(SimulationCtrlDevice::new()))
         .expect("Adding simulation control device is expected to succeed");
 
     sim_environment.hart_state.pc = cli_opts.start_addr;
@@ -204,22 +206,32 @@ fn setup_sim_environment(args: &ArgMatches) -> Result<SimEnvironment, String> {
     Ok(sim_environment)
 }
 
-fn run_sim(sim_environment: &mut SimEnvironment) {
-    let mut executor = InstructionExecutor {
-        hart_state: &mut sim_environment.hart_state,
-        mem: &mut sim_environment.memory_space,
-    };
+// Runs the simulation until the emulated binary requests a stop.
+//
+// Returned errors are strings describing the error.
+fn run_sim(sim_environment: &mut SimEnvironment) -> Result<(), String> {
+    let mut executor = InstructionExecutor::new(
+        &mut sim_environment.memory_space,
+        &mut sim_environment.hart_state,
+    );
 
     let mut insn_count: u64 = 0;
     let start = Instant::now();
 
     loop {
         if let Some(log_file) = &mut sim_environment.log_file {
-            // Output current instruction disassembly to log
+            // Output current instruction disassembly to log, checking first that there's code to
+            // disassemble rather than data or unmapped memory past the end of it
+            if !executor.mem.is_executable(executor.hart_state.pc) {
+                return Err(format!(
+                    "Could not disassemble PC {:08x}, which isn't executable memory",
+                    executor.hart_state.pc
+                ));
+            }
             let insn_bits = executor
                 .mem
-                .read_mem(executor.hart_state.pc, MemAccessSize::Word)
-                .unwrap_or_else(|| panic!("Could not read PC {:08x}", executor.hart_state.pc));
+                .read_insn(executor.hart_state.pc)
+                .ok_or_else(|| format!("Could not read PC {:08x}", executor.hart_state.pc))?;
 
             let mut outputter = InstructionStringOutputter {
                 insn_pc: executor.hart_state.pc,
@@ -236,7 +248,12 @@ fn run_sim(sim_environment: &mut SimEnvironment) {
         }
 
         // Execute instruction
-        executor.step().expect("Exception during execution");
+        executor.step().map_err(|e| {
+            format!(
+                "Exception during execution at PC {:08x}: {:?}",
+                executor.hart_state.pc, e
+            )
+        })?;
 
         insn_count += 1;
 
@@ -271,6 +288,8 @@ fn run_sim(sim_environment: &mut SimEnvironment) {
         elapsed.as_millis(),
         mhz
     );
+
+    Ok(())
 }
 
 fn main() {
@@ -284,5 +303,7 @@ fn main() {
         }
     };
 
-    run_sim(&mut sim_environment)
+    if let Err(e) = run_sim(&mut sim_environment) {
+        println!("Failure running simulation: {}", e);
+    }
 }
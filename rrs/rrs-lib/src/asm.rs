@@ -0,0 +1,303 @@
+// Copyright 2021 Gregory Chadwick <mail@gregchadwick.co.uk>
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encode RV64I instructions, for building test programs without hand written hex
+//!
+//! There is one function per instruction taking its operands in assembly order, so `addi x1, x2,
+//! 3` is `addi(1, 2, 3)`. Branch and jump offsets are relative to the instruction's own address.
+//! Operands that don't fit the instruction's encoding panic rather than being silently truncated.
+//!
+//! Only available in rrs-lib's own tests or with the `asm` feature enabled.
+//!
+//! # Example
+//!
+//! ```
+//! # #[cfg(feature = "asm")]
+//! # {
+//! use rrs_lib::asm::{add, lui};
+//!
+//! assert_eq!(
+//!     [lui(2, 0x1234b), lui(3, 0xf387e), add(1, 2, 3)],
+//!     [0x1234b137, 0xf387e1b7, 0x003100b3]
+//! );
+//! # }
+//! ```
+
+use super::instruction_formats::*;
+
+fn reg(r: usize) -> u32 {
+    assert!(r < 32, "register x{} out of range", r);
+    r as u32
+}
+
+fn imm_bits(imm: i32, bits: u32) -> u32 {
+    let limit = 1 << (bits - 1);
+    assert!(
+        (-limit..limit).contains(&imm),
+        "immediate {} doesn't fit in {} bits",
+        imm,
+        bits
+    );
+    (imm as u32) & ((1 << bits) - 1)
+}
+
+fn offset_bits(offset: i32, bits: u32) -> u32 {
+    assert!(offset & 1 == 0, "offset {} isn't 2 byte aligned", offset);
+    imm_bits(offset, bits)
+}
+
+fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: usize, rs1: usize, rs2: usize) -> u32 {
+    (funct7 << 25) | (reg(rs2) << 20) | (reg(rs1) << 15) | (funct3 << 12) | (reg(rd) << 7) | opcode
+}
+
+fn i_type(opcode: u32, funct3: u32, rd: usize, rs1: usize, imm: i32) -> u32 {
+    (imm_bits(imm, 12) << 20) | (reg(rs1) << 15) | (funct3 << 12) | (reg(rd) << 7) | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs2: usize, rs1: usize, imm: i32) -> u32 {
+    let imm = imm_bits(imm, 12);
+    ((imm >> 5) << 25)
+        | (reg(rs2) << 20)
+        | (reg(rs1) << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1f) << 7)
+        | opcode
+}
+
+fn b_type(funct3: u32, rs1: usize, rs2: usize, offset: i32) -> u32 {
+    let imm = offset_bits(offset, 13);
+    (((imm >> 12) & 0x1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (reg(rs2) << 20)
+        | (reg(rs1) << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 0x1) << 7)
+        | OPCODE_BRANCH
+}
+
+fn u_type(opcode: u32, rd: usize, imm: u32) -> u32 {
+    assert!(
+        imm < (1 << 20),
+        "upper immediate {:#x} doesn't fit in 20 bits",
+        imm
+    );
+    (imm << 12) | (reg(rd) << 7) | opcode
+}
+
+fn shift_type(funct3: u32, funct6: u32, rd: usize, rs1: usize, shamt: u32) -> u32 {
+    assert!(shamt < 64, "shift amount {} out of range", shamt);
+    (funct6 << 26)
+        | (shamt << 20)
+        | (reg(rs1) << 15)
+        | (funct3 << 12)
+        | (reg(rd) << 7)
+        | OPCODE_OP_IMM
+}
+
+// Produces one function per instruction for a group of instructions sharing an encoding.
+macro_rules! r_type_insns {
+    ($($name:ident: $funct3:expr, $funct7:expr;)*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), " rd, rs1, rs2`")]
+            pub fn $name(rd: usize, rs1: usize, rs2: usize) -> u32 {
+                r_type(OPCODE_OP, $funct3, $funct7, rd, rs1, rs2)
+            }
+        )*
+    };
+}
+
+macro_rules! i_type_insns {
+    ($($name:ident: $opcode:expr, $funct3:expr;)*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), " rd, rs1, imm`")]
+            pub fn $name(rd: usize, rs1: usize, imm: i32) -> u32 {
+                i_type($opcode, $funct3, rd, rs1, imm)
+            }
+        )*
+    };
+}
+
+macro_rules! load_insns {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), " rd, imm(rs1)`")]
+            pub fn $name(rd: usize, rs1: usize, imm: i32) -> u32 {
+                i_type(OPCODE_LOAD, $funct3, rd, rs1, imm)
+            }
+        )*
+    };
+}
+
+macro_rules! store_insns {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), " rs2, imm(rs1)`")]
+            pub fn $name(rs2: usize, rs1: usize, imm: i32) -> u32 {
+                s_type(OPCODE_STORE, $funct3, rs2, rs1, imm)
+            }
+        )*
+    };
+}
+
+macro_rules! branch_insns {
+    ($($name:ident: $funct3:expr;)*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), " rs1, rs2, offset`")]
+            pub fn $name(rs1: usize, rs2: usize, offset: i32) -> u32 {
+                b_type($funct3, rs1, rs2, offset)
+            }
+        )*
+    };
+}
+
+macro_rules! shift_insns {
+    ($($name:ident: $funct3:expr, $funct6:expr;)*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), " rd, rs1, shamt`")]
+            pub fn $name(rd: usize, rs1: usize, shamt: u32) -> u32 {
+                shift_type($funct3, $funct6, rd, rs1, shamt)
+            }
+        )*
+    };
+}
+
+r_type_insns! {
+    add: 0b000, 0b0000000;
+    sub: 0b000, 0b0100000;
+    sll: 0b001, 0b0000000;
+    slt: 0b010, 0b0000000;
+    sltu: 0b011, 0b0000000;
+    xor: 0b100, 0b0000000;
+    srl: 0b101, 0b0000000;
+    sra: 0b101, 0b0100000;
+    or: 0b110, 0b0000000;
+    and: 0b111, 0b0000000;
+}
+
+i_type_insns! {
+    addi: OPCODE_OP_IMM, 0b000;
+    slti: OPCODE_OP_IMM, 0b010;
+    sltiu: OPCODE_OP_IMM, 0b011;
+    xori: OPCODE_OP_IMM, 0b100;
+    ori: OPCODE_OP_IMM, 0b110;
+    andi: OPCODE_OP_IMM, 0b111;
+    addiw: OPCODE_IW, 0b000;
+    jalr: OPCODE_JALR, 0b000;
+}
+
+shift_insns! {
+    slli: 0b001, 0b000000;
+    srli: 0b101, 0b000000;
+    srai: 0b101, 0b010000;
+}
+
+load_insns! {
+    lb: 0b000;
+    lh: 0b001;
+    lw: 0b010;
+    ld: 0b011;
+    lbu: 0b100;
+    lhu: 0b101;
+    lwu: 0b110;
+}
+
+store_insns! {
+    sb: 0b000;
+    sh: 0b001;
+    sw: 0b010;
+    sd: 0b011;
+}
+
+branch_insns! {
+    beq: 0b000;
+    bne: 0b001;
+    blt: 0b100;
+    bge: 0b101;
+    bltu: 0b110;
+    bgeu: 0b111;
+}
+
+/// `lui rd, imm`, `imm` being the 20 bit value placed in the upper bits of `rd`
+pub fn lui(rd: usize, imm: u32) -> u32 {
+    u_type(OPCODE_LUI, rd, imm)
+}
+
+/// `auipc rd, imm`, `imm` being the 20 bit value added to the upper bits of the PC
+pub fn auipc(rd: usize, imm: u32) -> u32 {
+    u_type(OPCODE_AUIPC, rd, imm)
+}
+
+/// `jal rd, offset`
+pub fn jal(rd: usize, offset: i32) -> u32 {
+    let imm = offset_bits(offset, 21);
+    (((imm >> 20) & 0x1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 0x1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (reg(rd) << 7)
+        | OPCODE_JAL
+}
+
+/// `fence`, ordering all prior memory accesses before all later ones
+pub fn fence() -> u32 {
+    0x0ff0000f
+}
+
+/// `ecall`
+pub fn ecall() -> u32 {
+    0x00000073
+}
+
+/// `ebreak`
+pub fn ebreak() -> u32 {
+    0x00100073
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction_decoder::{decode, DecodedInsn};
+
+    #[test]
+    fn test_asm() {
+        // The program used by the InstructionExecutor example
+        assert_eq!(
+            [lui(2, 0x1234b), lui(3, 0xf387e), add(1, 2, 3)],
+            [0x1234b137, 0xf387e1b7, 0x003100b3]
+        );
+
+        assert_eq!(addi(17, 12, 123), 0x07b60893);
+        assert_eq!(ld(8, 0, 0x400), 0x40003403);
+        assert_eq!(sd(7, 0, 0x400), 0x40703023);
+        assert_eq!(bne(5, 6, -16), 0xfe6298e3);
+        assert_eq!(jalr(0, 0, 0x400), 0x40000067);
+        assert_eq!(jal(0, 0), 0x0000006f);
+
+        match decode(jal(1, -2048)) {
+            Some(DecodedInsn::Jal(JType { rd: 1, imm })) => assert_eq!(imm, -2048),
+            other => panic!("expected jal, got {:?}", other),
+        }
+
+        match decode(beq(3, 4, 4094)) {
+            Some(DecodedInsn::Beq(BType { rs1, rs2, imm, .. })) => {
+                assert_eq!((rs1, rs2, imm), (3, 4, 4094))
+            }
+            other => panic!("expected beq, got {:?}", other),
+        }
+
+        match decode(srai(1, 2, 63)) {
+            Some(DecodedInsn::Srai(ITypeRV64Shamt { rd, rs1, shamt, .. })) => {
+                assert_eq!((rd, rs1, shamt), (1, 2, 63))
+            }
+            other => panic!("expected srai, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit in 12 bits")]
+    fn test_asm_imm_out_of_range() {
+        addi(1, 0, 2048);
+    }
+}
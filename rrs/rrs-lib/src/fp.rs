@@ -0,0 +1,397 @@
+// Copyright 2021 Gregory Chadwick <mail@gregchadwick.co.uk>
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Single-precision floating point support for the F extension
+//!
+//! Arithmetic is carried out on the host in `f64`, which is wide enough that the result of an
+//! `f32` add, subtract, multiply or divide computed in `f64` and then rounded to `f32` is correctly
+//! rounded. The error left over by the `f64` operation is tracked so the directed rounding modes
+//! and the inexact flag are exact too.
+
+/// `fcsr` accrued exception flag: inexact
+pub const FFLAGS_NX: u32 = 1 << 0;
+/// `fcsr` accrued exception flag: underflow
+pub const FFLAGS_UF: u32 = 1 << 1;
+/// `fcsr` accrued exception flag: overflow
+pub const FFLAGS_OF: u32 = 1 << 2;
+/// `fcsr` accrued exception flag: divide by zero
+pub const FFLAGS_DZ: u32 = 1 << 3;
+/// `fcsr` accrued exception flag: invalid operation
+pub const FFLAGS_NV: u32 = 1 << 4;
+
+/// Position of the dynamic rounding mode (`frm`) field in `fcsr`
+pub const FCSR_FRM_SHIFT: u32 = 5;
+/// Mask of the `frm` field once shifted down by [FCSR_FRM_SHIFT]
+pub const FCSR_FRM_MASK: u32 = 0x7;
+
+/// The `rm` encoding that selects the dynamic rounding mode from `fcsr`
+const RM_DYNAMIC: u32 = 0b111;
+
+/// The canonical NaN produced by any operation returning a NaN
+pub const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+/// IEEE-754 rounding modes, named as in the RISC-V specification
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even
+    Rne,
+    /// Round towards zero
+    Rtz,
+    /// Round down (towards negative infinity)
+    Rdn,
+    /// Round up (towards positive infinity)
+    Rup,
+    /// Round to nearest, ties to max magnitude
+    Rmm,
+}
+
+impl RoundingMode {
+    /// Decode the `rm` field of an instruction, using `fcsr` for the dynamic rounding mode.
+    ///
+    /// Returns `None` for the reserved encodings, which make the instruction illegal.
+    pub fn from_rm(rm: u32, fcsr: u32) -> Option<RoundingMode> {
+        let rm = if rm == RM_DYNAMIC {
+            (fcsr >> FCSR_FRM_SHIFT) & FCSR_FRM_MASK
+        } else {
+            rm
+        };
+
+        match rm {
+            0b000 => Some(RoundingMode::Rne),
+            0b001 => Some(RoundingMode::Rtz),
+            0b010 => Some(RoundingMode::Rdn),
+            0b011 => Some(RoundingMode::Rup),
+            0b100 => Some(RoundingMode::Rmm),
+            _ => None,
+        }
+    }
+}
+
+fn is_signaling_nan(x: f32) -> bool {
+    x.is_nan() && (x.to_bits() & 0x0040_0000) == 0
+}
+
+// The next representable f32 above `x`, treating both zeros as zero
+fn next_up(x: f32) -> f32 {
+    if x == 0.0 {
+        f32::from_bits(1)
+    } else if x > 0.0 {
+        f32::from_bits(x.to_bits() + 1)
+    } else {
+        f32::from_bits(x.to_bits() - 1)
+    }
+}
+
+fn next_down(x: f32) -> f32 {
+    -next_up(-x)
+}
+
+// Value of `x` for rounding decisions. Infinity stands in for 2^128, the next value above
+// f32::MAX were the exponent unbounded.
+fn grid_value(x: f32) -> f64 {
+    if x.is_infinite() {
+        2f64.powi(128).copysign(x as f64)
+    } else {
+        x as f64
+    }
+}
+
+/// Round `x + err` to an `f32` using `rm`, returning the result and the exception flags raised.
+///
+/// `x` is a finite, non-NaN `f64` and `err` the (possibly zero) error left by computing it, small
+/// enough that it never moves `x` past a neighbouring `f64`.
+fn round_to_f32(x: f64, err: f64, rm: RoundingMode) -> (f32, u32) {
+    if x.abs() >= 2f64.powi(128) {
+        // Beyond even the unbounded exponent successor of f32::MAX, so always overflows
+        let result = match (rm, x > 0.0) {
+            (RoundingMode::Rne, _) | (RoundingMode::Rmm, _) => f32::INFINITY,
+            (RoundingMode::Rtz, _) => f32::MAX,
+            (RoundingMode::Rdn, true) => f32::MAX,
+            (RoundingMode::Rdn, false) => f32::INFINITY,
+            (RoundingMode::Rup, true) => f32::INFINITY,
+            (RoundingMode::Rup, false) => f32::MAX,
+        };
+        return (result.copysign(x as f32), FFLAGS_OF | FFLAGS_NX);
+    }
+
+    let nearest = x as f32;
+
+    // Sign of the exact value relative to `nearest`
+    let diff = x - grid_value(nearest);
+    let direction = if diff != 0.0 { diff } else { err };
+
+    if direction == 0.0 {
+        return (nearest, 0);
+    }
+
+    let (lo, hi) = if direction > 0.0 {
+        (nearest, next_up(nearest))
+    } else {
+        (next_down(nearest), nearest)
+    };
+
+    let result = match rm {
+        RoundingMode::Rdn => lo,
+        RoundingMode::Rup => hi,
+        RoundingMode::Rtz => {
+            if x > 0.0 {
+                lo
+            } else {
+                hi
+            }
+        }
+        RoundingMode::Rne | RoundingMode::Rmm => {
+            let to_lo = (x - grid_value(lo)) + err;
+            let to_hi = (grid_value(hi) - x) - err;
+
+            if to_lo < to_hi {
+                lo
+            } else if to_hi < to_lo {
+                hi
+            } else if rm == RoundingMode::Rmm {
+                if x > 0.0 {
+                    hi
+                } else {
+                    lo
+                }
+            } else if lo.is_infinite() || lo.to_bits() & 1 == 0 {
+                // Ties to even, where infinity counts as even
+                lo
+            } else {
+                hi
+            }
+        }
+    };
+
+    let mut flags = FFLAGS_NX;
+
+    if result.is_infinite() {
+        flags |= FFLAGS_OF;
+    }
+
+    if result.abs() < f32::MIN_POSITIVE {
+        flags |= FFLAGS_UF;
+    }
+
+    (result, flags)
+}
+
+/// Operations supported by [binary_op]
+#[derive(Clone, Copy)]
+pub(crate) enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Compute `a op b` for `f32` register values `a` and `b` with IEEE-754 semantics, returning the
+/// result register value and the exception flags raised.
+pub(crate) fn binary_op(op: BinaryOp, a: u32, b: u32, rm: RoundingMode) -> (u32, u32) {
+    let a = f32::from_bits(a);
+    let b = f32::from_bits(b);
+
+    let nan_flags = if is_signaling_nan(a) || is_signaling_nan(b) {
+        FFLAGS_NV
+    } else {
+        0
+    };
+
+    if a.is_nan() || b.is_nan() {
+        return (CANONICAL_NAN, nan_flags);
+    }
+
+    let (a, b) = (a as f64, b as f64);
+
+    // The f64 result is exact for multiplication. For the others recover the error of the f64
+    // operation so the exact result is `x + err`.
+    let (x, err) = match op {
+        BinaryOp::Add | BinaryOp::Sub => {
+            let b = if let BinaryOp::Sub = op { -b } else { b };
+            let x = a + b;
+            if x.is_infinite() || x.is_nan() {
+                (x, 0.0)
+            } else {
+                let b_part = x - a;
+                (x, (a - (x - b_part)) + (b - b_part))
+            }
+        }
+        BinaryOp::Mul => (a * b, 0.0),
+        BinaryOp::Div => {
+            let x = a / b;
+            if x.is_finite() && x != 0.0 {
+                // The remainder `a - x * b` is exact when computed with a fused multiply-add
+                let rem = (-x).mul_add(b, a);
+                (x, rem / b)
+            } else {
+                (x, 0.0)
+            }
+        }
+    };
+
+    if x.is_nan() {
+        return (CANONICAL_NAN, FFLAGS_NV);
+    }
+
+    if x.is_infinite() {
+        let flags = match op {
+            BinaryOp::Div if b == 0.0 && a.is_finite() => FFLAGS_DZ,
+            _ => 0,
+        };
+        return ((x as f32).to_bits(), flags);
+    }
+
+    if x == 0.0 && err == 0.0 {
+        // Exact zero, sign already correct for round to nearest. RDN gives -0.0 for an exact
+        // zero sum of opposite signed operands.
+        let zero = match op {
+            BinaryOp::Add | BinaryOp::Sub if rm == RoundingMode::Rdn && a != 0.0 && b != 0.0 => {
+                -0.0f32
+            }
+            _ => x as f32,
+        };
+        return (zero.to_bits(), 0);
+    }
+
+    let (result, flags) = round_to_f32(x, err, rm);
+    (result.to_bits(), flags)
+}
+
+/// Convert the `f32` register value `a` to a signed 32-bit integer (FCVT.W.S), returning the
+/// integer and the exception flags raised.
+pub(crate) fn to_i32(a: u32, rm: RoundingMode) -> (i32, u32) {
+    let a = f32::from_bits(a);
+
+    if a.is_nan() {
+        return (i32::MAX, FFLAGS_NV);
+    }
+
+    let a = a as f64;
+    let rounded = match rm {
+        RoundingMode::Rtz => a.trunc(),
+        RoundingMode::Rdn => a.floor(),
+        RoundingMode::Rup => a.ceil(),
+        RoundingMode::Rmm => a.round(),
+        RoundingMode::Rne => {
+            let r = a.round();
+            // `round` breaks ties away from zero, step back to the even neighbour
+            if (r - a).abs() == 0.5 && r % 2.0 != 0.0 {
+                r - a.signum()
+            } else {
+                r
+            }
+        }
+    };
+
+    if rounded > i32::MAX as f64 {
+        (i32::MAX, FFLAGS_NV)
+    } else if rounded < i32::MIN as f64 {
+        (i32::MIN, FFLAGS_NV)
+    } else if rounded != a {
+        (rounded as i32, FFLAGS_NX)
+    } else {
+        (rounded as i32, 0)
+    }
+}
+
+/// Convert the signed 32-bit integer `a` to an `f32` register value (FCVT.S.W), returning the
+/// result and the exception flags raised.
+pub(crate) fn from_i32(a: i32, rm: RoundingMode) -> (u32, u32) {
+    if a == 0 {
+        return (0, 0);
+    }
+
+    let (result, flags) = round_to_f32(a as f64, 0.0, rm);
+    (result.to_bits(), flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(a: f32, b: f32, rm: RoundingMode) -> (f32, u32) {
+        let (result, flags) = binary_op(BinaryOp::Add, a.to_bits(), b.to_bits(), rm);
+        (f32::from_bits(result), flags)
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(add(1.5, 2.25, RoundingMode::Rne), (3.75, 0));
+
+        // 1 + 2^-24 is exactly halfway between 1.0 and the next f32 up
+        let half_ulp = 2f32.powi(-24);
+        assert_eq!(add(1.0, half_ulp, RoundingMode::Rne), (1.0, FFLAGS_NX));
+        assert_eq!(
+            add(1.0, half_ulp, RoundingMode::Rmm),
+            (next_up(1.0), FFLAGS_NX)
+        );
+        assert_eq!(
+            add(1.0, half_ulp, RoundingMode::Rup),
+            (next_up(1.0), FFLAGS_NX)
+        );
+        assert_eq!(add(1.0, half_ulp, RoundingMode::Rdn), (1.0, FFLAGS_NX));
+
+        // A tiny addend lost entirely by the f64 sum still rounds up under RUP
+        let tiny = f32::from_bits(1);
+        assert_eq!(add(1.0, tiny, RoundingMode::Rup), (next_up(1.0), FFLAGS_NX));
+        assert_eq!(
+            add(-1.0, tiny, RoundingMode::Rtz),
+            (next_up(-1.0), FFLAGS_NX)
+        );
+
+        assert_eq!(
+            add(f32::MAX, f32::MAX, RoundingMode::Rtz),
+            (f32::MAX, FFLAGS_OF | FFLAGS_NX)
+        );
+        assert_eq!(
+            add(f32::MAX, f32::MAX, RoundingMode::Rne),
+            (f32::INFINITY, FFLAGS_OF | FFLAGS_NX)
+        );
+
+        let (result, flags) = binary_op(
+            BinaryOp::Div,
+            1.0f32.to_bits(),
+            0.0f32.to_bits(),
+            RoundingMode::Rne,
+        );
+        assert_eq!((f32::from_bits(result), flags), (f32::INFINITY, FFLAGS_DZ));
+
+        let (result, flags) = binary_op(
+            BinaryOp::Sub,
+            f32::INFINITY.to_bits(),
+            f32::INFINITY.to_bits(),
+            RoundingMode::Rne,
+        );
+        assert_eq!((result, flags), (CANONICAL_NAN, FFLAGS_NV));
+    }
+
+    #[test]
+    fn test_conversion() {
+        assert_eq!(to_i32(2.5f32.to_bits(), RoundingMode::Rne), (2, FFLAGS_NX));
+        assert_eq!(to_i32(3.5f32.to_bits(), RoundingMode::Rne), (4, FFLAGS_NX));
+        assert_eq!(
+            to_i32((-2.5f32).to_bits(), RoundingMode::Rmm),
+            (-3, FFLAGS_NX)
+        );
+        assert_eq!(
+            to_i32((-2.5f32).to_bits(), RoundingMode::Rtz),
+            (-2, FFLAGS_NX)
+        );
+        assert_eq!(
+            to_i32(1e10f32.to_bits(), RoundingMode::Rne),
+            (i32::MAX, FFLAGS_NV)
+        );
+        assert_eq!(
+            to_i32(CANONICAL_NAN, RoundingMode::Rne),
+            (i32::MAX, FFLAGS_NV)
+        );
+
+        assert_eq!(from_i32(-7, RoundingMode::Rne), ((-7.0f32).to_bits(), 0));
+        // 2^24 + 1 isn't representable
+        assert_eq!(
+            from_i32(16_777_217, RoundingMode::Rup),
+            (16_777_218.0f32.to_bits(), FFLAGS_NX)
+        );
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2021 Gregory Chadwick <mail@gregchadwick.co.uk>
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decode instructions into a [DecodedInsn] without acting on them
+//!
+//! [decode] runs [process_instruction()] with an [InstructionProcessor] that simply returns which
+//! instruction was decoded along with its decoded fields. Tools that only need to inspect
+//! instructions can match on the result rather than implementing every
+//! [InstructionProcessor] function themselves. A [DecodedInsn] can later be handed to any
+//! [InstructionProcessor] with [DecodedInsn::process], so an instruction decoded once can be
+//! both inspected and executed.
+//!
+//! # Example
+//!
+//! ```
+//! use rrs_lib::instruction_decoder::{decode, DecodedInsn};
+//!
+//! // addi x17, x12, 123
+//! match decode(0x07b60893) {
+//!     Some(DecodedInsn::Addi(dec_insn)) => {
+//!         assert_eq!(dec_insn.rd, 17);
+//!         assert_eq!(dec_insn.rs1, 12);
+//!         assert_eq!(dec_insn.imm, 123);
+//!     }
+//!     _ => panic!("expected addi"),
+//! }
+//! ```
+
+use super::instruction_formats::*;
+use super::process_instruction;
+use super::InstructionProcessor;
+use super::Xlen;
+use paste::paste;
+
+// Produces the DecodedInsn enum with one variant per instruction along with the
+// InstructionProcessor implementation that constructs it.
+macro_rules! make_decoded_insn {
+    ($($name:ident: $format:ident),* $(,)?) => {
+        paste! {
+            /// A decoded instruction, one variant per instruction supported by
+            /// [InstructionProcessor], holding the instruction's decoded fields
+            #[derive(Clone, Copy, Debug, PartialEq)]
+            pub enum DecodedInsn {
+                $([<$name:camel>]($format),)*
+            }
+
+            impl DecodedInsn {
+                /// Call the `processor` function for this instruction, as
+                /// [process_instruction()] would for the instruction bits it was decoded from,
+                /// without decoding them again
+                pub fn process<T: InstructionProcessor>(self, processor: &mut T) -> T::InstructionResult {
+                    match self {
+                        $(DecodedInsn::[<$name:camel>](dec_insn) => processor.[<process_ $name>](dec_insn),)*
+                    }
+                }
+            }
+
+            impl InstructionProcessor for InstructionDecoder {
+                type InstructionResult = DecodedInsn;
+
+                $(
+                    fn [<process_ $name>](&mut self, dec_insn: $format) -> Self::InstructionResult {
+                        DecodedInsn::[<$name:camel>](dec_insn)
+                    }
+                )*
+            }
+        }
+    };
+}
+
+struct InstructionDecoder;
+
+make_decoded_insn! {
+    add: RType,
+    sub: RType,
+    sll: RType,
+    slt: RType,
+    sltu: RType,
+    xor: RType,
+    srl: RType,
+    sra: RType,
+    or: RType,
+    and: RType,
+
+    addi: IType,
+    addiw: IType,
+    slli: ITypeRV64Shamt,
+    slti: IType,
+    sltui: IType,
+    xori: IType,
+    srli: ITypeRV64Shamt,
+    srai: ITypeRV64Shamt,
+    ori: IType,
+    andi: IType,
+
+    lui: UType,
+    auipc: UType,
+
+    beq: BType,
+    bne: BType,
+    blt: BType,
+    bltu: BType,
+    bge: BType,
+    bgeu: BType,
+
+    lb: IType,
+    lbu: IType,
+    lh: IType,
+    ld: IType,
+    lhu: IType,
+    lw: IType,
+    lwu: IType,
+
+    sb: SType,
+    sh: SType,
+    sw: SType,
+    sd: SType,
+
+    amoswapw: AType,
+    amoswapd: AType,
+    amoaddd: AType,
+    amolrd: AType,
+    amoscd: AType,
+    amolrw: AType,
+    amoscw: AType,
+    amoaddw: AType,
+    amoorw: AType,
+    amoandw: AType,
+    amoxorw: AType,
+    amominw: AType,
+    amomaxw: AType,
+    amominuw: AType,
+    amomaxuw: AType,
+    amoxord: AType,
+    amoord: AType,
+    amoandd: AType,
+    amomind: AType,
+    amomaxd: AType,
+    amominud: AType,
+    amomaxud: AType,
+
+    rdtime: CsrType,
+
+    jal: JType,
+    jalr: IType,
+
+    mul: RType,
+    mulh: RType,
+    mulhu: RType,
+    mulhsu: RType,
+    mulw: RType,
+    div: RType,
+    divu: RType,
+    rem: RType,
+    remu: RType,
+    remuw: RType,
+
+    fence: IType,
+
+    flw: IType,
+    fsw: SType,
+    fadd_s: RType,
+    fsub_s: RType,
+    fmul_s: RType,
+    fdiv_s: RType,
+    fcvt_w_s: RType,
+    fcvt_s_w: RType,
+}
+
+/// Decode `insn_bits`, returning `None` if it isn't a supported instruction.
+pub fn decode(insn_bits: u32) -> Option<DecodedInsn> {
+    process_instruction(&mut InstructionDecoder, insn_bits)
+}
+
+/// Decode `insn_bits` for a hart of width `xlen`, returning `None` if it isn't a supported
+/// instruction at that width.
+///
+/// [decode] assumes RV64, in RV32 a shift-immediate with shamt[5] set is illegal.
+pub fn decode_xlen(insn_bits: u32, xlen: Xlen) -> Option<DecodedInsn> {
+    let decoded = decode(insn_bits)?;
+    match decoded {
+        DecodedInsn::Slli(dec_insn) | DecodedInsn::Srli(dec_insn) | DecodedInsn::Srai(dec_insn)
+            if dec_insn.shamt & !xlen.shamt_mask() != 0 =>
+        {
+            None
+        }
+        _ => Some(decoded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_xlen() {
+        // srli x1, x2, 31
+        assert!(matches!(
+            decode_xlen(0x01f15093, Xlen::Rv32),
+            Some(DecodedInsn::Srli(ITypeRV64Shamt { shamt: 31, .. }))
+        ));
+        // srli x1, x2, 32
+        assert_eq!(decode_xlen(0x02015093, Xlen::Rv32), None);
+        assert!(matches!(
+            decode_xlen(0x02015093, Xlen::Rv64),
+            Some(DecodedInsn::Srli(ITypeRV64Shamt { shamt: 32, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_decode() {
+        // add x1, x2, x3
+        assert_eq!(
+            decode(0x003100b3),
+            Some(DecodedInsn::Add(RType {
+                funct7: 0,
+                rs2: 3,
+                rs1: 2,
+                funct3: 0,
+                rd: 1,
+            }))
+        );
+
+        // lw x5, 6(x0)
+        match decode(0x00602283) {
+            Some(DecodedInsn::Lw(IType { rd, rs1, imm, .. })) => {
+                assert_eq!((rd, rs1, imm), (5, 0, 6));
+            }
+            other => panic!("expected lw, got {:?}", other),
+        }
+
+        // sc.w x6, x5, (x10)
+        assert!(matches!(
+            decode(0x1855232f),
+            Some(DecodedInsn::Amoscw(AType {
+                rd: 6,
+                rs1: 10,
+                rs2: 5,
+                ..
+            }))
+        ));
+
+        // rdtime x5
+        assert!(matches!(
+            decode(0xc01022f3),
+            Some(DecodedInsn::Rdtime(CsrType {
+                csr: 0xc01,
+                rd: 5,
+                ..
+            }))
+        ));
+
+        // csrrs x5, mscratch, x0; csrrs x5, time, x1
+        assert_eq!(decode(0x340022f3), None);
+        assert_eq!(decode(0xc010a2f3), None);
+
+        assert_eq!(decode(0x0), None);
+    }
+
+    #[test]
+    fn test_decoded_process() {
+        use crate::instruction_string_outputter::InstructionStringOutputter;
+
+        let mut outputter = InstructionStringOutputter { insn_pc: 0x100 };
+
+        // add x1, x2, x3; lw x5, 6(x0); jal x1, 0x10; rdtime x5
+        for insn_bits in [0x003100b3, 0x00602283, 0x010000ef, 0xc01022f3] {
+            assert_eq!(
+                decode(insn_bits).map(|insn| insn.process(&mut outputter)),
+                process_instruction(&mut outputter, insn_bits)
+            );
+        }
+    }
+}
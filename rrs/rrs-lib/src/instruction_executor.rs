@@ -7,7 +7,9 @@
 //! The [InstructionExecutor] takes a [HartState] and a [Memory]. The [HartState] is updated by the
 //! instruction execution using the [Memory] for all memory accesses. A [InstructionExecutor::step]
 //! function is provided to deal with reading the next instruction from the memory, updating the PC
-//! appropriately and wraps the call to [process_instruction()]`.
+//! appropriately and wraps the call to [process_instruction()](super::process_instruction)`.
+//! Callers that have already decoded the instruction can use [InstructionExecutor::step_decoded]
+//! instead.
 //!
 //! # Example
 //!
@@ -25,10 +27,7 @@
 //!
 //! hart.pc = 0;
 //!
-//! let mut executor = InstructionExecutor {
-//!     hart_state: &mut hart,
-//!     mem: &mut mem,
-//! };
+//! let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
 //!
 //! assert_eq!(executor.step(), Ok(()));
 //! assert_eq!(executor.hart_state.registers[2], 0x1234b000);
@@ -40,10 +39,11 @@
 //! assert_eq!(executor.step(), Err(InstructionException::FetchError(0xc)));
 //! ```
 
-use std::convert::TryInto;
+use std::collections::{HashMap, VecDeque};
 
+use super::fp::{self, RoundingMode};
+use super::instruction_decoder::{decode_xlen, DecodedInsn};
 use super::instruction_formats;
-use super::process_instruction;
 use super::{HartState, InstructionProcessor, MemAccessSize, Memory};
 use paste::paste;
 
@@ -56,6 +56,111 @@ pub enum InstructionException {
     LoadAccessFault(u64),
     StoreAccessFault(u64),
     AlignmentFault(u64),
+    /// A `c.ebreak` at the given PC, which is left pointing at it
+    Breakpoint(u64),
+}
+
+/// Mask of the PC bits that must be zero to fetch an instruction. IALIGN is 16 as compressed
+/// instructions are supported, if only `c.nop` and `c.ebreak`, so an instruction can start at any
+/// 2 byte boundary. Jump and branch targets aren't checked separately: their offsets are even and
+/// `jalr` clears bit 0, so a target can only be misaligned if the PC already was, which the fetch
+/// rejects.
+const INSN_ALIGN_MASK: u64 = 0x1;
+
+/// Mask of the address bits that are zero for the words instructions are fetched in.
+const FETCH_WORD_MASK: u64 = 0x3;
+
+/// Encoding of `c.nop`
+const C_NOP_INSN: u16 = 0x0001;
+
+/// Encoding of `c.ebreak`
+const C_EBREAK_INSN: u16 = 0x9002;
+
+/// Size in bytes of an instruction, used to compute the next PC and link addresses.
+const INSN_SIZE: u64 = 4;
+
+/// Encoding of ECALL, which [InstructionExecutor::run_bounded] treats as a request to halt
+const ECALL_INSN: u32 = 0x0000_0073;
+
+/// Register holding the exit code when the program halts with ECALL (a0)
+const EXIT_CODE_REG: usize = 10;
+
+/// Why [InstructionExecutor::run_bounded] stopped
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    /// The requested number of instructions were executed
+    LimitReached,
+    /// The program executed an ECALL, giving the exit code from a0. The PC is left pointing at
+    /// the ECALL.
+    Halted(u64),
+    /// An instruction raised an exception. The hart state is left as it was before that
+    /// instruction.
+    Exception(InstructionException),
+    /// The PC reached a breakpoint added with [InstructionExecutor::add_breakpoint] whose
+    /// [BreakCondition] held, or a `c.ebreak`. The instruction there has not been executed.
+    Breakpoint(u64),
+}
+
+/// When a breakpoint added with [InstructionExecutor::add_breakpoint] stops
+/// [InstructionExecutor::run_bounded]
+pub enum BreakCondition {
+    /// Every time the PC reaches the breakpoint
+    Always,
+    /// Only the `n`th time the PC reaches the breakpoint, counting from 1
+    HitCount(u64),
+    /// Whenever the PC reaches the breakpoint with a hart state the predicate holds for
+    Predicate(Box<dyn Fn(&HartState) -> bool>),
+}
+
+struct Breakpoint {
+    condition: BreakCondition,
+    /// Number of times the PC has reached the breakpoint
+    hits: u64,
+}
+
+/// Result of [InstructionExecutor::run_block]
+#[derive(Debug, PartialEq)]
+pub struct BlockResult {
+    /// PC of the first instruction in the block
+    pub start_pc: u64,
+    /// PC of the taken branch or jump ending the block
+    pub end_pc: u64,
+    /// Number of instructions executed, including the branch or jump
+    pub insn_count: usize,
+}
+
+/// An exception that ended an [InstructionExecutor::run_block] early
+#[derive(Debug, PartialEq)]
+pub struct BlockException {
+    /// Number of instructions executed before the one that raised the exception
+    pub insn_count: usize,
+    /// The exception the faulting instruction raised
+    pub exception: InstructionException,
+}
+
+/// Result of [InstructionExecutor::run_bounded]
+#[derive(Debug, PartialEq)]
+pub struct StepOutcome {
+    /// Number of instructions successfully executed
+    pub cycles_run: usize,
+    pub stop_reason: StopReason,
+}
+
+/// Maximum number of steps [InstructionExecutor::step_back] can undo. Once the history is full
+/// the oldest record is dropped for each new step.
+pub const MAX_HISTORY: usize = 1024;
+
+/// Everything needed to revert a single step
+struct UndoRecord {
+    pc: u64,
+    reservation: Option<u64>,
+    /// Index and previous value of the register written by the step
+    register: Option<(usize, u64)>,
+    /// Index and previous value of the floating point register written by the step
+    fregister: Option<(usize, u32)>,
+    fcsr: u32,
+    /// Address, size and previous value of each memory write made by the step, in order
+    mem: Vec<(u64, MemAccessSize, u64)>,
 }
 
 /// An `InstructionProcessor` that execute instructions, updating `hart_state` as appropriate.
@@ -63,9 +168,74 @@ pub struct InstructionExecutor<'a, M: Memory> {
     /// Memory used by load and store instructions
     pub mem: &'a mut M,
     pub hart_state: &'a mut HartState,
+    /// When set each successful [InstructionExecutor::step] records how to undo itself so it can
+    /// be reverted with [InstructionExecutor::step_back]
+    pub record_history: bool,
+    /// Called with the decoded fields of every FENCE executed. FENCE is otherwise a no-op as a
+    /// single hart always observes its own memory accesses in order.
+    pub on_fence: Option<Box<dyn FnMut(instruction_formats::Fence)>>,
+    history: VecDeque<UndoRecord>,
+    pending_mem_undo: Vec<(u64, MemAccessSize, u64)>,
+    breakpoints: HashMap<u64, Breakpoint>,
 }
 
 impl<'a, M: Memory> InstructionExecutor<'a, M> {
+    /// Create an executor for `hart_state` using `mem`, with history recording disabled.
+    pub fn new(mem: &'a mut M, hart_state: &'a mut HartState) -> Self {
+        InstructionExecutor {
+            mem,
+            hart_state,
+            record_history: false,
+            on_fence: None,
+            history: VecDeque::new(),
+            pending_mem_undo: Vec::new(),
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    /// Stop [InstructionExecutor::run_bounded] before executing the instruction at `addr` when
+    /// `condition` holds, replacing any breakpoint already at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u64, condition: BreakCondition) {
+        self.breakpoints
+            .insert(addr, Breakpoint { condition, hits: 0 });
+    }
+
+    /// Remove the breakpoint at `addr`, returning `false` if there wasn't one.
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Remove every breakpoint.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Count a hit of any breakpoint at the PC, returning whether it stops execution.
+    fn hit_breakpoint(&mut self) -> bool {
+        let Some(breakpoint) = self.breakpoints.get_mut(&self.hart_state.pc) else {
+            return false;
+        };
+
+        breakpoint.hits += 1;
+        match &breakpoint.condition {
+            BreakCondition::Always => true,
+            BreakCondition::HitCount(n) => breakpoint.hits == *n,
+            BreakCondition::Predicate(predicate) => predicate(self.hart_state),
+        }
+    }
+
+    // Remember the current contents of memory about to be overwritten so the write can be undone
+    fn save_mem_for_undo(&mut self, addr: u64, size: MemAccessSize) {
+        // Reading volatile memory could have side effects, and there'd be no undoing them
+        if !self.record_history || self.mem.is_volatile(addr) {
+            return;
+        }
+
+        if let Some(old_data) = self.mem.read_mem(addr, size) {
+            self.pending_mem_undo.push((addr, size, old_data));
+        }
+    }
+
     fn execute_amow<F>(
         &mut self,
         dec_insn: instruction_formats::AType,
@@ -87,7 +257,9 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
 
         self.hart_state
             .write_register(dec_insn.rd, rs1_value_signed_ext);
+        self.save_mem_for_undo(rs1_addr, MemAccessSize::Word);
         self.mem.write_mem(rs1_addr, MemAccessSize::Word, r1_final);
+        self.hart_state.last_mem_write = Some(rs1_addr);
 
         Ok(())
     }
@@ -111,8 +283,10 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
         let rs1_final = op(rs1_value, rs2_value);
 
         self.hart_state.write_register(dec_insn.rd, rs1_value);
+        self.save_mem_for_undo(rs1_addr, MemAccessSize::DoubleWord);
         self.mem
             .write_mem(rs1_addr, MemAccessSize::DoubleWord, rs1_final);
+        self.hart_state.last_mem_write = Some(rs1_addr);
 
         Ok(())
     }
@@ -127,6 +301,16 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
         self.hart_state.write_register(dec_insn.rd, result);
     }
 
+    fn execute_shift_reg_op<F>(&mut self, dec_insn: instruction_formats::RType, op: F)
+    where
+        F: Fn(u64, u32) -> u64,
+    {
+        let a = self.hart_state.read_register(dec_insn.rs1);
+        let b = self.hart_state.read_register(dec_insn.rs2) as u32;
+        let result = op(a, b & self.hart_state.xlen.shamt_mask());
+        self.hart_state.write_register(dec_insn.rd, result);
+    }
+
     fn execute_reg_imm_op<F>(&mut self, dec_insn: instruction_formats::IType, op: F)
     where
         F: Fn(u64, u64) -> u64,
@@ -142,12 +326,16 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
         F: Fn(u64, u32) -> u64,
     {
         let a = self.hart_state.read_register(dec_insn.rs1);
-        let result = op(a, dec_insn.shamt);
+        let result = op(a, dec_insn.shamt & self.hart_state.xlen.shamt_mask());
         self.hart_state.write_register(dec_insn.rd, result)
     }
 
     // Returns true if branch succeeds
-    fn execute_branch<F>(&mut self, dec_insn: instruction_formats::BType, cond: F) -> bool
+    fn execute_branch<F>(
+        &mut self,
+        dec_insn: instruction_formats::BType,
+        cond: F,
+    ) -> Result<bool, InstructionException>
     where
         F: Fn(u64, u64) -> bool,
     {
@@ -155,15 +343,19 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
         let b = self.hart_state.read_register(dec_insn.rs2);
 
         if cond(a, b) {
-            let new_pc = self.hart_state.pc.wrapping_add(dec_insn.imm as u64);
-            self.hart_state.pc = new_pc;
-            true
+            self.hart_state.pc = self.hart_state.pc.wrapping_add(dec_insn.imm as u64);
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
-    // XXX assume single core, and assume lock works
+    // Address of the instruction following the current one, used as the link value by jumps.
+    fn link_addr(&self) -> u64 {
+        self.hart_state.pc.wrapping_add(INSN_SIZE)
+    }
+
+    // LR: load and sign extend as a normal load, then place a reservation on the address
     fn execute_amo_load(
         &mut self,
         dec_insn: instruction_formats::AType,
@@ -200,9 +392,12 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
 
         // Write load data to destination register
         self.hart_state.write_register(dec_insn.rd, load_data);
+        self.hart_state.reservation = Some(addr);
         Ok(())
     }
 
+    // SC: only performs the store if the reservation from a previous LR on the same address is
+    // still held. `rd` gets 0 on success and 1 on failure. The reservation is always released.
     fn execute_amo_store(
         &mut self,
         dec_insn: instruction_formats::AType,
@@ -225,9 +420,16 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
             return Err(InstructionException::AlignmentFault(addr));
         }
 
+        if self.hart_state.reservation.take() != Some(addr) {
+            self.hart_state.write_register(dec_insn.rd, 1u64);
+            return Ok(());
+        }
+
         // Write store data to memory, returning a StoreAccessFault as an error if write fails.
+        self.save_mem_for_undo(addr, size);
         if self.mem.write_mem(addr, size, data) {
             self.hart_state.write_register(dec_insn.rd, 0u64);
+            self.hart_state.last_mem_write = Some(addr);
             Ok(())
         } else {
             Err(InstructionException::StoreAccessFault(addr))
@@ -245,7 +447,7 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
             .read_register(dec_insn.rs1)
             .wrapping_add(dec_insn.imm as u64);
         // Determine if address is aligned to size, returning an AlignmentFault as an error if it
-        // is not.
+        // is not and the memory can't handle misaligned accesses.
         let align_mask = match size {
             MemAccessSize::Byte => 0x0,
             MemAccessSize::HalfWord => 0x1,
@@ -253,7 +455,7 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
             MemAccessSize::DoubleWord => 0x7,
         };
 
-        if (addr & align_mask) != 0x0 {
+        if (addr & align_mask) != 0x0 && !self.mem.supports_misaligned() {
             return Err(InstructionException::AlignmentFault(addr));
         }
 
@@ -267,12 +469,7 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
 
         // Sign extend loaded data if required
         if signed {
-            load_data = (match size {
-                MemAccessSize::Byte => (load_data as i8) as i64,
-                MemAccessSize::HalfWord => (load_data as i16) as i64,
-                MemAccessSize::Word => (load_data as i32) as i64,
-                MemAccessSize::DoubleWord => load_data as i64,
-            }) as u64;
+            load_data = size.sign_extend(load_data) as u64;
         }
 
         // Write load data to destination register
@@ -300,58 +497,352 @@ impl<'a, M: Memory> InstructionExecutor<'a, M> {
         };
 
         // Determine if address is aligned to size, returning an AlignmentFault as an error if it
-        // is not.
-        if (addr & align_mask) != 0x0 {
+        // is not and the memory can't handle misaligned accesses.
+        if (addr & align_mask) != 0x0 && !self.mem.supports_misaligned() {
             return Err(InstructionException::AlignmentFault(addr));
         }
 
-        if addr == 0xe0130 {
-            println!("wow write here!!!")
-        }
         // Write store data to memory, returning a StoreAccessFault as an error if write fails.
+        self.save_mem_for_undo(addr, size);
         if self.mem.write_mem(addr, size, data) {
+            self.hart_state.last_mem_write = Some(addr);
+            Ok(())
+        } else {
+            Err(InstructionException::StoreAccessFault(addr))
+        }
+    }
+
+    fn execute_flw(
+        &mut self,
+        dec_insn: instruction_formats::IType,
+    ) -> Result<(), InstructionException> {
+        let addr = self
+            .hart_state
+            .read_register(dec_insn.rs1)
+            .wrapping_add(dec_insn.imm as u64);
+
+        if (addr & 0x3) != 0x0 && !self.mem.supports_misaligned() {
+            return Err(InstructionException::AlignmentFault(addr));
+        }
+
+        match self.mem.read_mem(addr, MemAccessSize::Word) {
+            Some(d) => {
+                self.hart_state.fregisters[dec_insn.rd] = d as u32;
+                Ok(())
+            }
+            None => Err(InstructionException::LoadAccessFault(addr)),
+        }
+    }
+
+    fn execute_fsw(
+        &mut self,
+        dec_insn: instruction_formats::SType,
+    ) -> Result<(), InstructionException> {
+        let addr = self
+            .hart_state
+            .read_register(dec_insn.rs1)
+            .wrapping_add(dec_insn.imm as u64);
+
+        let data = self.hart_state.fregisters[dec_insn.rs2] as u64;
+
+        if (addr & 0x3) != 0x0 && !self.mem.supports_misaligned() {
+            return Err(InstructionException::AlignmentFault(addr));
+        }
+
+        self.save_mem_for_undo(addr, MemAccessSize::Word);
+        if self.mem.write_mem(addr, MemAccessSize::Word, data) {
+            self.hart_state.last_mem_write = Some(addr);
             Ok(())
         } else {
             Err(InstructionException::StoreAccessFault(addr))
         }
     }
 
+    // Rounding mode for an OP-FP instruction. A dynamic rounding mode with an invalid `frm` in
+    // `fcsr` makes the instruction illegal.
+    fn fp_rounding_mode(
+        &self,
+        dec_insn: &instruction_formats::RType,
+    ) -> Result<RoundingMode, InstructionException> {
+        RoundingMode::from_rm(dec_insn.funct3, self.hart_state.fcsr).ok_or_else(|| {
+            let insn_bits = (dec_insn.funct7 << 25)
+                | ((dec_insn.rs2 as u32) << 20)
+                | ((dec_insn.rs1 as u32) << 15)
+                | (dec_insn.funct3 << 12)
+                | ((dec_insn.rd as u32) << 7)
+                | instruction_formats::OPCODE_OP_FP;
+            InstructionException::IllegalInstruction(self.hart_state.pc, insn_bits)
+        })
+    }
+
+    fn execute_fp_binary_op(
+        &mut self,
+        dec_insn: instruction_formats::RType,
+        op: fp::BinaryOp,
+    ) -> Result<(), InstructionException> {
+        let rm = self.fp_rounding_mode(&dec_insn)?;
+        let (result, flags) = fp::binary_op(
+            op,
+            self.hart_state.fregisters[dec_insn.rs1],
+            self.hart_state.fregisters[dec_insn.rs2],
+            rm,
+        );
+
+        self.hart_state.fregisters[dec_insn.rd] = result;
+        self.hart_state.fcsr |= flags;
+        Ok(())
+    }
+
     /// Execute instruction pointed to by `hart_state.pc`
     ///
     /// Returns `Ok` where instruction execution was successful. `Err` with the relevant
     /// [InstructionException] is returned when the instruction execution causes an exception.
     pub fn step(&mut self) -> Result<(), InstructionException> {
+        self.step_with(Self::execute_step).map(|_| ())
+    }
+
+    /// Execute straight-line instructions from `hart_state.pc` up to and including the next
+    /// taken branch or jump, so `hart_state.pc` is left at its target.
+    ///
+    /// An exception ends the block early and is returned along with the number of instructions
+    /// executed before it, with `hart_state.pc` left pointing at the faulting instruction.
+    /// Breakpoints are ignored.
+    pub fn run_block(&mut self) -> Result<BlockResult, BlockException> {
+        let start_pc = self.hart_state.pc;
+        let mut insn_count = 0;
+
+        loop {
+            let pc = self.hart_state.pc;
+            let pc_updated =
+                self.step_with(Self::execute_step)
+                    .map_err(|exception| BlockException {
+                        insn_count,
+                        exception,
+                    })?;
+            insn_count += 1;
+
+            if pc_updated {
+                return Ok(BlockResult {
+                    start_pc,
+                    end_pc: pc,
+                    insn_count,
+                });
+            }
+        }
+    }
+
+    /// Execute `insn`, already decoded from the instruction pointed to by `hart_state.pc`
+    ///
+    /// This behaves as [InstructionExecutor::step] without fetching and decoding the instruction
+    /// again, for callers that decoded it to inspect it first.
+    pub fn step_decoded(&mut self, insn: DecodedInsn) -> Result<(), InstructionException> {
+        self.step_with(|executor| executor.execute_decoded(insn))
+            .map(|_| ())
+    }
+
+    /// Execute the raw instruction `insn` as though it had been fetched from `hart_state.pc`,
+    /// without reading memory for it
+    ///
+    /// This is intended for differential testing, feeding the executor instructions generated
+    /// elsewhere. A 16-bit instruction is given in the low half of `insn`. Returns whether the
+    /// instruction updated the PC itself, as with a taken branch or jump, rather than stepping
+    /// past it.
+    pub fn execute_raw(&mut self, insn: u32) -> Result<bool, InstructionException> {
+        self.step_with(|executor| executor.execute_insn(insn))
+    }
+
+    // Run `execute` as a single step, which returns whether the instruction updated the PC itself
+    fn step_with(
+        &mut self,
+        execute: impl FnOnce(&mut Self) -> Result<bool, InstructionException>,
+    ) -> Result<bool, InstructionException> {
         self.hart_state.last_register_write = None;
+        self.hart_state.last_register_reads = [None; 2];
+        self.hart_state.last_mem_write = None;
+
+        if !self.record_history {
+            return execute(self);
+        }
+
+        let pc = self.hart_state.pc;
+        let reservation = self.hart_state.reservation;
+        let registers = self.hart_state.registers;
+        let fregisters = self.hart_state.fregisters;
+        let fcsr = self.hart_state.fcsr;
+        self.pending_mem_undo.clear();
+
+        let pc_updated = execute(self)?;
+
+        if self.history.len() == MAX_HISTORY {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(UndoRecord {
+            pc,
+            reservation,
+            register: self
+                .hart_state
+                .last_register_write
+                .map(|idx| (idx, registers[idx])),
+            fregister: (0..fregisters.len())
+                .find(|&idx| fregisters[idx] != self.hart_state.fregisters[idx])
+                .map(|idx| (idx, fregisters[idx])),
+            fcsr,
+            mem: std::mem::take(&mut self.pending_mem_undo),
+        });
+
+        Ok(pc_updated)
+    }
+
+    /// Revert the most recent step recorded while `record_history` was set, restoring the PC,
+    /// the registers, `fcsr` and any memory it wrote other than [volatile](Memory::is_volatile)
+    /// memory.
+    ///
+    /// Returns `false` if there is no recorded step left to revert.
+    pub fn step_back(&mut self) -> bool {
+        let record = match self.history.pop_back() {
+            Some(record) => record,
+            None => return false,
+        };
+
+        // Restore in reverse order so the oldest value wins where one step wrote a location twice
+        for (addr, size, old_data) in record.mem.into_iter().rev() {
+            self.mem.write_mem(addr, size, old_data);
+        }
+
+        if let Some((idx, old_data)) = record.register {
+            self.hart_state.registers[idx] = old_data;
+        }
+
+        if let Some((idx, old_data)) = record.fregister {
+            self.hart_state.fregisters[idx] = old_data;
+        }
+
+        self.hart_state.pc = record.pc;
+        self.hart_state.reservation = record.reservation;
+        self.hart_state.fcsr = record.fcsr;
+        self.hart_state.last_register_write = None;
+        self.hart_state.last_register_reads = [None; 2];
+        self.hart_state.last_mem_write = None;
+
+        true
+    }
+
+    /// Execute up to `n` instructions, stopping early on an ECALL, an exception or a breakpoint,
+    /// either from [InstructionExecutor::add_breakpoint] or a `c.ebreak`.
+    ///
+    /// rrs-lib doesn't implement system calls so an ECALL is taken to mean the program has
+    /// finished. A breakpoint at the PC execution starts from is ignored, and isn't counted as
+    /// reached, so calling `run_bounded` again after stopping at a breakpoint continues past it.
+    pub fn run_bounded(&mut self, n: usize) -> StepOutcome {
+        let mut cycles_run = 0;
+
+        let stop_reason = loop {
+            if cycles_run == n {
+                break StopReason::LimitReached;
+            }
+
+            let pc = self.hart_state.pc;
 
-        // Fetch next instruction from memory
-        if let Some(next_insn) = self.mem.read_mem(self.hart_state.pc, MemAccessSize::Word) {
+            if cycles_run != 0 && self.hit_breakpoint() {
+                break StopReason::Breakpoint(pc);
+            }
+
+            if self.fetch() == Ok(ECALL_INSN) {
+                break StopReason::Halted(self.hart_state.registers[EXIT_CODE_REG]);
+            }
+
+            match self.step() {
+                Ok(()) => {}
+                Err(InstructionException::Breakpoint(pc)) => break StopReason::Breakpoint(pc),
+                Err(e) => break StopReason::Exception(e),
+            }
+
+            cycles_run += 1;
+        };
+
+        StepOutcome {
+            cycles_run,
+            stop_reason,
+        }
+    }
+
+    // Fetch the instruction at the PC. A compressed instruction is returned in the low 16 bits
+    // with whatever follows it above. After a `c.nop` the PC is only 2 byte aligned, so the
+    // instruction is assembled from the two words it spans. A PC that isn't even 2 byte aligned
+    // raises an AlignmentFault.
+    fn fetch(&mut self) -> Result<u32, InstructionException> {
+        let pc = self.hart_state.pc;
+        if pc & INSN_ALIGN_MASK != 0 {
+            return Err(InstructionException::AlignmentFault(pc));
+        }
+        let mut fetch_word = |addr: u64| {
+            self.mem
+                .read_insn(addr)
+                .map(|word| (word & 0xffffffff) as u32) // assume instruction only use lower 32 bit
+                .ok_or(InstructionException::FetchError(pc))
+        };
+
+        let word = fetch_word(pc & !FETCH_WORD_MASK)?;
+        if pc & 0x2 == 0 {
+            return Ok(word);
+        }
+
+        let low = word >> 16;
+        if low & 0x3 != 0x3 {
+            return Ok(low);
+        }
+        let high = fetch_word(pc.wrapping_add(2) & !FETCH_WORD_MASK)?;
+        Ok(low | (high << 16))
+    }
+
+    fn execute_step(&mut self) -> Result<bool, InstructionException> {
+        let next_insn = self.fetch()?;
+        self.execute_insn(next_insn)
+    }
+
+    fn execute_insn(&mut self, next_insn: u32) -> Result<bool, InstructionException> {
+        // The low two bits of a 32-bit instruction are both set
+        if next_insn & 0x3 != 0x3 {
+            return self.execute_compressed(next_insn as u16);
+        }
+
+        match decode_xlen(next_insn, self.hart_state.xlen) {
             // Execute the instruction
-            let next_insn: u32 = (next_insn & 0xffffffff)
-                .try_into()
-                .expect("invalid instruction");
-            let step_result = process_instruction(self, next_insn); // assume instruction only use lower 32 bit
-
-            match step_result {
-                Some(Ok(pc_updated)) => {
-                    if !pc_updated {
-                        // Instruction didn't update PC so increment to next instruction
-                        self.hart_state.pc += 4;
-                    }
-                    Ok(())
-                }
-                // Instruction produced an error so return it
-                Some(Err(e)) => Err(e),
-                // Instruction decode failed so return an IllegalInstruction as an error
-                None => Err(InstructionException::IllegalInstruction(
-                    self.hart_state.pc,
-                    next_insn,
-                )),
+            Some(insn) => self.execute_decoded(insn),
+            // Instruction decode failed so return an IllegalInstruction as an error
+            None => Err(InstructionException::IllegalInstruction(
+                self.hart_state.pc,
+                next_insn,
+            )),
+        }
+    }
+
+    fn execute_compressed(&mut self, insn: u16) -> Result<bool, InstructionException> {
+        match insn {
+            C_NOP_INSN => {
+                self.hart_state.pc = self.hart_state.pc.wrapping_add(2);
+                Ok(false)
             }
-        } else {
-            // Return a FetchError as an error if instruction fetch fails
-            Err(InstructionException::FetchError(self.hart_state.pc))
+            C_EBREAK_INSN => Err(InstructionException::Breakpoint(self.hart_state.pc)),
+            _ => Err(InstructionException::IllegalInstruction(
+                self.hart_state.pc,
+                insn as u32,
+            )),
         }
     }
+
+    fn execute_decoded(&mut self, insn: DecodedInsn) -> Result<bool, InstructionException> {
+        // An instruction producing an error returns it here
+        let pc_updated = insn.process(self)?;
+
+        if !pc_updated {
+            // Instruction didn't update PC so increment to next instruction
+            self.hart_state.pc = self.hart_state.pc.wrapping_add(INSN_SIZE);
+        }
+
+        Ok(pc_updated)
+    }
 }
 
 fn sign_extend_u64(x: u64) -> i128 {
@@ -417,7 +908,17 @@ macro_rules! make_alu_op_fns {
 
 macro_rules! make_shift_op_fns {
     ($name:ident, $op_fn:expr) => {
-        make_alu_op_reg_fn! {$name, $op_fn}
+        paste! {
+            fn [<process_ $name>](
+                &mut self,
+                dec_insn: instruction_formats::RType
+            ) -> Self::InstructionResult {
+                self.execute_shift_reg_op(dec_insn, $op_fn);
+
+                Ok(false)
+            }
+        }
+
         make_alu_op_imm_shamt_fn! {$name, $op_fn}
     };
 }
@@ -429,7 +930,7 @@ macro_rules! make_branch_op_fn {
                 &mut self,
                 dec_insn: instruction_formats::BType
             ) -> Self::InstructionResult {
-                Ok(self.execute_branch(dec_insn, $cond_fn))
+                self.execute_branch(dec_insn, $cond_fn)
             }
         }
     };
@@ -533,10 +1034,37 @@ macro_rules! make_amod_op_reg_fn {
     };
 }
 
+macro_rules! make_fp_binary_op_fn {
+    ($name:ident, $op:expr) => {
+        paste! {
+            fn [<process_ $name>](
+                &mut self,
+                dec_insn: instruction_formats::RType
+            ) -> Self::InstructionResult {
+                self.execute_fp_binary_op(dec_insn, $op)?;
+
+                Ok(false)
+            }
+        }
+    };
+}
+
 impl<'a, M: Memory> InstructionProcessor for InstructionExecutor<'a, M> {
-    /// Result is `Ok` when instruction execution is successful. `Ok(true) indicates the
-    /// instruction updated the PC and Ok(false) indicates it did not (so the PC must be
+    /// Result is `Ok` when instruction execution is successful. `Ok(true)` indicates the
+    /// instruction updated the PC and `Ok(false)` indicates it did not (so the PC must be
     /// incremented to execute the next instruction).
+    ///
+    /// Precisely, the `process_` functions return:
+    /// - `Ok(true)` from a jump (`jal`, `jalr`), always, having set the PC to the target, even
+    ///   when the target is the next instruction
+    /// - `Ok(true)` from a taken branch, having set the PC to the target
+    /// - `Ok(false)` from a branch that isn't taken, and from every other instruction, leaving the
+    ///   PC untouched
+    /// - `Err` on an exception, leaving the PC at the faulting instruction
+    ///
+    /// The executor then adds 4 to the PC after an `Ok(false)`, exactly once, so every retired
+    /// instruction moves the PC once and the `bool` seen by [InstructionExecutor::execute_raw]
+    /// and [InstructionExecutor::run_block] means "control flow changed".
     type InstructionResult = Result<bool, InstructionException>;
 
     make_alu_op_fns! {add, |a, b| a.wrapping_add(b)}
@@ -547,12 +1075,17 @@ impl<'a, M: Memory> InstructionProcessor for InstructionExecutor<'a, M> {
     make_alu_op_fns! {and, |a, b| a & b}
     make_alu_op_fns! {xor, |a, b| a ^ b}
 
-    make_shift_op_fns! {sll, |a, b| a << (b & 0x3f)} // RV64: 0x1f -> 0x3f, shamt take 6 bits
-    make_shift_op_fns! {srl, |a, b| a >> (b & 0x3f)} // RV64: 0x1f -> 0x3f, shamt take 6 bits
-    make_shift_op_fns! {sra, |a, b| ((a as i64) >> (b & 0x3f)) as u64} // RV64: 0x1f -> 0x3f, shamt take 6 bits
+    // Shift amounts arrive masked to the hart's `xlen`
+    make_shift_op_fns! {sll, |a, b| a << b}
+    make_shift_op_fns! {srl, |a, b| a >> b}
+    make_shift_op_fns! {sra, |a, b| ((a as i64) >> b) as u64}
 
-    fn process_rdtime(&mut self, dec_insn: instruction_formats::CType) -> Self::InstructionResult {
-        self.hart_state.write_register(dec_insn.rd, 0u64);
+    fn process_rdtime(
+        &mut self,
+        dec_insn: instruction_formats::CsrType,
+    ) -> Self::InstructionResult {
+        self.hart_state
+            .write_register(dec_insn.rd, self.hart_state.time);
         Ok(false)
     }
     /*
@@ -579,6 +1112,19 @@ impl<'a, M: Memory> InstructionProcessor for InstructionExecutor<'a, M> {
     make_amow_op_reg_fn! {amoaddw, |a, b| a.wrapping_add(b)}
     make_amod_op_reg_fn! {amoaddd, |a, b| a.wrapping_add(b)}
     make_amod_op_reg_fn! {amoswapd, |_, b| b}
+    // The word operands are sign extended, which leaves their unsigned order unchanged
+    make_amow_op_reg_fn! {amoxorw, |a, b| a ^ b}
+    make_amow_op_reg_fn! {amominw, |a, b| (a as i64).min(b as i64) as u64}
+    make_amow_op_reg_fn! {amomaxw, |a, b| (a as i64).max(b as i64) as u64}
+    make_amow_op_reg_fn! {amominuw, |a, b| a.min(b)}
+    make_amow_op_reg_fn! {amomaxuw, |a, b| a.max(b)}
+    make_amod_op_reg_fn! {amoxord, |a, b| a ^ b}
+    make_amod_op_reg_fn! {amoord, |a, b| a | b}
+    make_amod_op_reg_fn! {amoandd, |a, b| a & b}
+    make_amod_op_reg_fn! {amomind, |a, b| (a as i64).min(b as i64) as u64}
+    make_amod_op_reg_fn! {amomaxd, |a, b| (a as i64).max(b as i64) as u64}
+    make_amod_op_reg_fn! {amominud, |a, b| a.min(b)}
+    make_amod_op_reg_fn! {amomaxud, |a, b| a.max(b)}
     // fn process_amoswapw(
     //     &mut self,
     //     dec_insn: instruction_formats::AType,
@@ -644,7 +1190,7 @@ impl<'a, M: Memory> InstructionProcessor for InstructionExecutor<'a, M> {
         let target_pc = self.hart_state.pc.wrapping_add(dec_insn.imm as u64);
 
         self.hart_state
-            .write_register(dec_insn.rd, self.hart_state.pc + 4);
+            .write_register(dec_insn.rd, self.link_addr());
         self.hart_state.pc = target_pc;
 
         Ok(true)
@@ -658,7 +1204,7 @@ impl<'a, M: Memory> InstructionProcessor for InstructionExecutor<'a, M> {
         target_pc &= 0xfffffffffffffffe;
 
         self.hart_state
-            .write_register(dec_insn.rd, self.hart_state.pc + 4);
+            .write_register(dec_insn.rd, self.link_addr());
         self.hart_state.pc = target_pc;
 
         Ok(true)
@@ -676,7 +1222,56 @@ impl<'a, M: Memory> InstructionProcessor for InstructionExecutor<'a, M> {
     make_alu_op_reg_fn! {remu, |a, b| if b == 0 {a} else {a % b}}
     make_alu_op_reg_fn! {remuw, |a, b| if b == 0 {(a & 0xffffffff) as u32 as u64} else {((a & 0xffffffff) as u32 % (b & 0xffffffff) as u32) as i32 as i64 as u64}}
 
-    fn process_fence(&mut self, _dec_insn: instruction_formats::IType) -> Self::InstructionResult {
+    fn process_fence(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult {
+        if let Some(on_fence) = self.on_fence.as_mut() {
+            on_fence(instruction_formats::Fence::new(dec_insn));
+        }
+
+        Ok(false)
+    }
+
+    fn process_flw(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult {
+        self.execute_flw(dec_insn)?;
+
+        Ok(false)
+    }
+
+    fn process_fsw(&mut self, dec_insn: instruction_formats::SType) -> Self::InstructionResult {
+        self.execute_fsw(dec_insn)?;
+
+        Ok(false)
+    }
+
+    make_fp_binary_op_fn! {fadd_s, fp::BinaryOp::Add}
+    make_fp_binary_op_fn! {fsub_s, fp::BinaryOp::Sub}
+    make_fp_binary_op_fn! {fmul_s, fp::BinaryOp::Mul}
+    make_fp_binary_op_fn! {fdiv_s, fp::BinaryOp::Div}
+
+    fn process_fcvt_w_s(
+        &mut self,
+        dec_insn: instruction_formats::RType,
+    ) -> Self::InstructionResult {
+        let rm = self.fp_rounding_mode(&dec_insn)?;
+        let (result, flags) = fp::to_i32(self.hart_state.fregisters[dec_insn.rs1], rm);
+
+        self.hart_state
+            .write_register(dec_insn.rd, result as i64 as u64);
+        self.hart_state.fcsr |= flags;
+
+        Ok(false)
+    }
+
+    fn process_fcvt_s_w(
+        &mut self,
+        dec_insn: instruction_formats::RType,
+    ) -> Self::InstructionResult {
+        let rm = self.fp_rounding_mode(&dec_insn)?;
+        let a = self.hart_state.read_register(dec_insn.rs1) as i32;
+        let (result, flags) = fp::from_i32(a, rm);
+
+        self.hart_state.fregisters[dec_insn.rd] = result;
+        self.hart_state.fcsr |= flags;
+
         Ok(false)
     }
 }
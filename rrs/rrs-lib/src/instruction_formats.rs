@@ -9,22 +9,27 @@
 //! further details
 
 pub const OPCODE_LOAD: u32 = 0x03;
+pub const OPCODE_LOAD_FP: u32 = 0x07;
 pub const OPCODE_MISC_MEM: u32 = 0x0f;
 pub const OPCODE_OP_IMM: u32 = 0b0010011; // 13
 pub const OPCODE_AUIPC: u32 = 0x17;
 pub const OPCODE_IW: u32 = 0b0011011;
 pub const OPCODE_STORE: u32 = 0x23;
+pub const OPCODE_STORE_FP: u32 = 0x27;
 pub const OPCODE_OP: u32 = 0b0110011; // 33
 pub const OPCODE_FENCE: u32 = 0b0001111;
 pub const OPCODE_OPW: u32 = 0b0111011;
 pub const OPCODE_AMO: u32 = 0b0101111;
+pub const OPCODE_OP_FP: u32 = 0b1010011;
 pub const OPCODE_LUI: u32 = 0x37;
 pub const OPCODE_BRANCH: u32 = 0x63;
 pub const OPCODE_JALR: u32 = 0x67;
 pub const OPCODE_JAL: u32 = 0x6f;
 pub const OPCODE_SYSTEM: u32 = 0x73;
 
-#[derive(Debug, PartialEq)]
+pub const CSR_TIME: u16 = 0xc01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RType {
     pub funct7: u32,
     pub rs2: usize,
@@ -45,7 +50,7 @@ impl RType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct IType {
     pub imm: i64,
     pub rs1: usize,
@@ -75,7 +80,7 @@ impl IType {
 }
 
 // RV64, bits[25] is for shamt[5]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ITypeRV64Shamt {
     pub funct7: u32,
     pub shamt: u32,
@@ -99,7 +104,7 @@ impl ITypeRV64Shamt {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct SType {
     pub imm: i64,
     pub rs2: usize,
@@ -128,7 +133,7 @@ impl SType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct BType {
     pub imm: i64,
     pub rs2: usize,
@@ -158,7 +163,7 @@ impl BType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct UType {
     pub imm: i64,
     pub rd: usize,
@@ -173,7 +178,7 @@ impl UType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct JType {
     pub imm: i64,
     pub rd: usize,
@@ -200,7 +205,7 @@ impl JType {
 }
 
 // Atomic
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct AType {
     pub funct5: u32,
     pub aq: u32,
@@ -225,26 +230,55 @@ impl AType {
     }
 }
 
-// Atomic
-#[derive(Debug, PartialEq)]
-pub struct CType {
-    pub csr: u32,
-    pub rs1: usize,
-    pub funct3: u32,
+// CSR access (Zicsr). `rs1_or_zimm` is the source register index for the register forms
+// (csrrw/csrrs/csrrc) and the 5-bit zero-extended immediate for the immediate forms
+// (csrrwi/csrrsi/csrrci).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CsrType {
+    pub csr: u16,
+    pub rs1_or_zimm: usize,
     pub rd: usize,
 }
 
-impl CType {
-    pub fn new(insn: u32) -> CType {
-        CType {
-            csr: (insn >> 20) & 0xfff,
-            rs1: ((insn >> 15) & 0x1f) as usize,
-            funct3: (insn >> 12) & 0x7,
+impl CsrType {
+    pub fn new(insn: u32) -> CsrType {
+        CsrType {
+            csr: ((insn >> 20) & 0xfff) as u16,
+            rs1_or_zimm: ((insn >> 15) & 0x1f) as usize,
             rd: ((insn >> 7) & 0x1f) as usize,
         }
     }
 }
 
+// Memory ordering (FENCE). The fields are packed into the immediate of the `IType` encoding, fm in
+// imm[11:8], pred in imm[7:4] and succ in imm[3:0]. pred and succ are sets of the FENCE_* bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fence {
+    pub fm: u32,
+    pub pred: u32,
+    pub succ: u32,
+}
+
+pub const FENCE_I: u32 = 0b1000;
+pub const FENCE_O: u32 = 0b0100;
+pub const FENCE_R: u32 = 0b0010;
+pub const FENCE_W: u32 = 0b0001;
+
+/// `fm` value of `fence.tso`
+pub const FENCE_FM_TSO: u32 = 0b1000;
+
+impl Fence {
+    pub fn new(dec_insn: IType) -> Fence {
+        let imm = (dec_insn.imm as u32) & 0xfff;
+
+        Fence {
+            fm: imm >> 8,
+            pred: (imm >> 4) & 0xf,
+            succ: imm & 0xf,
+        }
+    }
+}
+
 #[cfg(test)]
 
 mod tests {
@@ -281,16 +315,49 @@ mod tests {
     }
 
     #[test]
-    fn test_ctype() {
+    fn test_csrtype() {
+        // rdtime x10
         assert_eq!(
-            CType::new(0xc0102573),
-            CType {
-                csr: 3073,
-                rs1: 0,
-                funct3: 0,
+            CsrType::new(0xc0102573),
+            CsrType {
+                csr: 0xc01,
+                rs1_or_zimm: 0,
                 rd: 10,
             }
-        )
+        );
+
+        // csrrwi x7, mscratch, 21
+        assert_eq!(
+            CsrType::new(0x340ad3f3),
+            CsrType {
+                csr: 0x340,
+                rs1_or_zimm: 21,
+                rd: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fence() {
+        // fence rw, rw
+        assert_eq!(
+            Fence::new(IType::new(0x0330000f)),
+            Fence {
+                fm: 0,
+                pred: FENCE_R | FENCE_W,
+                succ: FENCE_R | FENCE_W,
+            }
+        );
+
+        // fence.tso
+        assert_eq!(
+            Fence::new(IType::new(0x8330000f)),
+            Fence {
+                fm: FENCE_FM_TSO,
+                pred: FENCE_R | FENCE_W,
+                succ: FENCE_R | FENCE_W,
+            }
+        );
     }
 
     #[test]
@@ -19,9 +19,24 @@
 //! ```
 
 use super::instruction_formats;
-use super::InstructionProcessor;
+use super::{process_instruction, InstructionProcessor};
 use paste::paste;
 
+/// Disassemble the 32-bit instruction `insn` at `pc`, `pc` being needed for the targets of PC
+/// relative instructions such as branches.
+///
+/// Returns `None` if `insn` isn't a supported instruction.
+///
+/// ```
+/// assert_eq!(
+///     rrs_lib::disassemble(0x003100b3, 0),
+///     Some(String::from("add x1, x2, x3"))
+/// );
+/// ```
+pub fn disassemble(insn: u32, pc: u64) -> Option<String> {
+    process_instruction(&mut InstructionStringOutputter { insn_pc: pc }, insn)
+}
+
 pub struct InstructionStringOutputter {
     /// PC of the instruction being output. Used to generate disassembly of instructions with PC
     /// relative fields (such as BEQ and JAL).
@@ -165,6 +180,22 @@ macro_rules! string_out_for_amo {
     }
 }
 
+macro_rules! string_out_for_fp_binary_ops {
+    ($($name:ident),*) => {
+        $(
+            paste! {
+                fn [<process_ $name _s>](
+                    &mut self,
+                    dec_insn: instruction_formats::RType
+                ) -> Self::InstructionResult {
+                    format!("{}.s f{}, f{}, f{}", stringify!($name), dec_insn.rd, dec_insn.rs1,
+                        dec_insn.rs2)
+                }
+            }
+        )*
+    }
+}
+
 impl InstructionProcessor for InstructionStringOutputter {
     type InstructionResult = String;
 
@@ -186,15 +217,24 @@ impl InstructionProcessor for InstructionStringOutputter {
     }
 
     string_out_for_amo! {amoswapw, amoswapd, amoaddd, amolrd, amoscd, amolrw, amoscw, amoorw, amoandw, amoaddw}
+    string_out_for_amo! {amoxorw, amominw, amomaxw, amominuw, amomaxuw}
+    string_out_for_amo! {amoxord, amoord, amoandd, amomind, amomaxd, amominud, amomaxud}
 
-    fn process_rdtime(&mut self, dec_insn: instruction_formats::CType) -> Self::InstructionResult {
+    fn process_rdtime(
+        &mut self,
+        dec_insn: instruction_formats::CsrType,
+    ) -> Self::InstructionResult {
         format!(
             "rdtime x{}, x{}, {}",
-            dec_insn.rd, dec_insn.rs1, dec_insn.csr
+            dec_insn.rd, dec_insn.rs1_or_zimm, dec_insn.csr
         )
     }
 
     fn process_addiw(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult {
+        if dec_insn.imm == 0 {
+            return format!("sext.w x{}, x{}", dec_insn.rd, dec_insn.rs1);
+        }
+
         format!(
             "addiw x{}, x{}, {}",
             dec_insn.rd, dec_insn.rs1, dec_insn.imm
@@ -228,8 +268,266 @@ impl InstructionProcessor for InstructionStringOutputter {
 
     string_out_for_alu_reg_ops! {mul, mulh, mulhu, mulhsu, mulw, div, divu, rem, remu, remuw}
 
-    fn process_fence(&mut self, _dec_insn: instruction_formats::IType) -> Self::InstructionResult {
-        String::from("fence")
+    fn process_fence(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult {
+        let fence = instruction_formats::Fence::new(dec_insn);
+        let all = instruction_formats::FENCE_I
+            | instruction_formats::FENCE_O
+            | instruction_formats::FENCE_R
+            | instruction_formats::FENCE_W;
+
+        if fence.fm == instruction_formats::FENCE_FM_TSO {
+            String::from("fence.tso")
+        } else if fence.pred == all && fence.succ == all {
+            String::from("fence")
+        } else {
+            format!(
+                "fence {}, {}",
+                fence_set_string(fence.pred),
+                fence_set_string(fence.succ)
+            )
+        }
+    }
+
+    fn process_flw(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult {
+        format!("flw f{}, {}(x{})", dec_insn.rd, dec_insn.imm, dec_insn.rs1)
+    }
+
+    fn process_fsw(&mut self, dec_insn: instruction_formats::SType) -> Self::InstructionResult {
+        format!("fsw f{}, {}(x{})", dec_insn.rs2, dec_insn.imm, dec_insn.rs1)
+    }
+
+    string_out_for_fp_binary_ops! {fadd, fsub, fmul, fdiv}
+
+    fn process_fcvt_w_s(
+        &mut self,
+        dec_insn: instruction_formats::RType,
+    ) -> Self::InstructionResult {
+        format!("fcvt.w.s x{}, f{}", dec_insn.rd, dec_insn.rs1)
+    }
+
+    fn process_fcvt_s_w(
+        &mut self,
+        dec_insn: instruction_formats::RType,
+    ) -> Self::InstructionResult {
+        format!("fcvt.s.w f{}, x{}", dec_insn.rd, dec_insn.rs1)
+    }
+}
+
+// The pred or succ set of a FENCE in assembler syntax, e.g. "rw"
+fn fence_set_string(set: u32) -> String {
+    let bits = [
+        (instruction_formats::FENCE_I, 'i'),
+        (instruction_formats::FENCE_O, 'o'),
+        (instruction_formats::FENCE_R, 'r'),
+        (instruction_formats::FENCE_W, 'w'),
+    ];
+    let set: String = bits
+        .iter()
+        .filter(|(bit, _)| set & bit != 0)
+        .map(|(_, c)| c)
+        .collect();
+
+    if set.is_empty() {
+        String::from("0")
+    } else {
+        set
+    }
+}
+
+// Extract bits `hi..=lo` of a compressed instruction, shifted down to bit 0
+fn c_bits(insn: u16, hi: u32, lo: u32) -> u32 {
+    (insn as u32 >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+// Sign extend the low `bits` bits of `value`
+fn c_sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 32 - bits;
+    ((value << shift) as i32 >> shift) as i64
+}
+
+// The 3-bit register fields of the CIW, CL, CS, CA and CB formats address x8-x15
+fn c_reg(insn: u16, lo: u32) -> u32 {
+    c_bits(insn, lo + 2, lo) + 8
+}
+
+// The 6-bit immediate of the CI and CB formats, imm[5] in bit 12 and imm[4:0] in bits 6:2
+fn c_imm6(insn: u16) -> u32 {
+    (c_bits(insn, 12, 12) << 5) | c_bits(insn, 6, 2)
+}
+
+impl InstructionStringOutputter {
+    /// Output the disassembly of the 16-bit compressed (RVC) instruction `insn`
+    ///
+    /// Registers are named as they are for 32-bit instructions, with the 3-bit register fields of
+    /// the compact formats mapped onto x8-x15. Returns `None` if `insn` isn't a valid RV64C
+    /// instruction, including the all zero illegal instruction and 32-bit instruction parcels.
+    pub fn process_compressed(&mut self, insn: u16) -> Option<String> {
+        let funct3 = c_bits(insn, 15, 13);
+        let rd = c_bits(insn, 11, 7);
+        let rs2 = c_bits(insn, 6, 2);
+        // rd' or rs2' depending on the format
+        let reg_lo = c_reg(insn, 2);
+        // rs1' or rd'/rs1'
+        let reg_hi = c_reg(insn, 7);
+
+        match (c_bits(insn, 1, 0), funct3) {
+            (0b00, 0b000) => {
+                let uimm = (c_bits(insn, 12, 11) << 4)
+                    | (c_bits(insn, 10, 7) << 6)
+                    | (c_bits(insn, 6, 6) << 2)
+                    | (c_bits(insn, 5, 5) << 3);
+                if uimm == 0 {
+                    return None;
+                }
+                Some(format!("c.addi4spn x{}, x2, {}", reg_lo, uimm))
+            }
+            (0b00, 0b001..=0b011 | 0b101..=0b111) => {
+                let (name, reg_prefix) = match funct3 {
+                    0b001 => ("c.fld", "f"),
+                    0b010 => ("c.lw", "x"),
+                    0b011 => ("c.ld", "x"),
+                    0b101 => ("c.fsd", "f"),
+                    0b110 => ("c.sw", "x"),
+                    _ => ("c.sd", "x"),
+                };
+                let uimm = if funct3 & 0b001 == 0 {
+                    // Word accesses: uimm[5:3] in bits 12:10, uimm[2] in bit 6 and uimm[6] in
+                    // bit 5
+                    (c_bits(insn, 12, 10) << 3)
+                        | (c_bits(insn, 6, 6) << 2)
+                        | (c_bits(insn, 5, 5) << 6)
+                } else {
+                    // Doubleword accesses: uimm[5:3] in bits 12:10, uimm[7:6] in bits 6:5
+                    (c_bits(insn, 12, 10) << 3) | (c_bits(insn, 6, 5) << 6)
+                };
+                Some(format!(
+                    "{} {}{}, {}(x{})",
+                    name, reg_prefix, reg_lo, uimm, reg_hi
+                ))
+            }
+            (0b01, 0b000) => {
+                let imm = c_sign_extend(c_imm6(insn), 6);
+                if rd == 0 {
+                    Some(String::from("c.nop"))
+                } else {
+                    Some(format!("c.addi x{}, {}", rd, imm))
+                }
+            }
+            (0b01, 0b001) if rd != 0 => Some(format!(
+                "c.addiw x{}, {}",
+                rd,
+                c_sign_extend(c_imm6(insn), 6)
+            )),
+            (0b01, 0b010) => Some(format!("c.li x{}, {}", rd, c_sign_extend(c_imm6(insn), 6))),
+            (0b01, 0b011) if rd == 2 => {
+                let nzimm = (c_bits(insn, 12, 12) << 9)
+                    | (c_bits(insn, 6, 6) << 4)
+                    | (c_bits(insn, 5, 5) << 6)
+                    | (c_bits(insn, 4, 3) << 7)
+                    | (c_bits(insn, 2, 2) << 5);
+                if nzimm == 0 {
+                    return None;
+                }
+                Some(format!("c.addi16sp x2, {}", c_sign_extend(nzimm, 10)))
+            }
+            (0b01, 0b011) => {
+                let nzimm = c_imm6(insn);
+                if nzimm == 0 {
+                    return None;
+                }
+                // Shown as the 20-bit upper immediate, as for lui
+                let imm = (c_sign_extend(nzimm, 6) as u32) & 0xfffff;
+                Some(format!("c.lui x{}, 0x{:05x}", rd, imm))
+            }
+            (0b01, 0b100) => match (
+                c_bits(insn, 11, 10),
+                c_bits(insn, 12, 12),
+                c_bits(insn, 6, 5),
+            ) {
+                (0b00, _, _) => Some(format!("c.srli x{}, {}", reg_hi, c_imm6(insn))),
+                (0b01, _, _) => Some(format!("c.srai x{}, {}", reg_hi, c_imm6(insn))),
+                (0b10, _, _) => Some(format!(
+                    "c.andi x{}, {}",
+                    reg_hi,
+                    c_sign_extend(c_imm6(insn), 6)
+                )),
+                (0b11, bit12, funct2) => {
+                    let name = match (bit12, funct2) {
+                        (0, 0b00) => "c.sub",
+                        (0, 0b01) => "c.xor",
+                        (0, 0b10) => "c.or",
+                        (0, 0b11) => "c.and",
+                        (1, 0b00) => "c.subw",
+                        (1, 0b01) => "c.addw",
+                        _ => return None,
+                    };
+                    Some(format!("{} x{}, x{}", name, reg_hi, reg_lo))
+                }
+                _ => None,
+            },
+            (0b01, 0b101) => {
+                let offset = (c_bits(insn, 12, 12) << 11)
+                    | (c_bits(insn, 11, 11) << 4)
+                    | (c_bits(insn, 10, 9) << 8)
+                    | (c_bits(insn, 8, 8) << 10)
+                    | (c_bits(insn, 7, 7) << 6)
+                    | (c_bits(insn, 6, 6) << 7)
+                    | (c_bits(insn, 5, 3) << 1)
+                    | (c_bits(insn, 2, 2) << 5);
+                let target_pc = self.insn_pc.wrapping_add(c_sign_extend(offset, 12) as u64);
+                Some(format!("c.j 0x{:08x}", target_pc))
+            }
+            (0b01, 0b110 | 0b111) => {
+                let offset = (c_bits(insn, 12, 12) << 8)
+                    | (c_bits(insn, 11, 10) << 3)
+                    | (c_bits(insn, 6, 5) << 6)
+                    | (c_bits(insn, 4, 3) << 1)
+                    | (c_bits(insn, 2, 2) << 5);
+                let target_pc = self.insn_pc.wrapping_add(c_sign_extend(offset, 9) as u64);
+                let name = if funct3 == 0b110 { "c.beqz" } else { "c.bnez" };
+                Some(format!("{} x{}, 0x{:08x}", name, reg_hi, target_pc))
+            }
+            (0b10, 0b000) if rd != 0 => Some(format!("c.slli x{}, {}", rd, c_imm6(insn))),
+            (0b10, 0b001) => {
+                let uimm = (c_bits(insn, 12, 12) << 5)
+                    | (c_bits(insn, 6, 5) << 3)
+                    | (c_bits(insn, 4, 2) << 6);
+                Some(format!("c.fldsp f{}, {}(x2)", rd, uimm))
+            }
+            (0b10, 0b010) if rd != 0 => {
+                let uimm = (c_bits(insn, 12, 12) << 5)
+                    | (c_bits(insn, 6, 4) << 2)
+                    | (c_bits(insn, 3, 2) << 6);
+                Some(format!("c.lwsp x{}, {}(x2)", rd, uimm))
+            }
+            (0b10, 0b011) if rd != 0 => {
+                let uimm = (c_bits(insn, 12, 12) << 5)
+                    | (c_bits(insn, 6, 5) << 3)
+                    | (c_bits(insn, 4, 2) << 6);
+                Some(format!("c.ldsp x{}, {}(x2)", rd, uimm))
+            }
+            (0b10, 0b100) => match (c_bits(insn, 12, 12), rd, rs2) {
+                (0, 0, 0) => None,
+                (0, rs1, 0) => Some(format!("c.jr x{}", rs1)),
+                (0, rd, rs2) => Some(format!("c.mv x{}, x{}", rd, rs2)),
+                (_, 0, 0) => Some(String::from("c.ebreak")),
+                (_, rs1, 0) => Some(format!("c.jalr x{}", rs1)),
+                (_, rd, rs2) => Some(format!("c.add x{}, x{}", rd, rs2)),
+            },
+            (0b10, 0b101) => {
+                let uimm = (c_bits(insn, 12, 10) << 3) | (c_bits(insn, 9, 7) << 6);
+                Some(format!("c.fsdsp f{}, {}(x2)", rs2, uimm))
+            }
+            (0b10, 0b110) => {
+                let uimm = (c_bits(insn, 12, 9) << 2) | (c_bits(insn, 8, 7) << 6);
+                Some(format!("c.swsp x{}, {}(x2)", rs2, uimm))
+            }
+            (0b10, 0b111) => {
+                let uimm = (c_bits(insn, 12, 10) << 3) | (c_bits(insn, 9, 7) << 6);
+                Some(format!("c.sdsp x{}, {}(x2)", rs2, uimm))
+            }
+            _ => None,
+        }
     }
 }
 
@@ -487,7 +785,49 @@ mod tests {
 
         assert_eq!(
             process_instruction(&mut outputter, test_insns[45]),
-            Some(String::from("fence"))
+            Some(String::from("fence irw, io"))
         );
     }
+
+    #[test]
+    fn test_compressed_string_output() {
+        let mut outputter = InstructionStringOutputter { insn_pc: 0x100 };
+
+        let test_insns = [
+            (0x1141, "c.addi x2, -16"),
+            (0x8082, "c.jr x1"),
+            (0x0001, "c.nop"),
+            (0x0040, "c.addi4spn x8, x2, 4"),
+            (0x4108, "c.lw x10, 0(x10)"),
+            (0xe01c, "c.sd x15, 0(x8)"),
+            (0x557d, "c.li x10, -1"),
+            (0x7139, "c.addi16sp x2, -64"),
+            (0x6505, "c.lui x10, 0x00001"),
+            (0x8d05, "c.sub x10, x9"),
+            (0x9d3d, "c.addw x10, x15"),
+            (0xa001, "c.j 0x00000100"),
+            (0xc111, "c.beqz x10, 0x00000104"),
+            (0x0506, "c.slli x10, 1"),
+            (0x60e2, "c.ldsp x1, 24(x2)"),
+            (0xec06, "c.sdsp x1, 24(x2)"),
+            (0x852e, "c.mv x10, x11"),
+            (0x9502, "c.jalr x10"),
+            (0x9002, "c.ebreak"),
+            (0x952e, "c.add x10, x11"),
+        ];
+
+        for (insn, expected) in test_insns {
+            assert_eq!(
+                outputter.process_compressed(insn),
+                Some(String::from(expected)),
+                "0x{:04x}",
+                insn
+            );
+        }
+
+        // The all zero illegal instruction, a reserved encoding and a 32-bit parcel
+        assert_eq!(outputter.process_compressed(0x0000), None);
+        assert_eq!(outputter.process_compressed(0x8002), None);
+        assert_eq!(outputter.process_compressed(0x0893), None);
+    }
 }
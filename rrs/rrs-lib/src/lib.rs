@@ -7,14 +7,20 @@
 //! Containts the building blocks for a RISC-V ISS. The seperate rrs-cli uses rrs-lib to implement
 //! a CLI driven ISS.
 
+#[cfg(any(test, feature = "asm"))]
+pub mod asm;
+pub mod fp;
+pub mod instruction_decoder;
 pub mod instruction_executor;
 pub mod instruction_formats;
 pub mod instruction_string_outputter;
 pub mod memories;
 pub mod process_instruction;
+pub mod system;
 
 use downcast_rs::{impl_downcast, Downcast};
 
+pub use instruction_string_outputter::disassemble;
 pub use process_instruction::process_instruction;
 
 /// A trait for objects which do something with RISC-V instructions (e.g. execute them or print a
@@ -92,8 +98,25 @@ pub trait InstructionProcessor {
     fn process_amolrw(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
     fn process_amoscw(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
     fn process_amoaddw(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amoxorw(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amominw(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amomaxw(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amominuw(&mut self, dec_insn: instruction_formats::AType)
+        -> Self::InstructionResult;
+    fn process_amomaxuw(&mut self, dec_insn: instruction_formats::AType)
+        -> Self::InstructionResult;
+    fn process_amoxord(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amoord(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amoandd(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amomind(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amomaxd(&mut self, dec_insn: instruction_formats::AType) -> Self::InstructionResult;
+    fn process_amominud(&mut self, dec_insn: instruction_formats::AType)
+        -> Self::InstructionResult;
+    fn process_amomaxud(&mut self, dec_insn: instruction_formats::AType)
+        -> Self::InstructionResult;
 
-    fn process_rdtime(&mut self, dec_insn: instruction_formats::CType) -> Self::InstructionResult;
+    fn process_rdtime(&mut self, dec_insn: instruction_formats::CsrType)
+        -> Self::InstructionResult;
 
     fn process_jal(&mut self, dec_insn: instruction_formats::JType) -> Self::InstructionResult;
     fn process_jalr(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult;
@@ -112,6 +135,37 @@ pub trait InstructionProcessor {
     fn process_remuw(&mut self, dec_insn: instruction_formats::RType) -> Self::InstructionResult; // RV64M
 
     fn process_fence(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult;
+
+    fn process_flw(&mut self, dec_insn: instruction_formats::IType) -> Self::InstructionResult; // RV32F
+    fn process_fsw(&mut self, dec_insn: instruction_formats::SType) -> Self::InstructionResult; // RV32F
+    fn process_fadd_s(&mut self, dec_insn: instruction_formats::RType) -> Self::InstructionResult; // RV32F
+    fn process_fsub_s(&mut self, dec_insn: instruction_formats::RType) -> Self::InstructionResult; // RV32F
+    fn process_fmul_s(&mut self, dec_insn: instruction_formats::RType) -> Self::InstructionResult; // RV32F
+    fn process_fdiv_s(&mut self, dec_insn: instruction_formats::RType) -> Self::InstructionResult; // RV32F
+    fn process_fcvt_w_s(&mut self, dec_insn: instruction_formats::RType)
+        -> Self::InstructionResult; // RV32F
+    fn process_fcvt_s_w(&mut self, dec_insn: instruction_formats::RType)
+        -> Self::InstructionResult; // RV32F
+}
+
+/// Register width a hart executes with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Xlen {
+    /// 32-bit registers, shift amounts are 5 bits
+    Rv32,
+    /// 64-bit registers, shift amounts are 6 bits
+    #[default]
+    Rv64,
+}
+
+impl Xlen {
+    /// Mask selecting the bits of a shift amount that are used at this width
+    pub fn shamt_mask(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 0x1f,
+            Xlen::Rv64 => 0x3f,
+        }
+    }
 }
 
 /// State of a single RISC-V hart (hardware thread)
@@ -123,6 +177,25 @@ pub struct HartState {
     /// Gives index of the last register written if one occurred in the previous instruciton. Set
     /// to `None` if latest instruction did not write a register.
     pub last_register_write: Option<usize>,
+    /// Gives the index and value of each source register read by the previous instruction, in the
+    /// order read (rs1 then rs2). Unused entries are `None`. x0 reads are included, reading as 0.
+    pub last_register_reads: [Option<(usize, u64)>; 2],
+    /// Gives the address of the last memory write if one occurred in the previous instruction. Set
+    /// to `None` if latest instruction did not write memory.
+    pub last_mem_write: Option<u64>,
+    /// Address reserved by the last LR instruction. Cleared by SC (whether it succeeds or not) and
+    /// by [system::System] when another hart writes to the reserved granule.
+    pub reservation: Option<u64>,
+    /// Value of the `time` CSR returned by RDTIME. It is never advanced by instruction execution,
+    /// the embedding simulator is responsible for keeping it up to date.
+    pub time: u64,
+    /// f0 - f31 single-precision floating point register values, as raw IEEE-754 bits
+    pub fregisters: [u32; 32],
+    /// The `fcsr` CSR, holding the accrued exception flags (`fflags`) in bits 4:0 and the dynamic
+    /// rounding mode (`frm`) in bits 7:5. See [fp] for the field constants.
+    pub fcsr: u32,
+    /// Register width. Only shift decoding and execution currently depend on it.
+    pub xlen: Xlen,
 }
 
 impl HartState {
@@ -131,6 +204,13 @@ impl HartState {
             registers: [0; 32],
             pc: 0,
             last_register_write: None,
+            last_register_reads: [None; 2],
+            last_mem_write: None,
+            reservation: None,
+            time: 0,
+            fregisters: [0; 32],
+            fcsr: 0,
+            xlen: Xlen::Rv64,
         }
     }
 
@@ -146,16 +226,104 @@ impl HartState {
     }
 
     /// Read a register from the hart state. Used by executing instructions for correct zero
-    /// register handling
-    fn read_register(&self, reg_index: usize) -> u64 {
-        if reg_index == 0 {
+    /// register handling and to record the read in `last_register_reads`
+    fn read_register(&mut self, reg_index: usize) -> u64 {
+        let data = if reg_index == 0 {
             0
         } else {
             self.registers[reg_index]
+        };
+
+        if let Some(slot) = self
+            .last_register_reads
+            .iter_mut()
+            .find(|slot| slot.is_none())
+        {
+            *slot = Some((reg_index, data));
         }
+
+        data
+    }
+
+    /// Compare the architectural registers of this state (the old values) against `other` (the
+    /// new values).
+    ///
+    /// Returns one [RegDiff] per differing register, ordered PC, x1 - x31, f0 - f31 then `fcsr`.
+    /// x0 is never reported as its stored contents are ignored.
+    pub fn diff(&self, other: &HartState) -> Vec<RegDiff> {
+        let pc = std::iter::once((Register::Pc, self.pc, other.pc));
+        let x = (1..32).map(|idx| (Register::X(idx), self.registers[idx], other.registers[idx]));
+        let f = (0..32).map(|idx| {
+            (
+                Register::F(idx),
+                self.fregisters[idx] as u64,
+                other.fregisters[idx] as u64,
+            )
+        });
+        let fcsr = std::iter::once((Register::Fcsr, self.fcsr as u64, other.fcsr as u64));
+
+        pc.chain(x)
+            .chain(f)
+            .chain(fcsr)
+            .filter(|(_, old, new)| old != new)
+            .map(|(reg, old, new)| RegDiff { reg, old, new })
+            .collect()
     }
 }
 
+/// A register of a [HartState]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    /// The program counter
+    Pc,
+    /// Integer register x1 - x31
+    X(usize),
+    /// Floating point register f0 - f31
+    F(usize),
+    /// The `fcsr` CSR
+    Fcsr,
+}
+
+/// ABI names of the integer registers, indexed by register number
+pub const ABI_REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Parse an integer register name, either `xN` or its ABI name (including `fp` for `s0`), into
+/// its index. Names are case-insensitive.
+///
+/// Returns `None` if `name` isn't an integer register.
+pub fn parse_register(name: &str) -> Option<usize> {
+    let name = name.to_ascii_lowercase();
+
+    if let Some(num) = name.strip_prefix('x') {
+        // Reject forms like "x+1" or "x01" that `parse` would otherwise accept
+        if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        if num.len() > 1 && num.starts_with('0') {
+            return None;
+        }
+        return num.parse().ok().filter(|&idx| idx < 32);
+    }
+
+    if name == "fp" {
+        return Some(8);
+    }
+
+    ABI_REGISTER_NAMES.iter().position(|&abi| abi == name)
+}
+
+/// A register that differs between two [HartState]s, as returned by [HartState::diff]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegDiff {
+    pub reg: Register,
+    pub old: u64,
+    pub new: u64,
+}
+
 impl Default for HartState {
     fn default() -> Self {
         Self::new()
@@ -163,7 +331,7 @@ impl Default for HartState {
 }
 
 /// The different sizes used for memory accesses
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MemAccessSize {
     /// 8 bits
     Byte,
@@ -175,6 +343,19 @@ pub enum MemAccessSize {
     DoubleWord,
 }
 
+impl MemAccessSize {
+    /// Sign extend the low bits of `data` read by an access of this size to 64 bits, as a signed
+    /// load does.
+    pub fn sign_extend(self, data: u64) -> i64 {
+        match self {
+            MemAccessSize::Byte => (data as i8) as i64,
+            MemAccessSize::HalfWord => (data as i16) as i64,
+            MemAccessSize::Word => (data as i32) as i64,
+            MemAccessSize::DoubleWord => data as i64,
+        }
+    }
+}
+
 /// A trait for objects which implement memory operations
 pub trait Memory: Downcast {
     /// Read `size` bytes from `addr`.
@@ -188,6 +369,32 @@ pub trait Memory: Downcast {
     /// `addr` must be aligned to `size`.
     /// Returns `true` if write succeeds.
     fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool;
+
+    /// Read the 32-bit instruction at `addr` for execution.
+    ///
+    /// Returns `None` if `addr` doesn't exist in this memory or isn't executable. By default this
+    /// is a [MemAccessSize::Word] [Memory::read_mem].
+    fn read_insn(&mut self, addr: u64) -> Option<u64> {
+        self.read_mem(addr, MemAccessSize::Word)
+    }
+
+    /// Returns `true` if this memory accepts accesses where `addr` isn't aligned to `size`.
+    ///
+    /// When this returns `true` an [instruction_executor::InstructionExecutor] passes misaligned
+    /// loads and stores through to the memory instead of raising an alignment fault.
+    fn supports_misaligned(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if reading `addr` may have side effects or give a value that changes
+    /// without being written, as with memory-mapped IO.
+    ///
+    /// Volatile memory is only read when an instruction reads it, each read being issued to the
+    /// memory, so e.g. [instruction_executor::InstructionExecutor::record_history] doesn't read it
+    /// to be able to undo writes to it.
+    fn is_volatile(&self, _addr: u64) -> bool {
+        false
+    }
 }
 
 impl_downcast!(Memory);
@@ -196,7 +403,10 @@ impl_downcast!(Memory);
 mod tests {
     use std::convert::TryInto;
 
-    use super::instruction_executor::{InstructionException, InstructionExecutor};
+    use super::instruction_executor::{
+        BlockException, BlockResult, BreakCondition, InstructionException, InstructionExecutor,
+        StepOutcome, StopReason,
+    };
     use super::instruction_string_outputter::InstructionStringOutputter;
     use super::*;
 
@@ -226,10 +436,7 @@ mod tests {
         // access those things we either do it via the executor or create a new one before the next
         // step to allow access via the 'main' object, could just make step part of the 'main'
         // object? Having the executor only coupled to a bare minimum of state could be good?
-        let mut executor = InstructionExecutor {
-            hart_state: &mut hart,
-            mem: &mut mem,
-        };
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
 
         while executor.hart_state.pc != 0x54 {
             let mut outputter = InstructionStringOutputter {
@@ -276,4 +483,873 @@ mod tests {
             Err(InstructionException::IllegalInstruction(0x54, 0))
         );
     }
+
+    // Memory that returns the same instruction from every address
+    struct SingleInsnMemory(u32);
+
+    impl Memory for SingleInsnMemory {
+        fn read_mem(&mut self, _addr: u64, _size: MemAccessSize) -> Option<u64> {
+            Some(self.0 as u64)
+        }
+
+        fn write_mem(&mut self, _addr: u64, _size: MemAccessSize, _store_data: u64) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_jump_link_and_alignment() {
+        let mut hart = HartState::new();
+        // jal x1, 8
+        let mut mem = SingleInsnMemory(0x008000ef);
+
+        hart.pc = 0xffff_ffff_ffff_fffc;
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        // Both the link address and target wrap around the top of the address space
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 0x0);
+        assert_eq!(executor.hart_state.pc, 0x4);
+
+        // jal x1, 2
+        executor.mem.0 = 0x002000ef;
+
+        // With compressed instructions a 2 byte aligned target is aligned
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 0x8);
+        assert_eq!(executor.hart_state.pc, 0x6);
+
+        // jalr x1, 7(x2)
+        executor.mem.0 = 0x007100e7;
+        executor.hart_state.pc = 0x4;
+        executor.hart_state.registers[2] = 0x100;
+
+        // jalr clears the low bit of its target
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 0x8);
+        assert_eq!(executor.hart_state.pc, 0x106);
+
+        // An odd PC, e.g. one set by the host, faults on fetch without running anything
+        executor.hart_state.pc = 0x107;
+        assert_eq!(
+            executor.step(),
+            Err(InstructionException::AlignmentFault(0x107))
+        );
+        assert_eq!(executor.hart_state.pc, 0x107);
+        assert_eq!(executor.hart_state.registers[1], 0x8);
+    }
+
+    #[test]
+    fn test_misaligned_load() {
+        let mut hart = HartState::new();
+        // lw x5, 6(x0)
+        let mut mem = memories::VecMemory::new(vec![0x8877665500602283, 0xaa99]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        assert_eq!(
+            executor.step(),
+            Err(InstructionException::AlignmentFault(0x6))
+        );
+
+        // The load spans both backing doublewords once the memory accepts misaligned accesses
+        executor.mem.allow_misaligned(true);
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[5], 0xffff_ffff_aa99_8877);
+    }
+
+    #[test]
+    fn test_rv32_shifts() {
+        let mut hart = HartState::new();
+        hart.xlen = Xlen::Rv32;
+        hart.registers[2] = 0x8000_0000;
+        hart.registers[3] = 33;
+        // srli x1, x2, 32
+        let mut mem = SingleInsnMemory(0x02015093);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        // shamt[5] is only legal in RV64
+        assert_eq!(
+            executor.step(),
+            Err(InstructionException::IllegalInstruction(0x0, 0x02015093))
+        );
+        executor.hart_state.xlen = Xlen::Rv64;
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 0x0);
+
+        // srl x1, x2, x3 only uses the low 5 bits of x3 in RV32
+        executor.mem.0 = 0x003150b3;
+        executor.hart_state.xlen = Xlen::Rv32;
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 0x4000_0000);
+    }
+
+    #[test]
+    fn test_compressed_nop_and_ebreak() {
+        let mut hart = HartState::new();
+        // c.nop
+        // addi x1, x0, 5
+        // c.ebreak
+        let mut mem = memories::VecMemory::new(vec![0x9002_0050_0093_0001]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x2);
+        // A 32-bit instruction spanning two words
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 5);
+        assert_eq!(executor.hart_state.pc, 0x6);
+        assert_eq!(executor.step(), Err(InstructionException::Breakpoint(0x6)));
+        assert_eq!(executor.hart_state.pc, 0x6);
+
+        executor.hart_state.pc = 0;
+        assert_eq!(
+            executor.run_bounded(10),
+            StepOutcome {
+                cycles_run: 2,
+                stop_reason: StopReason::Breakpoint(0x6),
+            }
+        );
+    }
+
+    #[test]
+    fn test_compressed_nop_then_branch() {
+        let mut hart = HartState::new();
+        hart.registers[3] = 2;
+        // c.nop
+        // 1: addi x1, x1, 1
+        // bne x1, x3, 1b
+        let mut mem = memories::VecMemory::new(vec![0x9ee3_0010_8093_0001, 0xfe30]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.step(), Ok(()));
+        // The branch back to the 2 byte aligned addi is taken
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x2);
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[1], 2);
+        assert_eq!(executor.hart_state.pc, 0xa);
+    }
+
+    #[test]
+    fn test_branch_jump_offsets() {
+        let mut hart = HartState::new();
+        hart.registers[1] = 1;
+        hart.registers[3] = 0x1804;
+        hart.registers[4] = 0x2;
+        hart.pc = 0x1000;
+        let mut mem = memories::VecMemory::new(vec![0; 0x20400]);
+        let insns = [
+            // beq x0, x0, .-4096
+            (0x1000, 0x80000063),
+            // blt x0, x1, .+4092
+            (0x0, 0x7e104ee3),
+            // jal x1, .+0xffffc
+            (0xffc, 0x7fdff0ef),
+            // jal x0, .-0x100000
+            (0x100ff8, 0x8000006f),
+            // jalr x2, -2048(x3)
+            (0xff8, 0x80018167),
+            // jalr x0, 2047(x4)
+            (0x1004, 0x7ff20067),
+        ];
+        for (addr, insn) in insns {
+            assert!(mem.write_mem(addr, MemAccessSize::Word, insn));
+        }
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        // The most negative and most positive (aligned) B-type offsets
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x0);
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0xffc);
+
+        // The most positive (aligned) and most negative J-type offsets
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x100ff8);
+        assert_eq!(executor.hart_state.registers[1], 0x1000);
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0xff8);
+
+        // The most negative and most positive JALR offsets, the latter with bit 0 of the target
+        // cleared
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x1004);
+        assert_eq!(executor.hart_state.registers[2], 0xffc);
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x800);
+    }
+
+    #[test]
+    fn test_addiw() {
+        let mut hart = HartState::new();
+        hart.registers[2] = 0x0000_0000_8000_0000;
+        hart.registers[4] = 0x1234_5678_7fff_ffff;
+        hart.registers[6] = 0xffff_ffff_0000_0000;
+        hart.registers[8] = 0xdead_beef_7fff_ffff;
+        // addiw x1, x2, 0 (sext.w x1, x2)
+        // addiw x3, x4, 1
+        // addiw x5, x6, -1
+        // addiw x7, x8, 0 (sext.w x7, x8)
+        let mut mem = memories::VecMemory::new(vec![0x0012019b_0001009b, 0x0004039b_fff3029b]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        for _ in 0..4 {
+            assert_eq!(executor.step(), Ok(()));
+        }
+
+        // Bit 31 set extends to all-ones in the upper half
+        assert_eq!(executor.hart_state.registers[1], 0xffff_ffff_8000_0000);
+        // The 32-bit sum overflows into bit 31 before being sign extended, ignoring rs1[63:32]
+        assert_eq!(executor.hart_state.registers[3], 0xffff_ffff_8000_0000);
+        // The sum borrows from rs1[63:32], which is discarded
+        assert_eq!(executor.hart_state.registers[5], 0xffff_ffff_ffff_ffff);
+        // Bit 31 clear zeroes the upper half
+        assert_eq!(executor.hart_state.registers[7], 0x0000_0000_7fff_ffff);
+
+        let mut outputter = InstructionStringOutputter { insn_pc: 0 };
+        assert_eq!(
+            process_instruction(&mut outputter, 0x0001009b),
+            Some(String::from("sext.w x1, x2"))
+        );
+        assert_eq!(
+            process_instruction(&mut outputter, 0xfff3029b),
+            Some(String::from("addiw x5, x6, -1"))
+        );
+    }
+
+    #[test]
+    fn test_on_fence() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut hart = HartState::new();
+        // fence rw, rw
+        // fence
+        let mut mem = memories::VecMemory::new(vec![0x0ff0000f_0330000f]);
+        let fences = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&fences);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+        executor.on_fence = Some(Box::new(move |fence| seen.borrow_mut().push(fence)));
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.pc, 0x8);
+
+        let rw = instruction_formats::FENCE_R | instruction_formats::FENCE_W;
+        assert_eq!(
+            *fences.borrow(),
+            vec![
+                instruction_formats::Fence {
+                    fm: 0,
+                    pred: rw,
+                    succ: rw,
+                },
+                instruction_formats::Fence {
+                    fm: 0,
+                    pred: 0xf,
+                    succ: 0xf,
+                },
+            ]
+        );
+
+        let mut outputter = InstructionStringOutputter { insn_pc: 0 };
+        assert_eq!(
+            process_instruction(&mut outputter, 0x0330000f),
+            Some(String::from("fence rw, rw"))
+        );
+        assert_eq!(
+            process_instruction(&mut outputter, 0x0ff0000f),
+            Some(String::from("fence"))
+        );
+    }
+
+    #[test]
+    fn test_register_reads() {
+        let mut hart = HartState::new();
+        hart.registers[2] = 0x10;
+        hart.registers[3] = 0x20;
+        // add x1, x2, x3
+        // addi x4, x0, 5
+        let mut mem = memories::VecMemory::new(vec![0x00500213_003100b3]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(
+            executor.hart_state.last_register_reads,
+            [Some((2, 0x10)), Some((3, 0x20))]
+        );
+        assert_eq!(executor.hart_state.last_register_write, Some(1));
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(
+            executor.hart_state.last_register_reads,
+            [Some((0, 0)), None]
+        );
+        assert_eq!(executor.hart_state.last_register_write, Some(4));
+    }
+
+    #[test]
+    fn test_execute_raw() {
+        let mut hart = HartState::new();
+        hart.pc = 0x100;
+        hart.registers[5] = 40;
+        hart.registers[6] = 2;
+        // Memory holds only zeroes, so anything fetched from it would be illegal
+        let mut mem = memories::VecMemory::new(vec![0; 0x40]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        // add x7, x5, x6
+        assert_eq!(executor.execute_raw(0x006283b3), Ok(false));
+        assert_eq!(executor.hart_state.registers[7], 42);
+        assert_eq!(executor.hart_state.last_register_write, Some(7));
+        assert_eq!(executor.hart_state.pc, 0x104);
+
+        // jal x0, -4
+        assert_eq!(executor.execute_raw(0xffdff06f), Ok(true));
+        assert_eq!(executor.hart_state.pc, 0x100);
+
+        // c.nop
+        assert_eq!(executor.execute_raw(0x0001), Ok(false));
+        assert_eq!(executor.hart_state.pc, 0x102);
+
+        assert_eq!(
+            executor.execute_raw(0xffffffff),
+            Err(InstructionException::IllegalInstruction(0x102, 0xffffffff))
+        );
+    }
+
+    // Run `insn` at 0x100 with x5 = 1 and x6 = 2, both from memory with `step` and directly with
+    // `execute_raw`, checking the two agree. Returns whether the instruction reported updating
+    // the PC and the hart state after it.
+    fn run_pc_contract(insn: u32) -> (bool, HartState) {
+        let new_hart = || {
+            let mut hart = HartState::new();
+            hart.pc = 0x100;
+            hart.registers[5] = 1;
+            hart.registers[6] = 2;
+            hart
+        };
+        let mut mem = memories::VecMemory::new(vec![0; 0x40]);
+        mem.mem[0x20] = insn as u64;
+
+        let mut stepped = new_hart();
+        InstructionExecutor::new(&mut mem, &mut stepped)
+            .step()
+            .unwrap();
+
+        let mut hart = new_hart();
+        let pc_updated = InstructionExecutor::new(&mut mem, &mut hart)
+            .execute_raw(insn)
+            .unwrap();
+        assert_eq!(stepped.pc, hart.pc);
+        assert_eq!(stepped.registers, hart.registers);
+        (pc_updated, hart)
+    }
+
+    #[test]
+    fn test_pc_contract_taken_branch() {
+        // beq x5, x5, 16
+        let (pc_updated, hart) = run_pc_contract(0x00528863);
+        assert!(pc_updated);
+        assert_eq!(hart.pc, 0x110);
+    }
+
+    #[test]
+    fn test_pc_contract_not_taken_branch() {
+        // beq x5, x6, 16
+        let (pc_updated, hart) = run_pc_contract(0x00628863);
+        assert!(!pc_updated);
+        assert_eq!(hart.pc, 0x104);
+    }
+
+    #[test]
+    fn test_pc_contract_jal() {
+        // jal x1, 0x20
+        let (pc_updated, hart) = run_pc_contract(0x020000ef);
+        assert!(pc_updated);
+        assert_eq!(hart.pc, 0x120);
+        assert_eq!(hart.registers[1], 0x104);
+    }
+
+    #[test]
+    fn test_pc_contract_straight_line() {
+        // add x7, x5, x6
+        let (pc_updated, hart) = run_pc_contract(0x006283b3);
+        assert!(!pc_updated);
+        assert_eq!(hart.pc, 0x104);
+        assert_eq!(hart.registers[7], 3);
+    }
+
+    #[test]
+    fn test_run_block() {
+        let mut hart = HartState::new();
+        // addi x10, x0, 0
+        // addi x11, x0, 3
+        // loop:
+        // addi x10, x10, 1
+        // bne x10, x11, loop
+        // ecall
+        let mut mem = memories::VecMemory::new(vec![
+            0x00300593_00000513,
+            0xfeb51ee3_00150513,
+            0x00000000_00000073,
+        ]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        assert_eq!(
+            executor.run_block(),
+            Ok(BlockResult {
+                start_pc: 0x0,
+                end_pc: 0xc,
+                insn_count: 4,
+            })
+        );
+        assert_eq!(executor.hart_state.pc, 0x8);
+
+        assert_eq!(
+            executor.run_block(),
+            Ok(BlockResult {
+                start_pc: 0x8,
+                end_pc: 0xc,
+                insn_count: 2,
+            })
+        );
+        assert_eq!(executor.hart_state.registers[10], 2);
+
+        // The untaken branch doesn't end the block, the ecall faults
+        assert_eq!(
+            executor.run_block(),
+            Err(BlockException {
+                insn_count: 2,
+                exception: InstructionException::IllegalInstruction(0x10, 0x73),
+            })
+        );
+        assert_eq!(executor.hart_state.registers[10], 3);
+        assert_eq!(executor.hart_state.pc, 0x10);
+    }
+
+    #[test]
+    fn test_fadd_s() {
+        let mut hart = HartState::new();
+        // flw f1, 0x20(x0)
+        // flw f2, 0x24(x0)
+        // fadd.s f3, f1, f2
+        // fsw f3, 0x28(x0)
+        // fcvt.w.s x5, f3, rtz
+        let mut mem = memories::VecMemory::new(vec![
+            0x02402107_02002087,
+            0x02302427_0020f1d3,
+            0x00000000_c00192d3,
+            0x00000000_00000000,
+            // 2.25, 1.5
+            0x40100000_3fc00000,
+            0x00000000_00000000,
+        ]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        for _ in 0..3 {
+            assert_eq!(executor.step(), Ok(()));
+        }
+        assert_eq!(f32::from_bits(executor.hart_state.fregisters[3]), 3.75);
+        assert_eq!(executor.hart_state.fcsr, 0);
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(
+            executor.mem.read_mem(0x28, MemAccessSize::Word),
+            Some(3.75f32.to_bits() as u64)
+        );
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[5], 3);
+        assert_eq!(executor.hart_state.fcsr, fp::FFLAGS_NX);
+    }
+
+    #[test]
+    fn test_memory_permissions() {
+        let mut hart = HartState::new();
+        let mut mem = memories::MemorySpace::new();
+
+        // addi x5, x0, 0x100
+        // sw x5, 0(x5)
+        // lw x6, 0(x5)
+        mem.add_memory_with_perms(
+            0x0,
+            0x10,
+            Box::new(memories::VecMemory::new(vec![
+                0x0052a023_10000293,
+                0x00000000_0002a303,
+            ])),
+            memories::Permissions::READ_EXECUTE,
+        )
+        .unwrap();
+
+        let read_only = memories::Permissions {
+            read: true,
+            write: false,
+            exec: false,
+        };
+        mem.add_memory_with_perms(
+            0x100,
+            0x8,
+            Box::new(memories::VecMemory::new(vec![0x1234])),
+            read_only,
+        )
+        .unwrap();
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(
+            executor.step(),
+            Err(InstructionException::StoreAccessFault(0x100))
+        );
+
+        executor.hart_state.pc = 0x8;
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[6], 0x1234);
+
+        // Text can be read but not written, data can't be executed
+        assert_eq!(
+            executor.mem.read_mem(0x0, MemAccessSize::Word),
+            Some(0x10000293)
+        );
+        assert!(!executor.mem.write_mem(0x0, MemAccessSize::Word, 0));
+
+        executor.hart_state.pc = 0x100;
+        assert_eq!(
+            executor.step(),
+            Err(InstructionException::FetchError(0x100))
+        );
+    }
+
+    #[test]
+    fn test_run_bounded() {
+        let mut hart = HartState::new();
+        // addi x10, x0, 5
+        // addi x10, x10, 1
+        // addi x11, x0, 7
+        // ecall
+        let mut mem = memories::VecMemory::new(vec![0x00150513_00500513, 0x00000073_00700593]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        executor.add_breakpoint(0x8, BreakCondition::Always);
+        assert_eq!(
+            executor.run_bounded(10),
+            StepOutcome {
+                cycles_run: 2,
+                stop_reason: StopReason::Breakpoint(0x8),
+            }
+        );
+
+        assert_eq!(
+            executor.run_bounded(10),
+            StepOutcome {
+                cycles_run: 1,
+                stop_reason: StopReason::Halted(6),
+            }
+        );
+
+        executor.hart_state.pc = 0;
+        executor.clear_breakpoints();
+        assert_eq!(
+            executor.run_bounded(10),
+            StepOutcome {
+                cycles_run: 3,
+                stop_reason: StopReason::Halted(6),
+            }
+        );
+
+        executor.hart_state.pc = 0;
+        assert_eq!(
+            executor.run_bounded(1),
+            StepOutcome {
+                cycles_run: 1,
+                stop_reason: StopReason::LimitReached,
+            }
+        );
+
+        executor.hart_state.pc = 0x10;
+        assert_eq!(
+            executor.run_bounded(10),
+            StepOutcome {
+                cycles_run: 0,
+                stop_reason: StopReason::Exception(InstructionException::FetchError(0x10)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut hart = HartState::new();
+        // addi x10, x0, 0
+        // addi x11, x0, 3
+        // loop:
+        // addi x10, x10, 1
+        // bne x10, x11, loop
+        // ecall
+        let mut mem = memories::VecMemory::new(vec![
+            0x00300593_00000513,
+            0xfeb51ee3_00150513,
+            0x00000000_00000073,
+        ]);
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        // Stops on the third iteration, before its increment
+        executor.add_breakpoint(0x8, BreakCondition::HitCount(3));
+        assert_eq!(
+            executor.run_bounded(100),
+            StepOutcome {
+                cycles_run: 6,
+                stop_reason: StopReason::Breakpoint(0x8),
+            }
+        );
+        assert_eq!(executor.hart_state.registers[10], 2);
+
+        // The count isn't reached again
+        assert_eq!(
+            executor.run_bounded(100),
+            StepOutcome {
+                cycles_run: 2,
+                stop_reason: StopReason::Halted(3),
+            }
+        );
+
+        executor.hart_state.pc = 0;
+        executor.add_breakpoint(
+            0x8,
+            BreakCondition::Predicate(Box::new(|hart| hart.registers[10] == 1)),
+        );
+        assert_eq!(
+            executor.run_bounded(100),
+            StepOutcome {
+                cycles_run: 4,
+                stop_reason: StopReason::Breakpoint(0x8),
+            }
+        );
+        assert_eq!(executor.hart_state.registers[10], 1);
+
+        assert!(executor.remove_breakpoint(0x8));
+        assert!(!executor.remove_breakpoint(0x8));
+        assert_eq!(
+            executor.run_bounded(100),
+            StepOutcome {
+                cycles_run: 4,
+                stop_reason: StopReason::Halted(3),
+            }
+        );
+    }
+
+    #[test]
+    fn test_amo_min_max() {
+        let mut hart = HartState::new();
+        // amomin.w x7, x6, (x5)
+        // amomaxu.w x7, x6, (x5)
+        // amomax.d x7, x6, (x5)
+        let mut init_mem = vec![0; 0x108 / 8];
+        init_mem[..2].copy_from_slice(&[0xe062a3af_8062a3af, 0x00000000_a062b3af]);
+        init_mem[0x100 / 8] = 0xfffffff0;
+        let mut mem = memories::VecMemory::new(init_mem);
+
+        hart.registers[5] = 0x100;
+        hart.registers[6] = u64::MAX;
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+
+        // Signed, -16 < -1
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[7], 0xffff_ffff_ffff_fff0);
+        assert_eq!(executor.mem.mem[0x100 / 8], 0xfffffff0);
+
+        // Unsigned, 0xfffffff0 < 0xffffffff
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[7], 0xffff_ffff_ffff_fff0);
+        assert_eq!(executor.mem.mem[0x100 / 8], 0xffffffff);
+
+        // Signed doubleword, 0xffffffff > -1
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.hart_state.registers[7], 0xffffffff);
+        assert_eq!(executor.mem.mem[0x100 / 8], 0xffffffff);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut hart = HartState::new();
+        // addi x5, x0, 0x100
+        // addi x6, x0, 7
+        // sw x6, 0(x5)
+        // amoadd.w x7, x6, (x5)
+        // sd x5, 8(x5)
+        // jal x1, 8
+        let mut init_mem = vec![0; 0x110 / 8];
+        init_mem[..3].copy_from_slice(&[
+            0x00700313_10000293,
+            0x0062a3af_0062a023,
+            0x008000ef_0052b423,
+        ]);
+        init_mem[0x100 / 8] = 0x1122334455667788;
+        init_mem[0x108 / 8] = 0x99aabbccddeeff00;
+        let mut mem = memories::VecMemory::new(init_mem.clone());
+
+        hart.registers[7] = 0xabcd;
+        let init_registers = hart.registers;
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+        executor.record_history = true;
+
+        for _ in 0..6 {
+            assert_eq!(executor.step(), Ok(()));
+        }
+
+        assert_eq!(executor.hart_state.pc, 0x1c);
+        assert_eq!(executor.hart_state.registers[7], 7);
+        assert_eq!(executor.mem.mem[0x100 / 8], 0x112233440000000e);
+        assert_eq!(executor.mem.mem[0x108 / 8], 0x100);
+
+        for _ in 0..6 {
+            assert!(executor.step_back());
+        }
+        assert!(!executor.step_back());
+
+        assert_eq!(executor.hart_state.pc, 0x0);
+        assert_eq!(executor.hart_state.registers, init_registers);
+        assert_eq!(executor.mem.mem, init_mem);
+    }
+
+    #[test]
+    fn test_volatile_memory() {
+        let mut hart = HartState::new();
+        hart.registers[7] = 100;
+        hart.registers[10] = 0x2000;
+        hart.registers[11] = 0x3000;
+        // ld x5, 0(x10)
+        // ld x6, 0(x10)
+        // sd x7, 0(x11)
+        let mut mem = memories::MemorySpace::new();
+        mem.add_memory(
+            0x0,
+            0x10,
+            Box::new(memories::VecMemory::new(vec![
+                0x00053303_00053283,
+                0x00000000_0075b023,
+            ])),
+        )
+        .unwrap();
+        // A timer is volatile whether or not its region is
+        mem.add_memory(
+            0x2000,
+            memories::TIMER_SIZE,
+            Box::new(memories::TimerMemory::new(
+                0,
+                memories::TimerIncrement::PerRead(1),
+            )),
+        )
+        .unwrap();
+        mem.add_volatile_memory(0x3000, 0x8, Box::new(memories::VecMemory::new(vec![0])))
+            .unwrap();
+
+        assert!(!mem.is_volatile(0x0));
+        assert!(mem.is_volatile(0x2000));
+        assert!(mem.is_volatile(0x3000));
+
+        let init_registers = hart.registers;
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+        executor.record_history = true;
+
+        for _ in 0..3 {
+            assert_eq!(executor.step(), Ok(()));
+        }
+
+        // Each load read the timer, advancing it
+        assert_eq!(executor.hart_state.registers[5], 0);
+        assert_eq!(executor.hart_state.registers[6], 1);
+
+        for _ in 0..3 {
+            assert!(executor.step_back());
+        }
+
+        // Registers are restored but volatile memory is neither read to record the store nor
+        // rewound
+        assert_eq!(executor.hart_state.registers, init_registers);
+        assert_eq!(
+            executor
+                .mem
+                .get_memory_ref::<memories::TimerMemory>(1)
+                .unwrap()
+                .time(),
+            2
+        );
+        assert_eq!(
+            executor.mem.read_mem(0x3000, MemAccessSize::DoubleWord),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn test_hart_state_diff() {
+        let mut old = HartState::new();
+        old.registers[5] = 1;
+        let mut new = HartState::new();
+        new.registers[5] = 2;
+        // x0's contents are ignored so it never differs
+        new.registers[0] = 3;
+
+        assert_eq!(
+            old.diff(&new),
+            vec![RegDiff {
+                reg: Register::X(5),
+                old: 1,
+                new: 2
+            }]
+        );
+        assert_eq!(old.diff(&old), vec![]);
+
+        new.pc = 4;
+        new.fregisters[1] = 5;
+        new.fcsr = 6;
+        let regs: Vec<Register> = old.diff(&new).iter().map(|diff| diff.reg).collect();
+        assert_eq!(
+            regs,
+            vec![Register::Pc, Register::X(5), Register::F(1), Register::Fcsr]
+        );
+    }
+
+    #[test]
+    fn test_parse_register() {
+        assert_eq!(parse_register("sp"), Some(2));
+        assert_eq!(parse_register("x2"), Some(2));
+        assert_eq!(parse_register("zero"), Some(0));
+        assert_eq!(parse_register("x0"), Some(0));
+        assert_eq!(parse_register("RA"), Some(1));
+        assert_eq!(parse_register("A0"), Some(10));
+        assert_eq!(parse_register("fp"), Some(8));
+        assert_eq!(parse_register("X31"), Some(31));
+        assert_eq!(parse_register("t6"), Some(31));
+
+        for (idx, name) in ABI_REGISTER_NAMES.iter().enumerate() {
+            assert_eq!(parse_register(name), Some(idx));
+            assert_eq!(parse_register(&format!("x{}", idx)), Some(idx));
+        }
+
+        for garbage in [
+            "", "x", "x32", "x-1", "x+1", "x01", "a8", "s12", "pc", "f0", " sp",
+        ] {
+            assert_eq!(parse_register(garbage), None, "{:?}", garbage);
+        }
+    }
 }
@@ -5,8 +5,10 @@
 //! Various [Memory] implementations useful for an ISS and utility functions
 
 use super::{MemAccessSize, Memory};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
 use std::io::Read;
+use std::sync::{Arc, Mutex};
 
 /// Read bytes from an [std::io::Read] into a [Memory] starting at the given address
 pub fn read_to_memory(
@@ -29,22 +31,109 @@ pub fn read_to_memory(
     Ok(())
 }
 
+// Number of bytes accessed by an access of `size`
+fn access_bytes(size: MemAccessSize) -> u64 {
+    match size {
+        MemAccessSize::Byte => 1,
+        MemAccessSize::HalfWord => 2,
+        MemAccessSize::Word => 4,
+        MemAccessSize::DoubleWord => 8,
+    }
+}
+
 /// [Vec] backed memory.
 ///
 /// The [Vec] uses `u32` as the base type. Any read or write that falls out of the [Vec]s size will
 /// result in a failed read or write.
 pub struct VecMemory {
     pub mem: Vec<u64>,
+    allow_misaligned: bool,
 }
 
 impl VecMemory {
     pub fn new(init_mem: Vec<u64>) -> VecMemory {
-        VecMemory { mem: init_mem }
+        VecMemory {
+            mem: init_mem,
+            allow_misaligned: false,
+        }
+    }
+
+    /// Enable or disable misaligned accesses.
+    ///
+    /// When enabled, reads and writes not aligned to their size are split into byte accesses
+    /// rather than panicking, so they may span two of the underlying `u64`s.
+    pub fn allow_misaligned(&mut self, allow: bool) {
+        self.allow_misaligned = allow;
+    }
+
+    /// View the whole backing store as bytes, address 0 first.
+    ///
+    /// RISC-V memory is little-endian and the view uses the host's byte order, so this is only
+    /// provided on little-endian hosts where the two agree. Use [VecMemory::copy_out] elsewhere.
+    #[cfg(target_endian = "little")]
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: `u8` has no alignment requirement and any `u64` is a valid sequence of 8 `u8`s,
+        // with the length covering exactly the `mem` allocation.
+        unsafe { std::slice::from_raw_parts(self.mem.as_ptr() as *const u8, self.mem.len() * 8) }
+    }
+
+    /// Copy `len` bytes starting at `addr` out in little-endian (memory) order, on any host.
+    ///
+    /// Returns `None` if any of the range falls outside the memory.
+    pub fn copy_out(&self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        let end = addr.checked_add(len)?;
+        if end > self.mem.len() as u64 * 8 {
+            return None;
+        }
+
+        Some(
+            (addr..end)
+                .map(|byte_addr| {
+                    (self.mem[(byte_addr >> 3) as usize] >> ((byte_addr & 0x7) * 8)) as u8
+                })
+                .collect(),
+        )
+    }
+
+    fn is_misaligned(addr: u64, size: MemAccessSize) -> bool {
+        (addr & (access_bytes(size) - 1)) != 0
+    }
+
+    fn read_misaligned(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
+        let mut read_data = 0;
+        for i in (0..access_bytes(size)).rev() {
+            let byte = self.read_mem(addr.checked_add(i)?, MemAccessSize::Byte)?;
+            read_data = (read_data << 8) | byte;
+        }
+
+        Some(read_data)
+    }
+
+    fn write_misaligned(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        let last_addr = match addr.checked_add(access_bytes(size) - 1) {
+            Some(a) => a,
+            None => return false,
+        };
+
+        // Check the whole access is in range first so a failing write leaves memory untouched
+        if (last_addr >> 3) as usize >= self.mem.len() {
+            return false;
+        }
+
+        for i in 0..access_bytes(size) {
+            self.write_mem(addr + i, MemAccessSize::Byte, store_data >> (i * 8));
+        }
+
+        true
     }
 }
 
 impl Memory for VecMemory {
     fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
+        if self.allow_misaligned && Self::is_misaligned(addr, size) {
+            return self.read_misaligned(addr, size);
+        }
+
         // Calculate a mask and shift to apply to a 32-bit word to get the required data
         let (shift, mask) = match size {
             MemAccessSize::Byte => (addr & 0x7, 0xff),       // 0x111
@@ -68,6 +157,10 @@ impl Memory for VecMemory {
     }
 
     fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        if self.allow_misaligned && Self::is_misaligned(addr, size) {
+            return self.write_misaligned(addr, size, store_data);
+        }
+
         // Calculate a mask and shift needed to update 32-bit word
         let (shift, mask) = match size {
             MemAccessSize::Byte => (addr & 0x7, 0xff),       // 0x111
@@ -95,11 +188,53 @@ impl Memory for VecMemory {
             false
         }
     }
+
+    fn supports_misaligned(&self) -> bool {
+        self.allow_misaligned
+    }
+}
+
+/// Access permissions of a [MemoryRegion]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Permissions {
+    /// Readable and writable but not executable, e.g. data and stack
+    pub const READ_WRITE: Permissions = Permissions {
+        read: true,
+        write: true,
+        exec: false,
+    };
+    /// Readable and executable but not writable, e.g. program text
+    pub const READ_EXECUTE: Permissions = Permissions {
+        read: true,
+        write: false,
+        exec: true,
+    };
+    /// No restrictions, the permissions given by [MemorySpace::add_memory]
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        write: true,
+        exec: true,
+    };
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions::ALL
+    }
 }
 
 pub struct MemoryRegion {
     pub base: u64,
     pub size: u64,
+    pub perms: Permissions,
+    /// Set for memory-mapped IO, see [Memory::is_volatile]
+    pub volatile: bool,
     memory: Box<dyn Memory>,
 }
 
@@ -111,6 +246,10 @@ pub struct MemoryRegion {
 /// address `0x0` if the [MemorySpace] gets a read at `0x100000`
 ///
 /// The inner memory regions cannot overlap and base addresses must be 32-bit aligned.
+///
+/// Each region has [Permissions]. Reads from a non-readable region and writes to a non-writable
+/// region fail as if the address were unmapped, and [Memory::read_insn] fails outside of
+/// executable regions.
 pub struct MemorySpace {
     memory_regions: Vec<MemoryRegion>,
 }
@@ -120,6 +259,8 @@ pub enum MemorySpaceError {
     RegionOverlap,
     Unaligned,
     UnallocateRegion(String),
+    /// Addresses of words given to [MemorySpace::load_image] that aren't wholly within a region
+    Unmapped(Vec<u64>),
 }
 
 impl MemorySpace {
@@ -146,6 +287,11 @@ impl MemorySpace {
         false
     }
 
+    /// The memory regions in the order they were added
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.memory_regions
+    }
+
     // Gets the memory region that covers an address if it exists.
     pub fn get_memory_region_by_addr(&mut self, addr: u64) -> Option<&mut MemoryRegion> {
         for memory_region in self.memory_regions.iter_mut() {
@@ -157,7 +303,26 @@ impl MemorySpace {
         None
     }
 
-    /// Add an inner memory.
+    /// Permissions of the memory region covering `addr`, `None` if there is no such region
+    pub fn permissions(&self, addr: u64) -> Option<Permissions> {
+        self.memory_regions
+            .iter()
+            .find(|region| addr >= region.base && addr < region.base + region.size)
+            .map(|region| region.perms)
+    }
+
+    /// Whether `addr` falls within any region, whatever its permissions
+    pub fn is_mapped(&self, addr: u64) -> bool {
+        self.permissions(addr).is_some()
+    }
+
+    /// Whether an instruction can be fetched from `addr`, i.e. it falls within an executable
+    /// region. Lets a debugger or disassembler check before a fetch rather than handling the fault.
+    pub fn is_executable(&self, addr: u64) -> bool {
+        matches!(self.permissions(addr), Some(perms) if perms.exec)
+    }
+
+    /// Add an inner memory with no access restrictions.
     ///
     /// When `Ok` is returned a memory index is provided which can be used with
     /// [MemorySpace::get_memory_ref] and [MemorySpace::get_memory_mut] to get a reference to that
@@ -167,6 +332,19 @@ impl MemorySpace {
         base: u64,
         size: u64,
         memory: Box<dyn Memory>,
+    ) -> Result<usize, MemorySpaceError> {
+        self.add_memory_with_perms(base, size, memory, Permissions::ALL)
+    }
+
+    /// Add an inner memory that can only be accessed as allowed by `perms`.
+    ///
+    /// Otherwise behaves as [MemorySpace::add_memory].
+    pub fn add_memory_with_perms(
+        &mut self,
+        base: u64,
+        size: u64,
+        memory: Box<dyn Memory>,
+        perms: Permissions,
     ) -> Result<usize, MemorySpaceError> {
         if ((base & 0x7) != 0) || ((size & 0x7) != 0) {
             return Err(MemorySpaceError::Unaligned);
@@ -177,12 +355,32 @@ impl MemorySpace {
         }
 
         let new_mem_index = self.memory_regions.len();
-        self.memory_regions
-            .push(MemoryRegion { base, size, memory });
+        self.memory_regions.push(MemoryRegion {
+            base,
+            size,
+            perms,
+            volatile: false,
+            memory,
+        });
 
         Ok(new_mem_index)
     }
 
+    /// Add an inner memory for memory-mapped IO, which is readable and writable and whose every
+    /// address is [volatile](Memory::is_volatile).
+    ///
+    /// Otherwise behaves as [MemorySpace::add_memory].
+    pub fn add_volatile_memory(
+        &mut self,
+        base: u64,
+        size: u64,
+        memory: Box<dyn Memory>,
+    ) -> Result<usize, MemorySpaceError> {
+        let index = self.add_memory_with_perms(base, size, memory, Permissions::READ_WRITE)?;
+        self.memory_regions[index].volatile = true;
+        Ok(index)
+    }
+
     /// remove_memory_by_base
     pub fn remove_memory_by_base(&mut self, base: u64) -> Result<(), MemorySpaceError> {
         match self.memory_regions.iter().position(|x| x.base == base) {
@@ -199,6 +397,76 @@ impl MemorySpace {
         }
     }
 
+    /// Write every word of `image`, a map from address to 32-bit word such as the loaded segments
+    /// of an ELF, to the inner memories.
+    ///
+    /// Loading isn't an access by the program so [Permissions] don't apply, e.g. program text is
+    /// loaded into a region that isn't writable. Each word must lie wholly within a region. If any
+    /// don't nothing is written and a [MemorySpaceError::Unmapped] listing them is returned.
+    pub fn load_image(&mut self, image: &BTreeMap<u64, u32>) -> Result<(), MemorySpaceError> {
+        let mut writes = Vec::with_capacity(image.len());
+        let mut unmapped = Vec::new();
+        for (&addr, &word) in image {
+            let region = self.memory_regions.iter().position(|region| {
+                addr >= region.base && addr.saturating_add(4) <= region.base + region.size
+            });
+            match region {
+                Some(idx) => writes.push((idx, addr, word)),
+                None => unmapped.push(addr),
+            }
+        }
+        if !unmapped.is_empty() {
+            return Err(MemorySpaceError::Unmapped(unmapped));
+        }
+
+        for (idx, addr, word) in writes {
+            let region = &mut self.memory_regions[idx];
+            region
+                .memory
+                .write_mem(addr - region.base, MemAccessSize::Word, word as u64);
+        }
+        Ok(())
+    }
+
+    /// Read from `offset` within the region based at `base`, as [Memory::read_mem] at
+    /// `base + offset`
+    ///
+    /// `None` is returned if there is no region based at `base` or the access doesn't fit within
+    /// it, as well as wherever [Memory::read_mem] would fail.
+    pub fn read_rel(&mut self, base: u64, offset: u64, size: MemAccessSize) -> Option<u64> {
+        let addr = self.rel_addr(base, offset, size)?;
+        self.read_mem(addr, size)
+    }
+
+    /// Write to `offset` within the region based at `base`, as [Memory::write_mem] at
+    /// `base + offset`
+    ///
+    /// `false` is returned if there is no region based at `base` or the access doesn't fit within
+    /// it, as well as wherever [Memory::write_mem] would fail.
+    pub fn write_rel(
+        &mut self,
+        base: u64,
+        offset: u64,
+        size: MemAccessSize,
+        store_data: u64,
+    ) -> bool {
+        match self.rel_addr(base, offset, size) {
+            Some(addr) => self.write_mem(addr, size, store_data),
+            None => false,
+        }
+    }
+
+    // Translates `offset` within the region based at `base` to an absolute address, provided an
+    // access of `size` there stays within the region.
+    fn rel_addr(&self, base: u64, offset: u64, size: MemAccessSize) -> Option<u64> {
+        let region = self
+            .memory_regions
+            .iter()
+            .find(|region| region.base == base)?;
+        let end = offset.checked_add(access_bytes(size))?;
+        (end <= region.size).then(|| base + offset)
+    }
+
     /// Get a reference to an inner memory
     ///
     /// This performs downcasting to the provided `T`. `None` is returned where the downcast fails.
@@ -227,18 +495,420 @@ impl Memory for MemorySpace {
     fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
         let memory_region = self.get_memory_region_by_addr(addr)?;
 
+        if !memory_region.perms.read {
+            return None;
+        }
+
         memory_region
             .memory
             .read_mem(addr - memory_region.base, size)
     }
 
     fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
-        if let Some(memory_region) = self.get_memory_region_by_addr(addr) {
-            memory_region
-                .memory
-                .write_mem(addr - memory_region.base, size, store_data)
-        } else {
-            false
+        match self.get_memory_region_by_addr(addr) {
+            Some(memory_region) if memory_region.perms.write => {
+                memory_region
+                    .memory
+                    .write_mem(addr - memory_region.base, size, store_data)
+            }
+            _ => false,
+        }
+    }
+
+    fn read_insn(&mut self, addr: u64) -> Option<u64> {
+        let memory_region = self.get_memory_region_by_addr(addr)?;
+
+        if !memory_region.perms.exec {
+            return None;
+        }
+
+        memory_region.memory.read_insn(addr - memory_region.base)
+    }
+
+    // An empty space has nothing to access, misaligned or not, so it doesn't claim support
+    fn supports_misaligned(&self) -> bool {
+        !self.memory_regions.is_empty()
+            && self
+                .memory_regions
+                .iter()
+                .all(|region| region.memory.supports_misaligned())
+    }
+
+    fn is_volatile(&self, addr: u64) -> bool {
+        self.memory_regions.iter().any(|region| {
+            addr >= region.base
+                && addr < region.base + region.size
+                && (region.volatile || region.memory.is_volatile(addr - region.base))
+        })
+    }
+}
+
+/// How the `mtime` register of a [TimerMemory] advances
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerIncrement {
+    /// `mtime` only changes when written, by the guest or with [TimerMemory::set_time]
+    Fixed,
+    /// `mtime` advances by the given amount after every read of it
+    PerRead(u64),
+    /// `mtime` advances by the number of cycles passed to [TimerMemory::tick], which the embedding
+    /// simulator calls as it executes
+    PerCycle,
+}
+
+/// Offset of `mtime` within a [TimerMemory]
+pub const TIMER_MTIME_OFFSET: u64 = 0x0;
+/// Offset of `mtimecmp` within a [TimerMemory]
+pub const TIMER_MTIMECMP_OFFSET: u64 = 0x8;
+/// Size of a [TimerMemory] region
+pub const TIMER_SIZE: u64 = 0x10;
+
+/// A [Memory] holding memory-mapped `mtime` and `mtimecmp` timer registers, for guests that read
+/// the time from memory rather than with `rdtime`.
+///
+/// Both registers are 64 bits, `mtime` at [TIMER_MTIME_OFFSET] and `mtimecmp` at
+/// [TIMER_MTIMECMP_OFFSET], and are readable and writable at any access size. Add it to a
+/// [MemorySpace] at the platform's `mtime` address (e.g. `0x0200_bff8` for a SiFive CLINT) with a
+/// size of [TIMER_SIZE]. `mtimecmp` then directly follows `mtime` rather than being at its CLINT
+/// address.
+pub struct TimerMemory {
+    regs: VecMemory,
+    increment: TimerIncrement,
+}
+
+impl TimerMemory {
+    pub fn new(initial_time: u64, increment: TimerIncrement) -> Self {
+        TimerMemory {
+            regs: VecMemory::new(vec![initial_time, u64::MAX]),
+            increment,
+        }
+    }
+
+    /// The current value of `mtime`
+    pub fn time(&self) -> u64 {
+        self.regs.mem[0]
+    }
+
+    /// Overwrite `mtime`
+    pub fn set_time(&mut self, time: u64) {
+        self.regs.mem[0] = time;
+    }
+
+    /// The current value of `mtimecmp`, initially `u64::MAX`
+    pub fn mtimecmp(&self) -> u64 {
+        self.regs.mem[1]
+    }
+
+    /// Advance `mtime` by `cycles` under [TimerIncrement::PerCycle], otherwise do nothing.
+    pub fn tick(&mut self, cycles: u64) {
+        if self.increment == TimerIncrement::PerCycle {
+            self.set_time(self.time().wrapping_add(cycles));
+        }
+    }
+}
+
+impl Memory for TimerMemory {
+    fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
+        let data = self.regs.read_mem(addr, size)?;
+
+        if let TimerIncrement::PerRead(amount) = self.increment {
+            if addr < TIMER_MTIMECMP_OFFSET {
+                self.set_time(self.time().wrapping_add(amount));
+            }
+        }
+
+        Some(data)
+    }
+
+    fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        self.regs.write_mem(addr, size, store_data)
+    }
+
+    // Registers can't hold instructions
+    fn read_insn(&mut self, _addr: u64) -> Option<u64> {
+        None
+    }
+
+    fn is_volatile(&self, _addr: u64) -> bool {
+        true
+    }
+}
+
+/// The type of a [MemAccess]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessType {
+    /// A [Memory::read_mem]
+    Read,
+    /// A [Memory::write_mem]
+    Write,
+    /// A [Memory::read_insn]
+    Fetch,
+}
+
+/// A memory access observed by an [InstrumentedMemory]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemAccess {
+    pub access_type: AccessType,
+    pub addr: u64,
+    pub size: MemAccessSize,
+    /// The data read or written, `None` if the access failed
+    pub value: Option<u64>,
+}
+
+/// A [Memory] wrapping another, calling a callback with every access made to it.
+///
+/// Accesses are forwarded to the inner memory first and the callback sees their result, so it
+/// observes failed accesses too. Instruction fetches are reported as [AccessType::Fetch] rather
+/// than as reads. As it is just another [Memory] it can instrument a whole [MemorySpace] or be
+/// added to one to instrument a single region.
+pub struct InstrumentedMemory<M: Memory> {
+    pub inner: M,
+    callback: Box<dyn FnMut(&MemAccess)>,
+}
+
+impl<M: Memory> InstrumentedMemory<M> {
+    pub fn new(inner: M, callback: impl FnMut(&MemAccess) + 'static) -> Self {
+        InstrumentedMemory {
+            inner,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Remove the instrumentation, returning the inner memory
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    fn report(
+        &mut self,
+        access_type: AccessType,
+        addr: u64,
+        size: MemAccessSize,
+        value: Option<u64>,
+    ) {
+        (self.callback)(&MemAccess {
+            access_type,
+            addr,
+            size,
+            value,
+        });
+    }
+}
+
+impl<M: Memory> Memory for InstrumentedMemory<M> {
+    fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
+        let value = self.inner.read_mem(addr, size);
+        self.report(AccessType::Read, addr, size, value);
+        value
+    }
+
+    fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        let success = self.inner.write_mem(addr, size, store_data);
+        self.report(AccessType::Write, addr, size, success.then_some(store_data));
+        success
+    }
+
+    fn read_insn(&mut self, addr: u64) -> Option<u64> {
+        let value = self.inner.read_insn(addr);
+        self.report(AccessType::Fetch, addr, MemAccessSize::Word, value);
+        value
+    }
+
+    fn supports_misaligned(&self) -> bool {
+        self.inner.supports_misaligned()
+    }
+
+    fn is_volatile(&self, addr: u64) -> bool {
+        self.inner.is_volatile(addr)
+    }
+}
+
+/// A [Memory] wrapping another, calling a callback the first time a read hits an address that has
+/// never been written.
+///
+/// Reads still return whatever the inner memory holds (usually 0), the callback only reports them.
+/// Each uninitialized read address is reported once. Anything written through the memory counts as
+/// initialized, including an image loaded with [read_to_memory].
+/// [UninitTrackingMemory::mark_written] marks contents the inner memory was constructed with.
+pub struct UninitTrackingMemory<M: Memory> {
+    pub inner: M,
+    callback: Box<dyn FnMut(&MemAccess)>,
+    /// Written byte ranges as start -> end (exclusive), merged so none touch
+    written: BTreeMap<u64, u64>,
+    reported: BTreeSet<u64>,
+}
+
+impl<M: Memory> UninitTrackingMemory<M> {
+    pub fn new(inner: M, callback: impl FnMut(&MemAccess) + 'static) -> Self {
+        UninitTrackingMemory {
+            inner,
+            callback: Box::new(callback),
+            written: BTreeMap::new(),
+            reported: BTreeSet::new(),
+        }
+    }
+
+    /// Remove the tracking, returning the inner memory
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Treat the `len` bytes from `addr` as written
+    pub fn mark_written(&mut self, addr: u64, len: u64) {
+        let mut start = addr;
+        let mut end = addr.saturating_add(len);
+        if start == end {
+            return;
+        }
+
+        // Absorb every range overlapping or adjacent to the new one
+        let touching: Vec<(u64, u64)> = self
+            .written
+            .range(..=end)
+            .rev()
+            .take_while(|(_, &range_end)| range_end >= start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect();
+        for (range_start, range_end) in touching {
+            self.written.remove(&range_start);
+            start = start.min(range_start);
+            end = end.max(range_end);
+        }
+        self.written.insert(start, end);
+    }
+
+    /// Whether all `len` bytes from `addr` have been written
+    pub fn is_written(&self, addr: u64, len: u64) -> bool {
+        match self.written.range(..=addr).next_back() {
+            Some((_, &end)) => end >= addr.saturating_add(len),
+            None => false,
+        }
+    }
+}
+
+impl<M: Memory> Memory for UninitTrackingMemory<M> {
+    fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
+        let value = self.inner.read_mem(addr, size);
+        if value.is_some()
+            && !self.is_written(addr, access_bytes(size))
+            && self.reported.insert(addr)
+        {
+            (self.callback)(&MemAccess {
+                access_type: AccessType::Read,
+                addr,
+                size,
+                value,
+            });
+        }
+        value
+    }
+
+    fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        let success = self.inner.write_mem(addr, size, store_data);
+        if success {
+            self.mark_written(addr, access_bytes(size));
+        }
+        success
+    }
+
+    fn read_insn(&mut self, addr: u64) -> Option<u64> {
+        self.inner.read_insn(addr)
+    }
+
+    fn supports_misaligned(&self) -> bool {
+        self.inner.supports_misaligned()
+    }
+
+    fn is_volatile(&self, addr: u64) -> bool {
+        self.inner.is_volatile(addr)
+    }
+}
+
+/// A copy-on-write [Memory] layering private writes over a shared base memory.
+///
+/// Writes only go to the overlay, which holds whole 64-bit words keyed by their aligned address.
+/// Reads come from the overlay where it has the word and otherwise fall through to the base. Many
+/// [OverlayMemory]s can share one large base image, e.g. to fork execution into several
+/// continuations, without copying it.
+///
+/// The base is behind a [Mutex] as [Memory::read_mem] takes `&mut self`, it is never written
+/// through an [OverlayMemory]. A write must be able to read the enclosing word from the base
+/// first, so writes fail where the base has no memory. Accesses must be aligned.
+pub struct OverlayMemory<M: Memory> {
+    base: Arc<Mutex<M>>,
+    overlay: BTreeMap<u64, u64>,
+}
+
+impl<M: Memory> OverlayMemory<M> {
+    pub fn new(base: Arc<Mutex<M>>) -> Self {
+        OverlayMemory {
+            base,
+            overlay: BTreeMap::new(),
+        }
+    }
+
+    /// The shared base memory
+    pub fn base(&self) -> &Arc<Mutex<M>> {
+        &self.base
+    }
+
+    /// The words written through this memory, keyed by their 8 byte aligned address
+    pub fn overlay(&self) -> &BTreeMap<u64, u64> {
+        &self.overlay
+    }
+
+    // The shift and mask selecting an access of `size` at `addr` from its enclosing word
+    fn shift_mask(addr: u64, size: MemAccessSize) -> (u64, u64) {
+        let (align, mask) = match size {
+            MemAccessSize::Byte => (0x7, 0xff),
+            MemAccessSize::HalfWord => (0x6, 0xffff),
+            MemAccessSize::Word => (0x4, 0xffffffff),
+            MemAccessSize::DoubleWord => (0x0, 0xffffffffffffffff),
+        };
+
+        if (addr & 0x7) != (addr & align) {
+            panic!("Memory access must be aligned");
+        }
+
+        ((addr & align) * 8, mask)
+    }
+}
+
+impl<M: Memory> Memory for OverlayMemory<M> {
+    fn read_mem(&mut self, addr: u64, size: MemAccessSize) -> Option<u64> {
+        let (shift, mask) = Self::shift_mask(addr, size);
+        match self.overlay.get(&(addr & !0x7)) {
+            Some(word) => Some((word >> shift) & mask),
+            None => self.base.lock().unwrap().read_mem(addr, size),
+        }
+    }
+
+    fn write_mem(&mut self, addr: u64, size: MemAccessSize, store_data: u64) -> bool {
+        let (shift, mask) = Self::shift_mask(addr, size);
+        let word_addr = addr & !0x7;
+        let word = match self.overlay.get(&word_addr) {
+            Some(word) => *word,
+            None => match self
+                .base
+                .lock()
+                .unwrap()
+                .read_mem(word_addr, MemAccessSize::DoubleWord)
+            {
+                Some(word) => word,
+                None => return false,
+            },
+        };
+
+        let word = (word & !(mask << shift)) | ((store_data & mask) << shift);
+        self.overlay.insert(word_addr, word);
+        true
+    }
+
+    fn read_insn(&mut self, addr: u64) -> Option<u64> {
+        // The base decides whether `addr` is executable
+        let insn = self.base.lock().unwrap().read_insn(addr)?;
+        match self.overlay.get(&(addr & !0x7)) {
+            Some(word) => Some((word >> ((addr & 0x4) * 8)) & 0xffffffff),
+            None => Some(insn),
         }
     }
 }
@@ -291,6 +961,129 @@ mod tests {
         assert_eq!(test_mem.write_mem(0x10, MemAccessSize::Word, 0x0), false);
     }
 
+    #[test]
+    fn test_vec_memory_misaligned() {
+        let mut test_mem = VecMemory::new(vec![0x8877665544332211, 0x00ffeeddccbbaa99]);
+        assert!(!test_mem.supports_misaligned());
+
+        test_mem.allow_misaligned(true);
+        assert!(test_mem.supports_misaligned());
+
+        assert_eq!(
+            test_mem.read_mem(0x6, MemAccessSize::Word),
+            Some(0xaa998877)
+        );
+
+        assert_eq!(
+            test_mem.read_mem(0x3, MemAccessSize::DoubleWord),
+            Some(0xbbaa998877665544)
+        );
+
+        assert!(test_mem.write_mem(0x7, MemAccessSize::HalfWord, 0x1234));
+
+        assert_eq!(test_mem.mem, vec![0x3477665544332211, 0x00ffeeddccbbaa12]);
+
+        // Accesses running off the end fail without a partial write
+        assert_eq!(test_mem.read_mem(0xe, MemAccessSize::Word), None);
+        assert!(!test_mem.write_mem(0xe, MemAccessSize::Word, 0x0));
+        assert_eq!(test_mem.mem[1], 0x00ffeeddccbbaa12);
+    }
+
+    #[test]
+    fn test_memory_space_misaligned() {
+        let mut mem_space = MemorySpace::new();
+        assert!(!mem_space.supports_misaligned());
+
+        let mut misaligned_mem = VecMemory::new(vec![0; 2]);
+        misaligned_mem.allow_misaligned(true);
+        mem_space
+            .add_memory(0x0, 0x10, Box::new(misaligned_mem))
+            .unwrap();
+        assert!(mem_space.supports_misaligned());
+
+        mem_space
+            .add_memory(0x10, 0x10, Box::new(VecMemory::new(vec![0; 2])))
+            .unwrap();
+        assert!(!mem_space.supports_misaligned());
+    }
+
+    #[test]
+    fn test_timer_memory() {
+        const MTIME: u64 = 0x0200_bff8;
+
+        let mut mem_space = MemorySpace::new();
+        mem_space
+            .add_memory(
+                MTIME,
+                TIMER_SIZE,
+                Box::new(TimerMemory::new(100, TimerIncrement::PerRead(10))),
+            )
+            .unwrap();
+
+        let first = mem_space
+            .read_mem(MTIME, MemAccessSize::DoubleWord)
+            .unwrap();
+        let second = mem_space
+            .read_mem(MTIME, MemAccessSize::DoubleWord)
+            .unwrap();
+        assert_eq!(first, 100);
+        assert!(second > first);
+        // Reading mtimecmp doesn't advance mtime
+        assert_eq!(
+            mem_space.read_mem(MTIME + 8, MemAccessSize::DoubleWord),
+            Some(u64::MAX)
+        );
+        assert_eq!(mem_space.read_mem(MTIME, MemAccessSize::Word), Some(120));
+
+        assert!(mem_space.write_mem(MTIME + 8, MemAccessSize::Word, 0x1234));
+        assert!(mem_space.write_mem(MTIME + 12, MemAccessSize::Word, 0));
+        assert_eq!(
+            mem_space
+                .get_memory_ref::<TimerMemory>(0)
+                .unwrap()
+                .mtimecmp(),
+            0x1234
+        );
+        assert_eq!(mem_space.read_insn(MTIME), None);
+
+        let mut timer = TimerMemory::new(5, TimerIncrement::PerCycle);
+        assert_eq!(timer.read_mem(0, MemAccessSize::DoubleWord), Some(5));
+        assert_eq!(timer.read_mem(0, MemAccessSize::DoubleWord), Some(5));
+        timer.tick(3);
+        assert_eq!(timer.read_mem(0, MemAccessSize::DoubleWord), Some(8));
+
+        let mut timer = TimerMemory::new(5, TimerIncrement::Fixed);
+        timer.tick(3);
+        assert_eq!(timer.read_mem(0, MemAccessSize::DoubleWord), Some(5));
+        assert!(timer.write_mem(0, MemAccessSize::DoubleWord, 42));
+        assert_eq!(timer.time(), 42);
+        assert_eq!(timer.read_mem(0x10, MemAccessSize::DoubleWord), None);
+    }
+
+    #[test]
+    fn test_vec_memory_bytes() {
+        let mut test_mem = VecMemory::new(vec![0; 2]);
+        assert!(test_mem.write_mem(0x0, MemAccessSize::Word, 0x44332211));
+        assert!(test_mem.write_mem(0x6, MemAccessSize::HalfWord, 0x8877));
+        assert!(test_mem.write_mem(0x8, MemAccessSize::DoubleWord, 0xffeeddccbbaa9900));
+
+        let expected = [
+            0x11, 0x22, 0x33, 0x44, 0x00, 0x00, 0x77, 0x88, 0x00, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        #[cfg(target_endian = "little")]
+        assert_eq!(test_mem.as_bytes(), &expected);
+
+        assert_eq!(test_mem.copy_out(0x0, 16), Some(expected.to_vec()));
+        assert_eq!(
+            test_mem.copy_out(0x6, 4),
+            Some(vec![0x77, 0x88, 0x00, 0x99])
+        );
+        assert_eq!(test_mem.copy_out(0x10, 0), Some(vec![]));
+        assert_eq!(test_mem.copy_out(0xe, 4), None);
+        assert_eq!(test_mem.copy_out(u64::MAX, 2), None);
+    }
+
     struct TestMemory;
 
     impl Memory for TestMemory {
@@ -401,6 +1194,134 @@ mod tests {
         assert_eq!(test_mem_space.read_mem(0x30008, MemAccessSize::Word), None);
     }
 
+    #[test]
+    fn test_memory_space_is_mapped() {
+        let mut test_mem_space = MemorySpace::new();
+        assert!(!test_mem_space.is_mapped(0));
+
+        assert_eq!(
+            test_mem_space.add_memory_with_perms(
+                0x1000,
+                0x20,
+                Box::new(VecMemory::new(vec![0; 4])),
+                Permissions::READ_EXECUTE
+            ),
+            Ok(0)
+        );
+        assert_eq!(
+            test_mem_space.add_memory_with_perms(
+                0x1020,
+                0x20,
+                Box::new(VecMemory::new(vec![0; 4])),
+                Permissions::READ_WRITE
+            ),
+            Ok(1)
+        );
+
+        assert!(test_mem_space.is_mapped(0x1000) && test_mem_space.is_executable(0x1000));
+        assert!(test_mem_space.is_executable(0x101f));
+        assert!(test_mem_space.is_mapped(0x1020) && !test_mem_space.is_executable(0x1020));
+        assert!(test_mem_space.is_mapped(0x103f));
+
+        // Past the last region nothing is mapped
+        assert!(!test_mem_space.is_mapped(0x1040) && !test_mem_space.is_executable(0x1040));
+        assert!(!test_mem_space.is_mapped(0xfff));
+        assert!(!test_mem_space.is_mapped(u64::MAX));
+    }
+
+    #[test]
+    fn test_memory_space_load_image() {
+        let mut test_mem_space = MemorySpace::new();
+        assert_eq!(
+            test_mem_space.add_memory_with_perms(
+                0x1000,
+                0x10,
+                Box::new(VecMemory::new(vec![0; 2])),
+                Permissions::READ_EXECUTE
+            ),
+            Ok(0)
+        );
+        assert_eq!(
+            test_mem_space.add_memory(0x2000, 0x10, Box::new(VecMemory::new(vec![0; 2]))),
+            Ok(1)
+        );
+
+        let image = BTreeMap::from([(0x1000, 0x00000073), (0x100c, 0xdeadbeef), (0x2004, 42)]);
+        assert_eq!(test_mem_space.load_image(&image), Ok(()));
+        // Text is loaded although it isn't writable
+        assert_eq!(test_mem_space.read_insn(0x1000), Some(0x73));
+        assert_eq!(
+            test_mem_space.read_mem(0x100c, MemAccessSize::Word),
+            Some(0xdeadbeef)
+        );
+        assert_eq!(
+            test_mem_space.read_mem(0x2004, MemAccessSize::Word),
+            Some(42)
+        );
+
+        // A word past the end of a region, or straddling it, is unmapped and nothing is written
+        let image = BTreeMap::from([(0x2000, 1), (0x100e, 2), (0x3000, 3)]);
+        assert_eq!(
+            test_mem_space.load_image(&image),
+            Err(MemorySpaceError::Unmapped(vec![0x100e, 0x3000]))
+        );
+        assert_eq!(
+            test_mem_space.read_mem(0x2000, MemAccessSize::Word),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_memory_space_rel() {
+        let mut test_mem_space = MemorySpace::new();
+
+        assert_eq!(
+            test_mem_space.add_memory(0x1000, 0x20, Box::new(VecMemory::new(vec![0; 4]))),
+            Ok(0)
+        );
+        assert_eq!(
+            test_mem_space.add_memory(0xd000000000, 0x20, Box::new(VecMemory::new(vec![0; 4]))),
+            Ok(1)
+        );
+
+        assert!(test_mem_space.write_rel(0xd000000000, 0x10, MemAccessSize::Word, 0xdeadbeef));
+        assert_eq!(
+            test_mem_space.read_rel(0xd000000000, 0x10, MemAccessSize::Word),
+            Some(0xdeadbeef)
+        );
+        assert_eq!(
+            test_mem_space.read_mem(0xd000000010, MemAccessSize::Word),
+            Some(0xdeadbeef)
+        );
+        assert_eq!(
+            test_mem_space.read_rel(0xd000000000, 0x12, MemAccessSize::HalfWord),
+            Some(0xdead)
+        );
+        // The low region is untouched
+        assert_eq!(
+            test_mem_space.read_rel(0x1000, 0x10, MemAccessSize::Word),
+            Some(0)
+        );
+
+        // Offsets must stay within the region even where another region follows
+        assert_eq!(
+            test_mem_space.read_rel(0xd000000000, 0x1c, MemAccessSize::DoubleWord),
+            None
+        );
+        assert_eq!(
+            test_mem_space.read_rel(0xd000000000, u64::MAX, MemAccessSize::Byte),
+            None
+        );
+        assert!(!test_mem_space.write_rel(0xd000000000, 0x20, MemAccessSize::Byte, 0));
+
+        // The base must be the start of a region
+        assert_eq!(
+            test_mem_space.read_rel(0xd000000008, 0x8, MemAccessSize::Word),
+            None
+        );
+        assert!(!test_mem_space.write_rel(0x2000, 0, MemAccessSize::Word, 0));
+    }
+
     #[test]
     fn test_read_to_memory() {
         let test_bytes: Vec<u8> = (5..21).collect();
@@ -431,4 +1352,121 @@ mod tests {
             "\"Could not write byte at address 0x00000010\""
         );
     }
+
+    #[test]
+    fn test_instrumented_memory() {
+        use crate::asm::{lw, sw};
+        use crate::instruction_executor::InstructionExecutor;
+        use crate::HartState;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&accesses);
+        let insns = [sw(1, 0, 0x20), lw(2, 0, 0x20), lw(3, 0, 0x100)];
+        let mut mem = InstrumentedMemory::new(
+            VecMemory::new(vec![
+                (insns[1] as u64) << 32 | insns[0] as u64,
+                insns[2] as u64,
+                0,
+                0,
+                0,
+            ]),
+            move |access: &MemAccess| seen.borrow_mut().push(*access),
+        );
+        let mut hart = HartState::new();
+        hart.registers[1] = 0x1234;
+
+        let mut executor = InstructionExecutor::new(&mut mem, &mut hart);
+        assert_eq!(executor.step(), Ok(()));
+        assert_eq!(executor.step(), Ok(()));
+        assert!(executor.step().is_err());
+
+        let access = |access_type, addr, value| MemAccess {
+            access_type,
+            addr,
+            size: MemAccessSize::Word,
+            value,
+        };
+        assert_eq!(
+            *accesses.borrow(),
+            vec![
+                access(AccessType::Fetch, 0x0, Some(insns[0] as u64)),
+                access(AccessType::Write, 0x20, Some(0x1234)),
+                access(AccessType::Fetch, 0x4, Some(insns[1] as u64)),
+                access(AccessType::Read, 0x20, Some(0x1234)),
+                access(AccessType::Fetch, 0x8, Some(insns[2] as u64)),
+                access(AccessType::Read, 0x100, None),
+            ]
+        );
+        assert_eq!(mem.into_inner().mem[4], 0x1234);
+    }
+
+    #[test]
+    fn test_uninit_tracking_memory() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&reads);
+        let mut mem = UninitTrackingMemory::new(VecMemory::new(vec![0; 4]), move |access| {
+            seen.borrow_mut().push((access.addr, access.value))
+        });
+
+        // A loaded image counts as written
+        assert!(read_to_memory([1u8, 2, 3, 4].as_slice(), &mut mem, 0).is_ok());
+        assert!(mem.write_mem(0x10, MemAccessSize::Byte, 0xff));
+        mem.mark_written(0x4, 4);
+
+        assert_eq!(
+            mem.read_mem(0x0, MemAccessSize::DoubleWord),
+            Some(0x04030201)
+        );
+        assert_eq!(mem.read_mem(0x10, MemAccessSize::Byte), Some(0xff));
+        assert!(reads.borrow().is_empty());
+
+        // Partly initialized, then never written
+        assert_eq!(mem.read_mem(0x10, MemAccessSize::HalfWord), Some(0xff));
+        assert_eq!(mem.read_mem(0x18, MemAccessSize::DoubleWord), Some(0));
+        assert_eq!(mem.read_mem(0x18, MemAccessSize::DoubleWord), Some(0));
+        // Out of range reads fail rather than being reported
+        assert_eq!(mem.read_mem(0x20, MemAccessSize::Byte), None);
+        assert_eq!(*reads.borrow(), vec![(0x10, Some(0xff)), (0x18, Some(0))]);
+
+        assert!(mem.write_mem(0x1c, MemAccessSize::Word, 0));
+        assert!(mem.write_mem(0x18, MemAccessSize::Word, 0));
+        assert!(mem.is_written(0x18, 8));
+        assert!(!mem.is_written(0x11, 1));
+    }
+
+    #[test]
+    fn test_overlay_memory() {
+        let base = Arc::new(Mutex::new(VecMemory::new(vec![
+            0xdeadbeefdeadbeef,
+            0xbaadf00dbaadf00d,
+        ])));
+        let mut first = OverlayMemory::new(Arc::clone(&base));
+        let mut second = OverlayMemory::new(Arc::clone(&base));
+
+        assert!(first.write_mem(0x2, MemAccessSize::HalfWord, 0xface));
+        assert!(first.write_mem(0x8, MemAccessSize::Byte, 0x12));
+        assert_eq!(
+            first.read_mem(0x0, MemAccessSize::DoubleWord),
+            Some(0xdeadbeeffacebeef)
+        );
+        assert_eq!(first.read_mem(0x8, MemAccessSize::Word), Some(0xbaadf012));
+        assert_eq!(first.read_insn(0x0), Some(0xfacebeef));
+        assert_eq!(first.overlay().len(), 2);
+
+        // The base and the other overlay are unchanged
+        assert_eq!(
+            base.lock().unwrap().mem,
+            [0xdeadbeefdeadbeef, 0xbaadf00dbaadf00d]
+        );
+        assert_eq!(second.read_mem(0x0, MemAccessSize::Word), Some(0xdeadbeef));
+
+        // Writes outside the base fail
+        assert!(!first.write_mem(0x10, MemAccessSize::Word, 0));
+        assert_eq!(first.read_mem(0x10, MemAccessSize::Word), None);
+    }
 }
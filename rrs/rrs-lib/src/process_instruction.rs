@@ -120,10 +120,13 @@ fn process_opcode_system<T: InstructionProcessor>(
     processor: &mut T,
     insn_bits: u32,
 ) -> Option<T::InstructionResult> {
-    let dec_insn = instruction_formats::CType::new(insn_bits);
+    let dec_insn = instruction_formats::CsrType::new(insn_bits);
+    let funct3 = (insn_bits >> 12) & 0x7;
 
-    match dec_insn.funct3 {
-        0b010 => Some(processor.process_rdtime(dec_insn)),
+    // Only rdtime (csrrs rd, time, x0) is supported, any other CSR access is illegal. A non-zero
+    // rs1 would write the read-only time CSR so is illegal too.
+    match (funct3, dec_insn.csr, dec_insn.rs1_or_zimm) {
+        (0b010, instruction_formats::CSR_TIME, 0) => Some(processor.process_rdtime(dec_insn)),
         _ => None,
     }
 }
@@ -145,6 +148,18 @@ fn process_opcode_amo<T: InstructionProcessor>(
         (0b010, 0b01000) => Some(processor.process_amoorw(dec_insn)),
         (0b010, 0b01100) => Some(processor.process_amoandw(dec_insn)),
         (0b010, 0b00000) => Some(processor.process_amoaddw(dec_insn)),
+        (0b010, 0b00100) => Some(processor.process_amoxorw(dec_insn)),
+        (0b010, 0b10000) => Some(processor.process_amominw(dec_insn)),
+        (0b010, 0b10100) => Some(processor.process_amomaxw(dec_insn)),
+        (0b010, 0b11000) => Some(processor.process_amominuw(dec_insn)),
+        (0b010, 0b11100) => Some(processor.process_amomaxuw(dec_insn)),
+        (0b011, 0b00100) => Some(processor.process_amoxord(dec_insn)),
+        (0b011, 0b01000) => Some(processor.process_amoord(dec_insn)),
+        (0b011, 0b01100) => Some(processor.process_amoandd(dec_insn)),
+        (0b011, 0b10000) => Some(processor.process_amomind(dec_insn)),
+        (0b011, 0b10100) => Some(processor.process_amomaxd(dec_insn)),
+        (0b011, 0b11000) => Some(processor.process_amominud(dec_insn)),
+        (0b011, 0b11100) => Some(processor.process_amomaxud(dec_insn)),
         _ => None,
     }
 }
@@ -226,6 +241,28 @@ fn process_opcode_store<T: InstructionProcessor>(
     }
 }
 
+fn process_opcode_op_fp<T: InstructionProcessor>(
+    processor: &mut T,
+    insn_bits: u32,
+) -> Option<T::InstructionResult> {
+    let dec_insn = instruction_formats::RType::new(insn_bits);
+
+    // funct3 holds the rounding mode, 0b101 and 0b110 are reserved
+    if dec_insn.funct3 == 0b101 || dec_insn.funct3 == 0b110 {
+        return None;
+    }
+
+    match (dec_insn.funct7, dec_insn.rs2) {
+        (0b000_0000, _) => Some(processor.process_fadd_s(dec_insn)),
+        (0b000_0100, _) => Some(processor.process_fsub_s(dec_insn)),
+        (0b000_1000, _) => Some(processor.process_fmul_s(dec_insn)),
+        (0b000_1100, _) => Some(processor.process_fdiv_s(dec_insn)),
+        (0b110_0000, 0) => Some(processor.process_fcvt_w_s(dec_insn)),
+        (0b110_1000, 0) => Some(processor.process_fcvt_s_w(dec_insn)),
+        _ => None,
+    }
+}
+
 /// Decodes instruction in `insn_bits` calling the appropriate function in `processor` returning
 /// the result it produces.
 ///
@@ -258,6 +295,21 @@ pub fn process_instruction<T: InstructionProcessor>(
         instruction_formats::OPCODE_BRANCH => process_opcode_branch(processor, insn_bits),
         instruction_formats::OPCODE_LOAD => process_opcode_load(processor, insn_bits),
         instruction_formats::OPCODE_STORE => process_opcode_store(processor, insn_bits),
+        instruction_formats::OPCODE_LOAD_FP => {
+            let dec_insn = instruction_formats::IType::new(insn_bits);
+            match dec_insn.funct3 {
+                0b010 => Some(processor.process_flw(dec_insn)),
+                _ => None,
+            }
+        }
+        instruction_formats::OPCODE_STORE_FP => {
+            let dec_insn = instruction_formats::SType::new(insn_bits);
+            match dec_insn.funct3 {
+                0b010 => Some(processor.process_fsw(dec_insn)),
+                _ => None,
+            }
+        }
+        instruction_formats::OPCODE_OP_FP => process_opcode_op_fp(processor, insn_bits),
         instruction_formats::OPCODE_JAL => {
             Some(processor.process_jal(instruction_formats::JType::new(insn_bits)))
         }
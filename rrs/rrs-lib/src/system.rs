@@ -0,0 +1,259 @@
+// Copyright 2021 Gregory Chadwick <mail@gregchadwick.co.uk>
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A multi-hart (SMP) system built from several [HartState]s sharing one [Memory].
+//!
+//! [System::step] executes a single instruction on one hart, picked by the [Schedule]. After each
+//! step any LR reservation held by another hart on the granule that was just written is cleared, so
+//! an SC racing with a store from another hart fails as the A extension requires.
+//!
+//! Scheduling is deterministic: [Schedule::RoundRobin] steps each hart in turn and
+//! [Schedule::Seeded] picks harts with a PRNG seeded by the caller, so an interleaving can be
+//! reproduced by reusing the seed.
+
+use super::instruction_executor::{InstructionException, InstructionExecutor};
+use super::{HartState, Memory};
+
+/// Reservations placed by LR cover the naturally aligned doubleword containing the address.
+const RESERVATION_GRANULE_MASK: u64 = !0x7;
+
+/// One step of the SplitMix64 generator from `seed`, used to spread nearby seeds over the xorshift
+/// state space.
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// How [System::step] picks the next hart to execute
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    /// Step harts in index order, wrapping around after the last one
+    RoundRobin,
+    /// Step a pseudo-randomly chosen hart. The same seed always gives the same interleaving.
+    Seeded(u64),
+}
+
+/// An exception raised by one of the harts of a [System]
+#[derive(Debug, PartialEq)]
+pub struct HartException {
+    /// Index of the hart that raised the exception
+    pub hart: usize,
+    /// The exception the hart's instruction raised
+    pub exception: InstructionException,
+}
+
+/// A set of harts sharing a single memory
+pub struct System<M: Memory> {
+    /// State of each hart, indexed by hart number
+    pub harts: Vec<HartState>,
+    /// Memory shared by all the harts
+    pub mem: M,
+    schedule: Schedule,
+    next_hart: usize,
+    rng_state: u64,
+}
+
+impl<M: Memory> System<M> {
+    /// Create a system that steps `harts` in round-robin order.
+    pub fn new(harts: Vec<HartState>, mem: M) -> Self {
+        Self::with_schedule(harts, mem, Schedule::RoundRobin)
+    }
+
+    /// Create a system that steps `harts` according to `schedule`.
+    pub fn with_schedule(harts: Vec<HartState>, mem: M, schedule: Schedule) -> Self {
+        let rng_state = match schedule {
+            Schedule::Seeded(seed) => match splitmix64(seed) {
+                // xorshift must never be seeded with 0, it would stay 0 forever
+                0 => 0x9e37_79b9_7f4a_7c15,
+                state => state,
+            },
+            Schedule::RoundRobin => 0,
+        };
+
+        System {
+            harts,
+            mem,
+            schedule,
+            next_hart: 0,
+            rng_state,
+        }
+    }
+
+    fn pick_hart(&mut self) -> Option<usize> {
+        if self.harts.is_empty() {
+            return None;
+        }
+
+        let hart = match self.schedule {
+            Schedule::RoundRobin => {
+                let hart = self.next_hart;
+                self.next_hart = (self.next_hart + 1) % self.harts.len();
+                hart
+            }
+            Schedule::Seeded(_) => {
+                // xorshift64
+                self.rng_state ^= self.rng_state << 13;
+                self.rng_state ^= self.rng_state >> 7;
+                self.rng_state ^= self.rng_state << 17;
+                (self.rng_state % self.harts.len() as u64) as usize
+            }
+        };
+
+        Some(hart)
+    }
+
+    /// Execute one instruction on the next scheduled hart.
+    ///
+    /// Returns the index of the hart that was stepped, `None` if the system has no harts, or a
+    /// [HartException] naming the hart whose instruction raised an exception.
+    pub fn step(&mut self) -> Result<Option<usize>, HartException> {
+        match self.pick_hart() {
+            Some(hart) => {
+                self.step_hart(hart)?;
+                Ok(Some(hart))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Execute one instruction on hart `hart`, regardless of the schedule.
+    pub fn step_hart(&mut self, hart: usize) -> Result<(), HartException> {
+        InstructionExecutor::new(&mut self.mem, &mut self.harts[hart])
+            .step()
+            .map_err(|exception| HartException { hart, exception })?;
+
+        if let Some(addr) = self.harts[hart].last_mem_write {
+            let granule = addr & RESERVATION_GRANULE_MASK;
+            for (idx, other) in self.harts.iter_mut().enumerate() {
+                if idx == hart {
+                    continue;
+                }
+
+                if other.reservation.map(|r| r & RESERVATION_GRANULE_MASK) == Some(granule) {
+                    other.reservation = None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memories::VecMemory;
+    use crate::MemAccessSize;
+
+    const LOCK_ADDR: u64 = 0x100;
+    const COUNTER_ADDR: u64 = 0x108;
+    const DONE_PC: u64 = 0x24;
+
+    // Each hart takes a spinlock at LOCK_ADDR with LR/SC, increments the counter at COUNTER_ADDR
+    // and releases the lock:
+    //
+    // addi x5, x0, 1
+    // acquire: lr.w x6, (x10)
+    // bne x6, x0, acquire
+    // sc.w x6, x5, (x10)
+    // bne x6, x0, acquire
+    // lw x7, 0(x11)
+    // addi x7, x7, 1
+    // sw x7, 0(x11)
+    // sw x0, 0(x10)
+    // done: jal x0, done
+    fn spinlock_system(schedule: Schedule) -> System<VecMemory> {
+        let mut mem = vec![0; 0x110 / 8];
+        mem[..5].copy_from_slice(&[
+            0x1005232f_00100293,
+            0x1855232f_fe031ee3,
+            0x0005a383_fe031ae3,
+            0x0075a023_00138393,
+            0x0000006f_00052023,
+        ]);
+
+        let harts = (0..2)
+            .map(|_| {
+                let mut hart = HartState::new();
+                hart.registers[10] = LOCK_ADDR;
+                hart.registers[11] = COUNTER_ADDR;
+                hart
+            })
+            .collect();
+
+        System::with_schedule(harts, VecMemory::new(mem), schedule)
+    }
+
+    fn run_to_done(system: &mut System<VecMemory>) -> Vec<usize> {
+        let mut trace = Vec::new();
+        while system.harts.iter().any(|h| h.pc != DONE_PC) {
+            assert!(trace.len() < 1000, "harts failed to finish");
+            trace.push(system.step().unwrap().unwrap());
+        }
+
+        trace
+    }
+
+    #[test]
+    fn test_spinlock_handoff() {
+        let mut system = spinlock_system(Schedule::RoundRobin);
+        run_to_done(&mut system);
+
+        assert_eq!(
+            system.mem.read_mem(COUNTER_ADDR, MemAccessSize::Word),
+            Some(2)
+        );
+        assert_eq!(system.mem.read_mem(LOCK_ADDR, MemAccessSize::Word), Some(0));
+
+        for seed in 0..16 {
+            let mut system = spinlock_system(Schedule::Seeded(seed));
+            let trace = run_to_done(&mut system);
+
+            assert_eq!(
+                system.mem.read_mem(COUNTER_ADDR, MemAccessSize::Word),
+                Some(2)
+            );
+
+            // Same seed must reproduce the same interleaving
+            let mut replay = spinlock_system(Schedule::Seeded(seed));
+            assert_eq!(run_to_done(&mut replay), trace);
+        }
+    }
+
+    #[test]
+    fn test_seed_never_stalls_rng() {
+        // The seed that used to fold to a zero xorshift state, which always picked hart 0, and the
+        // seed SplitMix64 maps to 0
+        for seed in [0x9e37_79b9_7f4a_7c15, 0x61c8_8646_80b5_83eb] {
+            let mut system = spinlock_system(Schedule::Seeded(seed));
+            let picks: Vec<_> = (0..16).map(|_| system.pick_hart().unwrap()).collect();
+            assert!(picks.contains(&1), "seed {:#x} only picked hart 0", seed);
+        }
+    }
+
+    #[test]
+    fn test_step_no_harts() {
+        for schedule in [Schedule::RoundRobin, Schedule::Seeded(1)] {
+            let mut system = System::with_schedule(Vec::new(), VecMemory::new(vec![0]), schedule);
+            assert_eq!(system.step(), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_store_clears_other_reservation() {
+        let mut system = spinlock_system(Schedule::RoundRobin);
+
+        // Both harts load the free lock, hart 0 wins the SC which must fail hart 1's SC
+        for hart in [0, 1, 0, 1, 0, 0, 1, 1, 1] {
+            system.step_hart(hart).unwrap();
+        }
+
+        assert_eq!(system.harts[0].registers[6], 0);
+        assert_eq!(system.harts[1].registers[6], 1);
+        // Hart 1 goes back round to retry the acquire
+        assert_eq!(system.harts[1].pc, 0x4);
+    }
+}